@@ -1,6 +1,6 @@
 use config::Config;
 
-use crate::ArbitrageResult;
+use crate::{ArbitrageResult, HealthRegistry};
 
 
 
@@ -29,6 +29,9 @@ pub struct Context {
 
     /// Broadcaster
     pub app: AppBroadcaster,
+
+    /// Health of the application components
+    pub health: HealthRegistry,
 }
 
 
@@ -37,15 +40,15 @@ impl Context {
     pub fn from_config(config: Config) -> Self {
         let name = config.get_string("app_name").unwrap_or("default".to_string());
         let broadcaster = tokio::sync::broadcast::Sender::new(10);
-        Self { name, config, app: broadcaster }
+        Self { name, config, app: broadcaster, health: HealthRegistry::default() }
     }
 
     pub fn with_name(&self, name: &str) -> Self {
-        Self { name: name.to_string(), config: self.config.clone(), app: self.app.clone() }
+        Self { name: name.to_string(), config: self.config.clone(), app: self.app.clone(), health: self.health.clone() }
     }
 
     pub fn with_config(&self, config: Config) -> Self {
-        Self { name: self.name.clone(), config, app: self.app.clone() }
+        Self { name: self.name.clone(), config, app: self.app.clone(), health: self.health.clone() }
     }
 
     pub fn log_and_exit(&self, message: &str) -> ArbitrageResult<String> {
@@ -10,6 +10,8 @@ pub enum ArbitrageError {
     Warning(String),
     #[error("{0}")]
     UnrecoverableError(String),
+    #[error("{0}")]
+    RateLimited(String),
     #[error("exit")]
     Exit,
     #[error("{0}")]
@@ -0,0 +1,44 @@
+use std::collections::BTreeMap;
+
+use serde::Serialize;
+
+use crate::SharedRef;
+
+/// Health of a single component of the application
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "status", content = "reason", rename_all = "snake_case")]
+pub enum HealthStatus {
+    /// Component is working as expected
+    Healthy,
+    /// Component is working but lost some functionality, e.g. a dropped subscription
+    Degraded(String),
+    /// Component is not working
+    Unhealthy(String),
+}
+
+/// A registry of health statuses shared by all the components of the application
+#[derive(Clone, Default)]
+pub struct HealthRegistry {
+    statuses: SharedRef<BTreeMap<String, HealthStatus>>,
+}
+
+impl HealthRegistry {
+    /// Sets the health status of a component
+    pub fn set(&self, component: &str, status: HealthStatus) {
+        let mut statuses = self.statuses.lock();
+        if statuses.get(component) != Some(&status) {
+            log::info!("{} health changed to {:?}", component, status);
+            statuses.insert(component.to_string(), status);
+        }
+    }
+
+    /// Get the health status of a component
+    pub fn get(&self, component: &str) -> Option<HealthStatus> {
+        self.statuses.lock().get(component).cloned()
+    }
+
+    /// Get the health statuses of all the components
+    pub fn snapshot(&self) -> BTreeMap<String, HealthStatus> {
+        self.statuses.lock().clone()
+    }
+}
@@ -0,0 +1,89 @@
+use std::collections::BTreeMap;
+
+/// Growth factor between consecutive bucket bounds, quantiles are accurate within 5%
+const BUCKET_GROWTH: f64 = 1.05;
+
+/// A histogram of non negative values with exponentially growing buckets.
+///
+/// It keeps a bounded number of buckets regardless of the number of samples,
+/// which makes it cheap enough to record every message flowing through the pipeline.
+#[derive(Debug, Clone, Default)]
+pub struct Histogram {
+    buckets: BTreeMap<u32, u64>,
+    count: u64,
+    sum: u64,
+}
+
+impl Histogram {
+    /// Records a sample
+    pub fn record(&mut self, value: u64) {
+        *self.buckets.entry(bucket_index(value)).or_default() += 1;
+        self.count += 1;
+        self.sum = self.sum.saturating_add(value);
+    }
+
+    /// Number of samples recorded
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// Sum of the samples recorded
+    pub fn sum(&self) -> u64 {
+        self.sum
+    }
+
+    /// Get the upper bound of the bucket holding the given quantile, e.g. 0.99 for p99
+    pub fn quantile(&self, quantile: f64) -> Option<u64> {
+        if self.count == 0 {
+            return None;
+        }
+        let rank = ((quantile.clamp(0.0, 1.0) * self.count as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (index, count) in self.buckets.iter() {
+            seen += count;
+            if seen >= rank {
+                return Some(bucket_upper_bound(*index));
+            }
+        }
+        self.buckets.last_key_value().map(|(index, _)| bucket_upper_bound(*index))
+    }
+}
+
+fn bucket_index(value: u64) -> u32 {
+    if value <= 1 {
+        return value as u32;
+    }
+    ((value as f64).ln() / BUCKET_GROWTH.ln()).ceil() as u32
+}
+
+fn bucket_upper_bound(index: u32) -> u64 {
+    if index <= 1 {
+        return index as u64;
+    }
+    BUCKET_GROWTH.powi(index as i32).round() as u64
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_quantiles() {
+        let mut histogram = Histogram::default();
+        assert_eq!(histogram.quantile(0.5), None);
+
+        for value in 1..=1000 {
+            histogram.record(value);
+        }
+
+        assert_eq!(histogram.count(), 1000);
+        assert_eq!(histogram.sum(), 500500);
+
+        let p50 = histogram.quantile(0.5).unwrap();
+        assert!((500..=525).contains(&p50), "p50 was {}", p50);
+        let p99 = histogram.quantile(0.99).unwrap();
+        assert!((990..=1040).contains(&p99), "p99 was {}", p99);
+        assert_eq!(histogram.quantile(0.0), Some(1));
+    }
+}
@@ -6,6 +6,9 @@ mod runner;
 mod worker;
 mod utils;
 mod mpsc;
+mod health;
+mod histogram;
+mod metrics;
 
 pub use backoff::*;
 pub use errors::*;
@@ -15,3 +18,5 @@ pub use runner::*;
 pub use worker::*;
 pub use utils::*;
 pub use mpsc::*;
+pub use health::*;
+pub use histogram::*;
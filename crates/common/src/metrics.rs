@@ -0,0 +1,15 @@
+use std::sync::LazyLock;
+
+use prometheus::{register_int_counter_vec, IntCounterVec};
+
+/// Workers which exited with an error or panicked, per worker
+pub static WORKER_FAILURES: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!("worker_failures_total", "Number of times a worker failed", &["worker"])
+        .expect("worker_failures_total should be registered")
+});
+
+/// Workers restarted by their supervisor, per worker
+pub static WORKER_RESTARTS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!("worker_restarts_total", "Number of times a worker was restarted", &["worker"])
+        .expect("worker_restarts_total should be registered")
+});
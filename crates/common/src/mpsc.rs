@@ -1,5 +1,13 @@
+use std::sync::Arc;
+
 use tokio::sync::mpsc::{Receiver, Sender};
 
+/// A receiver which is handed over to the next task once the previous one is done with it.
+///
+/// A task holds the lock for as long as it consumes messages, which is what allows
+/// a supervised worker to be restarted without losing its channel.
+pub type SharedReceiver<M> = Arc<tokio::sync::Mutex<Receiver<M>>>;
+
 /// A Multi-Producer, Single-Consumer channel.
 ///
 /// Producers dispatch messages to a single consumer.
@@ -59,4 +67,10 @@ impl<M> MpSc<M> {
         self.reciever.take()
     }
 
+    /// Get a receiver which can be consumed by successive tasks.
+    /// Like `receiver`, it replaces the existing receiver with None.
+    pub fn shared_receiver(&mut self) -> Option<SharedReceiver<M>> {
+        self.receiver().map(|receiver| Arc::new(tokio::sync::Mutex::new(receiver)))
+    }
+
 }
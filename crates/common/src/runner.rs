@@ -1,7 +1,17 @@
-use config::Config;
+use std::{process::ExitCode, sync::OnceLock};
 
-use crate::ArbitrageResult;
-use tracing_subscriber::{prelude::*, EnvFilter};
+use config::{Config, ConfigError};
+
+use crate::{ArbitrageError, ArbitrageResult, Context};
+use tracing_subscriber::{prelude::*, reload, EnvFilter, Registry};
+
+/// Exit status when the configuration is invalid, as in sysexits.h
+pub const EXIT_CONFIG: u8 = 78;
+/// Handle to change the log filter once the telemetry is set up
+static LOG_FILTER: OnceLock<reload::Handle<EnvFilter, Registry>> = OnceLock::new();
+
+/// Exit status when a second signal interrupts the graceful shutdown
+const EXIT_INTERRUPTED: i32 = 130;
 
 /// Trait for running an app
 #[async_trait::async_trait]
@@ -10,37 +20,142 @@ pub trait Runner {
     async fn run(&mut self) -> ArbitrageResult<String>;
 
     fn config(&self) -> &Config;
+
+    /// Context whose exit is broadcasted on SIGINT or SIGTERM
+    fn context(&self) -> &Context;
 }
 
 
-pub fn run_app<R: Runner>(mut runner: R) {
+/// Runs the app until it stops, either on its own or on SIGINT/SIGTERM
+///
+/// The exit code is 0 when the app stopped cleanly, 78 when the configuration is invalid
+/// and 1 on any other failure.
+pub fn run_app<R: Runner>(mut runner: R) -> ExitCode {
     let config = runner.config();
-    setup_telemetry(config);
+    if let Err(e) = setup_telemetry(config) {
+        // The logs are not set up yet
+        eprintln!("invalid configuration: {}", e);
+        return ExitCode::from(EXIT_CONFIG);
+    }
     let worker_threads = config.get_int("tokio.worker_threads").unwrap_or(4);
-    tokio::runtime::Builder::new_multi_thread()
+    let result = tokio::runtime::Builder::new_multi_thread()
         .worker_threads(worker_threads as usize)
         .enable_all()
         .build()
         .unwrap()
         .block_on(async move {
-            runner.run().await.unwrap();
+            tokio::spawn(exit_on_signal(runner.context().clone()));
+            runner.run().await
         });
+
+    match result {
+        Ok(name) => {
+            log::info!("{} stopped", name);
+            ExitCode::SUCCESS
+        }
+        Err(ArbitrageError::ConfigError(e)) => {
+            log::error!("invalid configuration: {}", e);
+            ExitCode::from(EXIT_CONFIG)
+        }
+        Err(e) => {
+            log::error!("app failed: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+
+/// Broadcasts the exit of the app on the first signal, and exits right away on the second
+async fn exit_on_signal(context: Context) {
+    wait_for_signal().await;
+    log::warn!("{} shutting down, signal again to force exit", context.name);
+    if !context.exit() {
+        log::error!("failed to broadcast exit");
+    }
+
+    wait_for_signal().await;
+    log::error!("{} forced to exit", context.name);
+    std::process::exit(EXIT_INTERRUPTED);
+}
+
+#[cfg(unix)]
+async fn wait_for_signal() {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut terminate = signal(SignalKind::terminate()).expect("SIGTERM handler should be installed");
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {
+            log::info!("received SIGINT");
+        }
+        _ = terminate.recv() => {
+            log::info!("received SIGTERM");
+        }
+    }
+}
+
+#[cfg(not(unix))]
+async fn wait_for_signal() {
+    if tokio::signal::ctrl_c().await.is_ok() {
+        log::info!("received ctrl-c");
+    }
 }
 
 
-fn setup_telemetry(cfg: &Config) {
+/// Sets up the logs, fails with a config error when the log level is invalid
+fn setup_telemetry(cfg: &Config) -> ArbitrageResult<()> {
+    // Logs go to stderr, so that the output of the commands can be piped
     let log_formatter =  tracing_subscriber::fmt::layer()
+        .with_writer(std::io::stderr)
         .with_thread_names(true)
         .with_thread_ids(true)
         .boxed();
 
-    let log_level = cfg.get_string("log_level").unwrap_or("info".to_string());
-    let log_filter = EnvFilter::builder()
-        .with_default_directive(log_level.parse().unwrap())
-        .from_env_lossy();
+    let log_level = cfg.get_string("logging.level")
+        .or_else(|_| cfg.get_string("log_level"))
+        .unwrap_or("info".to_string());
+    let log_filter = log_filter(&log_level)?;
+    let (log_filter, handle) = reload::Layer::new(log_filter);
+    LOG_FILTER.set(handle).ok();
 
     tracing_subscriber::registry()
         .with(log_filter)
         .with(log_formatter)
         .init();
+    Ok(())
+}
+
+fn log_filter(log_level: &str) -> ArbitrageResult<EnvFilter> {
+    let directive = log_level
+        .parse()
+        .map_err(|e| ConfigError::Message(format!("invalid log level {:?}: {}", log_level, e)))?;
+    Ok(EnvFilter::builder()
+        .with_default_directive(directive)
+        .from_env_lossy())
+}
+
+/// Changes the default log level of the running app, `RUST_LOG` directives still apply
+///
+/// An invalid level is rejected with a config error and the running one is kept.
+pub fn set_log_level(log_level: &str) -> ArbitrageResult<()> {
+    let log_filter = log_filter(log_level)?;
+    let handle = LOG_FILTER
+        .get()
+        .ok_or_else(|| ArbitrageError::GenericError("telemetry is not set up".to_string()))?;
+    handle
+        .reload(log_filter)
+        .map_err(|e| ArbitrageError::GenericError(format!("failed to change log level: {}", e)))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_invalid_log_level_is_config_error() {
+        assert!(log_filter("debug").is_ok());
+        assert!(log_filter("server=debug").is_ok());
+        assert!(matches!(log_filter("server=loud"), Err(ArbitrageError::ConfigError(_))));
+        assert!(matches!(set_log_level("server=loud"), Err(ArbitrageError::ConfigError(_))));
+    }
 }
@@ -25,7 +25,7 @@ impl<T> SharedRef<T> {
         }
     }
 
-    pub fn lock(&self) -> MutexGuard<T> {
+    pub fn lock(&self) -> MutexGuard<'_, T> {
         self.inner.lock().unwrap()
     }
 }
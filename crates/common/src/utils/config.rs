@@ -1,19 +1,32 @@
 use std::collections::HashMap;
 use std::env::vars;
 
-use config::{builder::DefaultState, Config, ConfigBuilder, Environment};
+use config::{builder::DefaultState, Config, ConfigBuilder, Environment, File};
 
 pub type CfgBuilder = ConfigBuilder<DefaultState>;
 
 /// Create a new configuration builder
 ///
-/// It updates the environment variables from the provided path if it exists
+/// It sets the environment variables from the provided path if it exists,
+/// without overriding the ones already set
 ///
 /// Variables are read from the environment variables in any case.
 pub fn create_config(env_path: &str) -> CfgBuilder {
-    dotenvy::from_path_override(env_path).ok();
+    dotenvy::from_path(env_path).ok();
     let env_vars = vars().collect::<HashMap<String, String>>();
     let source = Environment::default()
         .source(Some(env_vars));
     Config::builder().add_source(source)
 }
+
+/// Create a new configuration builder from a TOML or YAML file, chosen by its extension
+///
+/// The environment variables take precedence over the file.
+pub fn create_config_from_file(config_path: &str) -> CfgBuilder {
+    let env_vars = vars().collect::<HashMap<String, String>>();
+    let source = Environment::default()
+        .source(Some(env_vars));
+    Config::builder()
+        .add_source(File::with_name(config_path))
+        .add_source(source)
+}
@@ -1,18 +1,28 @@
-use std::time::Duration;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+use futures::future::BoxFuture;
 use futures::prelude::*;
 use futures::stream::FuturesUnordered;
 use tokio::time::timeout;
 
-use crate::{ArbitrageResult, Context, SharedRef};
+use crate::{metrics, ArbitrageError, ArbitrageResult, Context, HealthRegistry, HealthStatus, SharedRef};
 
 pub type SpawnResult = tokio::task::JoinHandle<ArbitrageResult<String>>;
 pub type WorkerRef = Box<dyn Worker + Send + Sync>;
+type JoinResult = Result<ArbitrageResult<String>, tokio::task::JoinError>;
 
 /// A trait that defines an interface for a worker
 pub trait Worker {
     /// Spawns a new worker into a tokio task
+    ///
+    /// A supervised worker can be spawned again once its previous task completed.
     fn spawn(&mut self) -> SpawnResult;
 
+    /// Name of the worker used to report its status
+    fn name(&self) -> String {
+        std::any::type_name::<Self>().to_string()
+    }
+
     /// Checks if the worker is running
     fn is_running(&self) -> bool {
         true
@@ -38,10 +48,98 @@ impl RunningFlag {
     }
 }
 
+
+/// What the supervisor does when a worker exits
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RestartPolicy {
+    /// Any exit of the worker stops the application
+    Escalate,
+    /// Only the failed worker is restarted after `delay`.
+    ///
+    /// A clean exit, an exit request or an unrecoverable error still stop the application,
+    /// as does failing more than `max_restarts` times within `window`.
+    OneForOne {
+        max_restarts: usize,
+        window: Duration,
+        delay: Duration,
+    },
+}
+
+
+/// A worker along with its restart policy and history
+struct Supervised {
+    worker: WorkerRef,
+    name: String,
+    policy: RestartPolicy,
+    restarts: VecDeque<Instant>,
+}
+
+impl Supervised {
+    fn new(worker: WorkerRef, policy: RestartPolicy) -> Self {
+        let name = worker.name();
+        Self { worker, name, policy, restarts: VecDeque::new() }
+    }
+
+    /// Spawns the worker and reports its status
+    fn start(&mut self, index: usize, health: &HealthRegistry) -> BoxFuture<'static, Event> {
+        let component = format!("worker/{}", self.name);
+        let health = health.clone();
+        health.set(&component, HealthStatus::Healthy);
+        self.worker
+            .spawn()
+            .map(move |result| {
+                report_exit(&health, &component, &result);
+                Event::Exited(index, result)
+            })
+            .boxed()
+    }
+
+    /// Returns the delay after which the worker should be restarted,
+    /// or None if its exit should be escalated to the application
+    fn restart_delay(&mut self, result: &JoinResult, now: Instant) -> Option<Duration> {
+        let RestartPolicy::OneForOne { max_restarts, window, delay } = self.policy else {
+            return None;
+        };
+        if !is_restartable(result) {
+            return None;
+        }
+
+        while self.restarts.front().is_some_and(|restart| now.duration_since(*restart) > window) {
+            self.restarts.pop_front();
+        }
+        if self.restarts.len() >= max_restarts {
+            log::error!("worker {} failed {} times within {:?}", self.name, self.restarts.len() + 1, window);
+            return None;
+        }
+        self.restarts.push_back(now);
+        Some(delay)
+    }
+}
+
+enum Event {
+    Exited(usize, JoinResult),
+    Restart(usize),
+}
+
+/// Only failures can be recovered by restarting a worker
+fn is_restartable(result: &JoinResult) -> bool {
+    match result {
+        Ok(Ok(_)) => false,
+        Ok(Err(ArbitrageError::Exit)) | Ok(Err(ArbitrageError::UnrecoverableError(_))) => false,
+        Ok(Err(_)) => true,
+        Err(err) => err.is_panic(),
+    }
+}
+
+fn is_failure(result: &JoinResult) -> bool {
+    !matches!(result, Ok(Ok(_)) | Ok(Err(ArbitrageError::Exit)))
+}
+
+
 pub struct Workers {
     context: Context,
     delay_millis: u64,
-    workers: Vec<WorkerRef>,
+    workers: Vec<Supervised>,
     running: RunningFlag
 }
 
@@ -56,8 +154,14 @@ impl Workers {
         }
     }
 
+    /// Adds a worker whose exit stops the application
     pub fn add_worker(&mut self, worker: WorkerRef) {
-        self.workers.push(worker);
+        self.add_supervised_worker(worker, RestartPolicy::Escalate);
+    }
+
+    /// Adds a worker which is supervised with the given restart policy
+    pub fn add_supervised_worker(&mut self, worker: WorkerRef, policy: RestartPolicy) {
+        self.workers.push(Supervised::new(worker, policy));
     }
 
     pub async fn run(&mut self) -> ArbitrageResult<String> {
@@ -78,9 +182,46 @@ impl Clone for Workers {
 }
 
 
+/// Marks a worker which is not running anymore as unhealthy
+fn report_exit(health: &HealthRegistry, component: &str, result: &JoinResult) {
+    let reason = match result {
+        Ok(Ok(_)) => "exited".to_string(),
+        Ok(Err(ArbitrageError::Exit)) => "stopped".to_string(),
+        Ok(Err(err)) => format!("failed with error: {}", err),
+        Err(err) => format!("panicked: {}", err),
+    };
+    health.set(component, HealthStatus::Unhealthy(reason));
+}
+
+fn log_exit(context: &Context, worker: &str, result: &JoinResult) {
+    match result {
+        Ok(Err(ArbitrageError::Exit)) => {
+            log::info!("worker {} exited on request", worker);
+        }
+        Ok(Err(err)) => {
+            log::error!("{} worker {} failed with error: {:?}", context.name, worker, err);
+        }
+        Ok(Ok(name)) => {
+            log::info!("worker {} exited", name);
+        }
+        Err(err) => {
+            log::error!("worker {} error - {:?}", worker, err);
+        }
+    }
+}
+
+
 impl Worker for Workers {
+    fn name(&self) -> String {
+        self.context.name.clone()
+    }
+
+    fn is_running(&self) -> bool {
+        self.running.is_running()
+    }
+
     fn spawn(&mut self) -> SpawnResult {
-        let workers = self.workers.drain(..).collect::<Vec<WorkerRef>>();
+        let mut workers = self.workers.drain(..).collect::<Vec<Supervised>>();
         let running = self.running.clone();
         let context = self.context.clone();
         let delay_millis = self.delay_millis;
@@ -93,30 +234,57 @@ impl Worker for Workers {
             running.start();
             tokio::time::sleep(Duration::from_millis(delay_millis)).await;
 
-            let mut futures = vec![];
-            for mut worker in workers {
-                futures.push(worker.spawn());
-            }
-
-            log::info!("{} spawned {} workers", context.name, futures.len());
-            // Convert the vector of futures to a futures unordered as it allows us to
+            // Convert the futures to a futures unordered as it allows us to
             // run all the futures concurrently and get results as they complete, without
             // enforcing a specific order
-            let mut futures = futures.into_iter().collect::<FuturesUnordered<_>>();
-
-            // Wait for the first future to complete
-            if let Some(result) = futures.next().await {
-                if let Ok(result) = result {
-                    match result {
-                        Err(err) => {
-                            log::error!("{} worker failed with error: {:?}", context.name, err);
+            let mut futures = FuturesUnordered::new();
+            for (index, worker) in workers.iter_mut().enumerate() {
+                futures.push(worker.start(index, &context.health));
+            }
+            let mut num_running = workers.len();
+            // Name of the worker whose failure stopped the application
+            let mut failure = None;
+
+            log::info!("{} spawned {} workers", context.name, num_running);
+
+            // Restart the workers which fail according to their policy,
+            // until one of them has to be escalated to the application
+            while let Some(event) = futures.next().await {
+                match event {
+                    Event::Restart(index) => {
+                        let worker = &mut workers[index];
+                        log::warn!("restarting worker {}", worker.name);
+                        metrics::WORKER_RESTARTS.with_label_values(&[&worker.name]).inc();
+                        futures.push(worker.start(index, &context.health));
+                        num_running += 1;
+                    }
+                    Event::Exited(index, result) => {
+                        num_running -= 1;
+                        let worker = &mut workers[index];
+                        log_exit(&context, &worker.name, &result);
+                        if is_failure(&result) {
+                            metrics::WORKER_FAILURES.with_label_values(&[&worker.name]).inc();
                         }
-                        Ok(name) => {
-                            log::info!("worker {} exited", name);
+                        match worker.restart_delay(&result, Instant::now()) {
+                            Some(delay) => {
+                                log::warn!("worker {} will be restarted in {:?}", worker.name, delay);
+                                futures.push(
+                                    async move {
+                                        tokio::time::sleep(delay).await;
+                                        Event::Restart(index)
+                                    }
+                                    .boxed(),
+                                );
+                            }
+                            None => {
+                                log::warn!("worker {} exit escalated to {}", worker.name, context.name);
+                                if is_failure(&result) {
+                                    failure = Some(worker.name.clone());
+                                }
+                                break;
+                            }
                         }
                     }
-                } else {
-                    log::error!("worker error - {:?}", result);
                 }
             }
 
@@ -127,23 +295,19 @@ impl Worker for Workers {
                 // Wait for other workers to complete. The idea is if we consolidate multiple workers
                 // and if one worker completed, it means other workers should also be done.
                 // We wait for 5 seconds for other workers to complete or we consider them failed
-                // and exit the application
+                // and exit the application. Pending restarts are abandoned.
                 log::warn!("waiting for other workers to complete");
                 let timeout_duration = Duration::from_millis(timeout_millis);
 
                 match timeout(timeout_duration, async {
-                    while let Some(result) = futures.next().await {
-                        if let Ok(result) = result {
-                            match result {
-                                Err(err) => {
-                                    log::error!("{} worker failed with error: {:?}", context.name, err);
-                                }
-                                Ok(name) => {
-                                    log::info!("worker {} exited", name);
-                                }
+                    while num_running > 0 {
+                        match futures.next().await {
+                            Some(Event::Exited(index, result)) => {
+                                num_running -= 1;
+                                log_exit(&context, &workers[index].name, &result);
                             }
-                        } else {
-                            log::error!("worker error - {:?}", result);
+                            Some(Event::Restart(_)) => {}
+                            None => break,
                         }
                     }
                 }).await {
@@ -158,8 +322,53 @@ impl Worker for Workers {
 
             }
             running.stop();
-            context.log_and_exit("stopped")
+            match failure {
+                Some(worker) => Err(ArbitrageError::GenericError(format!("{} stopped as worker {} failed", context.name, worker))),
+                None => context.log_and_exit("stopped"),
+            }
         })
 
     }
 }
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct NoopWorker;
+
+    impl Worker for NoopWorker {
+        fn spawn(&mut self) -> SpawnResult {
+            tokio::spawn(async { Ok("noop".to_string()) })
+        }
+    }
+
+    #[test]
+    fn test_restart_delay() {
+        let policy = RestartPolicy::OneForOne {
+            max_restarts: 2,
+            window: Duration::from_secs(60),
+            delay: Duration::from_millis(10),
+        };
+        let mut worker = Supervised::new(Box::new(NoopWorker), policy);
+        let failure: JoinResult = Ok(Err(ArbitrageError::GenericError("failed".to_string())));
+        let now = Instant::now();
+
+        assert_eq!(worker.restart_delay(&failure, now), Some(Duration::from_millis(10)));
+        assert_eq!(worker.restart_delay(&failure, now), Some(Duration::from_millis(10)));
+        // Too many failures within the window are escalated
+        assert_eq!(worker.restart_delay(&failure, now + Duration::from_secs(1)), None);
+        // Failures outside of the window are forgotten
+        assert!(worker.restart_delay(&failure, now + Duration::from_secs(61)).is_some());
+
+        // Exits which are not failures are always escalated
+        assert_eq!(worker.restart_delay(&Ok(Ok("done".to_string())), now + Duration::from_secs(200)), None);
+        assert_eq!(worker.restart_delay(&Ok(Err(ArbitrageError::Exit)), now + Duration::from_secs(200)), None);
+        let unrecoverable = Ok(Err(ArbitrageError::UnrecoverableError("auth".to_string())));
+        assert_eq!(worker.restart_delay(&unrecoverable, now + Duration::from_secs(200)), None);
+
+        let mut escalated = Supervised::new(Box::new(NoopWorker), RestartPolicy::Escalate);
+        assert_eq!(escalated.restart_delay(&failure, now), None);
+    }
+}
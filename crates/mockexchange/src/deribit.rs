@@ -0,0 +1,195 @@
+use std::collections::BTreeSet;
+
+use rust_decimal::{prelude::{FromPrimitive, ToPrimitive}, Decimal};
+use serde_json::{json, Value};
+
+use crate::{Connection, MockBook, MockOrder, MockOrderState, MockSide};
+
+/// Error code of Deribit for an instrument which does not exist
+const INVALID_INSTRUMENT: i64 = 10020;
+
+/// JSON-RPC error code of an unknown method
+const METHOD_NOT_FOUND: i64 = -32601;
+
+/// JSON-RPC error code of a request which can not be parsed
+const PARSE_ERROR: i64 = -32700;
+
+/// Error code of Deribit for wrong client credentials
+const INVALID_CREDENTIALS: i64 = 13004;
+
+/// Error code of Deribit for a private method called before authenticating
+const UNAUTHORIZED: i64 = 13009;
+
+/// Error code of Deribit for an edit or cancel of an order which is not open
+const ORDER_NOT_FOUND: i64 = 11044;
+
+/// Prefix of the private channels of the order updates, e.g. `user.orders.any.any.raw`
+const USER_ORDERS_CHANNEL: &str = "user.orders.";
+
+
+/// Answers a JSON-RPC request, channels are named e.g. `book.BTC-21FEB25-90000-P.none.20.100ms`
+pub fn reply(connection: &Connection, text: &str) -> Vec<String> {
+    let Ok(request) = serde_json::from_str::<Value>(text) else {
+        return vec![error(None, PARSE_ERROR, "parse_error")];
+    };
+    let id = request["id"].as_str().map(str::to_string);
+    let channels = request["params"]["channels"]
+        .as_array()
+        .map(|channels| channels.iter().filter_map(|channel| channel.as_str().map(str::to_string)).collect::<Vec<_>>())
+        .unwrap_or_default();
+
+    match request["method"].as_str() {
+        Some("public/set_heartbeat") => vec![result(&id, json!("ok"))],
+        Some("public/test") if connection.keepalives_muted() => vec![],
+        Some("public/test") => vec![result(&id, json!({"version": "mock"}))],
+        Some("public/subscribe") => {
+            if channels.iter().any(|channel| connection.is_rejected(instrument(channel))) {
+                return vec![error(id, INVALID_INSTRUMENT, "invalid_instrument_name")];
+            }
+            let mut frames = vec![result(&id, json!(channels))];
+            for channel in channels.iter() {
+                connection.subscribe(channel);
+                if let Some(snapshot) = connection.snapshot(instrument(channel)) {
+                    frames.extend(book(&BTreeSet::from([channel.clone()]), &snapshot));
+                }
+            }
+            frames
+        }
+        Some("public/unsubscribe") => {
+            let unsubscribed = channels.into_iter().filter(|channel| connection.unsubscribe(channel)).collect::<Vec<_>>();
+            vec![result(&id, json!(unsubscribed))]
+        }
+        Some("public/auth") => {
+            let params = &request["params"];
+            let field = |name: &str| params[name].as_str().unwrap_or_default().to_string();
+            if !connection.authenticate(|credentials| credentials.key == field("client_id") && credentials.secret == field("client_secret")) {
+                return vec![error(id, INVALID_CREDENTIALS, "invalid_credentials")];
+            }
+            let token = json!({"access_token": "mock", "expires_in": 31536000, "refresh_token": "mock", "scope": "connection", "token_type": "bearer"});
+            vec![result(&id, token)]
+        }
+        Some(method) if method.starts_with("private/") && !connection.is_authenticated() => {
+            vec![error(id, UNAUTHORIZED, "unauthorized")]
+        }
+        Some("private/subscribe") => {
+            for channel in channels.iter() {
+                connection.subscribe(channel);
+            }
+            vec![result(&id, json!(channels))]
+        }
+        Some(method @ ("private/buy" | "private/sell" | "private/edit_by_label")) => {
+            let params = &request["params"];
+            let label = params["label"].as_str().unwrap_or_default();
+            let order = match method {
+                "private/edit_by_label" => connection.amend(label, decimal(&params["price"]), decimal(&params["amount"])),
+                _ => Some(connection.place(order(method, params))),
+            };
+            let Some(order) = order else {
+                return vec![error(id, ORDER_NOT_FOUND, "order_not_found")];
+            };
+            let trades = match order.average_price {
+                // The fills of the request are reported as a single trade
+                Some(price) => vec![json!({"trade_id": order.order_id, "price": price.to_f64(), "amount": order.filled_size.to_f64(), "fee": 0.0})],
+                None => vec![],
+            };
+            let mut frames = vec![result(&id, json!({"order": order_json(&order), "trades": trades}))];
+            frames.extend(orders(connection, &order));
+            frames
+        }
+        Some("private/cancel_by_label") => {
+            let label = request["params"]["label"].as_str().unwrap_or_default();
+            match connection.cancel(label) {
+                Some(order) => {
+                    let mut frames = vec![result(&id, json!(1))];
+                    frames.extend(orders(connection, &order));
+                    frames
+                }
+                None => vec![result(&id, json!(0))],
+            }
+        }
+        _ => vec![error(id, METHOD_NOT_FOUND, "Method not found")],
+    }
+}
+
+/// Notification of the first book channel subscribed for the instrument, levels are `[price, amount]`
+pub fn book(subscriptions: &BTreeSet<String>, book: &MockBook) -> Option<String> {
+    let channel = subscriptions.iter().find(|channel| instrument(channel) == book.instrument)?;
+    let levels = |levels: &[(Decimal, Decimal)]| {
+        levels.iter().map(|(price, amount)| json!([price.to_f64(), amount.to_f64()])).collect::<Vec<_>>()
+    };
+    let message = json!({
+        "jsonrpc": "2.0",
+        "method": "subscription",
+        "params": {
+            "channel": channel,
+            "data": {
+                "instrument_name": book.instrument,
+                "timestamp": book.timestamp_millis,
+                "asks": levels(&book.asks),
+                "bids": levels(&book.bids),
+            },
+        },
+    });
+    Some(message.to_string())
+}
+
+fn order(method: &str, params: &Value) -> MockOrder {
+    MockOrder {
+        client_order_id: params["label"].as_str().unwrap_or_default().to_string(),
+        order_id: String::new(),
+        instrument: params["instrument_name"].as_str().unwrap_or_default().to_string(),
+        side: if method == "private/sell" { MockSide::Sell } else { MockSide::Buy },
+        price: decimal(&params["price"]).unwrap_or_default(),
+        size: decimal(&params["amount"]).unwrap_or_default(),
+        immediate_or_cancel: params["time_in_force"] == "immediate_or_cancel",
+        filled_size: Decimal::ZERO,
+        average_price: None,
+        state: MockOrderState::Open,
+    }
+}
+
+fn order_json(order: &MockOrder) -> Value {
+    let order_state = match order.state {
+        MockOrderState::Open => "open",
+        MockOrderState::Filled => "filled",
+        MockOrderState::Cancelled => "cancelled",
+    };
+    json!({
+        "order_id": order.order_id,
+        "instrument_name": order.instrument,
+        "label": order.client_order_id,
+        "direction": if order.side == MockSide::Buy { "buy" } else { "sell" },
+        "order_state": order_state,
+        "price": order.price.to_f64(),
+        "amount": order.size.to_f64(),
+        "filled_amount": order.filled_size.to_f64(),
+        "average_price": order.average_price.unwrap_or_default().to_f64(),
+    })
+}
+
+/// Notification of the order to the `user.orders` channel the connection subscribed to, if any
+fn orders(connection: &Connection, order: &MockOrder) -> Option<String> {
+    let channel = connection.subscriptions().into_iter().find(|channel| channel.starts_with(USER_ORDERS_CHANNEL))?;
+    let message = json!({
+        "jsonrpc": "2.0",
+        "method": "subscription",
+        "params": {"channel": channel, "data": order_json(order)},
+    });
+    Some(message.to_string())
+}
+
+fn decimal(value: &Value) -> Option<Decimal> {
+    Decimal::from_f64(value.as_f64()?)
+}
+
+pub fn error(id: Option<String>, code: i64, message: &str) -> String {
+    json!({"jsonrpc": "2.0", "id": id, "error": {"code": code, "message": message}}).to_string()
+}
+
+fn result(id: &Option<String>, result: Value) -> String {
+    json!({"jsonrpc": "2.0", "id": id, "result": result}).to_string()
+}
+
+fn instrument(channel: &str) -> &str {
+    channel.split('.').nth(1).unwrap_or_default()
+}
@@ -0,0 +1,538 @@
+//! Scriptable websocket server speaking the Okex v5 public and private and the Deribit JSON-RPC protocols,
+//! to run the exchange adapters and the private sessions end to end without reaching the exchanges.
+
+use std::{collections::{BTreeSet, HashMap, HashSet}, time::Duration};
+
+use common::SharedRef;
+use futures_util::{SinkExt, StreamExt};
+use rust_decimal::Decimal;
+use tokio::{net::{TcpListener, TcpStream}, sync::broadcast, task::JoinHandle};
+use tokio_tungstenite::tungstenite::Message;
+
+mod deribit;
+mod okex;
+
+/// Commands buffered for each connection, a lagging connection misses the oldest ones
+const COMMANDS_BUFFER_SIZE: usize = 1000;
+
+/// Interval at which `MockExchange::wait_for` checks its condition
+const WAIT_INTERVAL: Duration = Duration::from_millis(10);
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MockProtocol {
+    Okex,
+    Deribit,
+}
+
+/// Book of an instrument, named as on the exchange, e.g. `BTC-USD-250221-90000-P` on Okex
+/// or `BTC-21FEB25-90000-P` on Deribit, sized as on the exchange, in contracts of 0.01 BTC for an option on Okex
+#[derive(Debug, Clone, PartialEq)]
+pub struct MockBook {
+    pub instrument: String,
+    pub bids: Vec<(Decimal, Decimal)>,
+    pub asks: Vec<(Decimal, Decimal)>,
+    pub timestamp_millis: u64,
+}
+
+impl MockBook {
+    pub fn new(instrument: &str, bids: Vec<(Decimal, Decimal)>, asks: Vec<(Decimal, Decimal)>) -> Self {
+        let timestamp_millis = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_millis() as u64)
+            .unwrap_or_default();
+        Self { instrument: instrument.to_string(), bids, asks, timestamp_millis }
+    }
+}
+
+/// Credentials the logins and authentications are checked against, any are accepted when none are set
+#[derive(Debug, Clone, PartialEq)]
+pub struct MockCredentials {
+    /// Okex api key or Deribit client id
+    pub key: String,
+    /// Okex secret key or Deribit client secret
+    pub secret: String,
+    /// Okex only
+    pub passphrase: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MockSide {
+    Buy,
+    Sell,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MockOrderState {
+    Open,
+    Filled,
+    Cancelled,
+}
+
+/// Order placed on the mock, matched against the last snapshot of its instrument without consuming it
+#[derive(Debug, Clone, PartialEq)]
+pub struct MockOrder {
+    /// Okex `clOrdId` or Deribit `label`
+    pub client_order_id: String,
+    pub order_id: String,
+    pub instrument: String,
+    pub side: MockSide,
+    pub price: Decimal,
+    pub size: Decimal,
+    /// Cancelled with what it filled on arrival rather than resting in the book
+    pub immediate_or_cancel: bool,
+    pub filled_size: Decimal,
+    pub average_price: Option<Decimal>,
+    pub state: MockOrderState,
+}
+
+#[derive(Debug, Clone)]
+enum MockCommand {
+    Book { book: MockBook, snapshot: bool },
+    Error { code: i64, message: String },
+    Text(String),
+    Drop,
+}
+
+#[derive(Default)]
+struct MockState {
+    /// Connections accepted so far
+    connections: u64,
+    /// Subscriptions of each open connection, instrument ids on Okex and channels on Deribit
+    subscriptions: HashMap<u64, BTreeSet<String>>,
+    /// Every text frame received, in order
+    requests: Vec<String>,
+    /// Instruments whose subscription is answered with an invalid instrument error
+    rejected: HashSet<String>,
+    /// Last snapshot of each instrument, sent right after it is subscribed
+    snapshots: HashMap<String, MockBook>,
+    credentials: Option<MockCredentials>,
+    /// Connections which logged in
+    authenticated: HashSet<u64>,
+    /// Every order placed, in order
+    orders: Vec<MockOrder>,
+    /// `ping` on Okex and `public/test` on Deribit are left unanswered
+    keepalives_muted: bool,
+    /// Nothing is sent to the connections, as over a half open connection
+    silent: bool,
+}
+
+
+/// Websocket server standing in for an exchange
+///
+/// It acknowledges subscriptions, answers keepalives and pushes the books it is given to the connections subscribed
+/// to them. Errors, dropped connections and unanswered keepalives are scripted from the test. It stops once dropped.
+///
+/// Connections which log in may place, amend and cancel orders, which fill against the last snapshot of their
+/// instrument. Their updates are pushed to the connection subscribed to its orders.
+pub struct MockExchange {
+    protocol: MockProtocol,
+    url: String,
+    state: SharedRef<MockState>,
+    commands: broadcast::Sender<MockCommand>,
+    listener: JoinHandle<()>,
+}
+
+impl MockExchange {
+    /// Listens on a free local port
+    pub async fn start(protocol: MockProtocol) -> std::io::Result<Self> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let url = format!("ws://{}", listener.local_addr()?);
+        let state = SharedRef::new(MockState::default());
+        let (commands, _) = broadcast::channel(COMMANDS_BUFFER_SIZE);
+
+        let connection_state = state.clone();
+        let connection_commands = commands.clone();
+        let listener = tokio::spawn(async move {
+            loop {
+                match listener.accept().await {
+                    Ok((stream, _)) => {
+                        let connection_id = {
+                            let mut state = connection_state.lock();
+                            state.connections += 1;
+                            state.connections
+                        };
+                        let connection = Connection {
+                            protocol,
+                            id: connection_id,
+                            state: connection_state.clone(),
+                            commands: connection_commands.subscribe(),
+                        };
+                        tokio::spawn(connection.serve(stream));
+                    }
+                    Err(e) => log::error!("mock exchange failed to accept a connection: {}", e),
+                }
+            }
+        });
+
+        Ok(Self { protocol, url, state, commands, listener })
+    }
+
+    /// Url to configure as the `ws_url` of the exchange
+    pub fn url(&self) -> String {
+        self.url.clone()
+    }
+
+    pub fn protocol(&self) -> MockProtocol {
+        self.protocol
+    }
+
+    /// Sends the book as a snapshot to the connections subscribed to its instrument, and to the next ones to subscribe
+    pub fn push_snapshot(&self, book: MockBook) {
+        self.state.lock().snapshots.insert(book.instrument.clone(), book.clone());
+        self.send(MockCommand::Book { book, snapshot: true });
+    }
+
+    /// Sends the levels of the book which changed to the connections subscribed to its instrument
+    pub fn push_update(&self, book: MockBook) {
+        self.send(MockCommand::Book { book, snapshot: false });
+    }
+
+    /// Sends an error, not tied to any request, to every connection
+    pub fn push_error(&self, code: i64, message: &str) {
+        self.send(MockCommand::Error { code, message: message.to_string() });
+    }
+
+    /// Sends a raw text frame to every connection
+    pub fn push_text(&self, text: &str) {
+        self.send(MockCommand::Text(text.to_string()));
+    }
+
+    /// Closes every connection without a close frame, as a network failure would
+    pub fn drop_connections(&self) {
+        self.send(MockCommand::Drop);
+    }
+
+    /// Stops answering `ping` on Okex and `public/test` on Deribit, or answers them again
+    pub fn mute_keepalives(&self, muted: bool) {
+        self.state.lock().keepalives_muted = muted;
+    }
+
+    /// Stops sending anything to the connections, which stay open and still record the requests, or sends again
+    pub fn go_silent(&self, silent: bool) {
+        self.state.lock().silent = silent;
+    }
+
+    /// Answers the next subscriptions to the instrument with an invalid instrument error
+    pub fn reject(&self, instrument: &str) {
+        self.state.lock().rejected.insert(instrument.to_string());
+    }
+
+    /// Checks the next logins against the credentials
+    pub fn require_credentials(&self, credentials: MockCredentials) {
+        self.state.lock().credentials = Some(credentials);
+    }
+
+    /// Orders placed so far, with their current state
+    pub fn orders(&self) -> Vec<MockOrder> {
+        self.state.lock().orders.clone()
+    }
+
+    /// Connections accepted so far
+    pub fn connections(&self) -> u64 {
+        self.state.lock().connections
+    }
+
+    /// Subscriptions of the open connections
+    pub fn subscriptions(&self) -> BTreeSet<String> {
+        self.state.lock().subscriptions.values().flatten().cloned().collect()
+    }
+
+    /// Text frames received so far
+    pub fn requests(&self) -> Vec<String> {
+        self.state.lock().requests.clone()
+    }
+
+    /// Waits for the condition to hold, returns false if it did not within the timeout
+    pub async fn wait_for(&self, timeout: Duration, condition: impl Fn(&MockExchange) -> bool) -> bool {
+        let deadline = tokio::time::Instant::now() + timeout;
+        while !condition(self) {
+            if tokio::time::Instant::now() >= deadline {
+                return false;
+            }
+            tokio::time::sleep(WAIT_INTERVAL).await;
+        }
+        true
+    }
+
+    fn send(&self, command: MockCommand) {
+        // No receiver means no connection, there is nobody to send to
+        let _ = self.commands.send(command);
+    }
+}
+
+impl Drop for MockExchange {
+    fn drop(&mut self) {
+        self.listener.abort();
+    }
+}
+
+
+struct Connection {
+    protocol: MockProtocol,
+    id: u64,
+    state: SharedRef<MockState>,
+    commands: broadcast::Receiver<MockCommand>,
+}
+
+impl Connection {
+    async fn serve(mut self, stream: TcpStream) {
+        let ws = match tokio_tungstenite::accept_async(stream).await {
+            Ok(ws) => ws,
+            Err(e) => {
+                log::error!("mock exchange failed the websocket handshake: {}", e);
+                return;
+            }
+        };
+        self.state.lock().subscriptions.insert(self.id, BTreeSet::new());
+        let (mut ws_tx, mut ws_rx) = ws.split();
+
+        'serve: loop {
+            let frames = tokio::select! {
+                message = ws_rx.next() => match message {
+                    Some(Ok(Message::Text(text))) => {
+                        self.state.lock().requests.push(text.to_string());
+                        self.reply(&text)
+                    }
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                    Some(Ok(_)) => vec![],
+                },
+
+                command = self.commands.recv() => match command {
+                    Ok(MockCommand::Book { book, snapshot }) => self.book(&book, snapshot).into_iter().collect(),
+                    Ok(MockCommand::Error { code, message }) => vec![self.error(code, &message)],
+                    Ok(MockCommand::Text(text)) => vec![text],
+                    Ok(MockCommand::Drop) | Err(broadcast::error::RecvError::Closed) => break,
+                    Err(broadcast::error::RecvError::Lagged(_)) => vec![],
+                },
+            };
+            if self.state.lock().silent {
+                continue;
+            }
+            for frame in frames {
+                if ws_tx.send(Message::text(frame)).await.is_err() {
+                    break 'serve;
+                }
+            }
+        }
+
+        let mut state = self.state.lock();
+        state.subscriptions.remove(&self.id);
+        state.authenticated.remove(&self.id);
+    }
+
+    /// Frames answering a request from the client
+    fn reply(&self, text: &str) -> Vec<String> {
+        match self.protocol {
+            MockProtocol::Okex => okex::reply(self, text),
+            MockProtocol::Deribit => deribit::reply(self, text),
+        }
+    }
+
+    /// Frame of the book, if the connection is subscribed to its instrument
+    fn book(&self, book: &MockBook, snapshot: bool) -> Option<String> {
+        let subscriptions = self.subscriptions();
+        match self.protocol {
+            MockProtocol::Okex => okex::book(&subscriptions, book, snapshot),
+            MockProtocol::Deribit => deribit::book(&subscriptions, book),
+        }
+    }
+
+    fn error(&self, code: i64, message: &str) -> String {
+        match self.protocol {
+            MockProtocol::Okex => okex::error(self.id, code, message),
+            MockProtocol::Deribit => deribit::error(None, code, message),
+        }
+    }
+
+    fn subscriptions(&self) -> BTreeSet<String> {
+        self.state.lock().subscriptions.get(&self.id).cloned().unwrap_or_default()
+    }
+
+    fn subscribe(&self, subscription: &str) {
+        if let Some(subscriptions) = self.state.lock().subscriptions.get_mut(&self.id) {
+            subscriptions.insert(subscription.to_string());
+        }
+    }
+
+    fn unsubscribe(&self, subscription: &str) -> bool {
+        self.state.lock().subscriptions.get_mut(&self.id).is_some_and(|subscriptions| subscriptions.remove(subscription))
+    }
+
+    fn keepalives_muted(&self) -> bool {
+        self.state.lock().keepalives_muted
+    }
+
+    fn is_rejected(&self, instrument: &str) -> bool {
+        self.state.lock().rejected.contains(instrument)
+    }
+
+    fn snapshot(&self, instrument: &str) -> Option<MockBook> {
+        self.state.lock().snapshots.get(instrument).cloned()
+    }
+
+    /// Logs the connection in if the credentials are accepted, `check` compares them to the required ones
+    fn authenticate(&self, check: impl Fn(&MockCredentials) -> bool) -> bool {
+        let mut state = self.state.lock();
+        if !state.credentials.as_ref().is_none_or(check) {
+            return false;
+        }
+        state.authenticated.insert(self.id);
+        true
+    }
+
+    fn is_authenticated(&self) -> bool {
+        self.state.lock().authenticated.contains(&self.id)
+    }
+
+    /// Matches the order against the book and stores it
+    fn place(&self, mut order: MockOrder) -> MockOrder {
+        let mut state = self.state.lock();
+        order.order_id = (state.orders.len() + 1).to_string();
+        order.filled_size = Decimal::ZERO;
+        order.average_price = None;
+        order.state = MockOrderState::Open;
+        execute(state.snapshots.get(&order.instrument), &mut order);
+        state.orders.push(order.clone());
+        order
+    }
+
+    /// Changes the price and size of an open order and matches it again, None if there is no such open order
+    fn amend(&self, client_order_id: &str, price: Option<Decimal>, size: Option<Decimal>) -> Option<MockOrder> {
+        let mut state = self.state.lock();
+        let MockState { orders, snapshots, .. } = &mut *state;
+        let order = orders
+            .iter_mut()
+            .rev()
+            .find(|order| order.client_order_id == client_order_id && order.state == MockOrderState::Open)?;
+        order.price = price.unwrap_or(order.price);
+        order.size = size.unwrap_or(order.size).max(order.filled_size);
+        execute(snapshots.get(&order.instrument), order);
+        Some(order.clone())
+    }
+
+    fn cancel(&self, client_order_id: &str) -> Option<MockOrder> {
+        let mut state = self.state.lock();
+        let order = state.orders
+            .iter_mut()
+            .rev()
+            .find(|order| order.client_order_id == client_order_id && order.state == MockOrderState::Open)?;
+        order.state = MockOrderState::Cancelled;
+        Some(order.clone())
+    }
+}
+
+
+/// Fills what crosses the book, best levels first, and moves the order to its state after matching
+fn execute(book: Option<&MockBook>, order: &mut MockOrder) {
+    let mut levels = match (book, order.side) {
+        (Some(book), MockSide::Buy) => book.asks.clone(),
+        (Some(book), MockSide::Sell) => book.bids.clone(),
+        (None, _) => vec![],
+    };
+    match order.side {
+        MockSide::Buy => levels.sort_by_key(|level| level.0),
+        MockSide::Sell => levels.sort_by_key(|level| std::cmp::Reverse(level.0)),
+    }
+    let mut notional = order.average_price.unwrap_or_default() * order.filled_size;
+    for (price, size) in levels {
+        let remaining = order.size - order.filled_size;
+        let crosses = match order.side {
+            MockSide::Buy => price <= order.price,
+            MockSide::Sell => price >= order.price,
+        };
+        if remaining <= Decimal::ZERO || !crosses {
+            break;
+        }
+        let taken = remaining.min(size);
+        order.filled_size += taken;
+        notional += taken * price;
+    }
+    if !order.filled_size.is_zero() {
+        order.average_price = Some((notional / order.filled_size).normalize());
+    }
+    order.state = if order.filled_size >= order.size {
+        MockOrderState::Filled
+    } else if order.immediate_or_cancel {
+        MockOrderState::Cancelled
+    } else {
+        MockOrderState::Open
+    };
+}
+
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal::Decimal;
+
+    use super::*;
+
+    async fn next_text<S>(ws: &mut S) -> String
+    where
+        S: StreamExt<Item = Result<Message, tokio_tungstenite::tungstenite::Error>> + Unpin,
+    {
+        match tokio::time::timeout(Duration::from_secs(5), ws.next()).await {
+            Ok(Some(Ok(Message::Text(text)))) => text.to_string(),
+            message => panic!("unexpected message {:?}", message),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_okex_subscribe_snapshot_and_drop() {
+        let exchange = MockExchange::start(MockProtocol::Okex).await.unwrap();
+        let instrument = "BTC-USD-250221-90000-P";
+        exchange.push_snapshot(MockBook::new(instrument, vec![(Decimal::new(9, 3), Decimal::ONE)], vec![]));
+        exchange.reject("BTC-USD-250221-1-P");
+
+        let (mut ws, _) = tokio_tungstenite::connect_async(exchange.url()).await.unwrap();
+        ws.send(Message::text("ping")).await.unwrap();
+        assert_eq!(next_text(&mut ws).await, "pong");
+
+        let request = r#"{"op":"subscribe","args":[{"channel":"books","instId":"BTC-USD-250221-90000-P"},{"channel":"books","instId":"BTC-USD-250221-1-P"}]}"#;
+        ws.send(Message::text(request)).await.unwrap();
+        assert!(next_text(&mut ws).await.contains(r#""event":"subscribe""#));
+        assert!(next_text(&mut ws).await.contains(r#""action":"snapshot""#));
+        assert!(next_text(&mut ws).await.contains(r#""code":"60018""#));
+        assert_eq!(exchange.subscriptions(), BTreeSet::from([instrument.to_string()]));
+
+        exchange.push_update(MockBook::new(instrument, vec![], vec![(Decimal::new(1, 2), Decimal::TWO)]));
+        assert!(next_text(&mut ws).await.contains(r#""asks":[["0.01","2","0","1"]]"#));
+
+        exchange.drop_connections();
+        assert!(matches!(tokio::time::timeout(Duration::from_secs(5), ws.next()).await, Ok(None | Some(Err(_)))));
+        assert!(exchange.wait_for(Duration::from_secs(5), |exchange| exchange.subscriptions().is_empty()).await);
+        assert_eq!(exchange.requests().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_deribit_orders_by_label() {
+        let exchange = MockExchange::start(MockProtocol::Deribit).await.unwrap();
+        exchange.require_credentials(MockCredentials { key: "id".to_string(), secret: "secret".to_string(), passphrase: None });
+        exchange.push_snapshot(MockBook::new("BTC-21FEB25-90000-P", vec![], vec![(Decimal::new(1, 2), Decimal::ONE)]));
+
+        let (mut ws, _) = tokio_tungstenite::connect_async(exchange.url()).await.unwrap();
+        let buy = r#"{"jsonrpc":"2.0","id":"1","method":"private/buy","params":{"instrument_name":"BTC-21FEB25-90000-P","label":"a1","amount":2.0,"price":0.009,"type":"limit"}}"#;
+        ws.send(Message::text(buy)).await.unwrap();
+        assert!(next_text(&mut ws).await.contains(r#""code":13009"#));
+
+        let auth = r#"{"jsonrpc":"2.0","id":"2","method":"public/auth","params":{"grant_type":"client_credentials","client_id":"id","client_secret":"secret"}}"#;
+        ws.send(Message::text(auth)).await.unwrap();
+        assert!(next_text(&mut ws).await.contains(r#""access_token""#));
+        ws.send(Message::text(r#"{"jsonrpc":"2.0","id":"3","method":"private/subscribe","params":{"channels":["user.orders.any.any.raw"]}}"#)).await.unwrap();
+        next_text(&mut ws).await;
+
+        ws.send(Message::text(buy)).await.unwrap();
+        assert!(next_text(&mut ws).await.contains(r#""order_state":"open""#));
+        assert!(next_text(&mut ws).await.contains(r#""method":"subscription""#));
+
+        let edit = r#"{"jsonrpc":"2.0","id":"4","method":"private/edit_by_label","params":{"instrument_name":"BTC-21FEB25-90000-P","label":"a1","amount":2.0,"price":0.01}}"#;
+        ws.send(Message::text(edit)).await.unwrap();
+        assert!(next_text(&mut ws).await.contains(r#""filled_amount":1.0"#));
+        next_text(&mut ws).await;
+
+        ws.send(Message::text(r#"{"jsonrpc":"2.0","id":"5","method":"private/cancel_by_label","params":{"label":"a1"}}"#)).await.unwrap();
+        assert!(next_text(&mut ws).await.contains(r#""result":1"#));
+        let order = &exchange.orders()[0];
+        assert_eq!((order.state, order.filled_size, order.average_price), (MockOrderState::Cancelled, Decimal::ONE, Some(Decimal::new(1, 2))));
+    }
+}
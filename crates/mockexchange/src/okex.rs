@@ -0,0 +1,193 @@
+use std::{collections::BTreeSet, str::FromStr};
+
+use openssl::{hash::MessageDigest, pkey::PKey, sign::Signer};
+use rust_decimal::Decimal;
+use serde_json::{json, Value};
+
+use crate::{Connection, MockBook, MockOrder, MockOrderState, MockSide};
+
+/// Error code of Okex for a channel or instrument which does not exist
+const INVALID_INSTRUMENT: i64 = 60018;
+
+/// Error code of Okex for a request it can not parse
+const INVALID_REQUEST: i64 = 60012;
+
+/// Error code of Okex for a login with wrong credentials
+const LOGIN_FAILED: i64 = 60009;
+
+/// Error code of Okex for a private request before logging in
+const NOT_LOGGED_IN: i64 = 60011;
+
+/// Error code of Okex for an amend or cancel of an order which is not open
+const ORDER_DOES_NOT_EXIST: &str = "51503";
+
+/// Private channel of the order updates
+const ORDERS_CHANNEL: &str = "orders";
+
+
+/// Answers a keepalive, a `subscribe` / `unsubscribe` request of the v5 public api, or a `login`
+/// and the order operations of the private api
+pub fn reply(connection: &Connection, text: &str) -> Vec<String> {
+    if text == "ping" {
+        return if connection.keepalives_muted() { vec![] } else { vec!["pong".to_string()] };
+    }
+    let Ok(request) = serde_json::from_str::<Value>(text) else {
+        return vec![error(connection.id, INVALID_REQUEST, &format!("Invalid request: {}", text))];
+    };
+    let args = request["args"].as_array().cloned().unwrap_or_default();
+
+    let mut frames = vec![];
+    match request["op"].as_str() {
+        Some("login") => frames.push(login(connection, &args)),
+        Some("subscribe") if args.iter().any(|arg| arg["channel"] == ORDERS_CHANNEL) => {
+            if !connection.is_authenticated() {
+                return vec![error(connection.id, NOT_LOGGED_IN, "Please log in")];
+            }
+            connection.subscribe(ORDERS_CHANNEL);
+            frames.push(json!({"event": "subscribe", "arg": args[0], "connId": conn_id(connection.id)}).to_string());
+        }
+        Some(op @ ("order" | "amend-order" | "cancel-order")) => {
+            let id = request["id"].as_str().unwrap_or_default();
+            let arg = args.first().cloned().unwrap_or_default();
+            if !connection.is_authenticated() {
+                return vec![error(connection.id, NOT_LOGGED_IN, "Please log in")];
+            }
+            let order = match op {
+                "order" => Some(connection.place(order(&arg))),
+                "amend-order" => connection.amend(client_order_id(&arg), decimal(&arg["newPx"]), decimal(&arg["newSz"])),
+                _ => connection.cancel(client_order_id(&arg)),
+            };
+            let Some(order) = order else {
+                let result = json!({"clOrdId": client_order_id(&arg), "ordId": "", "sCode": ORDER_DOES_NOT_EXIST, "sMsg": "Order does not exist"});
+                return vec![json!({"id": id, "op": op, "code": "1", "msg": "", "data": [result]}).to_string()];
+            };
+            let result = json!({"clOrdId": order.client_order_id, "ordId": order.order_id, "sCode": "0", "sMsg": ""});
+            frames.push(json!({"id": id, "op": op, "code": "0", "msg": "", "data": [result]}).to_string());
+            if connection.subscriptions().contains(ORDERS_CHANNEL) {
+                frames.push(orders(&order));
+            }
+        }
+        Some("subscribe") => {
+            for arg in args {
+                let instrument = arg["instId"].as_str().unwrap_or_default();
+                if connection.is_rejected(instrument) {
+                    let message = format!("Wrong URL or channel:{},instId:{} doesn't exist.", arg["channel"].as_str().unwrap_or_default(), instrument);
+                    frames.push(error(connection.id, INVALID_INSTRUMENT, &message));
+                    continue;
+                }
+                connection.subscribe(instrument);
+                frames.push(json!({"event": "subscribe", "arg": arg, "connId": conn_id(connection.id)}).to_string());
+                if let Some(snapshot) = connection.snapshot(instrument) {
+                    frames.extend(book(&connection.subscriptions(), &snapshot, true));
+                }
+            }
+        }
+        Some("unsubscribe") => {
+            for arg in args {
+                connection.unsubscribe(arg["instId"].as_str().unwrap_or_default());
+                frames.push(json!({"event": "unsubscribe", "arg": arg, "connId": conn_id(connection.id)}).to_string());
+            }
+        }
+        _ => frames.push(error(connection.id, INVALID_REQUEST, &format!("Invalid request: {}", text))),
+    }
+    frames
+}
+
+/// Message of the `books` channel, levels are `[price, size, liquidated orders, orders]`
+pub fn book(subscriptions: &BTreeSet<String>, book: &MockBook, snapshot: bool) -> Option<String> {
+    if !subscriptions.contains(&book.instrument) {
+        return None;
+    }
+    let levels = |levels: &[(Decimal, Decimal)]| {
+        levels.iter().map(|(price, size)| json!([price.to_string(), size.to_string(), "0", "1"])).collect::<Vec<_>>()
+    };
+    let message = json!({
+        "arg": {"channel": "books", "instId": book.instrument},
+        "action": if snapshot { "snapshot" } else { "update" },
+        "data": [{
+            "asks": levels(&book.asks),
+            "bids": levels(&book.bids),
+            "ts": book.timestamp_millis.to_string(),
+            "checksum": 0,
+        }],
+    });
+    Some(message.to_string())
+}
+
+fn login(connection: &Connection, args: &[Value]) -> String {
+    let arg = args.first().cloned().unwrap_or_default();
+    let field = |name: &str| arg[name].as_str().unwrap_or_default().to_string();
+    let accepted = connection.authenticate(|credentials| {
+        credentials.key == field("apiKey")
+            && credentials.passphrase.as_deref().unwrap_or_default() == field("passphrase")
+            && sign(&credentials.secret, &field("timestamp")).is_some_and(|sign| sign == field("sign"))
+    });
+    if !accepted {
+        return error(connection.id, LOGIN_FAILED, "Login failed.");
+    }
+    json!({"event": "login", "code": "0", "msg": "", "connId": conn_id(connection.id)}).to_string()
+}
+
+fn sign(secret: &str, timestamp: &str) -> Option<String> {
+    let key = PKey::hmac(secret.as_bytes()).ok()?;
+    let mut signer = Signer::new(MessageDigest::sha256(), &key).ok()?;
+    signer.update(format!("{}GET/users/self/verify", timestamp).as_bytes()).ok()?;
+    Some(openssl::base64::encode_block(&signer.sign_to_vec().ok()?))
+}
+
+fn order(arg: &Value) -> MockOrder {
+    MockOrder {
+        client_order_id: client_order_id(arg).to_string(),
+        order_id: String::new(),
+        instrument: arg["instId"].as_str().unwrap_or_default().to_string(),
+        side: if arg["side"] == "sell" { MockSide::Sell } else { MockSide::Buy },
+        price: decimal(&arg["px"]).unwrap_or_default(),
+        size: decimal(&arg["sz"]).unwrap_or_default(),
+        immediate_or_cancel: arg["ordType"] == "ioc",
+        filled_size: Decimal::ZERO,
+        average_price: None,
+        state: MockOrderState::Open,
+    }
+}
+
+/// Push of the `orders` channel, the numbers which are not set are empty strings
+fn orders(order: &MockOrder) -> String {
+    let state = match order.state {
+        MockOrderState::Open if order.filled_size.is_zero() => "live",
+        MockOrderState::Open => "partially_filled",
+        MockOrderState::Filled => "filled",
+        MockOrderState::Cancelled => "canceled",
+    };
+    let message = json!({
+        "arg": {"channel": ORDERS_CHANNEL, "instType": "OPTION"},
+        "data": [{
+            "instId": order.instrument,
+            "ordId": order.order_id,
+            "clOrdId": order.client_order_id,
+            "side": if order.side == MockSide::Buy { "buy" } else { "sell" },
+            "state": state,
+            "px": order.price.to_string(),
+            "sz": order.size.to_string(),
+            "accFillSz": order.filled_size.to_string(),
+            "avgPx": order.average_price.map(|price| price.to_string()).unwrap_or_default(),
+            "fee": "0",
+        }],
+    });
+    message.to_string()
+}
+
+fn client_order_id(arg: &Value) -> &str {
+    arg["clOrdId"].as_str().unwrap_or_default()
+}
+
+fn decimal(value: &Value) -> Option<Decimal> {
+    Decimal::from_str(value.as_str()?).ok()
+}
+
+pub fn error(connection_id: u64, code: i64, message: &str) -> String {
+    json!({"event": "error", "code": code.to_string(), "msg": message, "connId": conn_id(connection_id)}).to_string()
+}
+
+fn conn_id(connection_id: u64) -> String {
+    format!("{:08x}", connection_id)
+}
@@ -3,12 +3,15 @@ use std::fmt;
 use rust_decimal::{prelude::FromPrimitive, Decimal};
 use serde::{de::{Error, SeqAccess, Visitor}, Deserialize, Deserializer, Serialize};
 
+use crate::ExchangeErrorKind;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct DeribitRequest {
     pub method: DeribitRequestMethod,
     pub id: String,
     pub jsonrpc: String,
-    pub params: DeribitRequestParams,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub params: Option<DeribitRequestParams>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -18,12 +21,97 @@ pub struct DeribitResponse {
     pub result: Vec<String>
 }
 
+/// Any message Deribit sends over the websocket
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum DeribitMessage {
+    Response(DeribitResponse),
+    Error(DeribitErrorResponse),
+    Channel(DeribitChannelMessage),
+    Heartbeat(DeribitHeartbeat),
+    Ack(DeribitAck),
+}
+
+/// A JSON-RPC response to requests which do not return a list of channels,
+/// e.g. `public/set_heartbeat` or `public/test`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeribitAck {
+    pub jsonrpc: String,
+    pub id: String,
+    pub result: serde_json::Value,
+}
+
+/// Notification sent by Deribit once `public/set_heartbeat` is enabled
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeribitHeartbeat {
+    pub jsonrpc: String,
+    pub method: DeribitResponseMethod,
+    pub params: DeribitHeartbeatParams,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeribitHeartbeatParams {
+    #[serde(rename = "type")]
+    pub heartbeat_type: DeribitHeartbeatType,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DeribitHeartbeatType {
+    /// Informational, nothing to do
+    Heartbeat,
+    /// Deribit expects a `public/test` request in return, or it closes the connection
+    TestRequest,
+}
+
+/// A JSON-RPC error response
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeribitErrorResponse {
+    pub jsonrpc: String,
+    pub id: Option<String>,
+    pub error: DeribitError,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeribitError {
+    pub code: i64,
+    pub message: String,
+}
+
+impl DeribitError {
+    /// Classify the error based on the error code documented by Deribit
+    pub fn kind(&self) -> ExchangeErrorKind {
+        match self.code {
+            10020 => ExchangeErrorKind::InvalidInstrument,
+            10028 => ExchangeErrorKind::RateLimited,
+            10000 | 13004 | 13009 | 13021 => ExchangeErrorKind::Authentication,
+            _ => ExchangeErrorKind::Other,
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum DeribitRequestMethod {
     #[serde(rename = "public/subscribe")]
     PublicSubscribe,
     #[serde(rename = "public/unsubscribe")]
     PublicUnsubscribe,
+    #[serde(rename = "public/set_heartbeat")]
+    PublicSetHeartbeat,
+    #[serde(rename = "public/test")]
+    PublicTest,
+    #[serde(rename = "public/auth")]
+    PublicAuth,
+    #[serde(rename = "private/subscribe")]
+    PrivateSubscribe,
+    #[serde(rename = "private/buy")]
+    PrivateBuy,
+    #[serde(rename = "private/sell")]
+    PrivateSell,
+    #[serde(rename = "private/edit_by_label")]
+    PrivateEditByLabel,
+    #[serde(rename = "private/cancel_by_label")]
+    PrivateCancelByLabel,
 }
 
 
@@ -31,6 +119,144 @@ pub enum DeribitRequestMethod {
 #[serde(rename_all = "lowercase")]
 pub enum DeribitRequestParams {
     Channels(Vec<String>),
+    /// Heartbeat interval in seconds
+    Interval(u64),
+    #[serde(untagged)]
+    Auth(DeribitAuthParams),
+    #[serde(untagged)]
+    Order(DeribitOrderParams),
+}
+
+/// Params of `public/auth` with the `client_credentials` grant
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeribitAuthParams {
+    pub grant_type: String,
+    pub client_id: String,
+    pub client_secret: String,
+}
+
+/// Params of the order methods, each method only sets its own fields
+///
+/// Orders are identified by their label, `private/cancel_by_label` only needs the label.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct DeribitOrderParams {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub instrument_name: Option<String>,
+    pub label: String,
+    #[serde(default, skip_serializing_if = "Option::is_none", with = "rust_decimal::serde::float_option")]
+    pub amount: Option<Decimal>,
+    #[serde(default, skip_serializing_if = "Option::is_none", with = "rust_decimal::serde::float_option")]
+    pub price: Option<Decimal>,
+    #[serde(rename = "type", default, skip_serializing_if = "Option::is_none")]
+    pub order_type: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub time_in_force: Option<DeribitTimeInForce>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DeribitTimeInForce {
+    GoodTilCancelled,
+    ImmediateOrCancel,
+}
+
+/// Result of `private/buy`, `private/sell` and `private/edit_by_label`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeribitOrderResult {
+    pub order: DeribitOrder,
+    #[serde(default)]
+    pub trades: Vec<DeribitTrade>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeribitOrder {
+    pub order_id: String,
+    pub instrument_name: String,
+    /// Empty for the orders placed without a label
+    #[serde(default)]
+    pub label: String,
+    pub direction: DeribitDirection,
+    pub order_state: DeribitOrderState,
+    pub amount: Decimal,
+    pub filled_amount: Decimal,
+    /// Zero until the order fills
+    #[serde(default)]
+    pub average_price: Decimal,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeribitTrade {
+    pub trade_id: String,
+    pub price: Decimal,
+    pub amount: Decimal,
+    /// Charged fee, negative for a rebate
+    pub fee: Decimal,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DeribitDirection {
+    Buy,
+    Sell,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DeribitOrderState {
+    Open,
+    Filled,
+    Rejected,
+    Cancelled,
+    Untriggered,
+}
+
+/// Notification of the `user.orders` channels
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeribitOrderNotification {
+    pub jsonrpc: String,
+    pub method: DeribitResponseMethod,
+    pub params: DeribitOrderNotificationParams,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeribitOrderNotificationParams {
+    pub channel: String,
+    pub data: DeribitOrder,
+}
+
+/// Notification of the `user.portfolio` channels, sent when the account of a currency changes
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeribitPortfolioNotification {
+    pub jsonrpc: String,
+    pub method: DeribitResponseMethod,
+    pub params: DeribitPortfolioNotificationParams,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeribitPortfolioNotificationParams {
+    pub channel: String,
+    pub data: DeribitPortfolio,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeribitPortfolio {
+    /// e.g. `BTC`
+    pub currency: String,
+    /// Margin balance less the initial margin of the positions and open orders
+    pub available_funds: Decimal,
+    pub initial_margin: Decimal,
+    pub margin_balance: Decimal,
+}
+
+/// Any message Deribit sends over an authenticated websocket
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum DeribitPrivateMessage {
+    Error(DeribitErrorResponse),
+    Orders(DeribitOrderNotification),
+    Portfolio(DeribitPortfolioNotification),
+    Heartbeat(DeribitHeartbeat),
+    Ack(DeribitAck),
 }
 
 
@@ -51,6 +277,7 @@ pub struct DerbitResponseParams {
 #[serde(rename_all = "lowercase")]
 pub enum DeribitResponseMethod {
     Subscription,
+    Heartbeat,
 }
 
 
@@ -126,7 +353,7 @@ mod tests {
             method: DeribitRequestMethod::PublicSubscribe,
             id: "1".to_string(),
             jsonrpc: "2.0".to_string(),
-            params: DeribitRequestParams::Channels(vec!["book.BTC-10MAY24-66000-C.none.20.100ms".to_string()]),
+            params: Some(DeribitRequestParams::Channels(vec!["book.BTC-10MAY24-66000-C.none.20.100ms".to_string()])),
         };
 
         let serialized = serde_json::to_value(&request).unwrap();
@@ -143,6 +370,68 @@ mod tests {
         assert_eq!(serialized, expected);
     }
 
+    #[test]
+    fn test_serialize_heartbeat_requests() {
+        let request = DeribitRequest {
+            method: DeribitRequestMethod::PublicSetHeartbeat,
+            id: "2".to_string(),
+            jsonrpc: "2.0".to_string(),
+            params: Some(DeribitRequestParams::Interval(30)),
+        };
+        let expected = serde_json::json!({
+            "method": "public/set_heartbeat",
+            "id": "2",
+            "jsonrpc": "2.0",
+            "params": {
+                "interval": 30
+            }
+        });
+        assert_eq!(serde_json::to_value(&request).unwrap(), expected);
+
+        let request = DeribitRequest {
+            method: DeribitRequestMethod::PublicTest,
+            id: "3".to_string(),
+            jsonrpc: "2.0".to_string(),
+            params: None,
+        };
+        let expected = serde_json::json!({
+            "method": "public/test",
+            "id": "3",
+            "jsonrpc": "2.0"
+        });
+        assert_eq!(serde_json::to_value(&request).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_deserialize_heartbeat_messages() {
+        let heartbeat = serde_json::json!({
+            "jsonrpc": "2.0",
+            "method": "heartbeat",
+            "params": {
+                "type": "test_request"
+            }
+        });
+        match serde_json::from_value::<DeribitMessage>(heartbeat).unwrap() {
+            DeribitMessage::Heartbeat(heartbeat) => {
+                assert_eq!(heartbeat.method, DeribitResponseMethod::Heartbeat);
+                assert_eq!(heartbeat.params.heartbeat_type, DeribitHeartbeatType::TestRequest);
+            }
+            _ => panic!("Expected Heartbeat message"),
+        }
+
+        let test_response = serde_json::json!({
+            "jsonrpc": "2.0",
+            "id": "3",
+            "result": {
+                "version": "1.2.26"
+            }
+        });
+        match serde_json::from_value::<DeribitMessage>(test_response).unwrap() {
+            DeribitMessage::Ack(ack) => assert_eq!(ack.id, "3"),
+            _ => panic!("Expected Ack response"),
+        }
+    }
+
     #[test]
     fn test_serialize_response() {
         let response = serde_json::json!({
@@ -158,6 +447,56 @@ mod tests {
         assert_eq!(deserialized.result, vec!["book.BTC-10MAY24-66000-C.none.20.100ms"]);
     }
 
+    #[test]
+    fn test_deserialize_error_response() {
+        let response = serde_json::json!({
+            "jsonrpc": "2.0",
+            "id": "7",
+            "error": {
+                "code": 10028,
+                "message": "too_many_requests"
+            },
+            "usIn": 1717219200000000u64,
+            "usOut": 1717219200000100u64,
+            "usDiff": 100
+        });
+
+        let deserialized = serde_json::from_value::<DeribitMessage>(response).unwrap();
+        match deserialized {
+            DeribitMessage::Error(error) => {
+                assert_eq!(error.id, Some("7".to_string()));
+                assert_eq!(error.error.code, 10028);
+                assert_eq!(error.error.kind(), ExchangeErrorKind::RateLimited);
+            }
+            _ => panic!("Expected Error response"),
+        }
+    }
+
+    #[test]
+    fn test_deserialize_message_variants() {
+        let response = serde_json::json!({
+            "jsonrpc": "2.0",
+            "id": "1",
+            "result": ["book.BTC-10MAY24-66000-C.none.20.100ms"]
+        });
+        assert!(matches!(serde_json::from_value::<DeribitMessage>(response).unwrap(), DeribitMessage::Response(_)));
+
+        let channel_message = serde_json::json!({
+            "jsonrpc": "2.0",
+            "method": "subscription",
+            "params": {
+                "channel": "book.BTC-10MAY24-66000-C.none.20.100ms",
+                "data": {
+                    "instrument_name": "BTC-10MAY24-66000-C",
+                    "timestamp": 1717219200,
+                    "asks": [],
+                    "bids": [],
+                },
+            }
+        });
+        assert!(matches!(serde_json::from_value::<DeribitMessage>(channel_message).unwrap(), DeribitMessage::Channel(_)));
+    }
+
     #[test  ]
     fn test_deserialize_order_book() {
 
@@ -198,4 +537,93 @@ mod tests {
         assert_eq!(bids[0].amount, Decimal::from(100));
     }
 
+
+    #[test]
+    fn test_private_messages() {
+        let request = DeribitRequest {
+            method: DeribitRequestMethod::PrivateBuy,
+            id: "4".to_string(),
+            jsonrpc: "2.0".to_string(),
+            params: Some(DeribitRequestParams::Order(DeribitOrderParams {
+                instrument_name: Some("BTC-21FEB25-90000-P".to_string()),
+                label: "arb1s".to_string(),
+                amount: Some(Decimal::ONE),
+                price: Some(Decimal::new(105, 4)),
+                order_type: Some("limit".to_string()),
+                time_in_force: Some(DeribitTimeInForce::ImmediateOrCancel),
+            })),
+        };
+        let expected = serde_json::json!({
+            "method": "private/buy",
+            "id": "4",
+            "jsonrpc": "2.0",
+            "params": {
+                "instrument_name": "BTC-21FEB25-90000-P",
+                "label": "arb1s",
+                "amount": 1.0,
+                "price": 0.0105,
+                "type": "limit",
+                "time_in_force": "immediate_or_cancel"
+            }
+        });
+        assert_eq!(serde_json::to_value(&request).unwrap(), expected);
+
+        let order = serde_json::json!({
+            "order_id": "ETH-584849853",
+            "instrument_name": "BTC-21FEB25-90000-P",
+            "label": "arb1s",
+            "direction": "buy",
+            "order_state": "filled",
+            "amount": 1.0,
+            "filled_amount": 1.0,
+            "average_price": 0.01,
+            "price": 0.0105
+        });
+        let response = serde_json::json!({
+            "jsonrpc": "2.0",
+            "id": "4",
+            "result": {"order": order, "trades": [{"trade_id": "1", "price": 0.01, "amount": 1.0, "fee": 0.0003}]}
+        });
+        match serde_json::from_value::<DeribitPrivateMessage>(response).unwrap() {
+            DeribitPrivateMessage::Ack(ack) => {
+                let result = serde_json::from_value::<DeribitOrderResult>(ack.result).unwrap();
+                assert_eq!(result.order.order_state, DeribitOrderState::Filled);
+                assert_eq!(result.trades[0].fee, Decimal::new(3, 4));
+            }
+            message => panic!("unexpected message {:?}", message),
+        }
+
+        let notification = serde_json::json!({
+            "jsonrpc": "2.0",
+            "method": "subscription",
+            "params": {"channel": "user.orders.any.any.raw", "data": order}
+        });
+        match serde_json::from_value::<DeribitPrivateMessage>(notification).unwrap() {
+            DeribitPrivateMessage::Orders(notification) => assert_eq!(notification.params.data.filled_amount, Decimal::ONE),
+            message => panic!("unexpected message {:?}", message),
+        }
+
+        let notification = serde_json::json!({
+            "jsonrpc": "2.0",
+            "method": "subscription",
+            "params": {
+                "channel": "user.portfolio.btc",
+                "data": {
+                    "currency": "BTC",
+                    "balance": 1.0,
+                    "equity": 1.02,
+                    "available_funds": 0.82,
+                    "initial_margin": 0.2,
+                    "maintenance_margin": 0.15,
+                    "margin_balance": 1.02
+                }
+            }
+        });
+        match serde_json::from_value::<DeribitPrivateMessage>(notification).unwrap() {
+            DeribitPrivateMessage::Portfolio(notification) => {
+                assert_eq!((notification.params.data.currency.as_str(), notification.params.data.available_funds), ("BTC", Decimal::new(82, 2)));
+            }
+            message => panic!("unexpected message {:?}", message),
+        }
+    }
 }
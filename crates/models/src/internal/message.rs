@@ -1,3 +1,4 @@
+use jiff::Timestamp;
 use rust_decimal::Decimal;
 use serde::Serialize;
 
@@ -16,11 +17,39 @@ pub struct OrderBookUpdate {
     pub exchange_product: ExchangeProduct,
     pub bids: Vec<(Decimal, Decimal)>,
     pub asks: Vec<(Decimal, Decimal)>,
+    pub timestamps: PipelineTimestamps,
 }
 
 
-impl From<DeribitChannelData> for InternalMessage {
-    fn from(data: DeribitChannelData) -> Self {
+/// Timestamps of an update as it flows from the exchange to the websocket clients
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct PipelineTimestamps {
+    /// Time at which the exchange generated the update
+    pub exchange_time: Timestamp,
+    /// Time at which the update was received from the exchange
+    pub received_time: Timestamp,
+    /// Time at which the order book manager processed the update
+    pub processed_time: Option<Timestamp>,
+    /// Time at which the update was sent to a websocket client
+    pub sent_time: Option<Timestamp>,
+}
+
+impl PipelineTimestamps {
+    pub fn new(exchange_time: Timestamp, received_time: Timestamp) -> Self {
+        Self { exchange_time, received_time, processed_time: None, sent_time: None }
+    }
+}
+
+
+/// Converts a timestamp in milliseconds as sent by the exchanges, falling back
+/// to the local time if it is out of range
+fn exchange_timestamp(millis: u64, received_time: Timestamp) -> Timestamp {
+    Timestamp::from_millisecond(millis as i64).unwrap_or(received_time)
+}
+
+
+impl InternalMessage {
+    pub fn from_deribit(data: DeribitChannelData, received_time: Timestamp) -> Self {
         match data {
             DeribitChannelData::OrderBook(order_book) => {
                 let bids = order_book.bids.iter().map(|bid| (bid.price, bid.amount)).collect();
@@ -34,45 +63,44 @@ impl From<DeribitChannelData> for InternalMessage {
                     exchange_product: product,
                     bids,
                     asks,
+                    timestamps: PipelineTimestamps::new(exchange_timestamp(order_book.timestamp, received_time), received_time),
                 })
             }
         }
     }
-}
 
-impl From<OkexMessage> for InternalMessage {
-    fn from(message: OkexMessage) -> Self {
+    /// Converts a book of Okex, whose sizes are in contracts, to sizes in the underlying for an option
+    pub fn from_okex(message: OkexMessage, received_time: Timestamp) -> Self {
+        //TODO: handle errors better
+        let product = Product::from_okex_exhchange(&message.arg.instance_id).unwrap();
+        let size = |contracts| product.from_contracts(&Exchange::Okex, contracts);
         match message.action {
             OkexAction::Snapshot => {
                 let mut asks = Vec::new();
                 let mut bids = Vec::new();
+                let mut timestamp = 0;
                 for data in message.data {
-                    asks.extend(data.asks.iter().map(|ask| (ask.price, ask.amount)));
-                    bids.extend(data.bids.iter().map(|bid| (bid.price, bid.amount)));
+                    asks.extend(data.asks.iter().map(|ask| (ask.price, size(ask.amount))));
+                    bids.extend(data.bids.iter().map(|bid| (bid.price, size(bid.amount))));
+                    timestamp = timestamp.max(data.timestamp);
                 }
-                let product = ExchangeProduct {
-                    exchange: Exchange::Okex,
-                    product: Product::from_okex_exhchange(&message.arg.instance_id).unwrap(),
-                };
                 InternalMessage::OrderBookUpdate(OrderBookUpdate {
-                    exchange_product: product,
+                    exchange_product: ExchangeProduct { exchange: Exchange::Okex, product },
                     bids,
                     asks,
+                    timestamps: PipelineTimestamps::new(exchange_timestamp(timestamp, received_time), received_time),
                 })
             }
             OkexAction::Update => {
                 //TODO: handle errors better
                 let data = message.data.first().unwrap();
-                let bids = data.bids.iter().map(|bid| (bid.price, bid.amount)).collect();
-                let asks = data.asks.iter().map(|ask| (ask.price, ask.amount)).collect();
-                let product = ExchangeProduct {
-                    exchange: Exchange::Okex,
-                    product: Product::from_okex_exhchange(&message.arg.instance_id).unwrap(),
-                };
+                let bids = data.bids.iter().map(|bid| (bid.price, size(bid.amount))).collect();
+                let asks = data.asks.iter().map(|ask| (ask.price, size(ask.amount))).collect();
                 InternalMessage::OrderBookUpdate(OrderBookUpdate {
-                    exchange_product: product,
+                    exchange_product: ExchangeProduct { exchange: Exchange::Okex, product },
                     bids,
                     asks,
+                    timestamps: PipelineTimestamps::new(exchange_timestamp(data.timestamp, received_time), received_time),
                 })
             }
         }
@@ -88,4 +116,47 @@ pub struct ArbitrageOpportunity {
     pub buy_price: Decimal,
     pub sell_price: Decimal,
     pub size: Decimal,
+    /// Profit per unit, net of the fees of both legs
+    pub edge: Decimal,
+    /// Age of the book on the buy exchange when the opportunity was detected
+    pub buy_book_age_millis: i64,
+    /// Age of the book on the sell exchange when the opportunity was detected
+    pub sell_book_age_millis: i64,
+    /// Exchange whose update triggered the opportunity
+    pub trigger_exchange: Exchange,
+    /// Timestamps of the update which triggered the opportunity
+    pub timestamps: PipelineTimestamps,
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn okex_book(instance_id: &str) -> OkexMessage {
+        serde_json::from_value(serde_json::json!({
+            "action": "snapshot",
+            "arg": {"channel": "books", "instId": instance_id},
+            "data": [{"ts": "1740045600123", "asks": [["0.011", "250", "0", "1"]], "bids": [["0.0105", "12", "0", "1"]]}],
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_from_okex_sizes() {
+        let received_time = Timestamp::from_millisecond(1_740_045_600_200).unwrap();
+        // The options are sized in contracts of 0.01 BTC
+        let InternalMessage::OrderBookUpdate(update) = InternalMessage::from_okex(okex_book("BTC-USD-250221-90000-P"), received_time) else {
+            panic!("expected a book");
+        };
+        assert_eq!(update.asks, vec![(Decimal::new(11, 3), Decimal::new(25, 1))]);
+        assert_eq!(update.bids, vec![(Decimal::new(105, 4), Decimal::new(12, 2))]);
+        assert_eq!(update.timestamps.exchange_time, Timestamp::from_millisecond(1_740_045_600_123).unwrap());
+
+        // The perpetuals stay in contracts
+        let InternalMessage::OrderBookUpdate(update) = InternalMessage::from_okex(okex_book("BTC-USD-SWAP"), received_time) else {
+            panic!("expected a book");
+        };
+        assert_eq!(update.asks, vec![(Decimal::new(11, 3), Decimal::from(250))]);
+    }
 }
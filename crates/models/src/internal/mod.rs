@@ -6,7 +6,7 @@ mod message;
 pub use product::*;
 pub use order_book::*;
 pub use message::*;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 
 
 
@@ -15,8 +15,32 @@ pub struct ProductSubscription {
     pub product_id: String,
     pub subscribed: bool,
 }
-#[derive(Debug, Hash, Eq, PartialEq, Clone, Serialize)]
+#[derive(Debug, Hash, Eq, PartialEq, Clone, Serialize, Deserialize)]
 pub enum Exchange {
     Okex,
     Deribit,
 }
+
+
+/// Changes the set of products an exchange adapter is subscribed to while it is running.
+///
+/// Product ids use the exchange specific format, same as the `*_PRODUCTS_TO_SUBSCRIBE` variables.
+#[derive(Debug, Eq, PartialEq, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SubscriptionCommand {
+    Subscribe(Vec<String>),
+    Unsubscribe(Vec<String>),
+}
+
+/// Classification of an error reported by an exchange, used to decide how to react to it
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum ExchangeErrorKind {
+    /// The requested instrument does not exist, the subscription should be dropped
+    InvalidInstrument,
+    /// Too many requests were sent, the client should back off
+    RateLimited,
+    /// Credentials were rejected or are missing, this is fatal
+    Authentication,
+    /// Any other error
+    Other,
+}
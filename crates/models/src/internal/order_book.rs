@@ -1,4 +1,5 @@
 use std::collections::BTreeMap;
+use jiff::Timestamp;
 use rust_decimal::Decimal;
 use super::{ExchangeProduct, OrderBookUpdate};
 
@@ -8,12 +9,28 @@ pub struct OrderBook {
     pub exchange_product: ExchangeProduct,
     pub bids: BTreeMap<Decimal, Decimal>,
     pub asks: BTreeMap<Decimal, Decimal>,
+    /// Exchange time of the last update applied to the book
+    pub exchange_timestamp: Option<Timestamp>,
+    /// Local time at which the last update applied to the book was received
+    pub received_time: Option<Timestamp>,
 }
 
 
 impl OrderBook {
     pub fn new(exchange_product: &ExchangeProduct) -> Self {
-        Self { exchange_product: exchange_product.clone(), bids: BTreeMap::new(), asks: BTreeMap::new() }
+        Self {
+            exchange_product: exchange_product.clone(),
+            bids: BTreeMap::new(),
+            asks: BTreeMap::new(),
+            exchange_timestamp: None,
+            received_time: None,
+        }
+    }
+
+    /// Time elapsed in milliseconds between the reception of the last update and `now`,
+    /// None if the book never received an update
+    pub fn age_millis(&self, now: Timestamp) -> Option<i64> {
+        self.received_time.map(|received_time| now.duration_since(received_time).as_millis() as i64)
     }
 
     pub fn best_bid(&self) -> Option<(Decimal, Decimal)> {
@@ -47,5 +64,7 @@ impl OrderBook {
         for (price, size) in order_book_update.asks {
             self.add_ask(price, size);
         }
+        self.exchange_timestamp = Some(order_book_update.timestamps.exchange_time);
+        self.received_time = Some(order_book_update.timestamps.received_time);
     }
 }
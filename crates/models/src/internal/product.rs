@@ -1,8 +1,8 @@
-use std::str::FromStr;
+use std::{fmt, str::FromStr};
 
 use chrono::NaiveDate;
 use rust_decimal::Decimal;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 
 use super::Exchange;
 
@@ -23,6 +23,11 @@ pub enum Product {
         expiration: NaiveDate,
         option_type: OptionType,
     },
+    /// Inverse perpetual swap, sized in USD, e.g. `BTC-USD-SWAP` on Okex and `BTC-PERPETUAL` on Deribit
+    Perpetual {
+        underlying: CryptoAsset,
+        settlement: SettlementAsset,
+    },
 }
 
 
@@ -32,7 +37,7 @@ pub enum OptionType {
     Put,
 }
 
-#[derive(Debug, Hash, Eq, PartialEq, Clone, Serialize)]
+#[derive(Debug, Hash, Eq, PartialEq, Clone, Serialize, Deserialize)]
 pub enum CryptoAsset {
     BTC,
     ETH
@@ -44,26 +49,36 @@ pub enum SettlementAsset {
 }
 
 
+impl CryptoAsset {
+    /// e.g. `BTC`, as the exchanges name the currencies
+    pub fn from_symbol(symbol: &str) -> Option<Self> {
+        match symbol {
+            "BTC" => Some(CryptoAsset::BTC),
+            "ETH" => Some(CryptoAsset::ETH),
+            _ => None,
+        }
+    }
+}
+
 impl Product {
 
     pub fn from_okex_exhchange(s: &str) -> Option<Self> {
         let parts = s.split('-').collect::<Vec<&str>>();
-        if parts.len() != 5 {
+        if parts.len() != 5 && parts.len() != 3 {
             return None;
         }
 
-        let underlying = match parts[0] {
-            "BTC" => CryptoAsset::BTC,
-            "ETH" => CryptoAsset::ETH,
-            _ => return None,
-        };
+        let underlying = CryptoAsset::from_symbol(parts[0])?;
 
         let settlement = match parts[1] {
             "USD" => SettlementAsset::USD,
             _ => return None,
         };
+        if parts.len() == 3 {
+            return (parts[2] == "SWAP").then_some(Self::Perpetual { underlying, settlement });
+        }
         let strike = Decimal::from_str(parts[3]).unwrap_or_default();
-        let expiration = NaiveDate::parse_from_str(parts[2], "%y%m%d").unwrap();
+        let expiration = NaiveDate::parse_from_str(parts[2], "%y%m%d").ok()?;
 
         let option_type = match parts[4] {
             "C" => OptionType::Call,
@@ -76,19 +91,18 @@ impl Product {
 
     pub fn from_deribit_exchange(s: &str) -> Option<Self> {
         let parts = s.split('-').collect::<Vec<&str>>();
-        if parts.len() != 4 {
+        if parts.len() != 4 && parts.len() != 2 {
             return None;
         }
 
-        let underlying = match parts[0] {
-            "BTC" => CryptoAsset::BTC,
-            "ETH" => CryptoAsset::ETH,
-            _ => return None,
-        };
+        let underlying = CryptoAsset::from_symbol(parts[0])?;
 
         let settlement = SettlementAsset::USD;
+        if parts.len() == 2 {
+            return (parts[1] == "PERPETUAL").then_some(Self::Perpetual { underlying, settlement });
+        }
         let strike = Decimal::from_str(parts[2]).unwrap_or_default();
-        let expiration = NaiveDate::parse_from_str(parts[1], "%d%b%y").unwrap();
+        let expiration = NaiveDate::parse_from_str(parts[1], "%d%b%y").ok()?;
 
         let option_type = match parts[3] {
             "C" => OptionType::Call,
@@ -98,9 +112,83 @@ impl Product {
 
         Some(Self::Option { underlying, settlement, strike, expiration, option_type })
     }
+
+    pub fn underlying(&self) -> &CryptoAsset {
+        match self {
+            Product::Option { underlying, .. } | Product::Perpetual { underlying, .. } => underlying,
+        }
+    }
+
+    /// Value of a contract of the exchange, in USD for a perpetual and in the underlying for an option
+    ///
+    /// Okex sizes its options in contracts of `ctVal` × `ctMult` of the underlying, 0.01 BTC or 0.1 ETH, where Deribit
+    /// sizes them in the underlying.
+    pub fn contract_value(&self, exchange: &Exchange) -> Decimal {
+        match (self, exchange) {
+            (Product::Option { .. }, Exchange::Deribit) | (Product::Perpetual { .. }, Exchange::Deribit) => Decimal::ONE,
+            (Product::Option { underlying: CryptoAsset::BTC, .. }, Exchange::Okex) => Decimal::new(1, 2),
+            (Product::Option { underlying: CryptoAsset::ETH, .. }, Exchange::Okex) => Decimal::new(1, 1),
+            (Product::Perpetual { underlying: CryptoAsset::BTC, .. }, Exchange::Okex) => Decimal::ONE_HUNDRED,
+            (Product::Perpetual { underlying: CryptoAsset::ETH, .. }, Exchange::Okex) => Decimal::TEN,
+        }
+    }
+
+    /// Size of a number of contracts of the exchange, in the underlying for an option and in contracts for a perpetual
+    ///
+    /// The options are sized in their underlying on every exchange so that their books, orders and positions compare.
+    pub fn from_contracts(&self, exchange: &Exchange, contracts: Decimal) -> Decimal {
+        match self {
+            Product::Option { .. } => (contracts * self.contract_value(exchange)).normalize(),
+            Product::Perpetual { .. } => contracts,
+        }
+    }
+
+    /// Number of contracts of the exchange of a size, the reverse of `from_contracts`
+    pub fn to_contracts(&self, exchange: &Exchange, size: Decimal) -> Decimal {
+        match self {
+            Product::Option { .. } => (size / self.contract_value(exchange)).normalize(),
+            Product::Perpetual { .. } => size,
+        }
+    }
+
+    /// Instrument id on Okex, e.g. `BTC-USD-250221-99000-C`
+    pub fn to_okex_exchange(&self) -> String {
+        self.to_string()
+    }
+
+    /// Instrument name on Deribit, e.g. `BTC-21FEB25-99000-C`
+    pub fn to_deribit_exchange(&self) -> String {
+        match self {
+            Product::Option { underlying, strike, expiration, option_type, .. } => {
+                let option_type = match option_type {
+                    OptionType::Call => "C",
+                    OptionType::Put => "P",
+                };
+                let expiration = expiration.format("%-d%b%y").to_string().to_uppercase();
+                format!("{:?}-{}-{}-{}", underlying, expiration, strike, option_type)
+            }
+            Product::Perpetual { underlying, .. } => format!("{:?}-PERPETUAL", underlying),
+        }
+    }
 }
 
 
+/// Formats the product in an exchange agnostic way, e.g. `BTC-USD-250221-99000-C`
+impl fmt::Display for Product {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Product::Option { underlying, settlement, strike, expiration, option_type } => {
+                let option_type = match option_type {
+                    OptionType::Call => "C",
+                    OptionType::Put => "P",
+                };
+                write!(f, "{:?}-{:?}-{}-{}-{}", underlying, settlement, expiration.format("%y%m%d"), strike, option_type)
+            }
+            Product::Perpetual { underlying, settlement } => write!(f, "{:?}-{:?}-SWAP", underlying, settlement),
+        }
+    }
+}
+
 
 #[cfg(test)]
 mod tests {
@@ -118,6 +206,42 @@ mod tests {
         });
     }
 
+    #[test]
+    fn test_display() {
+        let product = Product::from_deribit_exchange("BTC-21FEB25-99000-C").unwrap();
+        assert_eq!(product.to_string(), "BTC-USD-250221-99000-C");
+    }
+
+    #[test]
+    fn test_to_exchange() {
+        let product = Product::from_okex_exhchange("BTC-USD-250307-99000-C").unwrap();
+        assert_eq!(product.to_okex_exchange(), "BTC-USD-250307-99000-C");
+        assert_eq!(product.to_deribit_exchange(), "BTC-7MAR25-99000-C");
+        assert_eq!(Product::from_deribit_exchange(&product.to_deribit_exchange()), Some(product.clone()));
+
+        let perpetual = Product::from_okex_exhchange("BTC-USD-SWAP").unwrap();
+        assert_eq!(perpetual, Product::Perpetual { underlying: CryptoAsset::BTC, settlement: SettlementAsset::USD });
+        assert_eq!(perpetual.to_deribit_exchange(), "BTC-PERPETUAL");
+        assert_eq!(Product::from_deribit_exchange("BTC-PERPETUAL"), Some(perpetual.clone()));
+        assert_eq!(Product::from_okex_exhchange("BTC-USD-FUTURES"), None);
+        assert_eq!(perpetual.contract_value(&Exchange::Okex), Decimal::ONE_HUNDRED);
+        assert_eq!(product.contract_value(&Exchange::Okex), Decimal::from_str("0.01").unwrap());
+        assert_eq!(product.contract_value(&Exchange::Deribit), Decimal::ONE);
+    }
+
+    #[test]
+    fn test_contracts() {
+        let product = Product::from_okex_exhchange("ETH-USD-250307-3000-P").unwrap();
+        assert_eq!(product.contract_value(&Exchange::Okex), Decimal::from_str("0.1").unwrap());
+        assert_eq!(product.from_contracts(&Exchange::Okex, Decimal::from(25)), Decimal::from_str("2.5").unwrap());
+        assert_eq!(product.to_contracts(&Exchange::Okex, Decimal::from_str("2.5").unwrap()), Decimal::from(25));
+        assert_eq!(product.to_contracts(&Exchange::Deribit, Decimal::from_str("2.5").unwrap()), Decimal::from_str("2.5").unwrap());
+
+        let perpetual = Product::from_okex_exhchange("BTC-USD-SWAP").unwrap();
+        assert_eq!(perpetual.from_contracts(&Exchange::Okex, Decimal::from(3)), Decimal::from(3));
+        assert_eq!(perpetual.to_contracts(&Exchange::Okex, Decimal::from(3)), Decimal::from(3));
+    }
+
     #[test]
     fn test_from_deribit_exchange() {
         let product = Product::from_deribit_exchange("BTC-21FEB25-99000-C").unwrap();
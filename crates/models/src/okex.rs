@@ -3,19 +3,28 @@ use std::fmt;
 use rust_decimal::Decimal;
 use serde::{de::{SeqAccess, Visitor, Error}, Deserialize, Deserializer, Serialize};
 
+use crate::ExchangeErrorKind;
 
+
+/// Request without an id, the arguments are the channels on subscriptions and the credentials on login
 #[derive(Clone, Serialize, Deserialize)]
-pub struct OkexRequest {
+pub struct OkexRequest<A = OkexArg> {
     pub op: OkexOperation,
-    pub args: Vec<OkexArg>,
+    pub args: Vec<A>,
 }
 
 
-#[derive(Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 #[serde(rename_all = "snake_case")]
 pub enum OkexOperation {
     Subscribe,
     Unsubscribe,
+    Login,
+    Order,
+    #[serde(rename = "amend-order")]
+    AmendOrder,
+    #[serde(rename = "cancel-order")]
+    CancelOrder,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
@@ -23,6 +32,7 @@ pub enum OkexOperation {
 pub enum OkexEvent {
     Subscribe,
     Unsubscribe,
+    Login,
     Error,
 }
 
@@ -66,6 +76,29 @@ pub struct OkexError {
     pub message: String,
 }
 
+impl OkexError {
+    /// Classify the error based on the error code documented by Okex
+    pub fn kind(&self) -> ExchangeErrorKind {
+        match self.code.as_str() {
+            "60018" => ExchangeErrorKind::InvalidInstrument,
+            "60014" | "50011" => ExchangeErrorKind::RateLimited,
+            "60004" | "60005" | "60006" | "60007" | "60009" | "60011" | "60022" | "60024" => ExchangeErrorKind::Authentication,
+            _ => ExchangeErrorKind::Other,
+        }
+    }
+
+    /// Okex does not echo the failed argument back, the instrument is only part of the message
+    /// e.g. `Wrong URL or channel:books,instId:BTC-USD-250221-9000-P doesn't exist.`
+    pub fn instrument_id(&self) -> Option<&str> {
+        let (_, rest) = self.message.split_once("instId:")?;
+        let end = rest.find(|c: char| c == ',' || c.is_whitespace()).unwrap_or(rest.len());
+        match &rest[..end] {
+            "" => None,
+            instrument_id => Some(instrument_id),
+        }
+    }
+}
+
 #[derive(Clone, Serialize, Deserialize, PartialEq, Eq, Debug)]
 #[serde(rename_all = "snake_case")]
 pub enum OkexAction {
@@ -141,6 +174,190 @@ impl<'de> Deserialize<'de> for OrderBookEntry {
     }
 }
 
+/// Credentials of the `login` operation of the private websocket
+///
+/// `sign` is the base64 HMAC SHA256, keyed by the secret key, of `timestamp + "GET" + "/users/self/verify"`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OkexLoginArg {
+    #[serde(rename = "apiKey")]
+    pub api_key: String,
+    pub passphrase: String,
+    /// Seconds since the epoch
+    pub timestamp: String,
+    pub sign: String,
+}
+
+/// Channel of the private websocket, e.g. `orders` of the `OPTION` instrument type or `account`, which has none
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OkexChannelArg {
+    pub channel: String,
+    #[serde(rename = "instType", default, skip_serializing_if = "Option::is_none")]
+    pub instrument_type: Option<String>,
+}
+
+/// Place, amend or cancel request, answered by an `OkexOrderResponse` with the same id
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OkexOrderRequest {
+    pub id: String,
+    pub op: OkexOperation,
+    pub args: Vec<OkexOrderArg>,
+}
+
+/// Arguments of the order operations, each operation only sets its own fields
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct OkexOrderArg {
+    #[serde(rename = "instId")]
+    pub instance_id: String,
+    #[serde(rename = "clOrdId")]
+    pub client_order_id: String,
+    #[serde(rename = "tdMode", default, skip_serializing_if = "Option::is_none")]
+    pub trade_mode: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub side: Option<OkexOrderSide>,
+    #[serde(rename = "ordType", default, skip_serializing_if = "Option::is_none")]
+    pub order_type: Option<OkexOrderType>,
+    #[serde(rename = "px", default, skip_serializing_if = "Option::is_none")]
+    pub price: Option<Decimal>,
+    #[serde(rename = "sz", default, skip_serializing_if = "Option::is_none")]
+    pub size: Option<Decimal>,
+    #[serde(rename = "newPx", default, skip_serializing_if = "Option::is_none")]
+    pub new_price: Option<Decimal>,
+    #[serde(rename = "newSz", default, skip_serializing_if = "Option::is_none")]
+    pub new_size: Option<Decimal>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum OkexOrderSide {
+    Buy,
+    Sell,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum OkexOrderType {
+    Limit,
+    /// Immediate or cancel
+    Ioc,
+}
+
+/// Answer to an order operation, `code` is `0` when all the orders were accepted
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OkexOrderResponse {
+    pub id: String,
+    pub op: OkexOperation,
+    pub code: String,
+    #[serde(rename = "msg")]
+    pub message: String,
+    pub data: Vec<OkexOrderResult>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OkexOrderResult {
+    #[serde(rename = "clOrdId")]
+    pub client_order_id: String,
+    #[serde(rename = "ordId", default)]
+    pub order_id: String,
+    #[serde(rename = "sCode")]
+    pub code: String,
+    #[serde(rename = "sMsg", default)]
+    pub message: String,
+}
+
+/// Push of the `orders` channel, sent whenever an order changes state or fills
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OkexOrdersMessage {
+    pub arg: OkexChannelArg,
+    pub data: Vec<OkexOrder>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OkexOrder {
+    #[serde(rename = "instId")]
+    pub instance_id: String,
+    #[serde(rename = "ordId")]
+    pub order_id: String,
+    /// Empty for the orders not placed with a client id
+    #[serde(rename = "clOrdId", default)]
+    pub client_order_id: String,
+    pub side: OkexOrderSide,
+    pub state: OkexOrderState,
+    #[serde(rename = "px", deserialize_with = "deserialize_optional_decimal", default)]
+    pub price: Option<Decimal>,
+    #[serde(rename = "sz")]
+    pub size: Decimal,
+    /// Cumulative filled size
+    #[serde(rename = "accFillSz", deserialize_with = "deserialize_optional_decimal", default)]
+    pub filled_size: Option<Decimal>,
+    #[serde(rename = "avgPx", deserialize_with = "deserialize_optional_decimal", default)]
+    pub average_price: Option<Decimal>,
+    /// Cumulative fee, negative when charged
+    #[serde(deserialize_with = "deserialize_optional_decimal", default)]
+    pub fee: Option<Decimal>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum OkexOrderState {
+    Live,
+    PartiallyFilled,
+    Filled,
+    Canceled,
+    MmpCanceled,
+}
+
+/// Push of the `account` channel, sent when a balance changes and at least every few seconds
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OkexAccountMessage {
+    pub arg: OkexChannelArg,
+    pub data: Vec<OkexAccount>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OkexAccount {
+    /// Balance of each currency
+    pub details: Vec<OkexAccountBalance>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OkexAccountBalance {
+    #[serde(rename = "ccy")]
+    pub currency: String,
+    /// Equity available for margin, set in the margin account modes
+    #[serde(rename = "availEq", deserialize_with = "deserialize_optional_decimal", default)]
+    pub available_equity: Option<Decimal>,
+    /// Balance available for new orders in the simple account mode
+    #[serde(rename = "availBal", deserialize_with = "deserialize_optional_decimal", default)]
+    pub available_balance: Option<Decimal>,
+}
+
+impl OkexAccountBalance {
+    /// Funds available for new orders, whatever the account mode
+    pub fn available(&self) -> Option<Decimal> {
+        self.available_equity.or(self.available_balance)
+    }
+}
+
+/// Event of the private websocket, e.g. the answer to `login` or to a subscription
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OkexEventMessage {
+    pub event: OkexEvent,
+    #[serde(default)]
+    pub code: Option<String>,
+    #[serde(rename = "msg", default)]
+    pub message: Option<String>,
+}
+
+/// Any message Okex sends over the private websocket
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum OkexPrivateMessage {
+    OrderResponse(OkexOrderResponse),
+    Orders(OkexOrdersMessage),
+    Account(OkexAccountMessage),
+    Event(OkexEventMessage),
+}
+
 
 fn deserialize_timestamp<'de, D>(deserializer: D) -> Result<u64, D::Error>
 where
@@ -150,6 +367,17 @@ where
     timestamp.parse::<u64>().map_err(Error::custom)
 }
 
+/// Okex sends the numbers which are not set yet as empty strings, e.g. `avgPx` of an order without fills
+fn deserialize_optional_decimal<'de, D>(deserializer: D) -> Result<Option<Decimal>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    match Option::<String>::deserialize(deserializer)? {
+        Some(value) if !value.is_empty() => value.parse::<Decimal>().map(Some).map_err(Error::custom),
+        _ => Ok(None),
+    }
+}
+
 
 
 
@@ -224,6 +452,26 @@ mod tests {
         assert_eq!(response.connection_id, "1234567890");
     }
 
+    #[test]
+    fn test_error_kind() {
+        let error = OkexError {
+            code: "60018".to_string(),
+            message: "Wrong URL or channel:books,instId:BTC-USD-250221-9000-P doesn't exist. Please use the correct URL".to_string(),
+        };
+        assert_eq!(error.kind(), ExchangeErrorKind::InvalidInstrument);
+        assert_eq!(error.instrument_id(), Some("BTC-USD-250221-9000-P"));
+
+        let error = OkexError { code: "60014".to_string(), message: "Requests too frequent.".to_string() };
+        assert_eq!(error.kind(), ExchangeErrorKind::RateLimited);
+        assert_eq!(error.instrument_id(), None);
+
+        let error = OkexError { code: "60009".to_string(), message: "Login failed.".to_string() };
+        assert_eq!(error.kind(), ExchangeErrorKind::Authentication);
+
+        let error = OkexError { code: "60012".to_string(), message: "Invalid request".to_string() };
+        assert_eq!(error.kind(), ExchangeErrorKind::Other);
+    }
+
     #[test]
     fn test_deserialize_snapshot_message() {
         let message_json = serde_json::json!({
@@ -297,4 +545,100 @@ mod tests {
         assert_eq!(data.bids[1].price, Decimal::from(83000));
         assert_eq!(data.bids[1].amount, Decimal::from(1));
     }
+
+    #[test]
+    fn test_private_messages() {
+        let request = OkexOrderRequest {
+            id: "1".to_string(),
+            op: OkexOperation::Order,
+            args: vec![OkexOrderArg {
+                instance_id: "BTC-USD-250221-90000-P".to_string(),
+                client_order_id: "arb1b".to_string(),
+                trade_mode: Some("cross".to_string()),
+                side: Some(OkexOrderSide::Buy),
+                order_type: Some(OkexOrderType::Ioc),
+                price: Some(Decimal::new(1, 2)),
+                size: Some(Decimal::ONE),
+                ..OkexOrderArg::default()
+            }],
+        };
+        let expected_json = serde_json::json!({
+            "id": "1",
+            "op": "order",
+            "args": [{
+                "instId": "BTC-USD-250221-90000-P",
+                "clOrdId": "arb1b",
+                "tdMode": "cross",
+                "side": "buy",
+                "ordType": "ioc",
+                "px": "0.01",
+                "sz": "1"
+            }]
+        });
+        assert_eq!(serde_json::to_value(&request).unwrap(), expected_json);
+
+        let login = serde_json::json!({"event": "login", "code": "0", "msg": "", "connId": "a4d3ae55"});
+        match serde_json::from_value::<OkexPrivateMessage>(login).unwrap() {
+            OkexPrivateMessage::Event(event) => assert_eq!((event.event, event.code.as_deref()), (OkexEvent::Login, Some("0"))),
+            message => panic!("unexpected message {:?}", message),
+        }
+
+        let response = serde_json::json!({
+            "id": "1",
+            "op": "order",
+            "code": "1",
+            "msg": "",
+            "data": [{"clOrdId": "arb1b", "ordId": "", "tag": "", "sCode": "51008", "sMsg": "Insufficient balance"}]
+        });
+        match serde_json::from_value::<OkexPrivateMessage>(response).unwrap() {
+            OkexPrivateMessage::OrderResponse(response) => assert_eq!(response.data[0].code, "51008"),
+            message => panic!("unexpected message {:?}", message),
+        }
+
+        let orders = serde_json::json!({
+            "arg": {"channel": "orders", "instType": "OPTION", "uid": "77982378738415879"},
+            "data": [{
+                "instId": "BTC-USD-250221-90000-P",
+                "ordId": "312269865356374016",
+                "clOrdId": "arb1b",
+                "side": "buy",
+                "state": "live",
+                "px": "0.01",
+                "sz": "1",
+                "accFillSz": "0",
+                "avgPx": "",
+                "fee": "0"
+            }]
+        });
+        match serde_json::from_value::<OkexPrivateMessage>(orders).unwrap() {
+            OkexPrivateMessage::Orders(orders) => {
+                assert_eq!(orders.data[0].state, OkexOrderState::Live);
+                assert_eq!(orders.data[0].filled_size, Some(Decimal::ZERO));
+                assert_eq!(orders.data[0].average_price, None);
+            }
+            message => panic!("unexpected message {:?}", message),
+        }
+
+        let account = serde_json::json!({
+            "arg": {"channel": "account", "uid": "77982378738415879"},
+            "data": [{
+                "uTime": "1597026383085",
+                "totalEq": "41624.32",
+                "details": [
+                    {"ccy": "BTC", "availEq": "", "availBal": "0.3", "cashBal": "0.3", "eq": "0.3"},
+                    {"ccy": "ETH", "availEq": "2.5", "availBal": "2", "cashBal": "2", "eq": "2.5"}
+                ]
+            }]
+        });
+        match serde_json::from_value::<OkexPrivateMessage>(account).unwrap() {
+            OkexPrivateMessage::Account(account) => {
+                let details = &account.data[0].details;
+                assert_eq!((details[0].currency.as_str(), details[0].available()), ("BTC", Some(Decimal::new(3, 1))));
+                assert_eq!(details[1].available(), Some(Decimal::new(25, 1)));
+            }
+            message => panic!("unexpected message {:?}", message),
+        }
+        let subscribe = OkexRequest { op: OkexOperation::Subscribe, args: vec![OkexChannelArg { channel: "account".to_string(), instrument_type: None }] };
+        assert_eq!(serde_json::to_value(&subscribe).unwrap(), serde_json::json!({"op": "subscribe", "args": [{"channel": "account"}]}));
+    }
 }
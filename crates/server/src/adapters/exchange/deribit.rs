@@ -1,53 +1,79 @@
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 
-use common::{ArbitrageError, ArbitrageResult, Context, WorkerRef};
-use models::{deribit::{DeribitChannelMessage, DeribitRequest, DeribitRequestMethod, DeribitRequestParams, DeribitResponse}, InternalMessage, ProductSubscription};
+use common::{ArbitrageError, ArbitrageResult, Context, HealthStatus, MpSc, WorkerRef};
+use models::{deribit::{DeribitAck, DeribitErrorResponse, DeribitHeartbeatType, DeribitMessage, DeribitRequest, DeribitRequestMethod, DeribitRequestParams, DeribitResponse}, ExchangeErrorKind, InternalMessage, ProductSubscription, SubscriptionCommand};
 use tokio::sync::mpsc::Sender;
 use tokio_tungstenite::tungstenite::{Message, Utf8Bytes};
-use wsclient::{WsCallback, WsClient};
+use wsclient::{ConnectionState, ReplayClient, WsCallback, WsClient};
 
-use super::get_products_to_subscibe;
+use crate::{metrics, settings::ExchangeConfig};
+
+use super::{add_product, get_products_to_subscibe, publish_subscriptions, remove_product, reset_subscriptions, set_subscribed, SUBSCRIPTION_COMMANDS_BUFFER_SIZE};
+
+const DERIBIT: &str = "deribit";
+
+/// Minimum heartbeat interval accepted by `public/set_heartbeat`
+const MIN_HEARTBEAT_INTERVAL_SECS: u64 = 10;
 
 pub struct DeribitExchangeAdapter {
     context: Context,
     ws_client: WsClient,
-    products_to_subscribe: HashSet<ProductSubscription>
+    products_to_subscribe: HashSet<ProductSubscription>,
+    commands: MpSc<SubscriptionCommand>,
 }
 
 impl DeribitExchangeAdapter {
-    /// Create a new DeribitExchangeAdapter
-    ///
-    /// The environment variables required are:
-    /// - `DERIBIT_WS_URL`: The URL of the Deribit WebSocket API
-    /// - `DERIBIT_PRODUCTS_TO_SUBSCRIBE`: The products to subscribe to, separated by commas
-    /// - `DERIBIT_HEARTBEAT_MILLIS`: The heartbeat interval in milliseconds
-    pub fn new(context: Context) -> ArbitrageResult<Self> {
-        let products_to_subscribe = context.config.get_string("deribit_products_to_subscribe")?;
-        let products_to_subscribe = get_products_to_subscibe(&products_to_subscribe);
+    /// Create a new DeribitExchangeAdapter from the `exchanges.deribit` section of the configuration
+    pub fn new(context: Context, config: &ExchangeConfig) -> Self {
+        let products_to_subscribe = get_products_to_subscibe(&config.products);
+        let ws_client = WsClient::new(config.ws_url.clone(), config.heartbeat_millis)
+            .with_client_id(DERIBIT.to_string())
+            .with_max_silent_heartbeats(config.max_silent_heartbeats);
 
-        let ws_url = context.config.get_string("deribit_ws_url")?;
-        let heartbeat_millis = context.config.get_int("deribit_heartbeat_millis")?;
-        let ws_client = WsClient::new(ws_url, heartbeat_millis as u64);
+        let commands = MpSc::new(SUBSCRIPTION_COMMANDS_BUFFER_SIZE);
 
-        Ok(Self { context, ws_client, products_to_subscribe })
+        Self { context, ws_client, products_to_subscribe, commands }
     }
 
     pub fn callback(&self, internal_message_producer: Sender<InternalMessage>) -> DeribitExchangeCallback {
+        publish_subscriptions(&self.ws_client.connection(), &self.products_to_subscribe);
         DeribitExchangeCallback {
-            _context: self.context.clone(),
+            context: self.context.clone(),
             ws_client: self.ws_client.clone(),
             products_to_subscribe: self.products_to_subscribe.clone(),
             inflight_subscription_requests: HashSet::new(),
+            pending_requests: HashMap::new(),
+            request_id: 0,
+            awaiting_test_response: false,
             internal_message_producer,
         }
     }
 
-    pub fn worker(&mut self, callback: DeribitExchangeCallback) -> WorkerRef {
+    /// State of the connection to the exchange, including the subscriptions
+    pub fn connection(&self) -> ConnectionState {
+        self.ws_client.connection()
+    }
+
+    /// Sender to add or remove products while the adapter is running
+    pub fn commands(&self) -> Sender<SubscriptionCommand> {
+        self.commands.sender()
+    }
+
+    /// The consumer of the websocket, `callback` is the adapter callback, possibly wrapped e.g. by a `RecordingCallback`
+    pub fn worker<C>(&mut self, callback: C) -> WorkerRef
+    where
+        C: WsCallback<Command = SubscriptionCommand> + Clone + Send + Sync + 'static,
+    {
         Box::new(
-            self.ws_client.consumer(self.context.with_name("deribit-ws-consumer"), callback)
+            self.ws_client.consumer(self.context.with_name("deribit-ws-consumer"), callback, self.commands.clone_with_receiver())
         )
     }
 
+    /// Feeds recorded frames to the callback, in place of the worker
+    pub fn replay(&mut self, callback: DeribitExchangeCallback) -> ReplayClient<DeribitExchangeCallback> {
+        self.ws_client.replay(callback)
+    }
+
 }
 
 
@@ -55,64 +81,201 @@ impl DeribitExchangeAdapter {
 /// This is used to handle the incoming messages from the Deribit exchange.
 #[derive(Clone)]
 pub struct DeribitExchangeCallback {
-    _context: Context,
+    context: Context,
     ws_client: WsClient,
     products_to_subscribe: HashSet<ProductSubscription>,
     inflight_subscription_requests: HashSet<String>,
+    /// Requests waiting for a response, keyed by the JSON-RPC id
+    pending_requests: HashMap<String, DeribitRequest>,
+    request_id: u64,
+    /// Set when a `public/test` request was sent on heartbeat and not yet answered
+    awaiting_test_response: bool,
     internal_message_producer: Sender<InternalMessage>,
 }
 
 
 impl DeribitExchangeCallback {
+    fn publish_subscription_state(&self) {
+        publish_subscriptions(&self.ws_client.connection(), &self.products_to_subscribe);
+    }
+
     pub fn subscribe_products(&mut self) -> ArbitrageResult<()> {
-        for (index, product) in self.products_to_subscribe.iter().enumerate() {
-            if !product.subscribed && !self.inflight_subscription_requests.contains(&product.product_id) {
-                self.inflight_subscription_requests.insert(product.product_id.clone());
-                let request = DeribitRequest {
-                    jsonrpc: "2.0".to_string(),
-                    method: DeribitRequestMethod::PublicSubscribe,
-                    params: DeribitRequestParams::Channels(vec![product.product_id.clone()]),
-                    id: format!("{}", index)
-                };
-                // TODO: handle errors better
-                let json = serde_json::to_string(&request).unwrap();
-                self.ws_client.write(Message::Text(Utf8Bytes::from(&json)))?;
+        let channels = self.products_to_subscribe
+            .iter()
+            .filter(|product| !product.subscribed && !self.inflight_subscription_requests.contains(&product.product_id))
+            .map(|product| product.product_id.clone())
+            .collect::<Vec<String>>();
+        self.publish_subscription_state();
+
+        for channel in channels {
+            self.inflight_subscription_requests.insert(channel.clone());
+            self.send_request(DeribitRequestMethod::PublicSubscribe, Some(DeribitRequestParams::Channels(vec![channel])))?;
+        }
+        Ok(())
+    }
+
+    /// Stops tracking the given channels and unsubscribes from them
+    pub fn unsubscribe_products(&mut self, channels: Vec<String>) -> ArbitrageResult<()> {
+        let channels = channels
+            .into_iter()
+            .filter(|channel| remove_product(&mut self.products_to_subscribe, channel))
+            .collect::<Vec<String>>();
+        self.publish_subscription_state();
+        if channels.is_empty() {
+            return Ok(());
+        }
+
+        for channel in channels.iter() {
+            log::info!("unsubscribing from deribit channel {}", channel);
+            self.inflight_subscription_requests.remove(channel);
+        }
+        self.send_request(DeribitRequestMethod::PublicUnsubscribe, Some(DeribitRequestParams::Channels(channels)))
+    }
+
+    /// Sends a JSON-RPC request and keeps track of it until a response is received
+    fn send_request(&mut self, method: DeribitRequestMethod, params: Option<DeribitRequestParams>) -> ArbitrageResult<()> {
+        self.request_id += 1;
+        let request = DeribitRequest {
+            jsonrpc: "2.0".to_string(),
+            method,
+            params,
+            id: self.request_id.to_string(),
+        };
+        let json = serde_json::to_string(&request).map_err(ArbitrageError::JsonError)?;
+        self.ws_client.write(Message::Text(Utf8Bytes::from(&json)))?;
+        self.pending_requests.insert(request.id.clone(), request);
+        Ok(())
+    }
+
+    fn on_response(&mut self, response: DeribitResponse) -> ArbitrageResult<()> {
+        let Some(request) = self.pending_requests.remove(&response.id) else {
+            log::warn!("received deribit response for unknown request: {:?}", response);
+            return Ok(());
+        };
+
+        match (request.method, request.params) {
+            (DeribitRequestMethod::PublicSubscribe, Some(DeribitRequestParams::Channels(channels))) => {
+                for channel in channels {
+                    self.inflight_subscription_requests.remove(&channel);
+                    if response.result.contains(&channel) {
+                        log::info!("deribit subscription acknowledged for {}", channel);
+                        set_subscribed(&mut self.products_to_subscribe, &channel, true);
+                        self.publish_subscription_state();
+                    } else {
+                        // Deribit silently leaves out channels it can not subscribe to
+                        self.drop_subscription(&channel, "channel not acknowledged");
+                    }
+                }
+            }
+            (method, _) => {
+                log::info!("received deribit response for {:?}: {:?}", method, response.result);
             }
         }
         Ok(())
     }
+
+    fn on_ack(&mut self, ack: DeribitAck) {
+        match self.pending_requests.remove(&ack.id) {
+            Some(DeribitRequest { method: DeribitRequestMethod::PublicTest, .. }) => {
+                log::debug!("deribit answered public/test: {}", ack.result);
+                self.awaiting_test_response = false;
+            }
+            Some(request) => {
+                log::info!("received deribit response for {:?}: {}", request.method, ack.result);
+            }
+            None => {
+                log::warn!("received deribit response for unknown request: {:?}", ack);
+            }
+        }
+    }
+
+    /// Maps the error reported by Deribit onto the action to take
+    ///
+    /// - invalid instruments are dropped from the subscriptions
+    /// - rate limits close the connection and reconnect with backoff
+    /// - authentication errors are fatal
+    fn on_error(&mut self, response: DeribitErrorResponse) -> ArbitrageResult<()> {
+        let channels = match response.id.as_ref().and_then(|id| self.pending_requests.remove(id)) {
+            Some(DeribitRequest { params: Some(DeribitRequestParams::Channels(channels)), .. }) => channels,
+            _ => vec![],
+        };
+        for channel in channels.iter() {
+            self.inflight_subscription_requests.remove(channel);
+        }
+
+        let error = response.error;
+        match error.kind() {
+            ExchangeErrorKind::InvalidInstrument => {
+                if channels.is_empty() {
+                    log::error!("deribit reported an invalid instrument for unknown request: {} - {}", error.code, error.message);
+                }
+                for channel in channels {
+                    self.drop_subscription(&channel, &error.message);
+                }
+                Ok(())
+            }
+            ExchangeErrorKind::RateLimited => {
+                self.context.health.set(DERIBIT, HealthStatus::Degraded(format!("rate limited: {}", error.message)));
+                Err(ArbitrageError::RateLimited(format!("deribit rate limited: {} - {}", error.code, error.message)))
+            }
+            ExchangeErrorKind::Authentication => {
+                self.context.health.set(DERIBIT, HealthStatus::Unhealthy(format!("authentication failed: {}", error.message)));
+                Err(ArbitrageError::UnrecoverableError(format!("deribit authentication failed: {} - {}", error.code, error.message)))
+            }
+            ExchangeErrorKind::Other => {
+                log::error!("received deribit error: {} - {}", error.code, error.message);
+                Ok(())
+            }
+        }
+    }
+
+    fn drop_subscription(&mut self, channel: &str, reason: &str) {
+        log::warn!("dropping deribit subscription for {}: {}", channel, reason);
+        remove_product(&mut self.products_to_subscribe, channel);
+        self.publish_subscription_state();
+        self.context.health.set(DERIBIT, HealthStatus::Degraded(format!("dropped invalid instrument {}", channel)));
+    }
 }
 
 
 #[async_trait::async_trait]
 impl WsCallback for DeribitExchangeCallback {
+    type Command = SubscriptionCommand;
 
-    async fn on_message(&mut self, message: Message, _received_time: jiff::Timestamp) -> ArbitrageResult<()> {
+
+    async fn on_message(&mut self, message: Message, received_time: jiff::Timestamp) -> ArbitrageResult<()> {
 
 
         match message {
             Message::Text(text) => {
-                let result = serde_json::from_str::<DeribitResponse>(&text);
-                match result {
-                    Ok(response) => {
-                        log::info!("received deribit response: {:?}", response);
+                match serde_json::from_str::<DeribitMessage>(&text) {
+                    Ok(DeribitMessage::Response(response)) => {
+                        self.on_response(response)?;
                     }
-                    Err(_) => {
-                        let result = serde_json::from_str::<DeribitChannelMessage>(&text);
-                        match result {
-                            Ok(channel_message) => {
-                                match self.internal_message_producer.send(channel_message.params.data.into()).await {
-                                    Ok(_) => {}
-                                    Err(e) => {
-                                        log::error!("error sending internal message: {} hence the message is dropped", e);
-                                    }
-                                }
-                            }
+                    Ok(DeribitMessage::Error(response)) => {
+                        self.on_error(response)?;
+                    }
+                    Ok(DeribitMessage::Ack(ack)) => {
+                        self.on_ack(ack);
+                    }
+                    Ok(DeribitMessage::Heartbeat(heartbeat)) => {
+                        // Deribit closes the connection if a test request is not answered
+                        if heartbeat.params.heartbeat_type == DeribitHeartbeatType::TestRequest {
+                            self.send_request(DeribitRequestMethod::PublicTest, None)?;
+                        }
+                    }
+                    Ok(DeribitMessage::Channel(channel_message)) => {
+                        match self.internal_message_producer.send(InternalMessage::from_deribit(channel_message.params.data, received_time)).await {
+                            Ok(_) => {}
                             Err(e) => {
-                                log::error!("error parsing deribit channel message: {}", e);
+                                log::error!("error sending internal message: {} hence the message is dropped", e);
                             }
                         }
                     }
+                    Err(e) => {
+                        metrics::PARSE_ERRORS.with_label_values(&["Deribit"]).inc();
+                        log::error!("error parsing deribit message: {}", e);
+                    }
                 }
             }
             Message::Close(close) => {
@@ -132,17 +295,57 @@ impl WsCallback for DeribitExchangeCallback {
 
     async fn on_connect(&mut self, _timestamp: jiff::Timestamp) -> ArbitrageResult<()> {
         log::info!("connected to deribit");
+        self.context.health.set(DERIBIT, HealthStatus::Healthy);
+        let interval_secs = (self.ws_client.heartbeat_millis() / 1000).max(MIN_HEARTBEAT_INTERVAL_SECS);
+        self.send_request(DeribitRequestMethod::PublicSetHeartbeat, Some(DeribitRequestParams::Interval(interval_secs)))?;
         self.subscribe_products()?;
         Ok(())
     }
 
     fn on_disconnect(&mut self) -> ArbitrageResult<()> {
         log::info!("disconnected from deribit");
+        self.inflight_subscription_requests.clear();
+        self.pending_requests.clear();
+        self.awaiting_test_response = false;
+        reset_subscriptions(&mut self.products_to_subscribe);
+        self.publish_subscription_state();
         Ok(())
     }
 
     fn on_heartbeat(&mut self) -> ArbitrageResult<()> {
         log::debug!("heartbeat from deribit");
+        if self.awaiting_test_response {
+            return Err(ArbitrageError::Warning("deribit did not answer public/test since last heartbeat".to_string()));
+        }
+        self.send_request(DeribitRequestMethod::PublicTest, None)?;
+        self.awaiting_test_response = true;
         Ok(())
     }
+
+    async fn on_command(&mut self, command: SubscriptionCommand) -> ArbitrageResult<()> {
+        match command {
+            SubscriptionCommand::Subscribe(channels) => {
+                for channel in channels {
+                    if add_product(&mut self.products_to_subscribe, &channel) {
+                        log::info!("subscribing to deribit channel {}", channel);
+                    }
+                }
+                self.subscribe_products()
+            }
+            SubscriptionCommand::Unsubscribe(channels) => self.unsubscribe_products(channels),
+        }
+    }
+
+    fn on_shutdown(&mut self) -> ArbitrageResult<()> {
+        log::info!("unsubscribing from deribit before shutting down");
+        let channels = self.products_to_subscribe
+            .iter()
+            .filter(|product| product.subscribed)
+            .map(|product| product.product_id.clone())
+            .collect::<Vec<String>>();
+        if !channels.is_empty() {
+            self.send_request(DeribitRequestMethod::PublicUnsubscribe, Some(DeribitRequestParams::Channels(channels)))?;
+        }
+        self.ws_client.close()
+    }
 }
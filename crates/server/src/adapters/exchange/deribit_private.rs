@@ -0,0 +1,392 @@
+use std::collections::HashMap;
+
+use common::{ArbitrageError, ArbitrageResult, Context, HealthStatus, MpSc, WorkerRef};
+use models::{deribit::{DeribitAck, DeribitAuthParams, DeribitErrorResponse, DeribitHeartbeatType, DeribitOrder, DeribitOrderParams, DeribitOrderResult, DeribitOrderState, DeribitPrivateMessage, DeribitRequest, DeribitRequestMethod, DeribitRequestParams, DeribitTimeInForce}, CryptoAsset, Exchange, ExchangeErrorKind};
+use rust_decimal::Decimal;
+use tokio::sync::mpsc::Sender;
+use tokio_tungstenite::tungstenite::{Message, Utf8Bytes};
+use wsclient::{WsCallback, WsClient};
+
+use crate::{execution::{OrderCommand, OrderRequest, OrderSide, OrderState, OrderUpdate, TimeInForce, ORDER_COMMANDS_BUFFER_SIZE}, funding::Funding, metrics, settings::{DeribitLiveConfig, ExchangeConfig}};
+
+const DERIBIT_PRIVATE: &str = "deribit-private";
+
+/// Changes of every order of the account, sent as they happen
+const USER_ORDERS_CHANNEL: &str = "user.orders.any.any.raw";
+
+/// Accounts of the currencies the options are quoted in, sent as they change
+const USER_PORTFOLIO_CHANNELS: [&str; 2] = ["user.portfolio.btc", "user.portfolio.eth"];
+
+/// Minimum heartbeat interval accepted by `public/set_heartbeat`
+const MIN_HEARTBEAT_INTERVAL_SECS: u64 = 10;
+
+
+/// Authenticated session on the Deribit websocket, which places, edits and cancels the orders it is sent
+/// by their label and reports their updates from the `user.orders` channel
+pub struct DeribitPrivateAdapter {
+    context: Context,
+    ws_client: WsClient,
+    config: DeribitLiveConfig,
+    commands: MpSc<OrderCommand>,
+    funding: Option<Funding>,
+}
+
+impl DeribitPrivateAdapter {
+    /// Create a new DeribitPrivateAdapter from the `live.deribit` section of the configuration,
+    /// keeping the connection alive as configured in `exchanges.deribit`
+    pub fn new(context: Context, exchange_config: &ExchangeConfig, config: &DeribitLiveConfig) -> Self {
+        let ws_client = WsClient::new(config.ws_url.clone(), exchange_config.heartbeat_millis)
+            .with_client_id(DERIBIT_PRIVATE.to_string())
+            .with_max_silent_heartbeats(exchange_config.max_silent_heartbeats);
+        let commands = MpSc::new(ORDER_COMMANDS_BUFFER_SIZE);
+        Self { context, ws_client, config: config.clone(), commands, funding: None }
+    }
+
+    /// Learns the funds available on Deribit from the `user.portfolio` channels
+    pub fn with_funding(mut self, funding: Funding) -> Self {
+        self.funding = Some(funding);
+        self
+    }
+
+    pub fn callback(&self, order_updates: Sender<OrderUpdate>) -> DeribitPrivateCallback {
+        DeribitPrivateCallback {
+            context: self.context.clone(),
+            ws_client: self.ws_client.clone(),
+            config: self.config.clone(),
+            authenticated: false,
+            awaiting_test_response: false,
+            request_id: 0,
+            pending_requests: HashMap::new(),
+            order_updates,
+            funding: self.funding.clone(),
+        }
+    }
+
+    /// Sender of the orders to place, amend or cancel
+    pub fn commands(&self) -> Sender<OrderCommand> {
+        self.commands.sender()
+    }
+
+    pub fn worker(&mut self, callback: DeribitPrivateCallback) -> WorkerRef {
+        Box::new(
+            self.ws_client.consumer(self.context.with_name("deribit-private-ws-consumer"), callback, self.commands.clone_with_receiver())
+        )
+    }
+}
+
+
+#[derive(Clone)]
+pub struct DeribitPrivateCallback {
+    context: Context,
+    ws_client: WsClient,
+    config: DeribitLiveConfig,
+    /// Orders are refused until `public/auth` is answered
+    authenticated: bool,
+    /// Set when a `public/test` request was sent on heartbeat and not yet answered
+    awaiting_test_response: bool,
+    request_id: u64,
+    /// Requests waiting for a response, keyed by the JSON-RPC id
+    pending_requests: HashMap<String, DeribitRequest>,
+    order_updates: Sender<OrderUpdate>,
+    funding: Option<Funding>,
+}
+
+impl DeribitPrivateCallback {
+    fn send_request(&mut self, method: DeribitRequestMethod, params: Option<DeribitRequestParams>) -> ArbitrageResult<()> {
+        self.request_id += 1;
+        let request = DeribitRequest {
+            jsonrpc: "2.0".to_string(),
+            method,
+            params,
+            id: self.request_id.to_string(),
+        };
+        let json = serde_json::to_string(&request).map_err(ArbitrageError::JsonError)?;
+        self.ws_client.write(Message::Text(Utf8Bytes::from(&json)))?;
+        self.pending_requests.insert(request.id.clone(), request);
+        Ok(())
+    }
+
+    fn authenticate(&mut self) -> ArbitrageResult<()> {
+        let params = DeribitAuthParams {
+            grant_type: "client_credentials".to_string(),
+            client_id: self.config.client_id.clone(),
+            client_secret: self.config.client_secret.expose().to_string(),
+        };
+        self.send_request(DeribitRequestMethod::PublicAuth, Some(DeribitRequestParams::Auth(params)))
+    }
+
+    fn send_order(&mut self, command: OrderCommand) -> ArbitrageResult<()> {
+        let (method, params) = match command {
+            OrderCommand::Place(OrderRequest { client_order_id, product, side, price, size, time_in_force }) => {
+                let method = match side {
+                    OrderSide::Buy => DeribitRequestMethod::PrivateBuy,
+                    OrderSide::Sell => DeribitRequestMethod::PrivateSell,
+                };
+                let params = DeribitOrderParams {
+                    instrument_name: Some(product.to_deribit_exchange()),
+                    label: client_order_id,
+                    amount: Some(size),
+                    price: Some(price),
+                    order_type: Some("limit".to_string()),
+                    time_in_force: Some(match time_in_force {
+                        TimeInForce::ImmediateOrCancel => DeribitTimeInForce::ImmediateOrCancel,
+                        TimeInForce::GoodTilCancelled => DeribitTimeInForce::GoodTilCancelled,
+                    }),
+                };
+                (method, params)
+            }
+            OrderCommand::Amend { client_order_id, product, price, size } => {
+                let params = DeribitOrderParams {
+                    instrument_name: Some(product.to_deribit_exchange()),
+                    label: client_order_id,
+                    amount: Some(size),
+                    price: Some(price),
+                    ..DeribitOrderParams::default()
+                };
+                (DeribitRequestMethod::PrivateEditByLabel, params)
+            }
+            OrderCommand::Cancel { client_order_id, .. } => {
+                let params = DeribitOrderParams { label: client_order_id, ..DeribitOrderParams::default() };
+                (DeribitRequestMethod::PrivateCancelByLabel, params)
+            }
+        };
+        self.send_request(method, Some(DeribitRequestParams::Order(params)))
+    }
+
+    async fn publish(&self, update: OrderUpdate) {
+        if let Err(e) = self.order_updates.send(update).await {
+            log::error!("error sending deribit order update: {} hence the update is dropped", e);
+        }
+    }
+
+    async fn on_ack(&mut self, ack: DeribitAck) -> ArbitrageResult<()> {
+        let Some(request) = self.pending_requests.remove(&ack.id) else {
+            log::warn!("received deribit response for unknown request: {:?}", ack);
+            return Ok(());
+        };
+        match request.method {
+            DeribitRequestMethod::PublicAuth => {
+                log::info!("authenticated on deribit");
+                self.authenticated = true;
+                self.context.health.set(DERIBIT_PRIVATE, HealthStatus::Healthy);
+                let mut channels = vec![USER_ORDERS_CHANNEL.to_string()];
+                if self.funding.is_some() {
+                    channels.extend(USER_PORTFOLIO_CHANNELS.iter().map(|channel| channel.to_string()));
+                }
+                self.send_request(DeribitRequestMethod::PrivateSubscribe, Some(DeribitRequestParams::Channels(channels)))?;
+            }
+            DeribitRequestMethod::PublicTest => {
+                self.awaiting_test_response = false;
+            }
+            DeribitRequestMethod::PrivateBuy | DeribitRequestMethod::PrivateSell | DeribitRequestMethod::PrivateEditByLabel => {
+                match serde_json::from_value::<DeribitOrderResult>(ack.result) {
+                    Ok(result) => {
+                        // Only the result carries the fees, as the sum of those of the trades of the request
+                        let fee = result.trades.iter().map(|trade| trade.fee).sum::<Decimal>();
+                        let mut update = order_update(result.order);
+                        update.fee = Some(fee);
+                        self.publish(update).await;
+                    }
+                    Err(e) => {
+                        metrics::PARSE_ERRORS.with_label_values(&["Deribit"]).inc();
+                        log::error!("error parsing deribit order result: {}", e);
+                    }
+                }
+            }
+            method => {
+                log::info!("received deribit response for {:?}: {}", method, ack.result);
+            }
+        }
+        Ok(())
+    }
+
+    /// Refused orders are reported as rejected, refused edits and cancels are only logged
+    /// as the order they target keeps its state
+    ///
+    /// A rate limit on an order request refuses that request only and keeps the session up, as reconnecting would
+    /// lose the other requests in flight, a rate limit on any other request backs off.
+    async fn on_error(&mut self, response: DeribitErrorResponse) -> ArbitrageResult<()> {
+        let request = response.id.as_ref().and_then(|id| self.pending_requests.remove(id));
+        let error = response.error;
+        if error.kind() == ExchangeErrorKind::Authentication {
+            self.context.health.set(DERIBIT_PRIVATE, HealthStatus::Unhealthy(format!("authentication failed: {}", error.message)));
+            return Err(ArbitrageError::UnrecoverableError(format!("deribit authentication failed: {} - {}", error.code, error.message)));
+        }
+        let reason = format!("{} - {}", error.code, error.message);
+        match request {
+            Some(DeribitRequest {
+                method: DeribitRequestMethod::PrivateBuy | DeribitRequestMethod::PrivateSell,
+                params: Some(DeribitRequestParams::Order(params)),
+                ..
+            }) => {
+                log::warn!("deribit rejected order {}: {}", params.label, reason);
+                self.publish(OrderUpdate::rejected(Exchange::Deribit, &params.label, &reason)).await;
+            }
+            Some(DeribitRequest { method, params: Some(DeribitRequestParams::Order(params)), .. }) => {
+                log::error!("deribit refused {:?} of {}: {}", method, params.label, reason);
+            }
+            _ if error.kind() == ExchangeErrorKind::RateLimited => {
+                return Err(ArbitrageError::RateLimited(format!("deribit rate limited: {}", reason)));
+            }
+            Some(request) => log::error!("deribit refused {:?}: {}", request.method, reason),
+            None => log::error!("received deribit error: {}", reason),
+        }
+        Ok(())
+    }
+}
+
+fn order_update(order: DeribitOrder) -> OrderUpdate {
+    let state = match order.order_state {
+        DeribitOrderState::Open | DeribitOrderState::Untriggered => OrderState::Open,
+        DeribitOrderState::Filled => OrderState::Filled,
+        DeribitOrderState::Cancelled => OrderState::Cancelled,
+        DeribitOrderState::Rejected => OrderState::Rejected,
+    };
+    OrderUpdate {
+        exchange: Exchange::Deribit,
+        client_order_id: order.label,
+        state,
+        filled_size: order.filled_amount,
+        average_price: (!order.filled_amount.is_zero()).then_some(order.average_price),
+        fee: None,
+        reason: None,
+    }
+}
+
+
+#[async_trait::async_trait]
+impl WsCallback for DeribitPrivateCallback {
+    type Command = OrderCommand;
+
+    async fn on_message(&mut self, message: Message, _received_time: jiff::Timestamp) -> ArbitrageResult<()> {
+        match message {
+            Message::Text(text) => match serde_json::from_str::<DeribitPrivateMessage>(&text) {
+                Ok(DeribitPrivateMessage::Ack(ack)) => self.on_ack(ack).await?,
+                Ok(DeribitPrivateMessage::Error(response)) => self.on_error(response).await?,
+                Ok(DeribitPrivateMessage::Orders(notification)) => {
+                    let order = notification.params.data;
+                    // Orders without a label were not placed by the server
+                    if !order.label.is_empty() {
+                        self.publish(order_update(order)).await;
+                    }
+                }
+                Ok(DeribitPrivateMessage::Portfolio(notification)) => {
+                    let portfolio = notification.params.data;
+                    if let (Some(funding), Some(currency)) = (self.funding.as_ref(), CryptoAsset::from_symbol(&portfolio.currency)) {
+                        funding.set(&Exchange::Deribit, &currency, portfolio.available_funds);
+                    }
+                }
+                Ok(DeribitPrivateMessage::Heartbeat(heartbeat)) => {
+                    if heartbeat.params.heartbeat_type == DeribitHeartbeatType::TestRequest {
+                        self.send_request(DeribitRequestMethod::PublicTest, None)?;
+                    }
+                }
+                Err(e) => {
+                    metrics::PARSE_ERRORS.with_label_values(&["Deribit"]).inc();
+                    log::error!("error parsing deribit private message: {}", e);
+                }
+            },
+            Message::Close(close) => {
+                log::error!("deribit private connection closed: {:?}", close);
+            }
+            _ => {
+                return Err(ArbitrageError::Warning(format!("received unexpected message: {:?}", message)));
+            }
+        }
+        Ok(())
+    }
+
+    async fn on_connect(&mut self, _timestamp: jiff::Timestamp) -> ArbitrageResult<()> {
+        log::info!("connected to deribit private session, authenticating");
+        let interval_secs = (self.ws_client.heartbeat_millis() / 1000).max(MIN_HEARTBEAT_INTERVAL_SECS);
+        self.send_request(DeribitRequestMethod::PublicSetHeartbeat, Some(DeribitRequestParams::Interval(interval_secs)))?;
+        self.authenticate()
+    }
+
+    fn on_disconnect(&mut self) -> ArbitrageResult<()> {
+        log::info!("disconnected from deribit private session");
+        self.authenticated = false;
+        self.awaiting_test_response = false;
+        // The updates of the orders in flight are lost, the execution manager times them out
+        self.pending_requests.clear();
+        self.context.health.set(DERIBIT_PRIVATE, HealthStatus::Degraded("disconnected".to_string()));
+        Ok(())
+    }
+
+    fn on_heartbeat(&mut self) -> ArbitrageResult<()> {
+        if self.awaiting_test_response {
+            return Err(ArbitrageError::Warning("deribit did not answer public/test since last heartbeat".to_string()));
+        }
+        self.send_request(DeribitRequestMethod::PublicTest, None)?;
+        self.awaiting_test_response = true;
+        Ok(())
+    }
+
+    async fn on_command(&mut self, command: OrderCommand) -> ArbitrageResult<()> {
+        if !self.authenticated {
+            log::warn!("not authenticated on deribit, refusing {:?}", command);
+            if let OrderCommand::Place(request) = command {
+                self.publish(OrderUpdate::rejected(Exchange::Deribit, &request.client_order_id, "not authenticated")).await;
+            }
+            return Ok(());
+        }
+        self.send_order(command)
+    }
+
+    fn on_shutdown(&mut self) -> ArbitrageResult<()> {
+        self.ws_client.close()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use config::Config;
+    use models::Product;
+    use rust_decimal_macros::dec;
+    use tokio::sync::mpsc;
+
+    use super::*;
+
+    fn rate_limited(id: &str) -> Message {
+        let error = serde_json::json!({"jsonrpc": "2.0", "id": id, "error": {"code": 10028, "message": "too_many_requests"}});
+        Message::text(error.to_string())
+    }
+
+    #[tokio::test]
+    async fn test_rate_limited_order_rejected() {
+        let context = Context::from_config(Config::default());
+        let adapter = DeribitPrivateAdapter::new(context, &ExchangeConfig::default(), &DeribitLiveConfig::default());
+        let (sender, mut order_updates) = mpsc::channel(1);
+        let mut callback = adapter.callback(sender);
+        callback.authenticated = true;
+        let now = jiff::Timestamp::now();
+
+        let request = OrderRequest {
+            client_order_id: "arb1n1s".to_string(),
+            product: Product::from_deribit_exchange("BTC-21FEB25-90000-P").unwrap(),
+            side: OrderSide::Sell,
+            price: dec!(0.012),
+            size: dec!(1),
+            time_in_force: TimeInForce::ImmediateOrCancel,
+        };
+        callback.on_command(OrderCommand::Place(request.clone())).await.unwrap();
+        let cancel = OrderCommand::Cancel { client_order_id: "arb1n1b".to_string(), product: request.product.clone() };
+        callback.on_command(cancel).await.unwrap();
+        callback.on_command(OrderCommand::Place(OrderRequest { client_order_id: "arb1n2s".to_string(), ..request })).await.unwrap();
+
+        // The order is rejected and the session stays up with the other requests in flight
+        callback.on_message(rate_limited("1"), now).await.unwrap();
+        let update = order_updates.recv().await.unwrap();
+        assert_eq!((update.client_order_id.as_str(), update.state), ("arb1n1s", OrderState::Rejected));
+        assert_eq!(update.reason.as_deref(), Some("10028 - too_many_requests"));
+        // A cancel keeps the order it targets as is
+        callback.on_message(rate_limited("2"), now).await.unwrap();
+        assert!(order_updates.try_recv().is_err());
+        assert_eq!(callback.pending_requests.keys().collect::<Vec<_>>(), vec!["3"]);
+
+        // Any other request backs off
+        callback.on_heartbeat().unwrap();
+        assert!(matches!(callback.on_message(rate_limited("4"), now).await, Err(ArbitrageError::RateLimited(_))));
+    }
+}
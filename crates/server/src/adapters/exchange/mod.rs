@@ -1,7 +1,11 @@
 mod okex;
+mod okex_private;
 mod deribit;
+mod deribit_private;
 mod utils;
 
 pub use okex::*;
+pub use okex_private::*;
 pub use utils::*;
 pub use deribit::*;
+pub use deribit_private::*;
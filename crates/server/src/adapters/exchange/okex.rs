@@ -1,55 +1,75 @@
 use std::collections::HashSet;
 
-use common::{ArbitrageError, ArbitrageResult, Context, WorkerRef};
-use models::{okex::{OkexArg, OkexMessage, OkexOperation, OkexRequest, OkexResponse}, InternalMessage, ProductSubscription};
+use common::{ArbitrageError, ArbitrageResult, Context, HealthStatus, MpSc, WorkerRef};
+use models::{okex::{OkexArg, OkexError, OkexEvent, OkexMessage, OkexOperation, OkexRequest, OkexResponse, OkexResponseData}, ExchangeErrorKind, InternalMessage, ProductSubscription, SubscriptionCommand};
 use tokio::sync::mpsc::Sender;
 use tokio_tungstenite::tungstenite::{Message, Utf8Bytes};
-use wsclient::{WsCallback, WsClient};
+use wsclient::{ConnectionState, ReplayClient, WsCallback, WsClient};
 
-use super::get_products_to_subscibe;
+use crate::{metrics, settings::ExchangeConfig};
+
+use super::{add_product, get_products_to_subscibe, publish_subscriptions, remove_product, reset_subscriptions, set_subscribed, SUBSCRIPTION_COMMANDS_BUFFER_SIZE};
+
+const OKEX: &str = "okex";
 
 pub struct OkexExchangeAdapter {
     context: Context,
     ws_client: WsClient,
     products_to_subscribe: HashSet<ProductSubscription>,
+    commands: MpSc<SubscriptionCommand>,
 }
 
 
 
 impl OkexExchangeAdapter {
-    /// Create a new OkexExchangeAdapter
-    ///
-    /// The environment variables required are:
-    /// - `OKEX_WS_URL`: The URL of the Okex WebSocket API
-    /// - `OKEX_PRODUCTS_TO_SUBSCRIBE`: The products to subscribe to, separated by commas
-    /// - `OKEX_HEARTBEAT_MILLIS`: The heartbeat interval in milliseconds
-    pub fn new(context: Context) -> ArbitrageResult<Self> {
-        let products_to_subscribe = context.config.get_string("okex_products_to_subscribe")
-            .unwrap_or_default();
-        let products_to_subscribe = get_products_to_subscibe(&products_to_subscribe);
+    /// Create a new OkexExchangeAdapter from the `exchanges.okex` section of the configuration
+    pub fn new(context: Context, config: &ExchangeConfig) -> Self {
+        let products_to_subscribe = get_products_to_subscibe(&config.products);
+        let ws_client = WsClient::new(config.ws_url.clone(), config.heartbeat_millis)
+            .with_client_id(OKEX.to_string())
+            .with_max_silent_heartbeats(config.max_silent_heartbeats);
 
-        let ws_url = context.config.get_string("okex_ws_url")?;
-        let heartbeat_millis = context.config.get_int("okex_heartbeat_millis")?;
-        let ws_client = WsClient::new(ws_url, heartbeat_millis as u64);
+        let commands = MpSc::new(SUBSCRIPTION_COMMANDS_BUFFER_SIZE);
 
-        Ok(Self { context, ws_client, products_to_subscribe })
+        Self { context, ws_client, products_to_subscribe, commands }
     }
 
     pub fn callback(&self, internal_message_producer: Sender<InternalMessage>) -> OkexExchangeCallback {
+        publish_subscriptions(&self.ws_client.connection(), &self.products_to_subscribe);
         OkexExchangeCallback {
-            _context: self.context.clone(),
+            context: self.context.clone(),
             ws_client: self.ws_client.clone(),
             products_to_subscribe: self.products_to_subscribe.clone(),
             inflight_subscription_requests: HashSet::new(),
+            awaiting_pong: false,
             internal_message_producer,
         }
     }
 
-    pub fn worker(&mut self, callback: OkexExchangeCallback) -> WorkerRef {
+    /// State of the connection to the exchange, including the subscriptions
+    pub fn connection(&self) -> ConnectionState {
+        self.ws_client.connection()
+    }
+
+    /// Sender to add or remove products while the adapter is running
+    pub fn commands(&self) -> Sender<SubscriptionCommand> {
+        self.commands.sender()
+    }
+
+    /// The consumer of the websocket, `callback` is the adapter callback, possibly wrapped e.g. by a `RecordingCallback`
+    pub fn worker<C>(&mut self, callback: C) -> WorkerRef
+    where
+        C: WsCallback<Command = SubscriptionCommand> + Clone + Send + Sync + 'static,
+    {
         Box::new(
-            self.ws_client.consumer(self.context.with_name("okex-ws-consumer"), callback)
+            self.ws_client.consumer(self.context.with_name("okex-ws-consumer"), callback, self.commands.clone_with_receiver())
         )
     }
+
+    /// Feeds recorded frames to the callback, in place of the worker
+    pub fn replay(&mut self, callback: OkexExchangeCallback) -> ReplayClient<OkexExchangeCallback> {
+        self.ws_client.replay(callback)
+    }
 }
 
 
@@ -57,15 +77,21 @@ impl OkexExchangeAdapter {
 /// This is used to handle the incoming messages from the Okex exchange.
 #[derive(Clone)]
 pub struct OkexExchangeCallback {
-    _context: Context,
+    context: Context,
     ws_client: WsClient,
     products_to_subscribe: HashSet<ProductSubscription>,
     inflight_subscription_requests: HashSet<String>,
+    /// Set when a `ping` was sent on heartbeat and no `pong` was received yet
+    awaiting_pong: bool,
     internal_message_producer: Sender<InternalMessage>,
 }
 
 
 impl OkexExchangeCallback {
+    fn publish_subscription_state(&self) {
+        publish_subscriptions(&self.ws_client.connection(), &self.products_to_subscribe);
+    }
+
     pub fn subscribe_products(&mut self) -> ArbitrageResult<()> {
         let mut args = vec![];
         for product in self.products_to_subscribe.iter() {
@@ -78,35 +104,117 @@ impl OkexExchangeCallback {
                 });
             }
         }
+        self.publish_subscription_state();
+        self.send_request(OkexOperation::Subscribe, args)
+    }
+
+    /// Stops tracking the given products and unsubscribes from them
+    pub fn unsubscribe_products(&mut self, instance_ids: Vec<String>) -> ArbitrageResult<()> {
+        let mut args = vec![];
+        for instance_id in instance_ids {
+            if remove_product(&mut self.products_to_subscribe, &instance_id) {
+                log::info!("unsubscribing from okex product {}", instance_id);
+                self.inflight_subscription_requests.remove(&instance_id);
+                args.push(OkexArg {
+                    channel: "books".to_string(),
+                    instance_id,
+                });
+            }
+        }
+        self.publish_subscription_state();
+        self.send_request(OkexOperation::Unsubscribe, args)
+    }
+
+    fn send_request(&mut self, op: OkexOperation, args: Vec<OkexArg>) -> ArbitrageResult<()> {
+        if args.is_empty() {
+            return Ok(());
+        }
         let message = OkexRequest{
-            op: OkexOperation::Subscribe,
+            op,
             args
         };
 
-        //TODO: handle errors better
-        let json = serde_json::to_string(&message).unwrap();
+        let json = serde_json::to_string(&message).map_err(ArbitrageError::JsonError)?;
         self.ws_client.write(Message::Text(Utf8Bytes::from(&json)))?;
         Ok(())
     }
+
+    fn on_response(&mut self, response: OkexResponse) -> ArbitrageResult<()> {
+        match response.response_data {
+            OkexResponseData::Subscribe(subscribe_response) => {
+                let instance_id = subscribe_response.arg.instance_id;
+                log::info!("okex {:?} acknowledged for {}", response.event, instance_id);
+                if response.event == OkexEvent::Subscribe {
+                    self.inflight_subscription_requests.remove(&instance_id);
+                    set_subscribed(&mut self.products_to_subscribe, &instance_id, true);
+                    self.publish_subscription_state();
+                }
+                Ok(())
+            }
+            OkexResponseData::Error(error) => self.on_error(error),
+        }
+    }
+
+    /// Maps the error reported by Okex onto the action to take
+    ///
+    /// - invalid instruments are dropped from the subscriptions
+    /// - rate limits close the connection and reconnect with backoff
+    /// - authentication errors are fatal
+    fn on_error(&mut self, error: OkexError) -> ArbitrageResult<()> {
+        match error.kind() {
+            ExchangeErrorKind::InvalidInstrument => {
+                match error.instrument_id().map(|id| id.to_string()) {
+                    Some(instance_id) => {
+                        log::warn!("dropping okex subscription for invalid instrument {}: {}", instance_id, error.message);
+                        self.inflight_subscription_requests.remove(&instance_id);
+                        remove_product(&mut self.products_to_subscribe, &instance_id);
+                        self.publish_subscription_state();
+                        self.context.health.set(OKEX, HealthStatus::Degraded(format!("dropped invalid instrument {}", instance_id)));
+                    }
+                    None => {
+                        log::error!("okex reported an invalid instrument without naming it: {} - {}", error.code, error.message);
+                    }
+                }
+                Ok(())
+            }
+            ExchangeErrorKind::RateLimited => {
+                self.context.health.set(OKEX, HealthStatus::Degraded(format!("rate limited: {}", error.message)));
+                Err(ArbitrageError::RateLimited(format!("okex rate limited: {} - {}", error.code, error.message)))
+            }
+            ExchangeErrorKind::Authentication => {
+                self.context.health.set(OKEX, HealthStatus::Unhealthy(format!("authentication failed: {}", error.message)));
+                Err(ArbitrageError::UnrecoverableError(format!("okex authentication failed: {} - {}", error.code, error.message)))
+            }
+            ExchangeErrorKind::Other => {
+                log::error!("received okex error: {} - {}", error.code, error.message);
+                Ok(())
+            }
+        }
+    }
 }
 
 #[async_trait::async_trait]
 impl WsCallback for OkexExchangeCallback {
-    async fn on_message(&mut self, message: Message, _received_time: jiff::Timestamp) -> ArbitrageResult<()> {
+    type Command = SubscriptionCommand;
+
+    async fn on_message(&mut self, message: Message, received_time: jiff::Timestamp) -> ArbitrageResult<()> {
 
         match message {
+            Message::Text(text) if text.as_str() == "pong" => {
+                self.awaiting_pong = false;
+            }
             Message::Text(text) => {
                 let result = serde_json::from_str::<OkexResponse>(&text);
                 match result {
                     Ok(response) => {
-                        log::info!("received okex response: {:?}", response);
+                        self.on_response(response)?;
                     }
                     Err(_) => {
                         // Parse it as channel message
                         let result = serde_json::from_str::<OkexMessage>(&text);
                         match result {
                             Ok(channel_message) => {
-                                match self.internal_message_producer.send(channel_message.into()).await {
+                                match self.internal_message_producer.send(InternalMessage::from_okex(channel_message, received_time)).await {
                                     Ok(_) => {}
                                     Err(e) => {
                                         log::error!("error sending internal message: {} hence the message is dropped", e);
@@ -115,6 +223,7 @@ impl WsCallback for OkexExchangeCallback {
 
                             }
                             Err(e) => {
+                                metrics::PARSE_ERRORS.with_label_values(&["Okex"]).inc();
                                 log::error!("error parsing either okex response or channel message: {}", e);
                             }
                         }
@@ -141,17 +250,56 @@ impl WsCallback for OkexExchangeCallback {
 
     async fn on_connect(&mut self, _timestamp: jiff::Timestamp) -> ArbitrageResult<()> {
         log::info!("connected to Okex");
+        self.context.health.set(OKEX, HealthStatus::Healthy);
         self.subscribe_products()?;
         Ok(())
     }
 
     fn on_disconnect(&mut self) -> ArbitrageResult<()>  {
         log::info!("disconnected from Okex");
+        self.inflight_subscription_requests.clear();
+        self.awaiting_pong = false;
+        reset_subscriptions(&mut self.products_to_subscribe);
+        self.publish_subscription_state();
         Ok(())
     }
 
     fn on_heartbeat(&mut self) -> ArbitrageResult<()>  {
         log::debug!("heartbeat from Okex");
+        // Okex drops connections which are silent for 30 seconds, unless the client sends `ping`
+        if self.awaiting_pong {
+            return Err(ArbitrageError::Warning("okex did not answer ping since last heartbeat".to_string()));
+        }
+        self.ws_client.write(Message::Text(Utf8Bytes::from_static("ping")))?;
+        self.awaiting_pong = true;
         Ok(())
     }
+
+    async fn on_command(&mut self, command: SubscriptionCommand) -> ArbitrageResult<()> {
+        match command {
+            SubscriptionCommand::Subscribe(instance_ids) => {
+                for instance_id in instance_ids {
+                    if add_product(&mut self.products_to_subscribe, &instance_id) {
+                        log::info!("subscribing to okex product {}", instance_id);
+                    }
+                }
+                self.subscribe_products()
+            }
+            SubscriptionCommand::Unsubscribe(instance_ids) => self.unsubscribe_products(instance_ids),
+        }
+    }
+
+    fn on_shutdown(&mut self) -> ArbitrageResult<()> {
+        log::info!("unsubscribing from okex before shutting down");
+        let args = self.products_to_subscribe
+            .iter()
+            .filter(|product| product.subscribed)
+            .map(|product| OkexArg {
+                channel: "books".to_string(),
+                instance_id: product.product_id.clone(),
+            })
+            .collect::<Vec<OkexArg>>();
+        self.send_request(OkexOperation::Unsubscribe, args)?;
+        self.ws_client.close()
+    }
 }
@@ -0,0 +1,365 @@
+use std::collections::HashMap;
+
+use common::{ArbitrageError, ArbitrageResult, Context, HealthStatus, MpSc, WorkerRef};
+use models::{okex::{OkexAccount, OkexChannelArg, OkexError, OkexEvent, OkexEventMessage, OkexLoginArg, OkexOperation, OkexOrder, OkexOrderArg, OkexOrderRequest, OkexOrderResponse, OkexOrderSide, OkexOrderState, OkexOrderType, OkexPrivateMessage, OkexRequest}, CryptoAsset, Exchange, ExchangeErrorKind, Product};
+use openssl::{hash::MessageDigest, pkey::PKey, sign::Signer};
+use serde::Serialize;
+use tokio::sync::mpsc::Sender;
+use tokio_tungstenite::tungstenite::{Message, Utf8Bytes};
+use wsclient::{WsCallback, WsClient};
+
+use crate::{execution::{OrderCommand, OrderRequest, OrderSide, OrderState, OrderUpdate, TimeInForce, ORDER_COMMANDS_BUFFER_SIZE}, funding::Funding, metrics, settings::{ExchangeConfig, OkexLiveConfig}};
+
+const OKEX_PRIVATE: &str = "okex-private";
+
+/// Path signed along with the timestamp on login
+const LOGIN_PATH: &str = "/users/self/verify";
+
+
+/// Signature of the `login` operation, the base64 HMAC SHA256 of the timestamp, method and path
+pub fn okex_login_sign(secret_key: &str, timestamp: &str) -> ArbitrageResult<String> {
+    let sign = || -> Result<Vec<u8>, openssl::error::ErrorStack> {
+        let key = PKey::hmac(secret_key.as_bytes())?;
+        let mut signer = Signer::new(MessageDigest::sha256(), &key)?;
+        signer.update(format!("{}GET{}", timestamp, LOGIN_PATH).as_bytes())?;
+        signer.sign_to_vec()
+    };
+    let signature = sign().map_err(|e| ArbitrageError::GenericError(format!("failed to sign okex login: {}", e)))?;
+    Ok(openssl::base64::encode_block(&signature))
+}
+
+
+/// Session on the private websocket of Okex, which places, amends and cancels the orders it is sent
+/// and reports their updates from the `orders` channel
+pub struct OkexPrivateAdapter {
+    context: Context,
+    ws_client: WsClient,
+    config: OkexLiveConfig,
+    commands: MpSc<OrderCommand>,
+    funding: Option<Funding>,
+}
+
+impl OkexPrivateAdapter {
+    /// Create a new OkexPrivateAdapter from the `live.okex` section of the configuration,
+    /// keeping the connection alive as configured in `exchanges.okex`
+    pub fn new(context: Context, exchange_config: &ExchangeConfig, config: &OkexLiveConfig) -> Self {
+        let ws_client = WsClient::new(config.ws_url.clone(), exchange_config.heartbeat_millis)
+            .with_client_id(OKEX_PRIVATE.to_string())
+            .with_max_silent_heartbeats(exchange_config.max_silent_heartbeats);
+        let commands = MpSc::new(ORDER_COMMANDS_BUFFER_SIZE);
+        Self { context, ws_client, config: config.clone(), commands, funding: None }
+    }
+
+    /// Learns the funds available on Okex from the `account` channel
+    pub fn with_funding(mut self, funding: Funding) -> Self {
+        self.funding = Some(funding);
+        self
+    }
+
+    pub fn callback(&self, order_updates: Sender<OrderUpdate>) -> OkexPrivateCallback {
+        OkexPrivateCallback {
+            context: self.context.clone(),
+            ws_client: self.ws_client.clone(),
+            config: self.config.clone(),
+            logged_in: false,
+            awaiting_pong: false,
+            request_id: 0,
+            pending_requests: HashMap::new(),
+            order_updates,
+            funding: self.funding.clone(),
+        }
+    }
+
+    /// Sender of the orders to place, amend or cancel
+    pub fn commands(&self) -> Sender<OrderCommand> {
+        self.commands.sender()
+    }
+
+    pub fn worker(&mut self, callback: OkexPrivateCallback) -> WorkerRef {
+        Box::new(
+            self.ws_client.consumer(self.context.with_name("okex-private-ws-consumer"), callback, self.commands.clone_with_receiver())
+        )
+    }
+}
+
+
+#[derive(Clone)]
+pub struct OkexPrivateCallback {
+    context: Context,
+    ws_client: WsClient,
+    config: OkexLiveConfig,
+    /// Orders are refused until the login is acknowledged
+    logged_in: bool,
+    /// Set when a `ping` was sent on heartbeat and no `pong` was received yet
+    awaiting_pong: bool,
+    request_id: u64,
+    /// Order operations waiting for a response, keyed by request id
+    pending_requests: HashMap<String, OrderCommand>,
+    order_updates: Sender<OrderUpdate>,
+    funding: Option<Funding>,
+}
+
+impl OkexPrivateCallback {
+    fn write<T: Serialize>(&self, request: &T) -> ArbitrageResult<()> {
+        let json = serde_json::to_string(request).map_err(ArbitrageError::JsonError)?;
+        self.ws_client.write(Message::Text(Utf8Bytes::from(&json)))
+    }
+
+    fn login(&self) -> ArbitrageResult<()> {
+        let timestamp = jiff::Timestamp::now().as_second().to_string();
+        let sign = okex_login_sign(self.config.secret_key.expose(), &timestamp)?;
+        let request = OkexRequest {
+            op: OkexOperation::Login,
+            args: vec![OkexLoginArg {
+                api_key: self.config.api_key.clone(),
+                passphrase: self.config.passphrase.expose().to_string(),
+                timestamp,
+                sign,
+            }],
+        };
+        self.write(&request)
+    }
+
+    /// The sizes of the options are sent in contracts, see `Product::to_contracts`
+    fn send_order(&mut self, command: OrderCommand) -> ArbitrageResult<()> {
+        let (op, arg) = match &command {
+            OrderCommand::Place(OrderRequest { client_order_id, product, side, price, size, time_in_force }) => {
+                let arg = OkexOrderArg {
+                    instance_id: product.to_okex_exchange(),
+                    client_order_id: client_order_id.clone(),
+                    trade_mode: Some(self.config.trade_mode.clone()),
+                    side: Some(match side {
+                        OrderSide::Buy => OkexOrderSide::Buy,
+                        OrderSide::Sell => OkexOrderSide::Sell,
+                    }),
+                    order_type: Some(match time_in_force {
+                        TimeInForce::ImmediateOrCancel => OkexOrderType::Ioc,
+                        TimeInForce::GoodTilCancelled => OkexOrderType::Limit,
+                    }),
+                    price: Some(*price),
+                    size: Some(product.to_contracts(&Exchange::Okex, *size)),
+                    ..OkexOrderArg::default()
+                };
+                (OkexOperation::Order, arg)
+            }
+            OrderCommand::Amend { client_order_id, product, price, size } => {
+                let arg = OkexOrderArg {
+                    instance_id: product.to_okex_exchange(),
+                    client_order_id: client_order_id.clone(),
+                    new_price: Some(*price),
+                    new_size: Some(product.to_contracts(&Exchange::Okex, *size)),
+                    ..OkexOrderArg::default()
+                };
+                (OkexOperation::AmendOrder, arg)
+            }
+            OrderCommand::Cancel { client_order_id, product } => {
+                let arg = OkexOrderArg {
+                    instance_id: product.to_okex_exchange(),
+                    client_order_id: client_order_id.clone(),
+                    ..OkexOrderArg::default()
+                };
+                (OkexOperation::CancelOrder, arg)
+            }
+        };
+        self.request_id += 1;
+        let request = OkexOrderRequest { id: self.request_id.to_string(), op, args: vec![arg] };
+        self.write(&request)?;
+        self.pending_requests.insert(request.id, command);
+        Ok(())
+    }
+
+    async fn publish(&self, update: OrderUpdate) {
+        if let Err(e) = self.order_updates.send(update).await {
+            log::error!("error sending okex order update: {} hence the update is dropped", e);
+        }
+    }
+
+    /// Refused places are reported as rejected orders, refused amends and cancels are only logged
+    /// as the order they target keeps its state
+    async fn on_order_response(&mut self, response: OkexOrderResponse) {
+        let Some(command) = self.pending_requests.remove(&response.id) else {
+            log::warn!("received okex order response for unknown request: {:?}", response);
+            return;
+        };
+        let (code, message) = match response.data.first() {
+            Some(result) => (result.code.clone(), result.message.clone()),
+            None => (response.code.clone(), response.message.clone()),
+        };
+        if code == "0" {
+            log::debug!("okex accepted {:?} of {}", response.op, command.client_order_id());
+            return;
+        }
+        let reason = format!("{} - {}", code, message);
+        match command {
+            OrderCommand::Place(request) => {
+                log::warn!("okex rejected order {}: {}", request.client_order_id, reason);
+                self.publish(OrderUpdate::rejected(Exchange::Okex, &request.client_order_id, &reason)).await;
+            }
+            command => log::error!("okex refused {:?}: {}", command, reason),
+        }
+    }
+
+    /// The filled sizes of the options are converted from contracts to the underlying
+    async fn on_orders(&self, orders: Vec<OkexOrder>) {
+        for order in orders {
+            if order.client_order_id.is_empty() {
+                // Not placed by the server, e.g. from the website
+                continue;
+            }
+            let state = match order.state {
+                OkexOrderState::Live | OkexOrderState::PartiallyFilled => OrderState::Open,
+                OkexOrderState::Filled => OrderState::Filled,
+                OkexOrderState::Canceled | OkexOrderState::MmpCanceled => OrderState::Cancelled,
+            };
+            let filled_size = order.filled_size.unwrap_or_default();
+            let filled_size = match Product::from_okex_exhchange(&order.instance_id) {
+                Some(product) => product.from_contracts(&Exchange::Okex, filled_size),
+                None => {
+                    log::warn!("unknown okex instrument {} of order {}", order.instance_id, order.client_order_id);
+                    filled_size
+                }
+            };
+            let update = OrderUpdate {
+                exchange: Exchange::Okex,
+                client_order_id: order.client_order_id,
+                state,
+                filled_size,
+                average_price: order.average_price.filter(|price| !price.is_zero()),
+                // Okex reports the fee charged as a negative amount
+                fee: order.fee.map(|fee| -fee),
+                reason: None,
+            };
+            self.publish(update).await;
+        }
+    }
+
+    fn on_account(&self, accounts: Vec<OkexAccount>) {
+        let Some(funding) = self.funding.as_ref() else {
+            return;
+        };
+        for balance in accounts.iter().flat_map(|account| account.details.iter()) {
+            if let (Some(currency), Some(available)) = (CryptoAsset::from_symbol(&balance.currency), balance.available()) {
+                funding.set(&Exchange::Okex, &currency, available);
+            }
+        }
+    }
+
+    fn on_event(&mut self, event: OkexEventMessage) -> ArbitrageResult<()> {
+        match event.event {
+            OkexEvent::Login => {
+                log::info!("logged in to okex");
+                self.logged_in = true;
+                self.context.health.set(OKEX_PRIVATE, HealthStatus::Healthy);
+                // The hedges of the naked legs are swaps
+                let mut args = vec![OkexChannelArg { channel: "orders".to_string(), instrument_type: Some("ANY".to_string()) }];
+                if self.funding.is_some() {
+                    args.push(OkexChannelArg { channel: "account".to_string(), instrument_type: None });
+                }
+                self.write(&OkexRequest { op: OkexOperation::Subscribe, args })
+            }
+            OkexEvent::Subscribe | OkexEvent::Unsubscribe => {
+                log::info!("okex {:?} acknowledged for a private channel", event.event);
+                Ok(())
+            }
+            OkexEvent::Error => {
+                let error = OkexError { code: event.code.unwrap_or_default(), message: event.message.unwrap_or_default() };
+                match error.kind() {
+                    ExchangeErrorKind::Authentication => {
+                        self.context.health.set(OKEX_PRIVATE, HealthStatus::Unhealthy(format!("login failed: {}", error.message)));
+                        Err(ArbitrageError::UnrecoverableError(format!("okex login failed: {} - {}", error.code, error.message)))
+                    }
+                    ExchangeErrorKind::RateLimited => {
+                        Err(ArbitrageError::RateLimited(format!("okex rate limited: {} - {}", error.code, error.message)))
+                    }
+                    _ => {
+                        log::error!("received okex private error: {} - {}", error.code, error.message);
+                        Ok(())
+                    }
+                }
+            }
+        }
+    }
+}
+
+
+#[async_trait::async_trait]
+impl WsCallback for OkexPrivateCallback {
+    type Command = OrderCommand;
+
+    async fn on_message(&mut self, message: Message, _received_time: jiff::Timestamp) -> ArbitrageResult<()> {
+        match message {
+            Message::Text(text) if text.as_str() == "pong" => {
+                self.awaiting_pong = false;
+            }
+            Message::Text(text) => match serde_json::from_str::<OkexPrivateMessage>(&text) {
+                Ok(OkexPrivateMessage::OrderResponse(response)) => self.on_order_response(response).await,
+                Ok(OkexPrivateMessage::Orders(orders)) => self.on_orders(orders.data).await,
+                Ok(OkexPrivateMessage::Account(account)) => self.on_account(account.data),
+                Ok(OkexPrivateMessage::Event(event)) => self.on_event(event)?,
+                Err(e) => {
+                    metrics::PARSE_ERRORS.with_label_values(&["Okex"]).inc();
+                    log::error!("error parsing okex private message: {}", e);
+                }
+            },
+            Message::Close(close) => {
+                log::error!("okex private connection closed: {:?}", close);
+            }
+            _ => {
+                return Err(ArbitrageError::Warning(format!("received unexpected message: {:?}", message)));
+            }
+        }
+        Ok(())
+    }
+
+    async fn on_connect(&mut self, _timestamp: jiff::Timestamp) -> ArbitrageResult<()> {
+        log::info!("connected to okex private websocket, logging in");
+        self.login()
+    }
+
+    fn on_disconnect(&mut self) -> ArbitrageResult<()> {
+        log::info!("disconnected from okex private websocket");
+        self.logged_in = false;
+        self.awaiting_pong = false;
+        // The updates of the orders in flight are lost, the execution manager times them out
+        self.pending_requests.clear();
+        self.context.health.set(OKEX_PRIVATE, HealthStatus::Degraded("disconnected".to_string()));
+        Ok(())
+    }
+
+    fn on_heartbeat(&mut self) -> ArbitrageResult<()> {
+        if self.awaiting_pong {
+            return Err(ArbitrageError::Warning("okex did not answer ping since last heartbeat".to_string()));
+        }
+        self.ws_client.write(Message::Text(Utf8Bytes::from_static("ping")))?;
+        self.awaiting_pong = true;
+        Ok(())
+    }
+
+    async fn on_command(&mut self, command: OrderCommand) -> ArbitrageResult<()> {
+        if !self.logged_in {
+            log::warn!("not logged in to okex, refusing {:?}", command);
+            if let OrderCommand::Place(request) = command {
+                self.publish(OrderUpdate::rejected(Exchange::Okex, &request.client_order_id, "not logged in")).await;
+            }
+            return Ok(());
+        }
+        self.send_order(command)
+    }
+
+    fn on_shutdown(&mut self) -> ArbitrageResult<()> {
+        self.ws_client.close()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_login_sign() {
+        // Signature computed independently with Python's hmac module
+        let sign = okex_login_sign("22582BD0CFF14C41EDBF1AB98506286D", "1538054050").unwrap();
+        assert_eq!(sign, "+LdIr8lkkvhr5hoA3g9TMC0+uQJ849ftAcocA/ouu4M=");
+    }
+}
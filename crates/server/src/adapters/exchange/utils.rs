@@ -1,11 +1,14 @@
 use std::collections::HashSet;
 
 use models::ProductSubscription;
+use wsclient::ConnectionState;
 
+/// Size of the buffer for the subscription commands sent to an adapter
+pub const SUBSCRIPTION_COMMANDS_BUFFER_SIZE: usize = 100;
 
-pub fn get_products_to_subscibe(products_to_subscribe: &str) -> HashSet<ProductSubscription> {
+pub fn get_products_to_subscibe(products_to_subscribe: &[String]) -> HashSet<ProductSubscription> {
     let mut products = HashSet::new();
-    for product in products_to_subscribe.split(',') {
+    for product in products_to_subscribe {
         products.insert(ProductSubscription {
             product_id: product.to_string(),
             subscribed: false,
@@ -13,3 +16,68 @@ pub fn get_products_to_subscibe(products_to_subscribe: &str) -> HashSet<ProductS
     }
     products
 }
+
+/// Updates the subscription state of a product, returns false if the product is not tracked
+pub fn set_subscribed(products: &mut HashSet<ProductSubscription>, product_id: &str, subscribed: bool) -> bool {
+    let product = ProductSubscription { product_id: product_id.to_string(), subscribed: !subscribed };
+    if products.remove(&product) {
+        products.insert(ProductSubscription { product_id: product.product_id, subscribed });
+        return true;
+    }
+    products.iter().any(|product| product.product_id == product_id)
+}
+
+/// Starts tracking a product as unsubscribed, returns false if the product is already tracked
+pub fn add_product(products: &mut HashSet<ProductSubscription>, product_id: &str) -> bool {
+    if products.iter().any(|product| product.product_id == product_id) {
+        return false;
+    }
+    products.insert(ProductSubscription { product_id: product_id.to_string(), subscribed: false })
+}
+
+/// Stops tracking a product, returns false if the product is not tracked
+pub fn remove_product(products: &mut HashSet<ProductSubscription>, product_id: &str) -> bool {
+    let len = products.len();
+    products.retain(|product| product.product_id != product_id);
+    products.len() != len
+}
+
+/// Marks all the products as unsubscribed, used when the connection is lost
+pub fn reset_subscriptions(products: &mut HashSet<ProductSubscription>) {
+    *products = products
+        .drain()
+        .map(|product| ProductSubscription { product_id: product.product_id, subscribed: false })
+        .collect();
+}
+
+/// Publishes the number of acknowledged and requested subscriptions on the connection state
+pub fn publish_subscriptions(connection: &ConnectionState, products: &HashSet<ProductSubscription>) {
+    let subscribed = products.iter().filter(|product| product.subscribed).count();
+    connection.set_subscriptions(subscribed, products.len());
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_subscription_state() {
+        let mut products = get_products_to_subscibe(&["BTC-USD-250221-90000-P".to_string(), "BTC-USD-250221-90000-C".to_string()]);
+
+        assert!(set_subscribed(&mut products, "BTC-USD-250221-90000-P", true));
+        assert!(products.contains(&ProductSubscription { product_id: "BTC-USD-250221-90000-P".to_string(), subscribed: true }));
+        assert!(!set_subscribed(&mut products, "BTC-USD-250221-1000-P", true));
+
+        assert!(!add_product(&mut products, "BTC-USD-250221-90000-P"));
+        assert!(add_product(&mut products, "BTC-USD-250221-95000-C"));
+        assert!(remove_product(&mut products, "BTC-USD-250221-95000-C"));
+
+        assert!(remove_product(&mut products, "BTC-USD-250221-90000-C"));
+        assert!(!remove_product(&mut products, "BTC-USD-250221-90000-C"));
+        assert_eq!(products.len(), 1);
+
+        reset_subscriptions(&mut products);
+        assert!(products.contains(&ProductSubscription { product_id: "BTC-USD-250221-90000-P".to_string(), subscribed: false }));
+    }
+}
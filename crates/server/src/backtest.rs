@@ -0,0 +1,431 @@
+use std::{cmp::min, collections::{HashMap, VecDeque}, io::Write, path::PathBuf};
+
+use common::{ArbitrageError, ArbitrageResult, Context, Runner};
+use config::Config;
+use jiff::{SignedDuration, Timestamp};
+use models::{ArbitrageOpportunity, Exchange, ExchangeProduct, OrderBookUpdate, Product};
+use rust_decimal::Decimal;
+use serde::Serialize;
+use wsclient::ReplaySpeed;
+
+use crate::{execution::{Fill, Side, TakenLiquidity, VenuePair, BPS}, manager::OrderBookManager, replay::{create_output, output_error, ReplayObserver, ReplayRunner}, settings::{BacktestConfig, ConfigOverrides, FeesConfig}};
+
+
+impl From<&SimulatedTrade> for VenuePair {
+    fn from(trade: &SimulatedTrade) -> Self {
+        Self { product: trade.product.clone(), buy_exchange: trade.buy_exchange.clone(), sell_exchange: trade.sell_exchange.clone() }
+    }
+}
+
+
+/// Simulated execution of an opportunity
+#[derive(Debug, Clone, Serialize)]
+pub struct SimulatedTrade {
+    pub product: Product,
+    pub buy_exchange: Exchange,
+    pub sell_exchange: Exchange,
+    /// Receive time of the update which triggered the opportunity
+    pub detected_time: Timestamp,
+    /// Time the orders reached the exchanges, they are filled against the books as they were then
+    pub executed_time: Timestamp,
+    pub detected_edge: Decimal,
+    /// Quantity sent on each leg
+    pub order_size: Decimal,
+    pub buy_size: Decimal,
+    /// Average price of the buy leg, slippage included
+    pub buy_price: Option<Decimal>,
+    pub sell_size: Decimal,
+    /// Average price of the sell leg, slippage included
+    pub sell_price: Option<Decimal>,
+    /// Quantity both bought and sold
+    pub hedged_size: Decimal,
+    /// Excess of a leg traded back on its exchange
+    pub unwound_size: Decimal,
+    /// Excess of a leg which could not be traded back, valued at its cost
+    pub open_size: Decimal,
+    pub fees: Decimal,
+    /// Cost of filling away from the detected prices
+    pub slippage: Decimal,
+    pub pnl: Decimal,
+    /// Quantity which could have been bought and sold at a positive edge when the orders arrived
+    pub capacity: Decimal,
+}
+
+/// Outcome of the trades of a product and venue pair, or of all of them
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct BacktestSummary {
+    pub opportunities: u64,
+    /// Opportunities acted on, the others were detected while a trade of the same pair was in flight
+    pub trades: u64,
+    /// Trades with a positive pnl
+    pub hits: u64,
+    pub hit_rate: Decimal,
+    pub hedged_size: Decimal,
+    pub fees: Decimal,
+    pub realized_pnl: Decimal,
+    pub capacity: Decimal,
+}
+
+impl BacktestSummary {
+    fn add(&mut self, trade: &SimulatedTrade) {
+        self.trades += 1;
+        if trade.pnl > Decimal::ZERO {
+            self.hits += 1;
+        }
+        self.hit_rate = (Decimal::from(self.hits) / Decimal::from(self.trades)).round_dp(4);
+        self.hedged_size += trade.hedged_size;
+        self.fees += trade.fees;
+        self.realized_pnl += trade.pnl;
+        self.capacity += trade.capacity;
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PairReport {
+    pub product: Product,
+    pub buy_exchange: Exchange,
+    pub sell_exchange: Exchange,
+    #[serde(flatten)]
+    pub summary: BacktestSummary,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct BacktestReport {
+    pub config: BacktestConfig,
+    pub summary: BacktestSummary,
+    pub pairs: Vec<PairReport>,
+    pub trades: Vec<SimulatedTrade>,
+}
+
+
+struct PendingTrade {
+    arbitrage_opportunity: ArbitrageOpportunity,
+    executed_time: Timestamp,
+}
+
+/// Simulates acting on the opportunities of a replay
+///
+/// Both legs of an opportunity are sent as immediate or cancel orders for up to `max_order_size`, limited
+/// to `limit_bps` beyond the detected prices, and reach the exchanges `latency_millis` after the detection.
+/// They are filled against the depth of the books as they were then, less the liquidity taken by earlier trades,
+/// which is given back once the exchange updates the level. The excess of the larger leg is traded back on its
+/// exchange at any price. While the orders of a pair are in flight, its new opportunities are ignored.
+pub struct Backtester {
+    config: BacktestConfig,
+    fees: FeesConfig,
+    /// Ordered by execution time, the latency being the same for every trade
+    pending: VecDeque<PendingTrade>,
+    /// Liquidity taken by the trades on each level of the books
+    taken: TakenLiquidity,
+    summary: BacktestSummary,
+    pairs: HashMap<VenuePair, BacktestSummary>,
+    trades: Vec<SimulatedTrade>,
+}
+
+impl Backtester {
+    pub fn new(config: BacktestConfig, fees: FeesConfig) -> Self {
+        Self {
+            config,
+            fees,
+            pending: VecDeque::new(),
+            taken: TakenLiquidity::default(),
+            summary: BacktestSummary::default(),
+            pairs: HashMap::new(),
+            trades: vec![],
+        }
+    }
+
+    pub fn report(&self) -> BacktestReport {
+        let mut pairs = self.pairs
+            .iter()
+            .map(|(pair, summary)| PairReport {
+                product: pair.product.clone(),
+                buy_exchange: pair.buy_exchange.clone(),
+                sell_exchange: pair.sell_exchange.clone(),
+                summary: summary.clone(),
+            })
+            .collect::<Vec<_>>();
+        pairs.sort_by_key(|pair| (pair.product.to_string(), format!("{:?}", pair.buy_exchange)));
+        BacktestReport { config: self.config.clone(), summary: self.summary.clone(), pairs, trades: self.trades.clone() }
+    }
+
+    /// Executes the trades whose orders reached the exchanges by `now`
+    fn execute_until(&mut self, order_book_manager: &OrderBookManager, now: Option<Timestamp>) {
+        while self.pending.front().is_some_and(|pending| now.is_none_or(|now| pending.executed_time <= now)) {
+            let pending = self.pending.pop_front().expect("pending trade should exist");
+            let trade = self.execute(order_book_manager, pending);
+            self.summary.add(&trade);
+            self.pairs.entry(VenuePair::from(&trade)).or_default().add(&trade);
+            self.trades.push(trade);
+        }
+    }
+
+    fn execute(&mut self, order_book_manager: &OrderBookManager, pending: PendingTrade) -> SimulatedTrade {
+        let opportunity = pending.arbitrage_opportunity;
+        let buy_book = ExchangeProduct { exchange: opportunity.buy_exchange.clone(), product: opportunity.product.clone() };
+        let sell_book = ExchangeProduct { exchange: opportunity.sell_exchange.clone(), product: opportunity.product.clone() };
+        let buy_fee = self.fees.rate(&opportunity.buy_exchange);
+        let sell_fee = self.fees.rate(&opportunity.sell_exchange);
+        let slippage = self.config.slippage_bps / BPS;
+        let limit = self.config.limit_bps / BPS;
+
+        let asks = self.levels(order_book_manager, &buy_book, Side::Ask);
+        let bids = self.levels(order_book_manager, &sell_book, Side::Bid);
+        let capacity = capacity(&asks, &bids, buy_fee, sell_fee, slippage);
+
+        let order_size = min(opportunity.size, self.config.max_order_size);
+        let buy = Fill::take(&asks, Side::Ask, Some(opportunity.buy_price * (Decimal::ONE + limit)), order_size);
+        let sell = Fill::take(&bids, Side::Bid, Some(opportunity.sell_price * (Decimal::ONE - limit)), order_size);
+        self.consume(&buy_book, Side::Ask, &buy);
+        self.consume(&sell_book, Side::Bid, &sell);
+
+        // Cash received, negative when paid, and fees of each fill
+        let bought = |fill: &Fill, fee: Decimal| {
+            let notional = fill.notional * (Decimal::ONE + slippage);
+            (-notional * (Decimal::ONE + fee), notional * fee)
+        };
+        let sold = |fill: &Fill, fee: Decimal| {
+            let notional = fill.notional * (Decimal::ONE - slippage);
+            (notional * (Decimal::ONE - fee), notional * fee)
+        };
+        let (buy_cash, buy_fees) = bought(&buy, buy_fee);
+        let (sell_cash, sell_fees) = sold(&sell, sell_fee);
+        let mut pnl = buy_cash + sell_cash;
+        let mut fees = buy_fees + sell_fees;
+
+        let hedged_size = min(buy.size, sell.size);
+        let (unwound_size, open_size) = if buy.size > sell.size {
+            let excess = buy.size - sell.size;
+            let bids = self.levels(order_book_manager, &buy_book, Side::Bid);
+            let unwind = Fill::take(&bids, Side::Bid, None, excess);
+            self.consume(&buy_book, Side::Bid, &unwind);
+            let (cash, unwind_fees) = sold(&unwind, buy_fee);
+            let open_size = excess - unwind.size;
+            pnl += cash - buy_cash / buy.size * open_size;
+            fees += unwind_fees;
+            (unwind.size, open_size)
+        } else if sell.size > buy.size {
+            let excess = sell.size - buy.size;
+            let asks = self.levels(order_book_manager, &sell_book, Side::Ask);
+            let unwind = Fill::take(&asks, Side::Ask, None, excess);
+            self.consume(&sell_book, Side::Ask, &unwind);
+            let (cash, unwind_fees) = bought(&unwind, sell_fee);
+            let open_size = excess - unwind.size;
+            pnl += cash - sell_cash / sell.size * open_size;
+            fees += unwind_fees;
+            (unwind.size, open_size)
+        } else {
+            (Decimal::ZERO, Decimal::ZERO)
+        };
+
+        let buy_price = buy.price().map(|price| price * (Decimal::ONE + slippage));
+        let sell_price = sell.price().map(|price| price * (Decimal::ONE - slippage));
+        let slippage_cost = buy_price.map_or(Decimal::ZERO, |price| (price - opportunity.buy_price) * buy.size)
+            + sell_price.map_or(Decimal::ZERO, |price| (opportunity.sell_price - price) * sell.size);
+
+        SimulatedTrade {
+            product: opportunity.product,
+            buy_exchange: opportunity.buy_exchange,
+            sell_exchange: opportunity.sell_exchange,
+            detected_time: opportunity.timestamps.received_time,
+            executed_time: pending.executed_time,
+            detected_edge: opportunity.edge,
+            order_size,
+            buy_size: buy.size,
+            buy_price,
+            sell_size: sell.size,
+            sell_price,
+            hedged_size,
+            unwound_size,
+            open_size,
+            fees,
+            slippage: slippage_cost,
+            pnl,
+            capacity,
+        }
+    }
+
+    /// Levels of a side of a book, best first, less the liquidity taken by the trades
+    fn levels(&self, order_book_manager: &OrderBookManager, exchange_product: &ExchangeProduct, side: Side) -> Vec<(Decimal, Decimal)> {
+        self.taken.levels(order_book_manager.order_book(exchange_product).as_ref(), side)
+    }
+
+    fn consume(&mut self, exchange_product: &ExchangeProduct, side: Side, fill: &Fill) {
+        self.taken.consume(exchange_product, side, fill);
+    }
+}
+
+/// Quantity which can be bought on the asks and sold on the bids at a positive edge, after fees and slippage
+fn capacity(asks: &[(Decimal, Decimal)], bids: &[(Decimal, Decimal)], buy_fee: Decimal, sell_fee: Decimal, slippage: Decimal) -> Decimal {
+    let mut capacity = Decimal::ZERO;
+    let (mut asks, mut bids) = (asks.iter().copied().peekable(), bids.iter().copied().peekable());
+    let (mut ask_left, mut bid_left) = (Decimal::ZERO, Decimal::ZERO);
+    while let (Some((ask, ask_size)), Some((bid, bid_size))) = (asks.peek().copied(), bids.peek().copied()) {
+        let cost = ask * (Decimal::ONE + slippage) * (Decimal::ONE + buy_fee);
+        let proceeds = bid * (Decimal::ONE - slippage) * (Decimal::ONE - sell_fee);
+        if proceeds <= cost {
+            break;
+        }
+        if ask_left.is_zero() {
+            ask_left = ask_size;
+        }
+        if bid_left.is_zero() {
+            bid_left = bid_size;
+        }
+        let size = min(ask_left, bid_left);
+        capacity += size;
+        ask_left -= size;
+        bid_left -= size;
+        if ask_left.is_zero() {
+            asks.next();
+        }
+        if bid_left.is_zero() {
+            bids.next();
+        }
+    }
+    capacity
+}
+
+impl ReplayObserver for Backtester {
+    fn on_update(&mut self, order_book_manager: &OrderBookManager, order_book_update: &OrderBookUpdate) -> ArbitrageResult<()> {
+        self.execute_until(order_book_manager, Some(order_book_update.timestamps.received_time));
+
+        self.taken.release(order_book_update);
+        Ok(())
+    }
+
+    fn on_opportunity(&mut self, _order_book_manager: &OrderBookManager, arbitrage_opportunity: ArbitrageOpportunity) -> ArbitrageResult<()> {
+        let pair = VenuePair::from(&arbitrage_opportunity);
+        self.summary.opportunities += 1;
+        self.pairs.entry(pair.clone()).or_default().opportunities += 1;
+
+        if self.pending.iter().any(|pending| VenuePair::from(&pending.arbitrage_opportunity) == pair) {
+            return Ok(());
+        }
+        let latency = SignedDuration::from_millis(self.config.latency_millis as i64);
+        let executed_time = arbitrage_opportunity.timestamps.received_time + latency;
+        self.pending.push_back(PendingTrade { arbitrage_opportunity, executed_time });
+        Ok(())
+    }
+
+    /// The orders still in flight are filled against the last books
+    fn on_end(&mut self, order_book_manager: &OrderBookManager) -> ArbitrageResult<()> {
+        self.execute_until(order_book_manager, None);
+        Ok(())
+    }
+}
+
+
+/// Replays recordings as fast as possible and simulates acting on the opportunities, see `Backtester`
+pub struct BacktestRunner {
+    replay: ReplayRunner,
+    output: Option<PathBuf>,
+}
+
+impl BacktestRunner {
+    pub fn new(config_file: Option<&str>, overrides: ConfigOverrides, recordings: Vec<PathBuf>, output: Option<PathBuf>) -> ArbitrageResult<Self> {
+        let replay = ReplayRunner::new(config_file, overrides, recordings, ReplaySpeed::AsFastAsPossible, None)?;
+        Ok(Self { replay, output })
+    }
+}
+
+#[async_trait::async_trait]
+impl Runner for BacktestRunner {
+    async fn run(&mut self) -> ArbitrageResult<String> {
+        let server_config = self.replay.server_config();
+        let mut backtester = Backtester::new(server_config.backtest.clone(), server_config.manager.fees.clone());
+        let stats = self.replay.replay(&mut backtester).await?;
+        log::info!("{:?}", stats);
+
+        let report = backtester.report();
+        log::info!("{:?}", report.summary);
+        let mut output = create_output(self.output.as_ref())?;
+        serde_json::to_writer_pretty(&mut output, &report).map_err(ArbitrageError::JsonError)?;
+        writeln!(output).and_then(|_| output.flush()).map_err(output_error)?;
+        Ok("backtest".to_string())
+    }
+
+    fn config(&self) -> &Config {
+        self.replay.config()
+    }
+
+    fn context(&self) -> &Context {
+        self.replay.context()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use common::{MpSc, SharedRef};
+    use models::PipelineTimestamps;
+    use rust_decimal_macros::dec;
+    use tokio::sync::broadcast;
+
+    use crate::latency::LatencyTracker;
+
+    use super::*;
+
+    fn update(exchange: Exchange, millis: i64, bids: Vec<(Decimal, Decimal)>, asks: Vec<(Decimal, Decimal)>) -> OrderBookUpdate {
+        let time = Timestamp::from_millisecond(millis).unwrap();
+        OrderBookUpdate {
+            exchange_product: ExchangeProduct { exchange, product: Product::from_okex_exhchange("BTC-USD-250221-90000-P").unwrap() },
+            bids,
+            asks,
+            timestamps: PipelineTimestamps::new(time, time),
+        }
+    }
+
+    #[test]
+    fn test_backtest_partial_fill_and_taken_liquidity() {
+        let context = Context::from_config(Config::default());
+        let (broadcaster, _) = broadcast::channel(1);
+        let mut order_book_manager = OrderBookManager::new(context, SharedRef::default(), MpSc::new(1), broadcaster, LatencyTracker::default());
+        let config = BacktestConfig { latency_millis: 10, max_order_size: dec!(5), ..BacktestConfig::default() };
+        let mut backtester = Backtester::new(config, FeesConfig::default());
+
+        let updates = [
+            update(Exchange::Okex, 0, vec![(dec!(0.009), dec!(5))], vec![(dec!(0.010), dec!(2)), (dec!(0.011), dec!(5))]),
+            // Buy 2 on okex at 0.010 and sell on deribit at 0.012, the orders arrive at 15ms
+            update(Exchange::Deribit, 5, vec![(dec!(0.012), dec!(3)), (dec!(0.0105), dec!(4))], vec![(dec!(0.013), dec!(3))]),
+            // Only 1 left to sell at 0.012 by then, this opportunity is ignored as the orders are in flight
+            update(Exchange::Deribit, 10, vec![(dec!(0.012), dec!(1))], vec![]),
+            // The liquidity taken at 15ms on okex and deribit is gone for the orders arriving at 30ms
+            update(Exchange::Okex, 20, vec![(dec!(0.0085), dec!(1))], vec![]),
+        ];
+        for order_book_update in updates {
+            backtester.on_update(&order_book_manager, &order_book_update).unwrap();
+            if let Some(arbitrage_opportunity) = order_book_manager.process(order_book_update) {
+                backtester.on_opportunity(&order_book_manager, arbitrage_opportunity).unwrap();
+            }
+        }
+        backtester.on_end(&order_book_manager).unwrap();
+
+        let report = backtester.report();
+        assert_eq!(report.trades.len(), 2);
+        let trade = &report.trades[0];
+        assert_eq!(trade.executed_time, Timestamp::from_millisecond(15).unwrap());
+        assert_eq!((trade.buy_size, trade.buy_price), (dec!(2), Some(dec!(0.010))));
+        assert_eq!((trade.sell_size, trade.sell_price), (dec!(1), Some(dec!(0.012))));
+        // The extra unit bought is sold back on okex at 0.009
+        assert_eq!((trade.hedged_size, trade.unwound_size, trade.open_size), (dec!(1), dec!(1), dec!(0)));
+        assert_eq!(trade.pnl, dec!(0.001));
+        // 1 at 0.010 against 0.012, then 1 at 0.010 against 0.0105
+        assert_eq!(trade.capacity, dec!(2));
+
+        let trade = &report.trades[1];
+        assert_eq!((trade.buy_size, trade.sell_size, trade.pnl), (dec!(0), dec!(0), dec!(0)));
+
+        assert_eq!(report.pairs.len(), 1);
+        assert_eq!(report.summary, BacktestSummary {
+            opportunities: 3,
+            trades: 2,
+            hits: 1,
+            hit_rate: dec!(0.5),
+            hedged_size: dec!(1),
+            fees: dec!(0),
+            realized_pnl: dec!(0.001),
+            capacity: dec!(2),
+        });
+    }
+}
@@ -0,0 +1,39 @@
+use std::{collections::HashMap, sync::MutexGuard};
+
+use common::SharedRef;
+use models::{ExchangeProduct, OrderBook, OrderBookUpdate};
+
+
+/// Books of every product and exchange, as kept by the order book manager
+///
+/// Shared with the clients of `/stream/v1`, which read the books they subscribe to from them instead of keeping a copy
+/// of their own, which would drift once some of their updates are lost.
+#[derive(Clone, Default)]
+pub struct OrderBooks {
+    books: SharedRef<HashMap<ExchangeProduct, OrderBook>>,
+}
+
+impl OrderBooks {
+    pub fn book(&self, exchange_product: &ExchangeProduct) -> Option<OrderBook> {
+        self.books.lock().get(exchange_product).cloned()
+    }
+
+    pub fn lock(&self) -> MutexGuard<'_, HashMap<ExchangeProduct, OrderBook>> {
+        self.books.lock()
+    }
+
+    pub fn apply(&self, order_book_update: OrderBookUpdate) {
+        let mut books = self.books.lock();
+        // Entry api for rust hashmap creates a new copy of the key even it already exists
+        // hence we try to avoid it.
+        match books.get_mut(&order_book_update.exchange_product) {
+            Some(order_book) => order_book.update(order_book_update),
+            None => {
+                let mut order_book = OrderBook::new(&order_book_update.exchange_product);
+                let exchange_product = order_book_update.exchange_product.clone();
+                order_book.update(order_book_update);
+                books.insert(exchange_product, order_book);
+            }
+        }
+    }
+}
@@ -0,0 +1,111 @@
+use std::{collections::HashSet, time::Duration};
+
+use common::{ArbitrageError, ArbitrageResult, Context, MpSc, RestartPolicy, Runner, Workers};
+use config::Config;
+use models::{Exchange, InternalMessage, OrderBook};
+
+use crate::{runner::add_exchange_adapters, settings::{ConfigOverrides, ServerConfig}};
+
+
+/// Connects to every enabled exchange, subscribes to its products and prints the first book of each
+///
+/// Fails when an exchange did not send a book within the timeout.
+pub struct CheckRunner {
+    context: Context,
+    server_config: ServerConfig,
+    timeout: Duration,
+}
+
+impl CheckRunner {
+    pub fn new(config_file: Option<&str>, overrides: ConfigOverrides, timeout_secs: u64) -> ArbitrageResult<Self> {
+        let (config, server_config) = ServerConfig::load(config_file, &overrides)?;
+        let context = Context::from_config(config);
+        Ok(Self { context, server_config, timeout: Duration::from_secs(timeout_secs) })
+    }
+}
+
+
+#[async_trait::async_trait]
+impl Runner for CheckRunner {
+    async fn run(&mut self) -> ArbitrageResult<String> {
+        let mut internal_message_producer = MpSc::new(5000);
+        let mut receiver = internal_message_producer.receiver().expect("internal message receiver should not be taken");
+
+        // Any failure ends the check
+        let mut workers = Workers::new(self.context.with_name("check-workers"), 0);
+        let (_, connections) = add_exchange_adapters(
+            &self.context,
+            &self.server_config,
+            internal_message_producer.sender(),
+            &mut workers,
+            &RestartPolicy::Escalate,
+        );
+        let mut pending = connections.into_keys().collect::<HashSet<Exchange>>();
+        let mut workers = tokio::spawn(async move { workers.run().await });
+
+        let timeout = tokio::time::sleep(self.timeout);
+        tokio::pin!(timeout);
+        while !pending.is_empty() {
+            tokio::select! {
+                _ = &mut timeout => {
+                    break;
+                }
+
+                result = &mut workers => {
+                    return match result {
+                        Ok(Err(e)) => Err(e),
+                        _ => Err(ArbitrageError::GenericError("exchange adapters stopped before receiving a book".to_string())),
+                    };
+                }
+
+                message = receiver.recv() => {
+                    if let Some(InternalMessage::OrderBookUpdate(update)) = message {
+                        if pending.remove(&update.exchange_product.exchange) {
+                            let mut order_book = OrderBook::new(&update.exchange_product);
+                            order_book.update(update);
+                            println!("{}", describe(&order_book));
+                        }
+                    }
+                }
+            }
+        }
+
+        // Unsubscribes and closes the connections
+        self.context.exit();
+        workers.await.ok();
+
+        if !pending.is_empty() {
+            let mut pending = pending.iter().map(|exchange| format!("{:?}", exchange)).collect::<Vec<_>>();
+            pending.sort();
+            return Err(ArbitrageError::GenericError(format!(
+                "no book received within {:?} from {}", self.timeout, pending.join(", ")
+            )));
+        }
+        Ok("check".to_string())
+    }
+
+    fn config(&self) -> &Config {
+        &self.context.config
+    }
+
+    fn context(&self) -> &Context {
+        &self.context
+    }
+}
+
+/// e.g. `Okex BTC-USD-250221-90000-P: best bid 0.0125 x 10, best ask 0.013 x 5, 12 bids, 9 asks`
+fn describe(order_book: &OrderBook) -> String {
+    let level = |level: Option<(_, _)>| match level {
+        Some((price, size)) => format!("{} x {}", price, size),
+        None => "none".to_string(),
+    };
+    format!(
+        "{:?} {}: best bid {}, best ask {}, {} bids, {} asks",
+        order_book.exchange_product.exchange,
+        order_book.exchange_product.product,
+        level(order_book.best_bid()),
+        level(order_book.best_ask()),
+        order_book.bids.len(),
+        order_book.asks.len(),
+    )
+}
@@ -0,0 +1,188 @@
+use std::{path::PathBuf, process::ExitCode};
+
+use clap::{Args, Parser, Subcommand};
+use common::{run_app, ArbitrageError, ArbitrageResult, EXIT_CONFIG};
+use config::ConfigError;
+use models::Exchange;
+use wsclient::ReplaySpeed;
+
+use crate::{backtest::BacktestRunner, check::CheckRunner, replay::ReplayRunner, runner::ServerRunner, settings::{parse_product, ConfigOverrides, ServerConfig}};
+
+
+#[derive(Debug, Parser)]
+#[command(name = "server", about = "Detects option arbitrages between Okex and Deribit", args_conflicts_with_subcommands = true)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+    /// Arguments of `serve`, which runs when no command is given
+    #[command(flatten)]
+    pub serve: ConfigArgs,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Runs the server
+    Serve(ConfigArgs),
+    /// Validates the configuration and prints it once resolved
+    ValidateConfig(ConfigArgs),
+    /// Prints the product parsed from each subscription of the enabled exchanges
+    ListProducts(ConfigArgs),
+    /// Connects to each enabled exchange, prints the first book received and exits
+    Check {
+        #[command(flatten)]
+        config: ConfigArgs,
+        /// Time given to every exchange to send a book
+        #[arg(long, default_value_t = 30)]
+        timeout_secs: u64,
+    },
+    /// Replays recordings through the order book manager and prints the opportunities found
+    Replay {
+        #[command(flatten)]
+        config: ConfigArgs,
+        /// Multiple of the recorded pace, e.g. `1x` for real speed or `10x`, or `max` for as fast as possible
+        #[arg(long, default_value = "1x")]
+        speed: ReplaySpeed,
+        /// File the opportunities are written to as JSON lines, stdout without it
+        #[arg(long)]
+        output: Option<PathBuf>,
+        /// Recording files, or directories of recordings
+        #[arg(required = true)]
+        recordings: Vec<PathBuf>,
+    },
+    /// Replays recordings as fast as possible, simulates acting on the opportunities and prints a report
+    Backtest {
+        #[command(flatten)]
+        config: ConfigArgs,
+        /// File the report is written to as JSON, stdout without it
+        #[arg(long)]
+        output: Option<PathBuf>,
+        /// Recording files, or directories of recordings
+        #[arg(required = true)]
+        recordings: Vec<PathBuf>,
+    },
+}
+
+/// Where the configuration is read from, shared by all the commands
+#[derive(Debug, Clone, Args)]
+pub struct ConfigArgs {
+    /// TOML or YAML configuration file, `.env/server.env` and the environment are used without it
+    #[arg(long, env = "ARBITRAGE_CONFIG")]
+    pub config: Option<String>,
+    /// Only enable these exchanges, e.g. `okex,deribit`
+    #[arg(long, value_delimiter = ',', value_parser = parse_exchange)]
+    pub exchanges: Option<Vec<Exchange>>,
+    /// Port of the websocket and http endpoint
+    #[arg(long)]
+    pub port: Option<u16>,
+}
+
+impl ConfigArgs {
+    fn overrides(&self) -> ConfigOverrides {
+        ConfigOverrides { exchanges: self.exchanges.clone(), port: self.port }
+    }
+}
+
+fn parse_exchange(exchange: &str) -> Result<Exchange, String> {
+    match exchange.to_lowercase().as_str() {
+        "okex" => Ok(Exchange::Okex),
+        "deribit" => Ok(Exchange::Deribit),
+        _ => Err(format!("unknown exchange {:?}, expected okex or deribit", exchange)),
+    }
+}
+
+
+impl Cli {
+    pub fn run(self) -> ExitCode {
+        match self.command.unwrap_or(Command::Serve(self.serve)) {
+            Command::Serve(args) => exit_on_config_error(ServerRunner::new(args.config.as_deref(), args.overrides()), run_app),
+            Command::ValidateConfig(args) => exit_on_config_error(validate_config(&args), |_| ExitCode::SUCCESS),
+            Command::ListProducts(args) => exit_on_config_error(list_products(&args), |_| ExitCode::SUCCESS),
+            Command::Check { config, timeout_secs } => {
+                exit_on_config_error(CheckRunner::new(config.config.as_deref(), config.overrides(), timeout_secs), run_app)
+            }
+            Command::Replay { config, speed, output, recordings } => {
+                let runner = ReplayRunner::new(config.config.as_deref(), config.overrides(), recordings, speed, output);
+                exit_on_config_error(runner, run_app)
+            }
+            Command::Backtest { config, output, recordings } => {
+                exit_on_config_error(BacktestRunner::new(config.config.as_deref(), config.overrides(), recordings, output), run_app)
+            }
+        }
+    }
+}
+
+fn exit_on_config_error<T>(result: ArbitrageResult<T>, run: impl FnOnce(T) -> ExitCode) -> ExitCode {
+    match result {
+        Ok(value) => run(value),
+        Err(e) => {
+            eprintln!("{}", e);
+            ExitCode::from(EXIT_CONFIG)
+        }
+    }
+}
+
+fn validate_config(args: &ConfigArgs) -> ArbitrageResult<()> {
+    let (_, server_config) = ServerConfig::load(args.config.as_deref(), &args.overrides())?;
+    println!("{}", serde_json::to_string_pretty(&server_config).map_err(ArbitrageError::JsonError)?);
+    Ok(())
+}
+
+/// Lists the products even when some are unknown, so that they can be spotted, and fails if so
+fn list_products(args: &ConfigArgs) -> ArbitrageResult<()> {
+    let config = ServerConfig::resolve(args.config.as_deref(), &args.overrides())?;
+    let server_config = config.try_deserialize::<ServerConfig>()?;
+    let mut unknown = 0;
+    for (exchange, exchange_config) in server_config.exchanges() {
+        if !exchange_config.enabled {
+            continue;
+        }
+        for subscription in exchange_config.products.iter() {
+            let product = match parse_product(&exchange, subscription) {
+                Some(product) => format!("{} {:?}", product, product),
+                None => {
+                    unknown += 1;
+                    "not a known product".to_string()
+                }
+            };
+            println!("{:<8} {:<45} {}", format!("{:?}", exchange), subscription, product);
+        }
+    }
+    if unknown > 0 {
+        return Err(ConfigError::Message(format!("{} subscriptions are not known products", unknown)).into());
+    }
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_commands() {
+        let cli = Cli::try_parse_from(["server", "--port", "9100", "--exchanges", "okex,Deribit"]).unwrap();
+        assert!(cli.command.is_none());
+        assert_eq!(cli.serve.overrides(), ConfigOverrides { exchanges: Some(vec![Exchange::Okex, Exchange::Deribit]), port: Some(9100) });
+
+        let cli = Cli::try_parse_from(["server", "check", "--config", "server.toml", "--timeout-secs", "5"]).unwrap();
+        match cli.command {
+            Some(Command::Check { config, timeout_secs }) => {
+                assert_eq!(config.config.as_deref(), Some("server.toml"));
+                assert_eq!(timeout_secs, 5);
+            }
+            command => panic!("unexpected command {:?}", command),
+        }
+
+        let cli = Cli::try_parse_from(["server", "replay", "--speed", "max", "recordings"]).unwrap();
+        match cli.command {
+            Some(Command::Replay { speed, recordings, .. }) => {
+                assert_eq!(speed, ReplaySpeed::AsFastAsPossible);
+                assert_eq!(recordings, vec![PathBuf::from("recordings")]);
+            }
+            command => panic!("unexpected command {:?}", command),
+        }
+
+        assert!(Cli::try_parse_from(["server", "serve", "--exchanges", "binance"]).is_err());
+        assert!(Cli::try_parse_from(["server", "replay"]).is_err());
+    }
+}
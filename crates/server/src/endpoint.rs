@@ -1,40 +1,191 @@
-use common::{Context, SpawnResult, Worker};
-use models::InternalMessage;
-use tokio::sync::broadcast::Sender;
-use warp::{ws::WebSocket, Filter};
+use std::time::Duration;
+
+use common::{AppBroadcaster, Context, SpawnResult, Worker};
+use jiff::Timestamp;
+use models::{InternalMessage, OrderBookUpdate};
+use tokio::sync::{broadcast::{self, Sender}, mpsc};
+use warp::{http::StatusCode, ws::WebSocket, Filter, Reply};
+
+use crate::{books::OrderBooks, execution::ExecutionHandle, funding::Funding, health::{Connections, HealthReport}, journal::{Journal, JournalQuery}, latency::LatencyTracker, metrics, settings::EndpointConfig};
+
+/// Time given to the websocket clients to be closed on shutdown
+const CLIENTS_CLOSE_TIMEOUT: Duration = Duration::from_secs(2);
 
 #[derive(Clone)]
 pub struct Endpoint {
     context: Context,
     broadcaster: Sender<InternalMessage>,
+    connections: Connections,
+    latency: LatencyTracker,
+    /// Books of the order book manager, served to the clients of `/stream/v1`, empty unless set by `with_order_books`
+    order_books: OrderBooks,
+    /// Updates of the books subscribed to on `/stream/v1`, none are sent unless set by `with_order_books`
+    book_broadcaster: Sender<OrderBookUpdate>,
+    /// Queried by `/opportunities/v1`, None when the journal is disabled
+    journal: Option<Journal>,
+    /// Streamed by `/executions/v1` and served by `/portfolio/v1`, None when nothing executes the opportunities
+    execution: Option<ExecutionHandle>,
+    /// Served by `/funding/v1`, None when the opportunities are not sized by the funds
+    funding: Option<Funding>,
     port: u16,
 }
 
 
-#[allow(unused)]
 impl Endpoint {
-    pub fn new(context: Context, broadcaster: Sender<InternalMessage>) -> Self {
-        let port = context.config.get_int("websocket_server_endpoint").unwrap_or(9027) as u16;
-        Self { context, broadcaster, port }
+    pub fn new(
+        context: Context,
+        config: &EndpointConfig,
+        broadcaster: Sender<InternalMessage>,
+        connections: Connections,
+        latency: LatencyTracker,
+        journal: Option<Journal>,
+    ) -> Self {
+        let (book_broadcaster, _) = broadcast::channel(1);
+        Self {
+            context,
+            broadcaster,
+            connections,
+            latency,
+            order_books: OrderBooks::default(),
+            book_broadcaster,
+            journal,
+            execution: None,
+            funding: None,
+            port: config.port,
+        }
+    }
+
+    pub fn with_order_books(mut self, order_books: OrderBooks, book_broadcaster: Sender<OrderBookUpdate>) -> Self {
+        self.order_books = order_books;
+        self.book_broadcaster = book_broadcaster;
+        self
+    }
+
+    pub fn with_execution(mut self, execution: ExecutionHandle) -> Self {
+        self.execution = Some(execution);
+        self
+    }
+
+    pub fn with_funding(mut self, funding: Funding) -> Self {
+        self.funding = Some(funding);
+        self
     }
 }
 
 
 impl Worker for Endpoint {
+    fn name(&self) -> String {
+        self.context.name.clone()
+    }
+
     fn spawn(&mut self) -> SpawnResult {
 
         let endpoint = self.clone();
         tokio::spawn(async move {
             let receiver = endpoint.broadcaster.clone();
             let receiver = warp::any().map(move || receiver.clone());
+            let latency = endpoint.latency.clone();
+            let latency = warp::any().map(move || latency.clone());
+            let order_books = endpoint.order_books.clone();
+            let order_books = warp::any().map(move || order_books.clone());
+            let book_broadcaster = endpoint.book_broadcaster.clone();
+            let book_broadcaster = warp::any().map(move || book_broadcaster.clone());
+            let app = endpoint.context.app.clone();
+            let app = warp::any().map(move || app.clone());
+            // Every client holds a sender, the receiver completes once all of them are closed
+            let (clients_open, mut clients_closed) = mpsc::channel::<()>(1);
+            let clients = warp::any().map(move || clients_open.clone());
 
             let stream_v1 = warp::path!("stream" / "v1")
                 .and(warp::ws())
                 .and(receiver)
-                .map(|ws: warp::ws::Ws, receiver| {
-                    ws.on_upgrade(move |socket| socket_connected(socket, receiver))
+                .and(app.clone())
+                .and(latency.clone())
+                .and(order_books)
+                .and(book_broadcaster)
+                .and(clients.clone())
+                .map(|ws: warp::ws::Ws, receiver, app, latency, order_books, book_broadcaster, client| {
+                    ws.on_upgrade(move |socket| socket_connected(socket, receiver, app, latency, order_books, book_broadcaster, client))
+                });
+
+            let execution = endpoint.execution.clone();
+            let execution = warp::any().map(move || execution.clone());
+
+            let executions_v1 = warp::path!("executions" / "v1")
+                .and(warp::ws())
+                .and(execution.clone())
+                .and(app)
+                .and(clients)
+                .map(|ws: warp::ws::Ws, execution: Option<ExecutionHandle>, app: AppBroadcaster, client| {
+                    let Some(execution) = execution else {
+                        return warp::reply::with_status(warp::reply::json(&"execution is disabled"), StatusCode::NOT_FOUND).into_response();
+                    };
+                    ws.on_upgrade(move |socket| executions_connected(socket, execution, app, client)).into_response()
+                });
+
+            let portfolio_v1 = warp::path!("portfolio" / "v1")
+                .and(warp::get())
+                .and(execution.clone())
+                .map(|execution: Option<ExecutionHandle>| match execution {
+                    Some(execution) => warp::reply::with_status(warp::reply::json(&execution.portfolio.lock().report()), StatusCode::OK),
+                    None => warp::reply::with_status(warp::reply::json(&"execution is disabled"), StatusCode::NOT_FOUND),
+                });
+
+            let risk_v1 = warp::path!("risk" / "v1")
+                .and(warp::get())
+                .and(execution)
+                .map(|execution: Option<ExecutionHandle>| match execution {
+                    Some(execution) => {
+                        let report = execution.risk.lock().report(Timestamp::now());
+                        warp::reply::with_status(warp::reply::json(&report), StatusCode::OK)
+                    }
+                    None => warp::reply::with_status(warp::reply::json(&"execution is disabled"), StatusCode::NOT_FOUND),
+                });
+
+            let funding = endpoint.funding.clone();
+            let funding_v1 = warp::path!("funding" / "v1")
+                .and(warp::get())
+                .map(move || match funding.as_ref() {
+                    Some(funding) => warp::reply::with_status(warp::reply::json(&funding.report()), StatusCode::OK),
+                    None => warp::reply::with_status(warp::reply::json(&"funding is not tracked"), StatusCode::NOT_FOUND),
                 });
 
+            let latency_v1 = warp::path!("latency" / "v1")
+                .and(warp::get())
+                .and(latency.clone())
+                .map(|latency: LatencyTracker| warp::reply::json(&latency.report()));
+
+            let metrics = warp::path!("metrics")
+                .and(warp::get())
+                .and(latency)
+                .map(|latency: LatencyTracker| {
+                    warp::reply::with_header(metrics::render(&latency), "content-type", "text/plain; version=0.0.4")
+                });
+
+            let health = endpoint.context.health.clone();
+            let connections = endpoint.connections.clone();
+            let health_report = warp::any().map(move || HealthReport::new(&health, &connections));
+
+            // Liveness fails only when a worker stopped, readiness also fails when an exchange feed is down
+            let health = warp::path!("health")
+                .and(warp::get())
+                .and(health_report.clone())
+                .map(|report: HealthReport| health_reply(report.live, &report));
+
+            let ready = warp::path!("ready")
+                .and(warp::get())
+                .and(health_report)
+                .map(|report: HealthReport| health_reply(report.ready, &report));
+
+            let journal = endpoint.journal.clone();
+            let journal = warp::any().map(move || journal.clone());
+
+            let opportunities_v1 = warp::path!("opportunities" / "v1")
+                .and(warp::get())
+                .and(warp::query::<JournalQuery>())
+                .and(journal)
+                .then(query_journal);
+
             let not_found = warp::path::end()
                 .map(|| {
                     warp::reply::with_status(
@@ -43,7 +194,7 @@ impl Worker for Endpoint {
                     )
                 });
 
-            let routes = stream_v1.or(not_found);
+            let routes = stream_v1.or(executions_v1).or(opportunities_v1).or(portfolio_v1).or(risk_v1).or(funding_v1).or(latency_v1).or(metrics).or(health).or(ready).or(not_found);
 
             let mut app = endpoint.context.app.subscribe();
             let (_, server) = warp::serve(routes).bind_with_graceful_shutdown(
@@ -55,15 +206,31 @@ impl Worker for Endpoint {
 
             server.await;
 
+            // Upgraded connections are not tracked by the server, wait for the clients to be sent a close frame
+            if tokio::time::timeout(CLIENTS_CLOSE_TIMEOUT, clients_closed.recv()).await.is_err() {
+                log::warn!("websocket clients were not closed within {:?}", CLIENTS_CLOSE_TIMEOUT);
+            }
+
             Ok("websocket server exited".to_string())
         })
     }
 }
 
-async fn socket_connected(ws: WebSocket, broadcaster: Sender<InternalMessage>) {
-    let mut socket = super::websocket::WebSocket::new(broadcaster.subscribe());
+async fn socket_connected(
+    ws: WebSocket,
+    broadcaster: Sender<InternalMessage>,
+    app: AppBroadcaster,
+    latency: LatencyTracker,
+    order_books: OrderBooks,
+    book_broadcaster: Sender<OrderBookUpdate>,
+    client: mpsc::Sender<()>,
+) {
+    let mut socket = super::websocket::WebSocket::new(broadcaster.subscribe(), app.subscribe(), latency, order_books, book_broadcaster);
     tokio::spawn(async move {
+        metrics::WEBSOCKET_CLIENTS.inc();
         let result = socket.serve(ws).await;
+        metrics::WEBSOCKET_CLIENTS.dec();
+        drop(client);
         match result {
             Ok(_) => {
                 log::info!("websocket connection closed normally");
@@ -74,3 +241,32 @@ async fn socket_connected(ws: WebSocket, broadcaster: Sender<InternalMessage>) {
         }
     });
 }
+
+async fn executions_connected(ws: WebSocket, execution: ExecutionHandle, app: AppBroadcaster, client: mpsc::Sender<()>) {
+    let receiver = execution.reports.subscribe();
+    tokio::spawn(async move {
+        metrics::WEBSOCKET_CLIENTS.inc();
+        let result = super::websocket::forward(ws, receiver, app.subscribe()).await;
+        metrics::WEBSOCKET_CLIENTS.dec();
+        drop(client);
+        if let Err(e) = result {
+            log::error!("error streaming executions: {}", e);
+        }
+    });
+}
+
+fn health_reply(ok: bool, report: &HealthReport) -> warp::reply::WithStatus<warp::reply::Json> {
+    let status = if ok { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+    warp::reply::with_status(warp::reply::json(report), status)
+}
+
+async fn query_journal(query: JournalQuery, journal: Option<Journal>) -> warp::reply::WithStatus<warp::reply::Json> {
+    let Some(journal) = journal else {
+        return warp::reply::with_status(warp::reply::json(&"the journal is disabled"), StatusCode::NOT_FOUND);
+    };
+    match tokio::task::spawn_blocking(move || journal.query(&query)).await {
+        Ok(Ok(opportunities)) => warp::reply::with_status(warp::reply::json(&opportunities), StatusCode::OK),
+        Ok(Err(e)) => warp::reply::with_status(warp::reply::json(&e.to_string()), StatusCode::INTERNAL_SERVER_ERROR),
+        Err(e) => warp::reply::with_status(warp::reply::json(&e.to_string()), StatusCode::INTERNAL_SERVER_ERROR),
+    }
+}
@@ -0,0 +1,99 @@
+use std::{cmp::min, collections::{BTreeMap, HashMap}};
+
+use models::{ExchangeProduct, OrderBook, OrderBookUpdate};
+use rust_decimal::Decimal;
+
+/// Basis points in one
+pub const BPS: Decimal = Decimal::from_parts(10_000, 0, 0, false, 0);
+
+
+/// Side of a book
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Side {
+    Bid,
+    Ask,
+}
+
+/// Quantity taken from the levels of a book, best first
+#[derive(Debug, Default)]
+pub struct Fill {
+    pub size: Decimal,
+    pub notional: Decimal,
+    pub levels: Vec<(Decimal, Decimal)>,
+}
+
+impl Fill {
+    /// Takes up to `size` from the levels, at the limit price or better
+    pub fn take(levels: &[(Decimal, Decimal)], side: Side, limit: Option<Decimal>, size: Decimal) -> Self {
+        let mut fill = Fill::default();
+        for (price, available) in levels.iter() {
+            let within_limit = match (side, limit) {
+                (_, None) => true,
+                (Side::Ask, Some(limit)) => *price <= limit,
+                (Side::Bid, Some(limit)) => *price >= limit,
+            };
+            if fill.size >= size || !within_limit {
+                break;
+            }
+            let taken = min(*available, size - fill.size);
+            fill.size += taken;
+            fill.notional += price * taken;
+            fill.levels.push((*price, taken));
+        }
+        fill
+    }
+
+    /// Average price of the fill, None when nothing was filled
+    pub fn price(&self) -> Option<Decimal> {
+        (!self.size.is_zero()).then(|| self.notional / self.size)
+    }
+}
+
+
+/// Liquidity taken from the books by simulated orders
+///
+/// The books do not react to simulated orders, the quantity they took from a level is kept
+/// until the exchange sends an update of that level.
+#[derive(Debug, Default)]
+pub struct TakenLiquidity {
+    taken: HashMap<(ExchangeProduct, Side), BTreeMap<Decimal, Decimal>>,
+}
+
+impl TakenLiquidity {
+    /// Levels of a side of a book, best first, less the liquidity taken
+    pub fn levels(&self, order_book: Option<&OrderBook>, side: Side) -> Vec<(Decimal, Decimal)> {
+        let Some(order_book) = order_book else {
+            return vec![];
+        };
+        let levels: Box<dyn Iterator<Item = (&Decimal, &Decimal)>> = match side {
+            Side::Bid => Box::new(order_book.bids.iter().rev()),
+            Side::Ask => Box::new(order_book.asks.iter()),
+        };
+        let taken = self.taken.get(&(order_book.exchange_product.clone(), side));
+        levels
+            .map(|(price, size)| (*price, size - taken.and_then(|taken| taken.get(price)).copied().unwrap_or_default()))
+            .filter(|(_, size)| *size > Decimal::ZERO)
+            .collect()
+    }
+
+    pub fn consume(&mut self, exchange_product: &ExchangeProduct, side: Side, fill: &Fill) {
+        if fill.levels.is_empty() {
+            return;
+        }
+        let taken = self.taken.entry((exchange_product.clone(), side)).or_default();
+        for (price, size) in fill.levels.iter() {
+            *taken.entry(*price).or_default() += size;
+        }
+    }
+
+    /// Gives back the liquidity taken from the levels of the update, the exchange sent their size after the trades
+    pub fn release(&mut self, order_book_update: &OrderBookUpdate) {
+        for (side, levels) in [(Side::Bid, &order_book_update.bids), (Side::Ask, &order_book_update.asks)] {
+            if let Some(taken) = self.taken.get_mut(&(order_book_update.exchange_product.clone(), side)) {
+                for (price, _) in levels.iter() {
+                    taken.remove(price);
+                }
+            }
+        }
+    }
+}
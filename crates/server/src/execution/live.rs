@@ -0,0 +1,934 @@
+use std::{cmp::min, collections::{HashMap, VecDeque}, time::Duration};
+
+use common::{ArbitrageError, Context, MpSc, SharedReceiver, SharedRef, SpawnResult, Worker};
+use jiff::{SignedDuration, Timestamp};
+use models::{ArbitrageOpportunity, Exchange, ExchangeProduct, InternalMessage, OrderBook, OrderBookUpdate, Product, SettlementAsset};
+use rust_decimal::Decimal;
+use tokio::sync::{broadcast::{error::RecvError, Sender}, mpsc};
+
+use crate::{greeks, metrics, settings::{LegRiskAction, LiveConfig, RiskConfig}};
+
+use super::{
+    ExecutionHandle, ExecutionReport, LegFill, LegRiskStep, OpenOrders, OrderCommand, OrderRequest, OrderSide, OrderState, OrderUpdate,
+    Portfolio, RiskManager, TimeInForce, VenuePair, BPS,
+};
+
+
+/// Price at most `bps` worse than `price` for an order on `side`
+fn limit_price(side: OrderSide, price: Decimal, bps: Decimal) -> Decimal {
+    match side {
+        OrderSide::Buy => price * (Decimal::ONE + bps / BPS),
+        OrderSide::Sell => price * (Decimal::ONE - bps / BPS),
+    }
+}
+
+/// Applies the update to its order, and the fills it added to the portfolio
+fn apply_update(portfolio: &SharedRef<Portfolio>, leg: &mut LiveLeg, update: &OrderUpdate) {
+    let (filled, notional, fee) = leg.apply(update);
+    if let Some(reason) = update.reason.as_ref() {
+        log::warn!("order {} on {:?} is {:?}: {}", leg.client_order_id, leg.exchange, leg.state, reason);
+    }
+    if !filled.is_zero() || !fee.is_zero() {
+        // A perpetual moves no premium, only its position and the fees
+        let notional = if matches!(leg.product, Product::Perpetual { .. }) { Decimal::ZERO } else { notional };
+        let exchange_product = ExchangeProduct { exchange: leg.exchange.clone(), product: leg.product.clone() };
+        portfolio.lock().fill(&exchange_product, leg.side, filled, notional, fee);
+    }
+}
+
+/// Order sent for an execution, as last reported by its exchange
+struct LiveLeg {
+    client_order_id: String,
+    exchange: Exchange,
+    product: Product,
+    side: OrderSide,
+    limit_price: Decimal,
+    size: Decimal,
+    state: OrderState,
+    filled_size: Decimal,
+    notional: Decimal,
+    fee: Decimal,
+    /// Deribit only reports the fee in the response to the order, which may come after the final update
+    fee_known: bool,
+}
+
+impl LiveLeg {
+    fn new(client_order_id: String, exchange: &Exchange, product: &Product, side: OrderSide, limit_price: Decimal, size: Decimal) -> Self {
+        Self {
+            client_order_id,
+            exchange: exchange.clone(),
+            product: product.clone(),
+            side,
+            limit_price,
+            size,
+            state: OrderState::Open,
+            filled_size: Decimal::ZERO,
+            notional: Decimal::ZERO,
+            fee: Decimal::ZERO,
+            fee_known: false,
+        }
+    }
+
+    /// Nothing more is expected from the exchange
+    fn is_done(&self) -> bool {
+        self.state.is_final() && (self.fee_known || self.filled_size.is_zero())
+    }
+
+    fn average_price(&self) -> Option<Decimal> {
+        (!self.filled_size.is_zero()).then(|| self.notional / self.filled_size)
+    }
+
+    /// Immediate or cancel order for the leg
+    fn place(&self) -> (Exchange, OrderCommand) {
+        let request = OrderRequest {
+            client_order_id: self.client_order_id.clone(),
+            product: self.product.clone(),
+            side: self.side,
+            price: self.limit_price,
+            size: self.size,
+            time_in_force: TimeInForce::ImmediateOrCancel,
+        };
+        (self.exchange.clone(), OrderCommand::Place(request))
+    }
+
+    /// Applies the cumulative update, returns the size, the notional and the fee it added
+    ///
+    /// Updates may arrive out of order, a leg only moves forward.
+    fn apply(&mut self, update: &OrderUpdate) -> (Decimal, Decimal, Decimal) {
+        let mut filled = Decimal::ZERO;
+        let mut notional = Decimal::ZERO;
+        if update.filled_size > self.filled_size {
+            let total_notional = update.average_price.unwrap_or(self.limit_price) * update.filled_size;
+            filled = update.filled_size - self.filled_size;
+            notional = total_notional - self.notional;
+            self.filled_size = update.filled_size;
+            self.notional = total_notional;
+        }
+        let mut fee = Decimal::ZERO;
+        if let Some(update_fee) = update.fee {
+            fee = update_fee - self.fee;
+            self.fee = update_fee;
+            self.fee_known = true;
+        }
+        if !self.state.is_final() {
+            self.state = update.state;
+        }
+        (filled, notional, fee)
+    }
+
+    fn fill(&self) -> LegFill {
+        LegFill {
+            exchange: self.exchange.clone(),
+            side: self.side,
+            limit_price: self.limit_price,
+            size: self.size,
+            filled_size: self.filled_size,
+            average_price: self.average_price(),
+            fee: self.fee,
+        }
+    }
+}
+
+/// Order sent to chase, unwind or hedge the naked size of an execution
+struct LegRiskOrder {
+    action: LegRiskAction,
+    leg: LiveLeg,
+    reference_price: Decimal,
+    /// Why the order was not sent or was rejected
+    reason: Option<String>,
+}
+
+impl LegRiskOrder {
+    fn new(action: LegRiskAction, leg: LiveLeg, reference_price: Decimal) -> Self {
+        Self { action, leg, reference_price, reason: None }
+    }
+
+    /// Order which could not be priced, reported as rejected without being sent
+    fn not_sent(action: LegRiskAction, mut leg: LiveLeg, reference_price: Decimal, reason: String) -> Self {
+        leg.state = OrderState::Rejected;
+        Self { action, leg, reference_price, reason: Some(reason) }
+    }
+
+    fn step(&self) -> LegRiskStep {
+        LegRiskStep::new(self.action, &self.leg.product, self.leg.fill(), self.reference_price, self.reason.clone())
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum LegRef {
+    Buy,
+    Sell,
+    /// Index in the leg risk orders of the execution
+    LegRisk(usize),
+}
+
+struct LiveExecution {
+    arbitrage_opportunity: ArbitrageOpportunity,
+    executed_time: Timestamp,
+    /// Reported with the fills known so far once passed, moved forward by every leg risk order
+    deadline: Timestamp,
+    buy: LiveLeg,
+    sell: LiveLeg,
+    leg_risk: Vec<LegRiskOrder>,
+}
+
+impl LiveExecution {
+    fn leg_mut(&mut self, leg: LegRef) -> &mut LiveLeg {
+        match leg {
+            LegRef::Buy => &mut self.buy,
+            LegRef::Sell => &mut self.sell,
+            LegRef::LegRisk(index) => &mut self.leg_risk[index].leg,
+        }
+    }
+
+    fn is_done(&self) -> bool {
+        self.buy.is_done() && self.sell.is_done() && self.leg_risk.iter().all(|order| order.leg.is_done())
+    }
+
+    /// Quantity of the product bought beyond what was sold, by the legs and by the leg risk orders
+    fn naked_size(&self) -> Decimal {
+        [&self.buy, &self.sell]
+            .into_iter()
+            .chain(self.leg_risk.iter().map(|order| &order.leg))
+            .filter(|leg| leg.product == self.arbitrage_opportunity.product)
+            .map(|leg| match leg.side {
+                OrderSide::Buy => leg.filled_size,
+                OrderSide::Sell => -leg.filled_size,
+            })
+            .sum()
+    }
+
+    /// Leg which filled more than the other
+    fn naked_leg(&self, naked_size: Decimal) -> &LiveLeg {
+        if naked_size > Decimal::ZERO { &self.buy } else { &self.sell }
+    }
+
+    fn attempts(&self, action: LegRiskAction) -> u32 {
+        self.leg_risk.iter().filter(|order| order.action == action).count() as u32
+    }
+}
+
+/// What follows an order update, see `LiveExecutor::on_update`
+#[derive(Debug)]
+pub enum LiveProgress {
+    /// Orders of the execution are in flight
+    Pending,
+    /// Orders to send for the naked size of the execution
+    Orders(Vec<(Exchange, OrderCommand)>),
+    Done(Box<ExecutionReport>),
+}
+
+
+/// Sends the legs of the opportunities to the exchanges and follows their orders until they are done
+///
+/// Both legs are sent at once as immediate or cancel orders for up to `max_order_size`, limited to `limit_bps`
+/// beyond the detected prices. The fills are applied to the portfolio as they are reported. Once both orders
+/// reached a final state, a naked size is handled as configured by `leg_risk`, one immediate or cancel order at a
+/// time:
+/// - a chase sends it again to the exchange of the leg which missed, each attempt further beyond the detected
+///   price until `chase_bps`, and unwinds what is left after `max_attempts`
+/// - an unwind closes it at the best price of the book of the leg which filled, for up to `max_attempts` orders
+/// - a hedge sells or buys the perpetual of `hedge_exchange` for the Black-Scholes delta of the naked size, at the
+///   volatility implied by the price it filled at
+///
+/// The execution is reported with every leg risk order once done, or with what is known when no update came for
+/// `order_timeout_millis`. The orders of an execution reported before they reached a final state are still live on
+/// their exchange, they are followed until they do and their late fills are applied to the portfolio. While the orders of a pair are in flight, its new opportunities are ignored, and those
+/// which would breach a risk limit are suppressed, see `RiskManager`.
+pub struct LiveExecutor {
+    config: LiveConfig,
+    /// Makes the client order ids unique across restarts of the server
+    session: String,
+    next_id: u64,
+    executions: HashMap<u64, LiveExecution>,
+    /// Execution and leg of each client order id
+    orders: HashMap<String, (u64, LegRef)>,
+    /// Orders of the executions reported before they reached a final state, by client order id
+    expired_orders: HashMap<String, LiveLeg>,
+    /// Prices the unwinds and the hedges
+    order_books: HashMap<ExchangeProduct, OrderBook>,
+    portfolio: SharedRef<Portfolio>,
+    risk: SharedRef<RiskManager>,
+}
+
+impl LiveExecutor {
+    pub fn new(config: LiveConfig, session: &str, portfolio: SharedRef<Portfolio>, risk: SharedRef<RiskManager>) -> Self {
+        Self {
+            config,
+            session: session.to_string(),
+            next_id: 0,
+            executions: HashMap::new(),
+            orders: HashMap::new(),
+            expired_orders: HashMap::new(),
+            order_books: HashMap::new(),
+            portfolio,
+            risk,
+        }
+    }
+
+    pub fn on_order_book_update(&mut self, order_book_update: OrderBookUpdate) {
+        match self.order_books.get_mut(&order_book_update.exchange_product) {
+            Some(order_book) => {
+                order_book.update(order_book_update);
+                self.risk.lock().on_order_book(order_book);
+            }
+            None => {
+                let mut order_book = OrderBook::new(&order_book_update.exchange_product);
+                let exchange_product = order_book_update.exchange_product.clone();
+                order_book.update(order_book_update);
+                self.risk.lock().on_order_book(&order_book);
+                self.order_books.insert(exchange_product, order_book);
+            }
+        }
+    }
+
+    /// Orders to send for the opportunity, None if it was ignored as the orders of its pair are in flight or
+    /// suppressed by a risk limit
+    pub fn on_opportunity(&mut self, arbitrage_opportunity: ArbitrageOpportunity, now: Timestamp) -> Option<Vec<(Exchange, OrderCommand)>> {
+        let pair = VenuePair::from(&arbitrage_opportunity);
+        if self.executions.values().any(|execution| VenuePair::from(&execution.arbitrage_opportunity) == pair) {
+            return None;
+        }
+        let size = min(arbitrage_opportunity.size, self.config.max_order_size);
+        if !self.risk.lock().approve(&arbitrage_opportunity, size, &self.open_orders(), now) {
+            return None;
+        }
+        self.next_id += 1;
+        let id = self.next_id;
+        let leg = |exchange: &Exchange, side: OrderSide, price: Decimal| {
+            let client_order_id = format!("arb{}n{}{}", self.session, id, if side == OrderSide::Buy { "b" } else { "s" });
+            let limit_price = limit_price(side, price, self.config.limit_bps);
+            LiveLeg::new(client_order_id, exchange, &arbitrage_opportunity.product, side, limit_price, size)
+        };
+        let buy = leg(&arbitrage_opportunity.buy_exchange, OrderSide::Buy, arbitrage_opportunity.buy_price);
+        let sell = leg(&arbitrage_opportunity.sell_exchange, OrderSide::Sell, arbitrage_opportunity.sell_price);
+
+        let commands = vec![buy.place(), sell.place()];
+        self.orders.insert(buy.client_order_id.clone(), (id, LegRef::Buy));
+        self.orders.insert(sell.client_order_id.clone(), (id, LegRef::Sell));
+        let deadline = now + SignedDuration::from_millis(self.config.order_timeout_millis as i64);
+        self.executions.insert(id, LiveExecution { arbitrage_opportunity, executed_time: now, deadline, buy, sell, leg_risk: vec![] });
+        Some(commands)
+    }
+
+    /// Orders which did not reach a final state yet, those of the executions reported on timeout included
+    fn open_orders(&self) -> OpenOrders {
+        let mut open = OpenOrders { executions: self.executions.len(), ..OpenOrders::default() };
+        let legs = self.executions
+            .values()
+            .flat_map(|execution| [&execution.buy, &execution.sell].into_iter().chain(execution.leg_risk.iter().map(|order| &order.leg)))
+            .chain(self.expired_orders.values());
+        for leg in legs.filter(|leg| !leg.state.is_final()) {
+            let exchange_product = ExchangeProduct { exchange: leg.exchange.clone(), product: leg.product.clone() };
+            open.add(exchange_product, leg.side, leg.size - leg.filled_size);
+        }
+        open
+    }
+
+    /// Applies the update to its order, then sends the next leg risk order or reports the execution once done
+    pub fn on_update(&mut self, update: OrderUpdate, now: Timestamp) -> LiveProgress {
+        let Some((id, leg_ref)) = self.orders.get(&update.client_order_id).copied() else {
+            self.on_expired_update(update);
+            return LiveProgress::Pending;
+        };
+        let Some(execution) = self.executions.get_mut(&id) else {
+            return LiveProgress::Pending;
+        };
+        apply_update(&self.portfolio, execution.leg_mut(leg_ref), &update);
+        if let (LegRef::LegRisk(index), Some(reason)) = (leg_ref, update.reason) {
+            execution.leg_risk[index].reason = Some(reason);
+        }
+        self.advance(id, now)
+    }
+
+    /// Applies the update of an order whose execution was already reported
+    fn on_expired_update(&mut self, update: OrderUpdate) {
+        let Some(leg) = self.expired_orders.get_mut(&update.client_order_id) else {
+            // e.g. Deribit notifies the final state of an order after answering it
+            log::debug!("received update of an order which is no longer followed: {:?}", update);
+            return;
+        };
+        log::warn!("order {} on {:?} updated after its execution was reported: {:?}", leg.client_order_id, leg.exchange, update);
+        apply_update(&self.portfolio, leg, &update);
+        if leg.is_done() {
+            self.expired_orders.remove(&update.client_order_id);
+        }
+    }
+
+    /// Sends the next leg risk order of the execution if all of its orders are done, reports it if there is none
+    fn advance(&mut self, id: u64, now: Timestamp) -> LiveProgress {
+        loop {
+            let execution = &self.executions[&id];
+            if !execution.is_done() {
+                return LiveProgress::Pending;
+            }
+            let Some(order) = self.next_leg_risk_order(id, execution, now) else {
+                return LiveProgress::Done(Box::new(self.report(id)));
+            };
+            let execution = self.executions.get_mut(&id).expect("execution should exist");
+            if let Some(reason) = order.reason.as_ref() {
+                log::warn!("could not {} the naked size of execution {}: {}", order.action.as_str(), id, reason);
+                execution.leg_risk.push(order);
+                continue;
+            }
+            log::info!(
+                "{} the naked size of execution {}: {:?} {} {} at {} on {:?}",
+                order.action.as_str(),
+                id,
+                order.leg.side,
+                order.leg.size,
+                order.leg.product,
+                order.leg.limit_price,
+                order.leg.exchange,
+            );
+            metrics::LEG_RISK_ORDERS.with_label_values(&[order.action.as_str()]).inc();
+            self.orders.insert(order.leg.client_order_id.clone(), (id, LegRef::LegRisk(execution.leg_risk.len())));
+            execution.deadline = now + SignedDuration::from_millis(self.config.order_timeout_millis as i64);
+            let command = order.leg.place();
+            execution.leg_risk.push(order);
+            return LiveProgress::Orders(vec![command]);
+        }
+    }
+
+    /// Next order for the naked size of the execution, None once there is nothing left to do
+    fn next_leg_risk_order(&self, id: u64, execution: &LiveExecution, now: Timestamp) -> Option<LegRiskOrder> {
+        let naked_size = execution.naked_size();
+        if naked_size.is_zero() {
+            return None;
+        }
+        let leg_risk = &self.config.leg_risk;
+        let client_order_id = format!("arb{}n{}r{}", self.session, id, execution.leg_risk.len() + 1);
+        match leg_risk.action {
+            LegRiskAction::Chase if execution.attempts(LegRiskAction::Chase) < leg_risk.max_attempts => {
+                Some(self.chase(execution, naked_size, client_order_id))
+            }
+            LegRiskAction::Chase | LegRiskAction::Unwind if execution.attempts(LegRiskAction::Unwind) < leg_risk.max_attempts => {
+                Some(self.unwind(execution, naked_size, client_order_id))
+            }
+            LegRiskAction::Hedge if execution.attempts(LegRiskAction::Hedge) == 0 => {
+                Some(self.hedge(execution, naked_size, client_order_id, now))
+            }
+            _ => None,
+        }
+    }
+
+    /// Sends the naked size to the exchange of the leg which missed, `limit_bps` stepping to `chase_bps`
+    fn chase(&self, execution: &LiveExecution, naked_size: Decimal, client_order_id: String) -> LegRiskOrder {
+        let opportunity = &execution.arbitrage_opportunity;
+        let (side, exchange, reference_price) = if naked_size > Decimal::ZERO {
+            (OrderSide::Sell, &opportunity.sell_exchange, opportunity.sell_price)
+        } else {
+            (OrderSide::Buy, &opportunity.buy_exchange, opportunity.buy_price)
+        };
+        let leg_risk = &self.config.leg_risk;
+        let attempt = Decimal::from(execution.attempts(LegRiskAction::Chase) + 1);
+        let bps = self.config.limit_bps + (leg_risk.chase_bps - self.config.limit_bps) * attempt / Decimal::from(leg_risk.max_attempts);
+        let leg = LiveLeg::new(client_order_id, exchange, &opportunity.product, side, limit_price(side, reference_price, bps), naked_size.abs());
+        LegRiskOrder::new(LegRiskAction::Chase, leg, reference_price)
+    }
+
+    /// Closes the naked size at the best price of the exchange of the leg which filled
+    fn unwind(&self, execution: &LiveExecution, naked_size: Decimal, client_order_id: String) -> LegRiskOrder {
+        let naked_leg = execution.naked_leg(naked_size);
+        let side = naked_leg.side.opposite();
+        let reference_price = naked_leg.average_price().unwrap_or(naked_leg.limit_price);
+        let exchange_product = ExchangeProduct { exchange: naked_leg.exchange.clone(), product: naked_leg.product.clone() };
+        let best_price = self.order_books.get(&exchange_product).and_then(|order_book| match side {
+            OrderSide::Buy => order_book.best_ask(),
+            OrderSide::Sell => order_book.best_bid(),
+        });
+        let leg = LiveLeg::new(client_order_id, &naked_leg.exchange, &naked_leg.product, side, Decimal::ZERO, naked_size.abs());
+        match best_price {
+            Some((price, _)) => LegRiskOrder::new(LegRiskAction::Unwind, LiveLeg { limit_price: price, ..leg }, reference_price),
+            None => {
+                let reason = format!("no price for {} on {:?}", exchange_product.product, exchange_product.exchange);
+                LegRiskOrder::not_sent(LegRiskAction::Unwind, leg, reference_price, reason)
+            }
+        }
+    }
+
+    /// Offsets the delta of the naked size with the perpetual of `hedge_exchange`
+    ///
+    /// The naked size is in the underlying on either exchange, the Okex adapters converting the contracts of its
+    /// options, and the hedge is sized in contracts of the perpetual.
+    fn hedge(&self, execution: &LiveExecution, naked_size: Decimal, client_order_id: String, now: Timestamp) -> LegRiskOrder {
+        let leg_risk = &self.config.leg_risk;
+        let naked_leg = execution.naked_leg(naked_size);
+        let product = &naked_leg.product;
+        let perpetual = Product::Perpetual { underlying: product.underlying().clone(), settlement: SettlementAsset::USD };
+        let exchange_product = ExchangeProduct { exchange: leg_risk.hedge_exchange.clone(), product: perpetual };
+        let not_sent = |reference_price: Decimal, reason: String| {
+            let leg = LiveLeg::new(client_order_id.clone(), &exchange_product.exchange, &exchange_product.product, OrderSide::Sell, Decimal::ZERO, Decimal::ZERO);
+            LegRiskOrder::not_sent(LegRiskAction::Hedge, leg, reference_price, reason)
+        };
+
+        let best_prices = self.order_books
+            .get(&exchange_product)
+            .and_then(|order_book| Some((order_book.best_bid()?.0, order_book.best_ask()?.0)));
+        let Some((bid, ask)) = best_prices else {
+            return not_sent(Decimal::ZERO, format!("no price for {} on {:?}", exchange_product.product, exchange_product.exchange));
+        };
+        let mid = (bid + ask) / Decimal::TWO;
+        let premium = naked_leg.average_price().unwrap_or(naked_leg.limit_price);
+        let Some(delta) = greeks::product_delta(product, mid, premium, now) else {
+            return not_sent(mid, format!("no delta for {} at {} with the underlying at {}", product, premium, mid));
+        };
+        // Value in USD of the delta of the naked size
+        let exposure = naked_size * delta * mid;
+        let contract_value = exchange_product.product.contract_value(&exchange_product.exchange);
+        let lots = (exposure.abs() / contract_value / leg_risk.hedge_lot_size).round();
+        if lots.is_zero() {
+            return not_sent(mid, format!("delta of {} USD is below a lot of {}", exposure.round_dp(2), exchange_product.product));
+        }
+        let (side, best_price) = if exposure > Decimal::ZERO { (OrderSide::Sell, bid) } else { (OrderSide::Buy, ask) };
+        let leg = LiveLeg::new(
+            client_order_id.clone(),
+            &exchange_product.exchange,
+            &exchange_product.product,
+            side,
+            limit_price(side, best_price, leg_risk.hedge_limit_bps),
+            lots * leg_risk.hedge_lot_size,
+        );
+        LegRiskOrder::new(LegRiskAction::Hedge, leg, mid)
+    }
+
+    /// Reports the executions whose orders did not all reach a final state by their deadline
+    pub fn expire(&mut self, now: Timestamp) -> Vec<ExecutionReport> {
+        let mut expired = self.executions
+            .iter()
+            .filter(|(_, execution)| execution.deadline <= now)
+            .map(|(id, _)| *id)
+            .collect::<Vec<_>>();
+        expired.sort();
+        expired
+            .into_iter()
+            .map(|id| {
+                log::warn!("execution {} timed out waiting for its orders, reporting the fills known so far", id);
+                self.report(id)
+            })
+            .collect()
+    }
+
+    /// Deadline of the oldest execution in flight
+    pub fn next_deadline(&self) -> Option<Timestamp> {
+        self.executions.values().map(|execution| execution.deadline).min()
+    }
+
+    fn report(&mut self, id: u64) -> ExecutionReport {
+        let execution = self.executions.remove(&id).expect("execution should exist");
+        let leg_risk = execution.leg_risk.iter().map(LegRiskOrder::step).collect();
+        let report = ExecutionReport::new(id, &execution.arbitrage_opportunity, execution.executed_time, execution.buy.fill(), execution.sell.fill())
+            .with_leg_risk(leg_risk);
+        let legs = [execution.buy, execution.sell].into_iter().chain(execution.leg_risk.into_iter().map(|order| order.leg));
+        for leg in legs {
+            self.orders.remove(&leg.client_order_id);
+            if !leg.is_done() {
+                self.expired_orders.insert(leg.client_order_id.clone(), leg);
+            }
+        }
+        report
+    }
+}
+
+
+/// Executes the opportunities broadcast by the order book manager on the exchanges, see `LiveExecutor`
+///
+/// Orders are sent to the private session of each exchange, which reports their updates back. An order which
+/// can not be sent, e.g. as its exchange is disabled, is rejected. The broadcast books price the unwinds and the
+/// hedges. The execution reports are broadcast to the endpoint.
+#[derive(Clone)]
+pub struct ExecutionManager {
+    context: Context,
+    broadcaster: Sender<InternalMessage>,
+    book_broadcaster: Sender<OrderBookUpdate>,
+    order_senders: HashMap<Exchange, mpsc::Sender<OrderCommand>>,
+    updates: SharedReceiver<OrderUpdate>,
+    /// Kept across restarts of the worker, so that the orders in flight are still followed
+    executor: SharedRef<LiveExecutor>,
+    handle: ExecutionHandle,
+}
+
+impl ExecutionManager {
+    pub fn new(
+        context: Context,
+        config: &LiveConfig,
+        risk: &RiskConfig,
+        broadcaster: Sender<InternalMessage>,
+        book_broadcaster: Sender<OrderBookUpdate>,
+        order_senders: HashMap<Exchange, mpsc::Sender<OrderCommand>>,
+        updates: &mut MpSc<OrderUpdate>,
+    ) -> Self {
+        let handle = ExecutionHandle::new(Portfolio::default(), risk);
+        let session = Timestamp::now().as_second().to_string();
+        let executor = SharedRef::new(LiveExecutor::new(config.clone(), &session, handle.portfolio.clone(), handle.risk.clone()));
+        let updates = updates.shared_receiver().expect("order updates receiver should not be taken");
+        Self { context, broadcaster, book_broadcaster, order_senders, updates, executor, handle }
+    }
+
+    pub fn handle(&self) -> ExecutionHandle {
+        self.handle.clone()
+    }
+
+    fn publish(&self, report: ExecutionReport) {
+        metrics::EXECUTIONS.with_label_values(&["live", report.status.as_str()]).inc();
+        log::info!("live execution: {:?}", report);
+        // Nobody may be listening to the reports
+        let _ = self.handle.reports.send(report);
+    }
+
+    /// Applies the updates, and those of the leg risk orders which could not be sent
+    async fn on_update(&self, update: OrderUpdate) {
+        let mut updates = VecDeque::from([update]);
+        while let Some(update) = updates.pop_front() {
+            let progress = self.executor.lock().on_update(update, Timestamp::now());
+            match progress {
+                LiveProgress::Pending => {}
+                LiveProgress::Orders(commands) => updates.extend(self.send(commands).await),
+                LiveProgress::Done(report) => self.publish(*report),
+            }
+        }
+    }
+
+    /// Sends the orders to the private sessions, returns the rejection of those which could not be sent
+    async fn send(&self, commands: Vec<(Exchange, OrderCommand)>) -> Vec<OrderUpdate> {
+        let mut rejected = vec![];
+        for (exchange, command) in commands {
+            let client_order_id = command.client_order_id().to_string();
+            let sent = match self.order_senders.get(&exchange) {
+                Some(sender) => sender.send(command).await.map_err(|e| e.to_string()),
+                None => Err("no private session".to_string()),
+            };
+            if let Err(reason) = sent {
+                log::error!("failed to send order {} to {:?}: {}", client_order_id, exchange, reason);
+                rejected.push(OrderUpdate::rejected(exchange, &client_order_id, &reason));
+            }
+        }
+        rejected
+    }
+
+    async fn execute(&self, arbitrage_opportunity: ArbitrageOpportunity) {
+        let commands = self.executor.lock().on_opportunity(arbitrage_opportunity, Timestamp::now());
+        let Some(commands) = commands else {
+            log::debug!("ignoring opportunity as the orders of its pair are in flight or it breaches a risk limit");
+            return;
+        };
+        for update in self.send(commands).await {
+            self.on_update(update).await;
+        }
+    }
+}
+
+impl Worker for ExecutionManager {
+    fn name(&self) -> String {
+        self.context.name.clone()
+    }
+
+    fn spawn(&mut self) -> SpawnResult {
+        let manager = self.clone();
+
+        tokio::spawn(async move {
+            let mut updates = manager.updates.clone().lock_owned().await;
+            let mut receiver = manager.broadcaster.subscribe();
+            let mut books = manager.book_broadcaster.subscribe();
+            let mut app = manager.context.app.subscribe();
+            loop {
+                let next_deadline = manager.executor
+                    .lock()
+                    .next_deadline()
+                    .map(|deadline| Duration::try_from(deadline.duration_since(Timestamp::now())).unwrap_or_default());
+                // The update which triggered an opportunity is sent ahead of it, the books are polled first for the
+                // opportunity to be priced against them
+                tokio::select! {
+                    biased;
+
+                    _ = app.recv() => {
+                        return Err(ArbitrageError::Exit);
+                    }
+
+                    update = books.recv() => match update {
+                        Ok(order_book_update) => manager.executor.lock().on_order_book_update(order_book_update),
+                        Err(RecvError::Lagged(skipped)) => {
+                            metrics::BROADCAST_LAGGED.inc_by(skipped);
+                            log::warn!("execution manager lagging behind, skipped {} book updates, its books may be out of date", skipped);
+                        }
+                        Err(RecvError::Closed) => {
+                            return Err(ArbitrageError::GenericError("book broadcaster closed".to_string()));
+                        }
+                    },
+
+                    update = updates.recv() => match update {
+                        Some(update) => manager.on_update(update).await,
+                        None => return Err(ArbitrageError::GenericError("order updates closed".to_string())),
+                    },
+
+                    message = receiver.recv() => match message {
+                        Ok(InternalMessage::ArbitrageOpportunity(arbitrage_opportunity)) => manager.execute(arbitrage_opportunity).await,
+                        Ok(InternalMessage::OrderBookUpdate(_)) => {}
+                        Err(RecvError::Lagged(skipped)) => {
+                            metrics::BROADCAST_LAGGED.inc_by(skipped);
+                            log::warn!("execution manager lagging behind, skipped {} messages", skipped);
+                        }
+                        Err(RecvError::Closed) => {
+                            return Err(ArbitrageError::GenericError("broadcaster closed".to_string()));
+                        }
+                    },
+
+                    _ = tokio::time::sleep(next_deadline.unwrap_or_default()), if next_deadline.is_some() => {
+                        let reports = manager.executor.lock().expire(Timestamp::now());
+                        for report in reports {
+                            manager.publish(report);
+                        }
+                    }
+                }
+            }
+        })
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use models::PipelineTimestamps;
+    use rust_decimal_macros::dec;
+
+    use crate::{execution::ExecutionStatus, settings::LegRiskConfig};
+
+    use super::*;
+
+    fn opportunity() -> ArbitrageOpportunity {
+        let now = Timestamp::from_millisecond(0).unwrap();
+        ArbitrageOpportunity {
+            product: Product::from_okex_exhchange("BTC-USD-250221-90000-P").unwrap(),
+            buy_exchange: Exchange::Okex,
+            sell_exchange: Exchange::Deribit,
+            buy_price: dec!(0.010),
+            sell_price: dec!(0.012),
+            size: dec!(3),
+            edge: dec!(0.002),
+            buy_book_age_millis: 0,
+            sell_book_age_millis: 0,
+            trigger_exchange: Exchange::Deribit,
+            timestamps: PipelineTimestamps::new(now, now),
+        }
+    }
+
+    fn update(exchange: Exchange, client_order_id: &str, state: OrderState, filled_size: Decimal, fee: Option<Decimal>) -> OrderUpdate {
+        OrderUpdate {
+            exchange,
+            client_order_id: client_order_id.to_string(),
+            state,
+            filled_size,
+            average_price: (!filled_size.is_zero()).then_some(if client_order_id.ends_with('b') { dec!(0.010) } else { dec!(0.012) }),
+            fee,
+            reason: None,
+        }
+    }
+
+    #[test]
+    fn test_live_execution_follows_updates() {
+        let leg_risk = LegRiskConfig { action: LegRiskAction::None, ..LegRiskConfig::default() };
+        let config = LiveConfig { enabled: true, max_order_size: dec!(2), limit_bps: dec!(100), leg_risk, ..LiveConfig::default() };
+        let handle = ExecutionHandle::new(Portfolio::default(), &RiskConfig::default());
+        let portfolio = handle.portfolio.clone();
+        let mut executor = LiveExecutor::new(config, "1", portfolio.clone(), handle.risk.clone());
+        let now = Timestamp::from_millisecond(1000).unwrap();
+
+        let commands = executor.on_opportunity(opportunity(), now).unwrap();
+        assert!(executor.on_opportunity(opportunity(), now).is_none());
+        let OrderCommand::Place(buy) = &commands[0].1 else { panic!("expected an order") };
+        assert_eq!((commands[0].0.clone(), buy.client_order_id.as_str(), buy.price, buy.size), (Exchange::Okex, "arb1n1b", dec!(0.0101), dec!(2)));
+        let OrderCommand::Place(sell) = &commands[1].1 else { panic!("expected an order") };
+        assert_eq!((sell.side, sell.price, sell.time_in_force), (OrderSide::Sell, dec!(0.01188), TimeInForce::ImmediateOrCancel));
+
+        assert!(matches!(executor.on_update(update(Exchange::Okex, "arb1n1b", OrderState::Open, dec!(1), Some(dec!(0.0001))), now), LiveProgress::Pending));
+        assert!(matches!(executor.on_update(update(Exchange::Okex, "arb1n1b", OrderState::Filled, dec!(2), Some(dec!(0.0002))), now), LiveProgress::Pending));
+        // Deribit notifies the fill before answering the order with its fee
+        assert!(matches!(executor.on_update(update(Exchange::Deribit, "arb1n1s", OrderState::Cancelled, dec!(1), None), now), LiveProgress::Pending));
+        assert_eq!(portfolio.lock().balance(&Exchange::Deribit), dec!(0.012));
+        let update = update(Exchange::Deribit, "arb1n1s", OrderState::Cancelled, dec!(1), Some(dec!(0.0003)));
+        let LiveProgress::Done(report) = executor.on_update(update, now) else { panic!("expected a report") };
+        assert_eq!(report.status, ExecutionStatus::Legged);
+        assert_eq!((report.buy.filled_size, report.buy.average_price, report.buy.fee), (dec!(2), Some(dec!(0.010)), dec!(0.0002)));
+        assert_eq!(report.naked_size, dec!(1));
+
+        let portfolio = portfolio.lock();
+        assert_eq!(portfolio.balance(&Exchange::Okex), dec!(-0.0202));
+        assert_eq!(portfolio.balance(&Exchange::Deribit), dec!(0.0117));
+        drop(portfolio);
+
+        // An order which never reaches a final state is reported at the deadline
+        executor.on_opportunity(opportunity(), now).unwrap();
+        assert!(matches!(executor.on_update(OrderUpdate::rejected(Exchange::Okex, "arb1n2b", "not logged in"), now), LiveProgress::Pending));
+        assert_eq!(executor.next_deadline(), Some(Timestamp::from_millisecond(6000).unwrap()));
+        assert!(executor.expire(Timestamp::from_millisecond(5999).unwrap()).is_empty());
+        let reports = executor.expire(Timestamp::from_millisecond(6000).unwrap());
+        assert_eq!(reports[0].status, ExecutionStatus::Missed);
+        assert!(executor.next_deadline().is_none());
+    }
+
+    fn order_book_update(exchange: Exchange, product: &str, bids: Vec<(Decimal, Decimal)>, asks: Vec<(Decimal, Decimal)>) -> OrderBookUpdate {
+        let now = Timestamp::from_millisecond(0).unwrap();
+        let product = Product::from_okex_exhchange(product).unwrap();
+        OrderBookUpdate { exchange_product: ExchangeProduct { exchange, product }, bids, asks, timestamps: PipelineTimestamps::new(now, now) }
+    }
+
+    fn placed(progress: LiveProgress) -> OrderRequest {
+        match progress {
+            LiveProgress::Orders(mut commands) => match commands.remove(0).1 {
+                OrderCommand::Place(request) => request,
+                command => panic!("expected an order, got {:?}", command),
+            },
+            progress => panic!("expected an order, got {:?}", progress),
+        }
+    }
+
+    #[test]
+    fn test_late_fills_applied_after_expiry() {
+        let leg_risk = LegRiskConfig { action: LegRiskAction::None, ..LegRiskConfig::default() };
+        let config = LiveConfig { enabled: true, max_order_size: dec!(2), limit_bps: dec!(100), leg_risk, ..LiveConfig::default() };
+        let handle = ExecutionHandle::new(Portfolio::default(), &RiskConfig::default());
+        let portfolio = handle.portfolio.clone();
+        let mut executor = LiveExecutor::new(config, "1", portfolio.clone(), handle.risk.clone());
+        let now = Timestamp::from_millisecond(1000).unwrap();
+
+        executor.on_opportunity(opportunity(), now).unwrap();
+        executor.on_update(update(Exchange::Okex, "arb1n1b", OrderState::Open, dec!(1), Some(dec!(0.0001))), now);
+        let deadline = executor.next_deadline().unwrap();
+        let reports = executor.expire(deadline);
+        assert_eq!(reports.len(), 1);
+        assert_eq!(reports[0].buy.filled_size, dec!(1));
+        assert_eq!(executor.next_deadline(), None);
+
+        // Both orders are still live on their exchange, what they fill later is still held
+        let later = deadline + SignedDuration::from_secs(1);
+        assert!(matches!(executor.on_update(update(Exchange::Okex, "arb1n1b", OrderState::Filled, dec!(2), Some(dec!(0.0002))), later), LiveProgress::Pending));
+        let okex = ExchangeProduct { exchange: Exchange::Okex, product: opportunity().product };
+        assert_eq!(portfolio.lock().position(&okex), dec!(2));
+        assert_eq!(portfolio.lock().balance(&Exchange::Okex), dec!(-0.0202));
+        assert_eq!(executor.expired_orders.len(), 1);
+
+        executor.on_update(update(Exchange::Deribit, "arb1n1s", OrderState::Cancelled, dec!(0), None), later);
+        assert!(executor.expired_orders.is_empty());
+        // Updates of orders which are done are ignored
+        executor.on_update(update(Exchange::Okex, "arb1n1b", OrderState::Filled, dec!(3), Some(dec!(0.0003))), later);
+        assert_eq!(portfolio.lock().position(&okex), dec!(2));
+    }
+
+    #[test]
+    fn test_leg_risk_chase_then_unwind() {
+        let leg_risk = LegRiskConfig { action: LegRiskAction::Chase, chase_bps: dec!(300), max_attempts: 2, ..LegRiskConfig::default() };
+        let config = LiveConfig { enabled: true, max_order_size: dec!(2), limit_bps: dec!(100), leg_risk, ..LiveConfig::default() };
+        let handle = ExecutionHandle::new(Portfolio::default(), &RiskConfig::default());
+        let portfolio = handle.portfolio.clone();
+        let mut executor = LiveExecutor::new(config, "1", portfolio.clone(), handle.risk.clone());
+        let now = Timestamp::from_millisecond(1000).unwrap();
+        executor.on_order_book_update(order_book_update(Exchange::Okex, "BTC-USD-250221-90000-P", vec![(dec!(0.0095), dec!(5))], vec![]));
+
+        // The sell leg misses, it is chased twice on deribit then the rest is unwound on okex
+        executor.on_opportunity(opportunity(), now).unwrap();
+        assert!(matches!(executor.on_update(update(Exchange::Okex, "arb1n1b", OrderState::Filled, dec!(2), Some(dec!(0.0002))), now), LiveProgress::Pending));
+        let chase = placed(executor.on_update(update(Exchange::Deribit, "arb1n1s", OrderState::Cancelled, dec!(0), None), now));
+        assert_eq!((chase.client_order_id.as_str(), chase.side, chase.price, chase.size), ("arb1n1r1", OrderSide::Sell, dec!(0.01176), dec!(2)));
+        let update_chase = OrderUpdate { average_price: Some(dec!(0.0118)), ..update(Exchange::Deribit, "arb1n1r1", OrderState::Cancelled, dec!(1), Some(dec!(0.0001))) };
+        let chase = placed(executor.on_update(update_chase, now));
+        assert_eq!((chase.client_order_id.as_str(), chase.price, chase.size), ("arb1n1r2", dec!(0.01164), dec!(1)));
+        let unwind = placed(executor.on_update(update(Exchange::Deribit, "arb1n1r2", OrderState::Cancelled, dec!(0), None), now));
+        assert_eq!((unwind.client_order_id.as_str(), unwind.side, unwind.price, unwind.size), ("arb1n1r3", OrderSide::Sell, dec!(0.0095), dec!(1)));
+        assert_eq!(executor.next_deadline(), Some(Timestamp::from_millisecond(6000).unwrap()));
+        let update_unwind = OrderUpdate { average_price: Some(dec!(0.0095)), ..update(Exchange::Okex, "arb1n1r3", OrderState::Filled, dec!(1), Some(dec!(0.00005))) };
+        let LiveProgress::Done(report) = executor.on_update(update_unwind, now) else { panic!("expected a report") };
+        assert_eq!((report.status, report.naked_size, report.residual_naked_size), (ExecutionStatus::Legged, dec!(2), dec!(0)));
+        let actions = report.leg_risk.iter().map(|step| step.action).collect::<Vec<_>>();
+        assert_eq!(actions, vec![LegRiskAction::Chase, LegRiskAction::Chase, LegRiskAction::Unwind]);
+        // Sold 0.0002 below the detected price and 0.0005 below the price of the naked leg, plus the fees
+        assert_eq!((report.leg_risk[0].cost, report.leg_risk[1].cost, report.leg_risk[2].cost), (dec!(0.0003), dec!(0), dec!(0.00055)));
+        assert_eq!(report.leg_risk_cost, dec!(0.00085));
+        assert_eq!(portfolio.lock().balance(&Exchange::Okex), dec!(-0.01075));
+    }
+
+    #[test]
+    fn test_leg_risk_unwind_without_book() {
+        let leg_risk = LegRiskConfig { action: LegRiskAction::Unwind, ..LegRiskConfig::default() };
+        let config = LiveConfig { enabled: true, max_order_size: dec!(2), limit_bps: dec!(100), leg_risk, ..LiveConfig::default() };
+        let handle = ExecutionHandle::new(Portfolio::default(), &RiskConfig::default());
+        let portfolio = handle.portfolio.clone();
+        let mut executor = LiveExecutor::new(config, "1", portfolio.clone(), handle.risk.clone());
+        let now = Timestamp::from_millisecond(1000).unwrap();
+
+        // Nothing is known of the okex book to close the bought put at
+        executor.on_opportunity(opportunity(), now).unwrap();
+        executor.on_update(update(Exchange::Okex, "arb1n1b", OrderState::Filled, dec!(2), Some(dec!(0.0002))), now);
+        let LiveProgress::Done(report) = executor.on_update(update(Exchange::Deribit, "arb1n1s", OrderState::Cancelled, dec!(0), None), now) else {
+            panic!("expected a report")
+        };
+        // Every attempt is given up, leaving the whole size naked
+        assert_eq!(report.leg_risk.len(), 3);
+        assert!(report.leg_risk.iter().all(|step| step.action == LegRiskAction::Unwind));
+        assert_eq!(report.leg_risk[0].reason.as_deref(), Some("no price for BTC-USD-250221-90000-P on Okex"));
+        assert_eq!((report.naked_size, report.residual_naked_size), (dec!(2), dec!(2)));
+        assert_eq!(report.leg_risk_cost, dec!(0));
+        let okex = ExchangeProduct { exchange: Exchange::Okex, product: opportunity().product };
+        assert_eq!(portfolio.lock().position(&okex), dec!(2));
+    }
+
+    #[test]
+    fn test_leg_risk_hedge_without_perpetual_book() {
+        let leg_risk = LegRiskConfig { action: LegRiskAction::Hedge, ..LegRiskConfig::default() };
+        let config = LiveConfig { enabled: true, max_order_size: dec!(2), limit_bps: dec!(100), leg_risk, ..LiveConfig::default() };
+        let handle = ExecutionHandle::new(Portfolio::default(), &RiskConfig::default());
+        let mut executor = LiveExecutor::new(config, "1", handle.portfolio.clone(), handle.risk.clone());
+        let now = Timestamp::from_second(1_735_689_600).unwrap(); // 2025-01-01T00:00:00Z
+
+        executor.on_opportunity(opportunity(), now).unwrap();
+        assert!(matches!(executor.on_update(update(Exchange::Okex, "arb1n1b", OrderState::Filled, dec!(1), Some(dec!(0.0001))), now), LiveProgress::Pending));
+        let LiveProgress::Done(report) = executor.on_update(update(Exchange::Deribit, "arb1n1s", OrderState::Cancelled, dec!(0), None), now) else {
+            panic!("expected a report")
+        };
+        assert_eq!(report.leg_risk[0].action, LegRiskAction::Hedge);
+        assert_eq!(report.leg_risk[0].reason.as_deref(), Some("no price for BTC-USD-SWAP on Deribit"));
+        assert_eq!(report.residual_naked_size, dec!(1));
+    }
+
+    #[test]
+    fn test_leg_risk_hedge_sizing() {
+        let leg_risk = LegRiskConfig { action: LegRiskAction::Hedge, ..LegRiskConfig::default() };
+        let config = LiveConfig { enabled: true, max_order_size: dec!(2), limit_bps: dec!(100), leg_risk, ..LiveConfig::default() };
+        let handle = ExecutionHandle::new(Portfolio::default(), &RiskConfig::default());
+        let portfolio = handle.portfolio.clone();
+        let mut executor = LiveExecutor::new(config, "1", portfolio.clone(), handle.risk.clone());
+        let now = Timestamp::from_second(1_735_689_600).unwrap(); // 2025-01-01T00:00:00Z
+        executor.on_order_book_update(order_book_update(Exchange::Deribit, "BTC-USD-SWAP", vec![(dec!(99990), dec!(1000))], vec![(dec!(100010), dec!(1000))]));
+
+        // The delta of a naked put is hedged by buying the perpetual
+        executor.on_opportunity(opportunity(), now).unwrap();
+        executor.on_update(update(Exchange::Okex, "arb1n1b", OrderState::Filled, dec!(1), Some(dec!(0.0001))), now);
+        let hedge = placed(executor.on_update(update(Exchange::Deribit, "arb1n1s", OrderState::Cancelled, dec!(0), None), now));
+        // The put is worth 1000 USD for a volatility of 30%, its delta is -0.16
+        assert_eq!((hedge.client_order_id.as_str(), hedge.side, hedge.price, hedge.size), ("arb1n1r1", OrderSide::Buy, dec!(100110.01), dec!(16030)));
+        let update_hedge = OrderUpdate { average_price: Some(dec!(100010)), ..update(Exchange::Deribit, "arb1n1r1", OrderState::Filled, dec!(16030), Some(dec!(0.00001))) };
+        let LiveProgress::Done(report) = executor.on_update(update_hedge, now) else { panic!("expected a report") };
+        assert_eq!(report.residual_naked_size, dec!(1));
+        assert_eq!(report.leg_risk[0].reference_price, dec!(100000));
+        assert_eq!(report.leg_risk[0].cost, dec!(16030) / dec!(100000) - dec!(16030) / dec!(100010) + dec!(0.00001));
+        let perpetual = ExchangeProduct { exchange: Exchange::Deribit, product: Product::from_okex_exhchange("BTC-USD-SWAP").unwrap() };
+        assert_eq!(portfolio.lock().position(&perpetual), dec!(16030));
+    }
+
+    #[test]
+    fn test_leg_risk_hedge_on_okex() {
+        let leg_risk = LegRiskConfig { action: LegRiskAction::Hedge, hedge_exchange: Exchange::Okex, hedge_lot_size: dec!(1), ..LegRiskConfig::default() };
+        let config = LiveConfig { enabled: true, max_order_size: dec!(2), limit_bps: dec!(100), leg_risk, ..LiveConfig::default() };
+        let handle = ExecutionHandle::new(Portfolio::default(), &RiskConfig::default());
+        let mut executor = LiveExecutor::new(config, "1", handle.portfolio.clone(), handle.risk.clone());
+        let now = Timestamp::from_second(1_735_689_600).unwrap(); // 2025-01-01T00:00:00Z
+        executor.on_order_book_update(order_book_update(Exchange::Okex, "BTC-USD-SWAP", vec![(dec!(99990), dec!(1000))], vec![(dec!(100010), dec!(1000))]));
+
+        // 100 contracts of 0.01 BTC filled on Okex are a naked put of 1, whose delta of 16030 USD is hedged by buying
+        // 160 contracts of 100 USD of the Okex perpetual
+        executor.on_opportunity(opportunity(), now).unwrap();
+        let filled_size = opportunity().product.from_contracts(&Exchange::Okex, dec!(100));
+        executor.on_update(update(Exchange::Okex, "arb1n1b", OrderState::Filled, filled_size, Some(dec!(0.0001))), now);
+        let hedge = placed(executor.on_update(update(Exchange::Deribit, "arb1n1s", OrderState::Cancelled, dec!(0), None), now));
+        assert_eq!((hedge.product.to_string().as_str(), hedge.side, hedge.size), ("BTC-USD-SWAP", OrderSide::Buy, dec!(160)));
+    }
+}
@@ -0,0 +1,227 @@
+use common::SharedRef;
+use jiff::Timestamp;
+use models::{ArbitrageOpportunity, Exchange, Product};
+use rust_decimal::Decimal;
+use serde::Serialize;
+use tokio::sync::broadcast::{self, Sender};
+
+use crate::settings::{LegRiskAction, RiskConfig};
+
+mod fill;
+mod live;
+mod order;
+mod paper;
+mod portfolio;
+mod risk;
+
+pub use fill::*;
+pub use live::*;
+pub use order::*;
+pub use paper::*;
+pub use portfolio::*;
+pub use risk::*;
+
+/// Execution reports retained for the clients of `/executions/v1`
+const REPORTS_BUFFER_SIZE: usize = 1000;
+
+
+/// Product and exchanges of the legs of an opportunity
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct VenuePair {
+    pub product: Product,
+    pub buy_exchange: Exchange,
+    pub sell_exchange: Exchange,
+}
+
+impl From<&ArbitrageOpportunity> for VenuePair {
+    fn from(arbitrage_opportunity: &ArbitrageOpportunity) -> Self {
+        Self {
+            product: arbitrage_opportunity.product.clone(),
+            buy_exchange: arbitrage_opportunity.buy_exchange.clone(),
+            sell_exchange: arbitrage_opportunity.sell_exchange.clone(),
+        }
+    }
+}
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+pub enum OrderSide {
+    Buy,
+    Sell,
+}
+
+impl OrderSide {
+    pub fn opposite(&self) -> Self {
+        match self {
+            OrderSide::Buy => OrderSide::Sell,
+            OrderSide::Sell => OrderSide::Buy,
+        }
+    }
+}
+
+/// Outcome of the order sent on one leg of an opportunity
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct LegFill {
+    pub exchange: Exchange,
+    pub side: OrderSide,
+    /// Worst price the order may be filled at
+    pub limit_price: Decimal,
+    pub size: Decimal,
+    pub filled_size: Decimal,
+    /// Average price of the fills, None when nothing was filled
+    pub average_price: Option<Decimal>,
+    pub fee: Decimal,
+}
+
+/// Order sent after both legs of an execution were done, to handle its naked size, see `LegRiskAction`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct LegRiskStep {
+    pub action: LegRiskAction,
+    /// The product of the opportunity, or the perpetual of a hedge
+    pub product: Product,
+    pub order: LegFill,
+    /// Price the fills are compared to, the detected price of the leg which missed for a chase, the average price
+    /// of the leg which filled for an unwind and the mid price of the perpetual for a hedge
+    pub reference_price: Decimal,
+    /// Why the order was rejected or not sent
+    pub reason: Option<String>,
+    /// Loss of the fills against the reference price, fee included, in the currency the options are quoted in
+    pub cost: Decimal,
+}
+
+impl LegRiskStep {
+    pub fn new(action: LegRiskAction, product: &Product, order: LegFill, reference_price: Decimal, reason: Option<String>) -> Self {
+        let slippage = match (order.average_price, product) {
+            (None, _) => Decimal::ZERO,
+            // The perpetual is sized in USD, its fills are worth `value / price` of the underlying
+            (Some(price), Product::Perpetual { .. }) => {
+                let value = order.filled_size * product.contract_value(&order.exchange);
+                value / reference_price - value / price
+            }
+            (Some(price), Product::Option { .. }) => (price - reference_price) * order.filled_size,
+        };
+        let cost = match order.side {
+            OrderSide::Buy => slippage + order.fee,
+            OrderSide::Sell => order.fee - slippage,
+        };
+        Self { action, product: product.clone(), order, reference_price, reason, cost }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum ExecutionStatus {
+    /// Both legs filled their whole size
+    Filled,
+    /// Both legs filled the same quantity, less than their size
+    PartiallyFilled,
+    /// One leg filled more than the other, the difference is an open position
+    Legged,
+    /// Neither leg filled
+    Missed,
+}
+
+impl ExecutionStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ExecutionStatus::Filled => "filled",
+            ExecutionStatus::PartiallyFilled => "partially_filled",
+            ExecutionStatus::Legged => "legged",
+            ExecutionStatus::Missed => "missed",
+        }
+    }
+}
+
+/// Execution of both legs of an opportunity, as streamed on `/executions/v1`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ExecutionReport {
+    pub id: u64,
+    pub product: Product,
+    /// Receive time of the update which triggered the opportunity
+    pub detected_time: Timestamp,
+    /// Time the orders reached the exchanges
+    pub executed_time: Timestamp,
+    pub detected_edge: Decimal,
+    pub buy: LegFill,
+    pub sell: LegFill,
+    pub status: ExecutionStatus,
+    /// Quantity bought less the quantity sold, left open on the exchange of the larger leg
+    pub naked_size: Decimal,
+    /// Profit locked in by the quantity both bought and sold, net of fees
+    pub hedged_pnl: Decimal,
+    /// Orders sent to handle the naked size, in the order they were sent
+    pub leg_risk: Vec<LegRiskStep>,
+    /// Naked size left once the leg risk was handled, a hedge leaves it open
+    pub residual_naked_size: Decimal,
+    /// Sum of the costs of the leg risk steps
+    pub leg_risk_cost: Decimal,
+}
+
+impl ExecutionReport {
+    pub fn new(id: u64, opportunity: &ArbitrageOpportunity, executed_time: Timestamp, buy: LegFill, sell: LegFill) -> Self {
+        let status = if buy.filled_size != sell.filled_size {
+            ExecutionStatus::Legged
+        } else if buy.filled_size.is_zero() {
+            ExecutionStatus::Missed
+        } else if buy.filled_size < buy.size || sell.filled_size < sell.size {
+            ExecutionStatus::PartiallyFilled
+        } else {
+            ExecutionStatus::Filled
+        };
+        let naked_size = buy.filled_size - sell.filled_size;
+        let hedged_size = buy.filled_size.min(sell.filled_size);
+        let hedged_pnl = match (buy.average_price, sell.average_price) {
+            (Some(buy_price), Some(sell_price)) => {
+                let buy_fee = buy.fee / buy.filled_size;
+                let sell_fee = sell.fee / sell.filled_size;
+                hedged_size * (sell_price - sell_fee - buy_price - buy_fee)
+            }
+            _ => Decimal::ZERO,
+        };
+        Self {
+            id,
+            product: opportunity.product.clone(),
+            detected_time: opportunity.timestamps.received_time,
+            executed_time,
+            detected_edge: opportunity.edge,
+            naked_size,
+            buy,
+            sell,
+            status,
+            hedged_pnl,
+            leg_risk: vec![],
+            residual_naked_size: naked_size,
+            leg_risk_cost: Decimal::ZERO,
+        }
+    }
+
+    /// Adds the steps which handled the naked size, what their orders on the product filled reduces it
+    pub fn with_leg_risk(mut self, leg_risk: Vec<LegRiskStep>) -> Self {
+        for step in leg_risk.iter().filter(|step| step.product == self.product) {
+            match step.order.side {
+                OrderSide::Buy => self.residual_naked_size += step.order.filled_size,
+                OrderSide::Sell => self.residual_naked_size -= step.order.filled_size,
+            }
+        }
+        self.leg_risk_cost = leg_risk.iter().map(|step| step.cost).sum();
+        self.leg_risk = leg_risk;
+        self
+    }
+}
+
+
+/// Shared with the endpoint, which streams the execution reports and serves the portfolio and its risk
+#[derive(Clone)]
+pub struct ExecutionHandle {
+    pub reports: Sender<ExecutionReport>,
+    pub portfolio: SharedRef<Portfolio>,
+    pub risk: SharedRef<RiskManager>,
+}
+
+impl ExecutionHandle {
+    pub fn new(portfolio: Portfolio, risk: &RiskConfig) -> Self {
+        let (reports, _) = broadcast::channel(REPORTS_BUFFER_SIZE);
+        let portfolio = SharedRef::new(portfolio);
+        let risk = SharedRef::new(RiskManager::new(risk.clone(), portfolio.clone()));
+        Self { reports, portfolio, risk }
+    }
+}
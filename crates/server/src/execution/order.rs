@@ -0,0 +1,97 @@
+use models::{Exchange, Product};
+use rust_decimal::Decimal;
+use serde::Serialize;
+
+use super::OrderSide;
+
+/// Size of the buffer for the order commands sent to a private session
+pub const ORDER_COMMANDS_BUFFER_SIZE: usize = 100;
+
+/// Size of the buffer for the order updates sent by the private sessions
+pub const ORDER_UPDATES_BUFFER_SIZE: usize = 1000;
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum TimeInForce {
+    /// Fills what it can on arrival, the rest is cancelled
+    ImmediateOrCancel,
+    /// Rests in the book until filled or cancelled
+    GoodTilCancelled,
+}
+
+/// Limit order sent to an exchange, identified by the client order id on every later command and update
+///
+/// The client order id is alphanumeric so that it is a valid Okex `clOrdId` as well as a Deribit `label`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OrderRequest {
+    pub client_order_id: String,
+    pub product: Product,
+    pub side: OrderSide,
+    pub price: Decimal,
+    pub size: Decimal,
+    pub time_in_force: TimeInForce,
+}
+
+/// Sent to the private session of an exchange, see `OkexPrivateAdapter` and `DeribitPrivateAdapter`
+#[derive(Debug, Clone, PartialEq)]
+pub enum OrderCommand {
+    Place(OrderRequest),
+    /// Changes the price and the size of an open order, the size includes what already filled
+    Amend { client_order_id: String, product: Product, price: Decimal, size: Decimal },
+    Cancel { client_order_id: String, product: Product },
+}
+
+impl OrderCommand {
+    pub fn client_order_id(&self) -> &str {
+        match self {
+            OrderCommand::Place(request) => &request.client_order_id,
+            OrderCommand::Amend { client_order_id, .. } | OrderCommand::Cancel { client_order_id, .. } => client_order_id,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum OrderState {
+    /// Accepted and possibly partially filled
+    Open,
+    Filled,
+    /// Cancelled with what it filled, which is how an immediate or cancel order ends unless it fills
+    Cancelled,
+    /// Refused by the exchange, or not sent as the session is down
+    Rejected,
+}
+
+impl OrderState {
+    pub fn is_final(&self) -> bool {
+        !matches!(self, OrderState::Open)
+    }
+}
+
+/// State of an order as reported by an exchange, the filled size and the fee are cumulative
+#[derive(Debug, Clone, PartialEq)]
+pub struct OrderUpdate {
+    pub exchange: Exchange,
+    pub client_order_id: String,
+    pub state: OrderState,
+    pub filled_size: Decimal,
+    /// Average price of the fills, None when nothing was filled
+    pub average_price: Option<Decimal>,
+    /// Fee paid so far, None when the update does not carry it
+    pub fee: Option<Decimal>,
+    /// Why the order was rejected
+    pub reason: Option<String>,
+}
+
+impl OrderUpdate {
+    pub fn rejected(exchange: Exchange, client_order_id: &str, reason: &str) -> Self {
+        Self {
+            exchange,
+            client_order_id: client_order_id.to_string(),
+            state: OrderState::Rejected,
+            filled_size: Decimal::ZERO,
+            average_price: None,
+            fee: None,
+            reason: Some(reason.to_string()),
+        }
+    }
+}
@@ -0,0 +1,359 @@
+use std::{cmp::min, collections::{HashMap, VecDeque}, time::Duration};
+
+use common::{ArbitrageError, Context, SharedRef, SpawnResult, Worker};
+use jiff::{SignedDuration, Timestamp};
+use models::{ArbitrageOpportunity, ExchangeProduct, InternalMessage, OrderBook, OrderBookUpdate};
+use rust_decimal::Decimal;
+use tokio::sync::broadcast::{error::RecvError, Sender};
+
+use crate::{funding::Funding, metrics, settings::{FeesConfig, ManagerConfig, PaperConfig, RiskConfig}};
+
+use super::{ExecutionHandle, ExecutionReport, Fill, LegFill, OpenOrders, OrderSide, Portfolio, RiskManager, Side, TakenLiquidity, VenuePair, BPS};
+
+
+struct PendingExecution {
+    arbitrage_opportunity: ArbitrageOpportunity,
+    executed_time: Timestamp,
+}
+
+/// Simulates the execution of the opportunities against local copies of the books
+///
+/// Both legs of an opportunity are sent as immediate or cancel orders for up to `max_order_size`, limited to
+/// `limit_bps` beyond the detected prices, and reach the exchanges `latency_millis` after the opportunity was
+/// received. They are filled against the books as they are then, less the liquidity taken by earlier orders.
+/// A leg which filled more than the other is left as an open position. While the orders of a pair are in flight,
+/// its new opportunities are ignored, and those which would breach a risk limit are suppressed, see `RiskManager`.
+pub struct PaperExecutor {
+    config: PaperConfig,
+    order_books: HashMap<ExchangeProduct, OrderBook>,
+    taken: TakenLiquidity,
+    /// Ordered by execution time, the latency being the same for every execution
+    pending: VecDeque<PendingExecution>,
+    next_id: u64,
+    portfolio: SharedRef<Portfolio>,
+    risk: SharedRef<RiskManager>,
+    /// Learns the virtual balances after every execution when set
+    funding: Option<Funding>,
+}
+
+impl PaperExecutor {
+    pub fn new(config: PaperConfig, portfolio: SharedRef<Portfolio>, risk: SharedRef<RiskManager>) -> Self {
+        Self {
+            config,
+            order_books: HashMap::new(),
+            taken: TakenLiquidity::default(),
+            pending: VecDeque::new(),
+            next_id: 0,
+            portfolio,
+            risk,
+            funding: None,
+        }
+    }
+
+    /// Sizes the next opportunities by the virtual balances, less the margin of the short options
+    pub fn with_funding(mut self, funding: Funding) -> Self {
+        funding.on_portfolio(&self.portfolio.lock());
+        self.funding = Some(funding);
+        self
+    }
+
+    pub fn on_update(&mut self, order_book_update: OrderBookUpdate) {
+        self.taken.release(&order_book_update);
+        match self.order_books.get_mut(&order_book_update.exchange_product) {
+            Some(order_book) => {
+                order_book.update(order_book_update);
+                self.risk.lock().on_order_book(order_book);
+            }
+            None => {
+                let mut order_book = OrderBook::new(&order_book_update.exchange_product);
+                let exchange_product = order_book_update.exchange_product.clone();
+                order_book.update(order_book_update);
+                self.risk.lock().on_order_book(&order_book);
+                self.order_books.insert(exchange_product, order_book);
+            }
+        }
+    }
+
+    /// Sends the orders of the opportunity, returns false if it was ignored as the orders of its pair are in flight
+    /// or suppressed by a risk limit
+    pub fn on_opportunity(&mut self, arbitrage_opportunity: ArbitrageOpportunity, received_time: Timestamp) -> bool {
+        let pair = VenuePair::from(&arbitrage_opportunity);
+        if self.pending.iter().any(|pending| VenuePair::from(&pending.arbitrage_opportunity) == pair) {
+            return false;
+        }
+        let size = min(arbitrage_opportunity.size, self.config.max_order_size);
+        if !self.risk.lock().approve(&arbitrage_opportunity, size, &self.open_orders(), received_time) {
+            return false;
+        }
+        let executed_time = received_time + SignedDuration::from_millis(self.config.latency_millis as i64);
+        self.pending.push_back(PendingExecution { arbitrage_opportunity, executed_time });
+        true
+    }
+
+    /// Both legs of each execution in flight
+    fn open_orders(&self) -> OpenOrders {
+        let mut open = OpenOrders { executions: self.pending.len(), ..OpenOrders::default() };
+        for pending in self.pending.iter() {
+            let opportunity = &pending.arbitrage_opportunity;
+            let size = min(opportunity.size, self.config.max_order_size);
+            open.add(ExchangeProduct { exchange: opportunity.buy_exchange.clone(), product: opportunity.product.clone() }, OrderSide::Buy, size);
+            open.add(ExchangeProduct { exchange: opportunity.sell_exchange.clone(), product: opportunity.product.clone() }, OrderSide::Sell, size);
+        }
+        open
+    }
+
+    /// Time the next orders in flight reach the exchanges
+    pub fn next_execution_time(&self) -> Option<Timestamp> {
+        self.pending.front().map(|pending| pending.executed_time)
+    }
+
+    /// Executes the orders which reached the exchanges by `now`
+    pub fn execute_until(&mut self, now: Timestamp, fees: &FeesConfig) -> Vec<ExecutionReport> {
+        let mut reports = vec![];
+        while self.pending.front().is_some_and(|pending| pending.executed_time <= now) {
+            let pending = self.pending.pop_front().expect("pending execution should exist");
+            reports.push(self.execute(pending, fees));
+        }
+        reports
+    }
+
+    fn execute(&mut self, pending: PendingExecution, fees: &FeesConfig) -> ExecutionReport {
+        let opportunity = pending.arbitrage_opportunity;
+        let buy_book = ExchangeProduct { exchange: opportunity.buy_exchange.clone(), product: opportunity.product.clone() };
+        let sell_book = ExchangeProduct { exchange: opportunity.sell_exchange.clone(), product: opportunity.product.clone() };
+        let limit = self.config.limit_bps / BPS;
+        let order_size = min(opportunity.size, self.config.max_order_size);
+
+        let buy = self.send(&buy_book, OrderSide::Buy, opportunity.buy_price * (Decimal::ONE + limit), order_size, fees);
+        let sell = self.send(&sell_book, OrderSide::Sell, opportunity.sell_price * (Decimal::ONE - limit), order_size, fees);
+
+        self.next_id += 1;
+        let report = ExecutionReport::new(self.next_id, &opportunity, pending.executed_time, buy, sell);
+        let mut portfolio = self.portfolio.lock();
+        portfolio.apply(&report.product, &report.buy);
+        portfolio.apply(&report.product, &report.sell);
+        if let Some(funding) = self.funding.as_ref() {
+            funding.on_portfolio(&portfolio);
+        }
+        report
+    }
+
+    /// Fills an immediate or cancel order against the book
+    fn send(&mut self, exchange_product: &ExchangeProduct, side: OrderSide, limit_price: Decimal, size: Decimal, fees: &FeesConfig) -> LegFill {
+        let book_side = match side {
+            OrderSide::Buy => Side::Ask,
+            OrderSide::Sell => Side::Bid,
+        };
+        let levels = self.taken.levels(self.order_books.get(exchange_product), book_side);
+        let fill = Fill::take(&levels, book_side, Some(limit_price), size);
+        self.taken.consume(exchange_product, book_side, &fill);
+        LegFill {
+            exchange: exchange_product.exchange.clone(),
+            side,
+            limit_price,
+            size,
+            filled_size: fill.size,
+            average_price: fill.price(),
+            fee: fill.notional * fees.rate(&exchange_product.exchange),
+        }
+    }
+}
+
+
+/// Paper trades the opportunities broadcast by the order book manager, see `PaperExecutor`
+///
+/// The books are rebuilt from the updates broadcast by the order book manager on their own channel, the execution
+/// reports are broadcast to the endpoint.
+#[derive(Clone)]
+pub struct PaperTrader {
+    context: Context,
+    config: PaperConfig,
+    /// Shared with the config watcher, the fees are read on every execution
+    manager_config: SharedRef<ManagerConfig>,
+    broadcaster: Sender<InternalMessage>,
+    book_broadcaster: Sender<OrderBookUpdate>,
+    handle: ExecutionHandle,
+    funding: Option<Funding>,
+}
+
+impl PaperTrader {
+    pub fn new(
+        context: Context,
+        config: &PaperConfig,
+        risk: &RiskConfig,
+        manager_config: SharedRef<ManagerConfig>,
+        broadcaster: Sender<InternalMessage>,
+        book_broadcaster: Sender<OrderBookUpdate>,
+    ) -> Self {
+        let handle = ExecutionHandle::new(Portfolio::new(&config.balances), risk);
+        Self { context, config: config.clone(), manager_config, broadcaster, book_broadcaster, handle, funding: None }
+    }
+
+    /// Sizes the opportunities by the virtual balances, see `PaperExecutor::with_funding`
+    pub fn with_funding(mut self, funding: Funding) -> Self {
+        self.funding = Some(funding);
+        self
+    }
+
+    pub fn handle(&self) -> ExecutionHandle {
+        self.handle.clone()
+    }
+
+    fn publish(&self, report: ExecutionReport) {
+        metrics::EXECUTIONS.with_label_values(&["paper", report.status.as_str()]).inc();
+        log::info!("paper execution: {:?}", report);
+        // Nobody may be listening to the reports
+        let _ = self.handle.reports.send(report);
+    }
+}
+
+impl Worker for PaperTrader {
+    fn name(&self) -> String {
+        self.context.name.clone()
+    }
+
+    fn spawn(&mut self) -> SpawnResult {
+        let trader = self.clone();
+
+        tokio::spawn(async move {
+            let mut executor = PaperExecutor::new(trader.config.clone(), trader.handle.portfolio.clone(), trader.handle.risk.clone());
+            if let Some(funding) = trader.funding.clone() {
+                executor = executor.with_funding(funding);
+            }
+            let mut receiver = trader.broadcaster.subscribe();
+            let mut books = trader.book_broadcaster.subscribe();
+            let mut app = trader.context.app.subscribe();
+            loop {
+                let next_execution = executor
+                    .next_execution_time()
+                    .map(|executed_time| Duration::try_from(executed_time.duration_since(Timestamp::now())).unwrap_or_default());
+                // The update which triggered an opportunity is sent ahead of it, the books are polled first for the
+                // opportunity to be executed against them
+                tokio::select! {
+                    biased;
+
+                    _ = app.recv() => {
+                        return Err(ArbitrageError::Exit);
+                    }
+
+                    update = books.recv() => match update {
+                        Ok(order_book_update) => executor.on_update(order_book_update),
+                        Err(RecvError::Lagged(skipped)) => {
+                            metrics::BROADCAST_LAGGED.inc_by(skipped);
+                            log::warn!("paper trader lagging behind, skipped {} book updates, its books may be out of date", skipped);
+                        }
+                        Err(RecvError::Closed) => {
+                            return Err(ArbitrageError::GenericError("book broadcaster closed".to_string()));
+                        }
+                    },
+
+                    message = receiver.recv() => match message {
+                        Ok(InternalMessage::ArbitrageOpportunity(arbitrage_opportunity)) => {
+                            if !executor.on_opportunity(arbitrage_opportunity, Timestamp::now()) {
+                                log::debug!("ignoring opportunity as the orders of its pair are in flight or it breaches a risk limit");
+                            }
+                        }
+                        Ok(InternalMessage::OrderBookUpdate(_)) => {}
+                        Err(RecvError::Lagged(skipped)) => {
+                            metrics::BROADCAST_LAGGED.inc_by(skipped);
+                            log::warn!("paper trader lagging behind, skipped {} messages", skipped);
+                        }
+                        Err(RecvError::Closed) => {
+                            return Err(ArbitrageError::GenericError("broadcaster closed".to_string()));
+                        }
+                    },
+
+                    _ = tokio::time::sleep(next_execution.unwrap_or_default()), if next_execution.is_some() => {
+                        let fees = trader.manager_config.lock().fees.clone();
+                        for report in executor.execute_until(Timestamp::now(), &fees) {
+                            trader.publish(report);
+                        }
+                    }
+                }
+            }
+        })
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use models::{Exchange, PipelineTimestamps, Product};
+    use rust_decimal_macros::dec;
+
+    use crate::{execution::ExecutionStatus, settings::{BalancesConfig, RiskConfig}};
+
+    use super::*;
+
+    fn exchange_product(exchange: Exchange) -> ExchangeProduct {
+        ExchangeProduct { exchange, product: Product::from_okex_exhchange("BTC-USD-250221-90000-P").unwrap() }
+    }
+
+    fn update(exchange: Exchange, bids: Vec<(Decimal, Decimal)>, asks: Vec<(Decimal, Decimal)>) -> OrderBookUpdate {
+        let now = Timestamp::from_millisecond(0).unwrap();
+        OrderBookUpdate { exchange_product: exchange_product(exchange), bids, asks, timestamps: PipelineTimestamps::new(now, now) }
+    }
+
+    fn opportunity(size: Decimal) -> ArbitrageOpportunity {
+        let now = Timestamp::from_millisecond(0).unwrap();
+        ArbitrageOpportunity {
+            product: exchange_product(Exchange::Okex).product,
+            buy_exchange: Exchange::Okex,
+            sell_exchange: Exchange::Deribit,
+            buy_price: dec!(0.010),
+            sell_price: dec!(0.012),
+            size,
+            edge: dec!(0.002),
+            buy_book_age_millis: 0,
+            sell_book_age_millis: 0,
+            trigger_exchange: Exchange::Deribit,
+            timestamps: PipelineTimestamps::new(now, now),
+        }
+    }
+
+    #[test]
+    fn test_paper_execution_legs_and_portfolio() {
+        let config = PaperConfig {
+            enabled: true,
+            latency_millis: 10,
+            max_order_size: dec!(5),
+            balances: BalancesConfig { okex: dec!(1), deribit: dec!(1) },
+            ..PaperConfig::default()
+        };
+        let handle = ExecutionHandle::new(Portfolio::new(&config.balances), &RiskConfig::default());
+        let portfolio = handle.portfolio.clone();
+        let mut executor = PaperExecutor::new(config, portfolio.clone(), handle.risk.clone());
+        let fees = FeesConfig { okex: dec!(0.01), deribit: Decimal::ZERO };
+
+        executor.on_update(update(Exchange::Okex, vec![(dec!(0.009), dec!(5))], vec![(dec!(0.010), dec!(2)), (dec!(0.011), dec!(5))]));
+        executor.on_update(update(Exchange::Deribit, vec![(dec!(0.012), dec!(3))], vec![(dec!(0.013), dec!(3))]));
+        let received_time = Timestamp::from_millisecond(100).unwrap();
+        assert!(executor.on_opportunity(opportunity(dec!(2)), received_time));
+        assert!(!executor.on_opportunity(opportunity(dec!(2)), received_time));
+        assert_eq!(executor.next_execution_time(), Some(Timestamp::from_millisecond(110).unwrap()));
+        assert!(executor.execute_until(Timestamp::from_millisecond(105).unwrap(), &fees).is_empty());
+
+        // Deribit bid shrank to 1 while the orders were in flight
+        executor.on_update(update(Exchange::Deribit, vec![(dec!(0.012), dec!(1))], vec![]));
+        let reports = executor.execute_until(Timestamp::from_millisecond(110).unwrap(), &fees);
+        assert_eq!(reports.len(), 1);
+        let report = &reports[0];
+        assert_eq!(report.status, ExecutionStatus::Legged);
+        assert_eq!((report.buy.filled_size, report.buy.average_price), (dec!(2), Some(dec!(0.010))));
+        assert_eq!((report.sell.filled_size, report.sell.average_price), (dec!(1), Some(dec!(0.012))));
+        assert_eq!(report.naked_size, dec!(1));
+        assert_eq!(report.hedged_pnl, dec!(0.0019));
+
+        let portfolio = portfolio.lock();
+        assert_eq!(portfolio.balance(&Exchange::Okex), dec!(0.9798));
+        assert_eq!(portfolio.balance(&Exchange::Deribit), dec!(1.012));
+        assert_eq!(portfolio.position(&exchange_product(Exchange::Okex)), dec!(2));
+        assert_eq!(portfolio.position(&exchange_product(Exchange::Deribit)), dec!(-1));
+
+        // The liquidity taken is gone for the next orders until the exchange updates the level
+        drop(portfolio);
+        assert!(executor.on_opportunity(opportunity(dec!(2)), received_time));
+        let report = executor.execute_until(Timestamp::from_millisecond(110).unwrap(), &fees).remove(0);
+        assert_eq!(report.status, ExecutionStatus::Missed);
+    }
+}
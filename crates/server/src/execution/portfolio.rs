@@ -0,0 +1,116 @@
+use std::collections::HashMap;
+
+use models::{Exchange, ExchangeProduct, Product};
+use rust_decimal::Decimal;
+use serde::Serialize;
+
+use crate::settings::BalancesConfig;
+
+use super::{LegFill, OrderSide};
+
+
+/// Balances and positions of each exchange, moved by the fills of the executions
+#[derive(Debug, Clone, Default)]
+pub struct Portfolio {
+    balances: HashMap<Exchange, Decimal>,
+    fees: HashMap<Exchange, Decimal>,
+    positions: HashMap<ExchangeProduct, Decimal>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct BalanceReport {
+    pub exchange: Exchange,
+    pub balance: Decimal,
+    /// Fees paid since the server started
+    pub fees: Decimal,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Position {
+    pub exchange: Exchange,
+    pub product: Product,
+    /// Positive when long
+    pub size: Decimal,
+}
+
+/// Portfolio as served by `/portfolio/v1`, the positions ordered by product and exchange
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PortfolioReport {
+    pub balances: Vec<BalanceReport>,
+    pub positions: Vec<Position>,
+}
+
+impl Portfolio {
+    pub fn new(balances: &BalancesConfig) -> Self {
+        let balances = [Exchange::Okex, Exchange::Deribit]
+            .into_iter()
+            .map(|exchange| {
+                let balance = balances.balance(&exchange);
+                (exchange, balance)
+            })
+            .collect();
+        Self { balances, ..Self::default() }
+    }
+
+    /// Moves the balance and the position of the exchange of the leg by its fills
+    pub fn apply(&mut self, product: &Product, leg: &LegFill) {
+        let Some(price) = leg.average_price else {
+            return;
+        };
+        let exchange_product = ExchangeProduct { exchange: leg.exchange.clone(), product: product.clone() };
+        self.fill(&exchange_product, leg.side, leg.filled_size, price * leg.filled_size, leg.fee);
+    }
+
+    /// Moves the balance and the position of the exchange by a fill of `size` for `notional`, e.g. the part of
+    /// an order which filled since its previous update
+    pub fn fill(&mut self, exchange_product: &ExchangeProduct, side: OrderSide, size: Decimal, notional: Decimal, fee: Decimal) {
+        let (cash, size) = match side {
+            OrderSide::Buy => (-notional, size),
+            OrderSide::Sell => (notional, -size),
+        };
+        let exchange = &exchange_product.exchange;
+        *self.balances.entry(exchange.clone()).or_default() += cash - fee;
+        *self.fees.entry(exchange.clone()).or_default() += fee;
+
+        let position = self.positions.entry(exchange_product.clone()).or_default();
+        *position += size;
+        if position.is_zero() {
+            self.positions.remove(exchange_product);
+        }
+    }
+
+    pub fn balance(&self, exchange: &Exchange) -> Decimal {
+        self.balances.get(exchange).copied().unwrap_or_default()
+    }
+
+    pub fn position(&self, exchange_product: &ExchangeProduct) -> Decimal {
+        self.positions.get(exchange_product).copied().unwrap_or_default()
+    }
+
+    /// Size of each open position, positive when long
+    pub fn positions(&self) -> impl Iterator<Item = (&ExchangeProduct, &Decimal)> {
+        self.positions.iter()
+    }
+
+    pub fn report(&self) -> PortfolioReport {
+        let mut balances = self.balances
+            .iter()
+            .map(|(exchange, balance)| BalanceReport {
+                exchange: exchange.clone(),
+                balance: *balance,
+                fees: self.fees.get(exchange).copied().unwrap_or_default(),
+            })
+            .collect::<Vec<_>>();
+        balances.sort_by_key(|balance| format!("{:?}", balance.exchange));
+        let mut positions = self.positions
+            .iter()
+            .map(|(exchange_product, size)| Position {
+                exchange: exchange_product.exchange.clone(),
+                product: exchange_product.product.clone(),
+                size: *size,
+            })
+            .collect::<Vec<_>>();
+        positions.sort_by_key(|position| (position.product.to_string(), format!("{:?}", position.exchange)));
+        PortfolioReport { balances, positions }
+    }
+}
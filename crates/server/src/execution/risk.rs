@@ -0,0 +1,421 @@
+use std::collections::{BTreeMap, HashMap};
+
+use chrono::NaiveDate;
+use common::SharedRef;
+use jiff::Timestamp;
+use models::{ArbitrageOpportunity, CryptoAsset, Exchange, ExchangeProduct, OrderBook, Product, SettlementAsset};
+use rust_decimal::Decimal;
+use serde::Serialize;
+
+use crate::{greeks::{self, Greeks}, metrics, settings::RiskConfig};
+
+use super::{OrderSide, Portfolio, Position};
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RiskLimit {
+    Position,
+    NetDelta,
+    Notional,
+    OpenOpportunities,
+    /// A limit on the delta or the notional can not be checked, as a product or its underlying has no price
+    Unpriced,
+}
+
+impl RiskLimit {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RiskLimit::Position => "position",
+            RiskLimit::NetDelta => "net_delta",
+            RiskLimit::Notional => "notional",
+            RiskLimit::OpenOpportunities => "open_opportunities",
+            RiskLimit::Unpriced => "unpriced",
+        }
+    }
+}
+
+/// Why an opportunity is not executed
+#[derive(Debug, Clone, PartialEq)]
+pub struct RiskBreach {
+    pub limit: RiskLimit,
+    pub reason: String,
+}
+
+impl RiskBreach {
+    fn new(limit: RiskLimit, reason: String) -> Self {
+        Self { limit, reason }
+    }
+}
+
+/// Orders in flight, whose fills are not in the portfolio yet
+#[derive(Debug, Clone, Default)]
+pub struct OpenOrders {
+    pub executions: usize,
+    /// Size left to fill of the orders of each product and exchange, negative for the sells
+    pub sizes: HashMap<ExchangeProduct, Decimal>,
+}
+
+impl OpenOrders {
+    pub fn add(&mut self, exchange_product: ExchangeProduct, side: OrderSide, size: Decimal) {
+        let size = match side {
+            OrderSide::Buy => size,
+            OrderSide::Sell => -size,
+        };
+        *self.sizes.entry(exchange_product).or_default() += size;
+    }
+}
+
+/// Greeks and notional of the positions of an underlying expiring on the same day, the perpetuals have no expiration
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ExpiryRisk {
+    pub underlying: CryptoAsset,
+    pub expiration: Option<NaiveDate>,
+    #[serde(flatten)]
+    pub greeks: Greeks,
+    /// Value in USD of the underlying of the positions, long and short alike
+    pub notional: Decimal,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct VenueRisk {
+    pub exchange: Exchange,
+    pub notional: Decimal,
+}
+
+/// Risk of the portfolio as served by `/risk/v1`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RiskReport {
+    /// Price in USD of each underlying, the mid price of its perpetual
+    pub spot: BTreeMap<String, Decimal>,
+    /// Ordered by underlying then expiration
+    pub expiries: Vec<ExpiryRisk>,
+    /// Net delta of each underlying across the exchanges
+    pub net_delta: BTreeMap<String, Decimal>,
+    pub venues: Vec<VenueRisk>,
+    /// Positions left out of the greeks and the notional, as they or their underlying have no price
+    pub unpriced: Vec<Position>,
+    pub limits: RiskConfig,
+}
+
+
+/// Greeks and notional of the positions of the portfolio, and gate of the opportunities on the limits of `RiskConfig`
+///
+/// The options are marked at the mid price of their book, or of their book on the other exchange, and the
+/// underlyings at the mid price of their perpetual, whose book has to be subscribed for the greeks and the notional
+/// to be known. The greeks are those of Black-Scholes at the volatility implied by the mark.
+///
+/// An opportunity is suppressed if executing it would breach a limit: the position on either exchange once both legs
+/// and the orders in flight filled, the notional of either exchange once both legs and the orders in flight filled,
+/// the net delta of the underlying should a single leg fill, or the number of executions in flight. When a limit on the delta or the notional is set, an opportunity whose
+/// risk can not be priced is suppressed.
+pub struct RiskManager {
+    config: RiskConfig,
+    portfolio: SharedRef<Portfolio>,
+    /// Mid price of each book
+    marks: HashMap<ExchangeProduct, Decimal>,
+}
+
+impl RiskManager {
+    pub fn new(config: RiskConfig, portfolio: SharedRef<Portfolio>) -> Self {
+        Self { config, portfolio, marks: HashMap::new() }
+    }
+
+    /// Marks the product of the book at its mid price, or unmarks it if either side is empty
+    pub fn on_order_book(&mut self, order_book: &OrderBook) {
+        match (order_book.best_bid(), order_book.best_ask()) {
+            (Some((bid, _)), Some((ask, _))) => {
+                self.marks.insert(order_book.exchange_product.clone(), (bid + ask) / Decimal::TWO);
+            }
+            _ => {
+                self.marks.remove(&order_book.exchange_product);
+            }
+        }
+    }
+
+    /// Price in USD of the underlying, the mid price of its perpetual on Deribit, else on Okex
+    pub fn spot(&self, underlying: &CryptoAsset) -> Option<Decimal> {
+        let perpetual = Product::Perpetual { underlying: underlying.clone(), settlement: SettlementAsset::USD };
+        [Exchange::Deribit, Exchange::Okex]
+            .into_iter()
+            .find_map(|exchange| self.marks.get(&ExchangeProduct { exchange, product: perpetual.clone() }).copied())
+    }
+
+    /// Mid price of the product on the exchange, else on the other exchange
+    fn mark(&self, exchange_product: &ExchangeProduct) -> Option<Decimal> {
+        self.marks.get(exchange_product).copied().or_else(|| {
+            self.marks
+                .iter()
+                .find(|(other, _)| other.product == exchange_product.product)
+                .map(|(_, mark)| *mark)
+        })
+    }
+
+    /// Greeks and notional in USD of a position, None if it can not be priced
+    ///
+    /// The size of an option is in its underlying on both exchanges, the Okex adapters converting its contracts, and
+    /// the size of a perpetual in contracts of the exchange.
+    fn position_risk(&self, exchange_product: &ExchangeProduct, size: Decimal, now: Timestamp) -> Option<(Greeks, Decimal)> {
+        let product = &exchange_product.product;
+        let spot = self.spot(product.underlying())?;
+        match product {
+            Product::Option { .. } => {
+                let greeks = greeks::product_greeks(product, spot, self.mark(exchange_product)?, now)?;
+                Some((greeks.scale(size), size.abs() * spot))
+            }
+            Product::Perpetual { .. } => {
+                let value = size * product.contract_value(&exchange_product.exchange);
+                Some((Greeks { delta: value / spot, ..Greeks::default() }, value.abs()))
+            }
+        }
+    }
+
+    /// Net delta of the underlying, None if a position on it can not be priced
+    fn net_delta(&self, portfolio: &Portfolio, underlying: &CryptoAsset, now: Timestamp) -> Option<Decimal> {
+        portfolio
+            .positions()
+            .filter(|(exchange_product, _)| exchange_product.product.underlying() == underlying)
+            .map(|(exchange_product, size)| self.position_risk(exchange_product, *size, now).map(|(greeks, _)| greeks.delta))
+            .sum()
+    }
+
+    /// Notional of the positions on the exchange, None if one of them can not be priced
+    fn venue_notional(&self, positions: &HashMap<ExchangeProduct, Decimal>, exchange: &Exchange, now: Timestamp) -> Option<Decimal> {
+        positions
+            .iter()
+            .filter(|(exchange_product, _)| &exchange_product.exchange == exchange)
+            .map(|(exchange_product, size)| self.position_risk(exchange_product, *size, now).map(|(_, notional)| notional))
+            .sum()
+    }
+
+    /// Checks the limits for the execution of `size` of the opportunity while the `open` orders are in flight
+    pub fn check(&self, opportunity: &ArbitrageOpportunity, size: Decimal, open: &OpenOrders, now: Timestamp) -> Result<(), RiskBreach> {
+        let config = &self.config;
+        if let Some(max) = config.max_open_opportunities.filter(|max| open.executions >= *max) {
+            return Err(RiskBreach::new(RiskLimit::OpenOpportunities, format!("{} executions are in flight, the limit is {}", open.executions, max)));
+        }
+
+        let portfolio = self.portfolio.lock();
+        // The orders in flight may all fill before this opportunity does
+        let mut positions = portfolio.positions().map(|(exchange_product, size)| (exchange_product.clone(), *size)).collect::<HashMap<_, _>>();
+        for (exchange_product, size) in open.sizes.iter() {
+            *positions.entry(exchange_product.clone()).or_default() += size;
+        }
+        let product = &opportunity.product;
+        let legs = [(&opportunity.buy_exchange, size), (&opportunity.sell_exchange, -size)].map(|(exchange, size)| {
+            let exchange_product = ExchangeProduct { exchange: exchange.clone(), product: product.clone() };
+            let position = positions.get(&exchange_product).copied().unwrap_or_default();
+            (exchange_product, position, position + size)
+        });
+        if let Some(max) = config.max_position {
+            if let Some((exchange_product, _, position)) = legs.iter().find(|(_, _, position)| position.abs() > max) {
+                let reason = format!("position of {} on {:?} would be {}, the limit is {}", product, exchange_product.exchange, position, max);
+                return Err(RiskBreach::new(RiskLimit::Position, reason));
+            }
+        }
+        if config.max_notional.is_none() && config.max_net_delta.is_none() {
+            return Ok(());
+        }
+
+        let underlying = product.underlying();
+        let unpriced = |what: String| RiskBreach::new(RiskLimit::Unpriced, format!("no price for {}", what));
+        let spot = self.spot(underlying).ok_or_else(|| unpriced(format!("{:?}", underlying)))?;
+        if let Some(max) = config.max_notional {
+            for (exchange_product, position, position_after) in legs.iter() {
+                let exchange = &exchange_product.exchange;
+                let notional = self.venue_notional(&positions, exchange, now).ok_or_else(|| unpriced(format!("the positions on {:?}", exchange)))?;
+                let notional = notional + (position_after.abs() - position.abs()) * spot;
+                if notional > max {
+                    let reason = format!("notional of {:?} would be {} USD, the limit is {}", exchange, notional.round_dp(2), max);
+                    return Err(RiskBreach::new(RiskLimit::Notional, reason));
+                }
+            }
+        }
+        if let Some(max) = config.max_net_delta {
+            let net_delta = self.net_delta(&portfolio, underlying, now).ok_or_else(|| unpriced(format!("the positions on {:?}", underlying)))?;
+            let delta = greeks::product_delta(product, spot, opportunity.buy_price, now).ok_or_else(|| unpriced(product.to_string()))?;
+            let naked_delta = net_delta.abs() + (delta * size).abs();
+            if naked_delta > max {
+                let reason = format!("net delta of {:?} would be {} should a single leg fill, the limit is {}", underlying, naked_delta.round_dp(4), max);
+                return Err(RiskBreach::new(RiskLimit::NetDelta, reason));
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks the limits like `check`, counting and logging a breach
+    pub fn approve(&self, opportunity: &ArbitrageOpportunity, size: Decimal, open: &OpenOrders, now: Timestamp) -> bool {
+        match self.check(opportunity, size, open, now) {
+            Ok(()) => true,
+            Err(breach) => {
+                metrics::RISK_SUPPRESSED.with_label_values(&[breach.limit.as_str()]).inc();
+                log::info!("suppressing opportunity on {}: {}", opportunity.product, breach.reason);
+                false
+            }
+        }
+    }
+
+    pub fn report(&self, now: Timestamp) -> RiskReport {
+        let portfolio = self.portfolio.lock();
+        let mut expiries = BTreeMap::<(String, Option<NaiveDate>), ExpiryRisk>::new();
+        let mut venues = BTreeMap::<String, VenueRisk>::new();
+        let mut unpriced = vec![];
+        for (exchange_product, size) in portfolio.positions() {
+            let product = &exchange_product.product;
+            let Some((greeks, notional)) = self.position_risk(exchange_product, *size, now) else {
+                unpriced.push(Position { exchange: exchange_product.exchange.clone(), product: product.clone(), size: *size });
+                continue;
+            };
+            let expiration = match product {
+                Product::Option { expiration, .. } => Some(*expiration),
+                Product::Perpetual { .. } => None,
+            };
+            let expiry = expiries
+                .entry((format!("{:?}", product.underlying()), expiration))
+                .or_insert_with(|| ExpiryRisk { underlying: product.underlying().clone(), expiration, greeks: Greeks::default(), notional: Decimal::ZERO });
+            expiry.greeks.add(&greeks);
+            expiry.notional += notional;
+            let exchange = &exchange_product.exchange;
+            venues
+                .entry(format!("{:?}", exchange))
+                .or_insert_with(|| VenueRisk { exchange: exchange.clone(), notional: Decimal::ZERO })
+                .notional += notional;
+        }
+        unpriced.sort_by_key(|position| (position.product.to_string(), format!("{:?}", position.exchange)));
+
+        let mut net_delta = BTreeMap::new();
+        for expiry in expiries.values() {
+            *net_delta.entry(format!("{:?}", expiry.underlying)).or_default() += expiry.greeks.delta;
+        }
+        let spot = [CryptoAsset::BTC, CryptoAsset::ETH]
+            .into_iter()
+            .filter_map(|underlying| Some((format!("{:?}", underlying), self.spot(&underlying)?)))
+            .collect();
+        RiskReport {
+            spot,
+            expiries: expiries.into_values().collect(),
+            net_delta,
+            venues: venues.into_values().collect(),
+            unpriced,
+            limits: self.config.clone(),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use models::PipelineTimestamps;
+    use rust_decimal_macros::dec;
+
+    use crate::execution::OrderSide;
+
+    use super::*;
+
+    const PUT: &str = "BTC-USD-250221-90000-P";
+
+    fn order_book(exchange: Exchange, product: &str, bid: Decimal, ask: Decimal) -> OrderBook {
+        let mut order_book = OrderBook::new(&ExchangeProduct { exchange, product: Product::from_okex_exhchange(product).unwrap() });
+        order_book.add_bid(bid, dec!(1));
+        order_book.add_ask(ask, dec!(1));
+        order_book
+    }
+
+    fn open(executions: usize) -> OpenOrders {
+        OpenOrders { executions, ..OpenOrders::default() }
+    }
+
+    fn opportunity(size: Decimal) -> ArbitrageOpportunity {
+        let now = Timestamp::from_millisecond(0).unwrap();
+        ArbitrageOpportunity {
+            product: Product::from_okex_exhchange(PUT).unwrap(),
+            buy_exchange: Exchange::Okex,
+            sell_exchange: Exchange::Deribit,
+            buy_price: dec!(0.010),
+            sell_price: dec!(0.012),
+            size,
+            edge: dec!(0.002),
+            buy_book_age_millis: 0,
+            sell_book_age_millis: 0,
+            trigger_exchange: Exchange::Deribit,
+            timestamps: PipelineTimestamps::new(now, now),
+        }
+    }
+
+    #[test]
+    fn test_risk_limits_and_report() {
+        let now = Timestamp::from_second(1_735_689_600).unwrap(); // 2025-01-01T00:00:00Z
+        let portfolio = SharedRef::new(Portfolio::default());
+        let put = ExchangeProduct { exchange: Exchange::Okex, product: Product::from_okex_exhchange(PUT).unwrap() };
+        // 200 contracts of 0.01 BTC on Okex are 2 puts
+        let size = put.product.from_contracts(&put.exchange, dec!(200));
+        portfolio.lock().fill(&put, OrderSide::Buy, size, dec!(0.02), Decimal::ZERO);
+        let config = RiskConfig { max_position: Some(dec!(3)), max_notional: Some(dec!(450000)), max_net_delta: Some(dec!(1)), max_open_opportunities: Some(2) };
+        let mut risk = RiskManager::new(config, portfolio.clone());
+
+        assert_eq!(risk.check(&opportunity(dec!(1)), dec!(1), &open(2), now).unwrap_err().limit, RiskLimit::OpenOpportunities);
+        assert_eq!(risk.check(&opportunity(dec!(2)), dec!(2), &open(0), now).unwrap_err().limit, RiskLimit::Position);
+        // Neither the underlying nor the put have a price
+        assert_eq!(risk.check(&opportunity(dec!(1)), dec!(1), &open(0), now).unwrap_err().reason, "no price for BTC");
+        assert_eq!(risk.report(now).unpriced.len(), 1);
+
+        risk.on_order_book(&order_book(Exchange::Deribit, "BTC-USD-SWAP", dec!(99990), dec!(100010)));
+        risk.on_order_book(&order_book(Exchange::Deribit, PUT, dec!(0.0095), dec!(0.0105)));
+        // The delta of the put is -0.16 at 1000 USD, a long position of 2 has a delta of -0.32
+        assert!(risk.check(&opportunity(dec!(1)), dec!(1), &open(1), now).is_ok());
+        assert_eq!(risk.check(&opportunity(dec!(5)), dec!(5), &open(1), now).unwrap_err().limit, RiskLimit::Position);
+
+        let report = risk.report(now);
+        assert_eq!(report.spot["BTC"], dec!(100000));
+        assert_eq!(report.expiries.len(), 1);
+        assert_eq!(report.expiries[0].expiration, NaiveDate::from_ymd_opt(2025, 2, 21));
+        assert!((report.net_delta["BTC"] - dec!(-0.32)).abs() < dec!(0.01), "{:?}", report.net_delta);
+        assert_eq!(report.venues, vec![VenueRisk { exchange: Exchange::Okex, notional: dec!(200000) }]);
+        assert!(report.unpriced.is_empty());
+
+        // A short perpetual of 40000 USD makes the net delta -0.72, a single leg of 2 puts would move it by 0.32
+        let perpetual = ExchangeProduct { exchange: Exchange::Deribit, product: Product::from_okex_exhchange("BTC-USD-SWAP").unwrap() };
+        portfolio.lock().fill(&perpetual, OrderSide::Sell, dec!(40000), Decimal::ZERO, Decimal::ZERO);
+        let mut config = risk.config.clone();
+        config.max_position = None;
+        let risk = RiskManager { config, ..risk };
+        assert_eq!(risk.check(&opportunity(dec!(2)), dec!(2), &open(0), now).unwrap_err().limit, RiskLimit::NetDelta);
+        // Buying 3 more puts on Okex would make its notional 500000 USD
+        let mut config = risk.config.clone();
+        config.max_net_delta = None;
+        let risk = RiskManager { config, ..risk };
+        assert_eq!(risk.check(&opportunity(dec!(3)), dec!(3), &open(0), now).unwrap_err().limit, RiskLimit::Notional);
+        assert!(risk.check(&opportunity(dec!(3)), dec!(2), &open(0), now).is_ok());
+        assert_eq!(risk.report(now).venues[0], VenueRisk { exchange: Exchange::Deribit, notional: dec!(40000) });
+    }
+
+    #[test]
+    fn test_limits_count_orders_in_flight() {
+        let now = Timestamp::from_second(1_735_689_600).unwrap(); // 2025-01-01T00:00:00Z
+        let portfolio = SharedRef::new(Portfolio::default());
+        let put = ExchangeProduct { exchange: Exchange::Okex, product: Product::from_okex_exhchange(PUT).unwrap() };
+        let config = RiskConfig { max_position: Some(dec!(3)), ..RiskConfig::default() };
+        let mut risk = RiskManager::new(config, portfolio.clone());
+
+        // Another pair of the same product has 2 puts to buy on Okex in flight
+        let mut open = open(1);
+        open.add(put.clone(), OrderSide::Buy, dec!(2));
+        assert!(risk.check(&opportunity(dec!(1)), dec!(1), &open, now).is_ok());
+        let breach = risk.check(&opportunity(dec!(2)), dec!(2), &open, now).unwrap_err();
+        assert_eq!(breach.limit, RiskLimit::Position);
+        assert_eq!(breach.reason, "position of BTC-USD-250221-90000-P on Okex would be 4, the limit is 3");
+
+        // Once filled, only what is left of the order is in flight
+        portfolio.lock().fill(&put, OrderSide::Buy, dec!(1), dec!(0.01), Decimal::ZERO);
+        open.sizes.insert(put.clone(), dec!(1));
+        assert!(risk.check(&opportunity(dec!(1)), dec!(1), &open, now).is_ok());
+        assert_eq!(risk.check(&opportunity(dec!(2)), dec!(2), &open, now).unwrap_err().limit, RiskLimit::Position);
+
+        // The notional of Okex counts the puts in flight, 2 held or to fill and 2 more are worth 400000 USD
+        risk.config = RiskConfig { max_notional: Some(dec!(350000)), ..RiskConfig::default() };
+        risk.on_order_book(&order_book(Exchange::Deribit, "BTC-USD-SWAP", dec!(99990), dec!(100010)));
+        risk.on_order_book(&order_book(Exchange::Deribit, PUT, dec!(0.0095), dec!(0.0105)));
+        assert!(risk.check(&opportunity(dec!(1)), dec!(1), &OpenOrders::default(), now).is_ok());
+        assert_eq!(risk.check(&opportunity(dec!(2)), dec!(2), &open, now).unwrap_err().limit, RiskLimit::Notional);
+    }
+}
@@ -0,0 +1,221 @@
+use std::{cmp::min, collections::HashMap};
+
+use common::SharedRef;
+use models::{ArbitrageOpportunity, CryptoAsset, Exchange, Product};
+use rust_decimal::Decimal;
+use serde::Serialize;
+
+use crate::{execution::{OrderSide, Portfolio}, settings::{FeesConfig, FundingConfig, FundingSource}};
+
+const CURRENCIES: [CryptoAsset; 2] = [CryptoAsset::BTC, CryptoAsset::ETH];
+
+
+/// Funds available on an exchange, as served by `/funding/v1`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct FundingReport {
+    pub exchange: Exchange,
+    pub currency: CryptoAsset,
+    pub available: Decimal,
+}
+
+/// Funds available for new orders on each exchange, in each currency the options are quoted in, shared between
+/// the workers which learn them and the order book manager which sizes the opportunities by them
+///
+/// Buying an option takes its premium and fee. Selling one takes the initial margin of a short option, `margin_rate`
+/// in units of the underlying as the premium received covers its mark price, and the fee. A perpetual takes
+/// `margin_rate` of its value either way. The funds of an exchange are unknown until learnt, which funds nothing.
+#[derive(Clone)]
+pub struct Funding {
+    config: FundingConfig,
+    available: SharedRef<HashMap<(Exchange, CryptoAsset), Decimal>>,
+}
+
+impl Funding {
+    /// Create a new Funding from the `funding` section of the configuration, holding the static balances
+    pub fn new(config: &FundingConfig) -> Self {
+        let funding = Self { config: config.clone(), available: SharedRef::default() };
+        if config.source == FundingSource::Static {
+            for exchange in [Exchange::Okex, Exchange::Deribit] {
+                funding.set_all(&exchange, config.balances.balance(&exchange));
+            }
+        }
+        funding
+    }
+
+    pub fn set(&self, exchange: &Exchange, currency: &CryptoAsset, available: Decimal) {
+        self.available.lock().insert((exchange.clone(), currency.clone()), available);
+    }
+
+    /// Sets the funds of the exchange in every currency, for the balances which are not kept per currency
+    fn set_all(&self, exchange: &Exchange, available: Decimal) {
+        for currency in CURRENCIES.iter() {
+            self.set(exchange, currency, available);
+        }
+    }
+
+    pub fn available(&self, exchange: &Exchange, currency: &CryptoAsset) -> Option<Decimal> {
+        self.available.lock().get(&(exchange.clone(), currency.clone())).copied()
+    }
+
+    /// Learns the funds of each exchange from the virtual balances of paper trading, less the margin of its short
+    /// options
+    pub fn on_portfolio(&self, portfolio: &Portfolio) {
+        for exchange in [Exchange::Okex, Exchange::Deribit] {
+            let margin = portfolio
+                .positions()
+                .filter(|(exchange_product, size)| {
+                    exchange_product.exchange == exchange
+                        && size.is_sign_negative()
+                        && matches!(exchange_product.product, Product::Option { .. })
+                })
+                .map(|(_, size)| size.abs() * self.config.margin_rate)
+                .sum::<Decimal>();
+            self.set_all(&exchange, portfolio.balance(&exchange) - margin);
+        }
+    }
+
+    /// Funds taken by a unit of the product bought or sold at `price` on the exchange
+    ///
+    /// A unit of an option is one of its underlying on either exchange, as the Okex adapters convert its contracts, so
+    /// its premium and margin are per unit as quoted. A unit of a perpetual is a contract of the exchange.
+    fn requirement(&self, product: &Product, exchange: &Exchange, side: OrderSide, price: Decimal, fee_rate: Decimal) -> Decimal {
+        match product {
+            Product::Option { .. } => match side {
+                OrderSide::Buy => price * (Decimal::ONE + fee_rate),
+                OrderSide::Sell => self.config.margin_rate + price * fee_rate,
+            },
+            // Inverse, its contract value in USD is worth that much of the underlying over the price
+            Product::Perpetual { .. } if price > Decimal::ZERO => {
+                product.contract_value(exchange) / price * (self.config.margin_rate + fee_rate)
+            }
+            Product::Perpetual { .. } => Decimal::ZERO,
+        }
+    }
+
+    /// Largest size of a leg the funds of its exchange pay for, rounded down to `lot_size`, None if unbounded
+    fn fundable_size(&self, product: &Product, exchange: &Exchange, side: OrderSide, price: Decimal, fee_rate: Decimal) -> Option<Decimal> {
+        let requirement = self.requirement(product, exchange, side, price, fee_rate);
+        if requirement <= Decimal::ZERO {
+            return None;
+        }
+        let available = self.available(exchange, product.underlying()).unwrap_or_default().max(Decimal::ZERO);
+        Some((available / requirement / self.config.lot_size).floor() * self.config.lot_size)
+    }
+
+    /// Caps the size of the opportunity by what the exchange of each leg can fund
+    ///
+    /// Returns the exchange of a leg which can not fund a single lot, in which case the size is left as is.
+    pub fn cap(&self, opportunity: &mut ArbitrageOpportunity, fees: &FeesConfig) -> Result<(), Exchange> {
+        let legs = [
+            (&opportunity.buy_exchange, OrderSide::Buy, opportunity.buy_price),
+            (&opportunity.sell_exchange, OrderSide::Sell, opportunity.sell_price),
+        ];
+        let mut size = opportunity.size;
+        for (exchange, side, price) in legs {
+            if let Some(fundable_size) = self.fundable_size(&opportunity.product, exchange, side, price, fees.rate(exchange)) {
+                if fundable_size.is_zero() {
+                    return Err(exchange.clone());
+                }
+                size = min(size, fundable_size);
+            }
+        }
+        opportunity.size = size;
+        Ok(())
+    }
+
+    /// Funds known of each exchange, ordered by exchange and currency
+    pub fn report(&self) -> Vec<FundingReport> {
+        let mut report = self.available
+            .lock()
+            .iter()
+            .map(|((exchange, currency), available)| FundingReport {
+                exchange: exchange.clone(),
+                currency: currency.clone(),
+                available: *available,
+            })
+            .collect::<Vec<_>>();
+        report.sort_by_key(|report| format!("{:?}{:?}", report.exchange, report.currency));
+        report
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use jiff::Timestamp;
+    use models::PipelineTimestamps;
+    use rust_decimal_macros::dec;
+
+    use crate::settings::BalancesConfig;
+
+    use super::*;
+
+    fn opportunity(size: Decimal) -> ArbitrageOpportunity {
+        let now = Timestamp::from_millisecond(0).unwrap();
+        ArbitrageOpportunity {
+            product: Product::from_okex_exhchange("BTC-USD-250221-90000-P").unwrap(),
+            buy_exchange: Exchange::Okex,
+            sell_exchange: Exchange::Deribit,
+            buy_price: dec!(0.01),
+            sell_price: dec!(0.012),
+            size,
+            edge: dec!(0.002),
+            buy_book_age_millis: 0,
+            sell_book_age_millis: 0,
+            trigger_exchange: Exchange::Okex,
+            timestamps: PipelineTimestamps::new(now, now),
+        }
+    }
+
+    #[test]
+    fn test_cap_opportunity_size() {
+        let config = FundingConfig {
+            source: FundingSource::Static,
+            balances: BalancesConfig { okex: dec!(0.055), deribit: dec!(1) },
+            ..FundingConfig::default()
+        };
+        let funding = Funding::new(&config);
+        let fees = FeesConfig::default();
+
+        // Okex pays the premium of 5.5, Deribit the margin of 6.66, rounded down to 6.6
+        let mut funded = opportunity(dec!(100));
+        funding.cap(&mut funded, &fees).unwrap();
+        assert_eq!(funded.size, dec!(5.5));
+        // 550 contracts of 0.01 BTC on Okex, each of them paying a premium of 0.0001
+        let contracts = funded.product.to_contracts(&Exchange::Okex, funded.size);
+        assert_eq!(contracts, dec!(550));
+        assert_eq!(contracts * funded.product.contract_value(&Exchange::Okex) * funded.buy_price, dec!(0.055));
+
+        // The fee of the sell leg takes its share of the margin
+        let fees = FeesConfig { okex: Decimal::ZERO, deribit: dec!(0.5) };
+        let mut funded = opportunity(dec!(100));
+        funding.cap(&mut funded, &fees).unwrap();
+        assert_eq!(funded.size, dec!(5.5));
+        funding.set(&Exchange::Deribit, &CryptoAsset::BTC, dec!(0.5));
+        funding.cap(&mut funded, &fees).unwrap();
+        assert_eq!(funded.size, dec!(3.2));
+
+        // Smaller than what can be funded
+        let mut funded = opportunity(dec!(0.05));
+        funding.cap(&mut funded, &fees).unwrap();
+        assert_eq!(funded.size, dec!(0.05));
+
+        funding.set(&Exchange::Okex, &CryptoAsset::BTC, dec!(0.0005));
+        assert_eq!(funding.cap(&mut opportunity(dec!(1)), &fees), Err(Exchange::Okex));
+
+        // Paper trading holds 1 on each exchange, short 2 options on Deribit
+        let funding = Funding::new(&FundingConfig { source: FundingSource::Account, ..FundingConfig::default() });
+        assert_eq!(funding.cap(&mut opportunity(dec!(1)), &fees), Err(Exchange::Okex));
+        let balances = BalancesConfig { okex: dec!(1), deribit: dec!(1) };
+        let mut portfolio = Portfolio::new(&balances);
+        let deribit = models::ExchangeProduct { exchange: Exchange::Deribit, product: opportunity(dec!(1)).product };
+        portfolio.fill(&deribit, OrderSide::Sell, dec!(2), dec!(0.024), Decimal::ZERO);
+        // and 200 contracts of 0.01 BTC, 2 options, on Okex
+        let okex = models::ExchangeProduct { exchange: Exchange::Okex, product: opportunity(dec!(1)).product };
+        portfolio.fill(&okex, OrderSide::Sell, okex.product.from_contracts(&okex.exchange, dec!(200)), dec!(0.024), Decimal::ZERO);
+        funding.on_portfolio(&portfolio);
+        assert_eq!(funding.available(&Exchange::Deribit, &CryptoAsset::BTC), Some(dec!(0.724)));
+        assert_eq!(funding.available(&Exchange::Okex, &CryptoAsset::BTC), Some(dec!(0.724)));
+        assert_eq!(funding.report().len(), 4);
+    }
+}
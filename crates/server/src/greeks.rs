@@ -0,0 +1,184 @@
+use chrono::NaiveDate;
+use jiff::Timestamp;
+use models::{OptionType, Product};
+use rust_decimal::{prelude::{FromPrimitive, ToPrimitive}, Decimal};
+use serde::Serialize;
+
+/// Hour of the day, in UTC, at which the options expire on both exchanges
+const EXPIRY_HOUR: u32 = 8;
+
+const SECONDS_PER_YEAR: f64 = 365.0 * 24.0 * 3600.0;
+
+const DAYS_PER_YEAR: f64 = 365.0;
+
+/// Decimal places the greeks are rounded to
+const GREEKS_DP: u32 = 8;
+
+/// Bounds of the volatility searched for the price of an option
+const MIN_VOLATILITY: f64 = 0.001;
+const MAX_VOLATILITY: f64 = 10.0;
+
+
+/// Cumulative distribution function of the standard normal distribution
+///
+/// Uses the approximation 7.1.26 of Abramowitz and Stegun, whose error is below 1e-7.
+fn norm_cdf(x: f64) -> f64 {
+    let z = x.abs() / std::f64::consts::SQRT_2;
+    let t = 1.0 / (1.0 + 0.3275911 * z);
+    let polynomial = t * (0.254829592 + t * (-0.284496736 + t * (1.421413741 + t * (-1.453152027 + t * 1.061405429))));
+    let erf = 1.0 - polynomial * (-z * z).exp();
+    if x >= 0.0 { 0.5 * (1.0 + erf) } else { 0.5 * (1.0 - erf) }
+}
+
+fn norm_pdf(x: f64) -> f64 {
+    (-x * x / 2.0).exp() / (2.0 * std::f64::consts::PI).sqrt()
+}
+
+fn d1(spot: f64, strike: f64, years: f64, volatility: f64) -> f64 {
+    ((spot / strike).ln() + volatility * volatility * years / 2.0) / (volatility * years.sqrt())
+}
+
+/// Time left until the expiration of an option, in years
+pub fn years_to_expiry(expiration: NaiveDate, now: Timestamp) -> f64 {
+    let expiry = expiration.and_hms_opt(EXPIRY_HOUR, 0, 0).expect("expiry hour should be valid").and_utc().timestamp();
+    (expiry - now.as_second()) as f64 / SECONDS_PER_YEAR
+}
+
+/// Black-Scholes price of an option without interest rate, in the currency of the spot and the strike
+pub fn price(option_type: &OptionType, spot: f64, strike: f64, years: f64, volatility: f64) -> f64 {
+    let d1 = d1(spot, strike, years, volatility);
+    let d2 = d1 - volatility * years.sqrt();
+    match option_type {
+        OptionType::Call => spot * norm_cdf(d1) - strike * norm_cdf(d2),
+        OptionType::Put => strike * norm_cdf(-d2) - spot * norm_cdf(-d1),
+    }
+}
+
+/// Black-Scholes delta of an option, between 0 and 1 for a call and between -1 and 0 for a put
+pub fn delta(option_type: &OptionType, spot: f64, strike: f64, years: f64, volatility: f64) -> f64 {
+    let delta = norm_cdf(d1(spot, strike, years, volatility));
+    match option_type {
+        OptionType::Call => delta,
+        OptionType::Put => delta - 1.0,
+    }
+}
+
+/// Sensitivities of the value of a position, in USD except for the delta which is in units of the underlying
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub struct Greeks {
+    pub delta: Decimal,
+    /// Change of the delta for a move of 1 USD of the underlying
+    pub gamma: Decimal,
+    /// Change of the value for a move of 1% of the volatility
+    pub vega: Decimal,
+    /// Change of the value over a day
+    pub theta: Decimal,
+}
+
+impl Greeks {
+    /// Greeks of `size` of the product
+    pub fn scale(&self, size: Decimal) -> Self {
+        Self { delta: self.delta * size, gamma: self.gamma * size, vega: self.vega * size, theta: self.theta * size }
+    }
+
+    pub fn add(&mut self, other: &Greeks) {
+        self.delta += other.delta;
+        self.gamma += other.gamma;
+        self.vega += other.vega;
+        self.theta += other.theta;
+    }
+}
+
+/// Black-Scholes greeks of an option without interest rate
+pub fn greeks(option_type: &OptionType, spot: f64, strike: f64, years: f64, volatility: f64) -> Option<Greeks> {
+    let d1 = d1(spot, strike, years, volatility);
+    let density = norm_pdf(d1);
+    let decimal = |value: f64| Decimal::from_f64(value).map(|value| value.round_dp(GREEKS_DP));
+    Some(Greeks {
+        delta: decimal(delta(option_type, spot, strike, years, volatility))?,
+        gamma: decimal(density / (spot * volatility * years.sqrt()))?,
+        vega: decimal(spot * density * years.sqrt() / 100.0)?,
+        theta: decimal(-spot * density * volatility / (2.0 * years.sqrt()) / DAYS_PER_YEAR)?,
+    })
+}
+
+/// Volatility for which the Black-Scholes price of the option is `price`, found by bisection
+///
+/// None if the price is outside of the range of prices of the searched volatilities, e.g. below the intrinsic value.
+pub fn implied_volatility(option_type: &OptionType, spot: f64, strike: f64, years: f64, target: f64) -> Option<f64> {
+    let (mut low, mut high) = (MIN_VOLATILITY, MAX_VOLATILITY);
+    if target < price(option_type, spot, strike, years, low) || target > price(option_type, spot, strike, years, high) {
+        return None;
+    }
+    for _ in 0..100 {
+        let middle = (low + high) / 2.0;
+        if price(option_type, spot, strike, years, middle) < target {
+            low = middle;
+        } else {
+            high = middle;
+        }
+    }
+    Some((low + high) / 2.0)
+}
+
+/// Greeks of a unit of an option quoted in its underlying, as the exchanges quote them, at the volatility implied by
+/// `premium`
+///
+/// A perpetual has a delta of 1 and no other greek. None if the option expired or its premium does not imply a
+/// volatility.
+pub fn product_greeks(product: &Product, spot: Decimal, premium: Decimal, now: Timestamp) -> Option<Greeks> {
+    match product {
+        Product::Option { strike, expiration, option_type, .. } => {
+            let years = years_to_expiry(*expiration, now);
+            if years <= 0.0 {
+                return None;
+            }
+            let (spot, strike) = (spot.to_f64()?, strike.to_f64()?);
+            let volatility = implied_volatility(option_type, spot, strike, years, premium.to_f64()? * spot)?;
+            greeks(option_type, spot, strike, years, volatility)
+        }
+        Product::Perpetual { .. } => Some(Greeks { delta: Decimal::ONE, ..Greeks::default() }),
+    }
+}
+
+/// Delta of a unit of the product, see `product_greeks`
+pub fn product_delta(product: &Product, spot: Decimal, premium: Decimal, now: Timestamp) -> Option<Decimal> {
+    product_greeks(product, spot, premium, now).map(|greeks| greeks.delta)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+
+    use super::*;
+
+    #[test]
+    fn test_black_scholes() {
+        assert!((norm_cdf(0.0) - 0.5).abs() < 1e-7);
+        assert!((norm_cdf(1.96) - 0.9750021).abs() < 1e-6);
+
+        // At the money, a year to expiry and 50% of volatility
+        let call = price(&OptionType::Call, 100.0, 100.0, 1.0, 0.5);
+        assert!((call - 19.741265).abs() < 1e-4, "{}", call);
+        let put = price(&OptionType::Put, 100.0, 100.0, 1.0, 0.5);
+        assert!((call - put).abs() < 1e-9);
+        assert!((delta(&OptionType::Call, 100.0, 100.0, 1.0, 0.5) - 0.598706).abs() < 1e-5);
+        assert!((delta(&OptionType::Put, 100.0, 100.0, 1.0, 0.5) + 0.401294).abs() < 1e-5);
+
+        let call_greeks = greeks(&OptionType::Call, 100.0, 100.0, 1.0, 0.5).unwrap();
+        assert_eq!((call_greeks.gamma, call_greeks.vega, call_greeks.theta), (dec!(0.00773336), dec!(0.38666812), dec!(-0.02648412)));
+
+        let volatility = implied_volatility(&OptionType::Call, 100.0, 100.0, 1.0, call).unwrap();
+        assert!((volatility - 0.5).abs() < 1e-6);
+        assert!(implied_volatility(&OptionType::Put, 100.0, 120.0, 1.0, 10.0).is_none());
+
+        let now = Timestamp::from_second(1_735_689_600).unwrap(); // 2025-01-01T00:00:00Z
+        let product = Product::from_okex_exhchange("BTC-USD-260101-100000-C").unwrap();
+        assert!((years_to_expiry(NaiveDate::from_ymd_opt(2026, 1, 1).unwrap(), now) - (1.0 + 8.0 / 24.0 / 365.0)).abs() < 1e-9);
+        let delta = product_delta(&product, Decimal::from(100_000), Decimal::from_f64(call / 100.0).unwrap(), now).unwrap();
+        assert!((delta.to_f64().unwrap() - 0.5987).abs() < 1e-3, "{}", delta);
+        let expired = Product::from_okex_exhchange("BTC-USD-241231-100000-C").unwrap();
+        assert!(product_delta(&expired, Decimal::from(100_000), Decimal::ONE, now).is_none());
+    }
+}
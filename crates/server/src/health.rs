@@ -0,0 +1,93 @@
+use std::collections::{BTreeMap, HashMap};
+
+use common::{HealthRegistry, HealthStatus};
+use models::Exchange;
+use serde::Serialize;
+use wsclient::{ConnectionState, ConnectionStatus};
+
+/// Connection state of every running exchange adapter
+pub type Connections = HashMap<Exchange, ConnectionState>;
+
+/// Prefix under which `Workers` reports the status of each worker
+const WORKER_COMPONENT_PREFIX: &str = "worker/";
+
+/// Health of the server served on `/health` and `/ready`
+#[derive(Debug, Clone, Serialize)]
+pub struct HealthReport {
+    /// All the workers are running
+    pub live: bool,
+    /// The server is live and every exchange feed is connected and fully subscribed
+    pub ready: bool,
+    /// Reasons why the server is not ready, empty when it is
+    pub problems: Vec<String>,
+    pub components: BTreeMap<String, HealthStatus>,
+    pub exchanges: BTreeMap<String, ConnectionStatus>,
+}
+
+impl HealthReport {
+    pub fn new(health: &HealthRegistry, connections: &Connections) -> Self {
+        let components = health.snapshot();
+        let exchanges = connections
+            .iter()
+            .map(|(exchange, connection)| (format!("{:?}", exchange), connection.status()))
+            .collect::<BTreeMap<_, _>>();
+
+        let mut live = true;
+        let mut problems = vec![];
+        for (component, status) in components.iter() {
+            if let HealthStatus::Unhealthy(reason) = status {
+                live &= !component.starts_with(WORKER_COMPONENT_PREFIX);
+                problems.push(format!("{} is unhealthy: {}", component, reason));
+            }
+        }
+        for (exchange, status) in exchanges.iter() {
+            if !status.connected {
+                problems.push(format!("{} is not connected", exchange));
+            } else if !status.is_ready() {
+                problems.push(format!("{} has {} of {} subscriptions", exchange, status.subscribed, status.subscriptions));
+            }
+        }
+
+        Self { live, ready: live && problems.is_empty(), problems, components, exchanges }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use jiff::Timestamp;
+
+    use super::*;
+
+    #[test]
+    fn test_health_report() {
+        let health = HealthRegistry::default();
+        health.set("worker/okex-ws-consumer", HealthStatus::Healthy);
+        let okex = ConnectionState::default();
+        okex.set_subscriptions(0, 1);
+        let connections = Connections::from([(Exchange::Okex, okex.clone())]);
+
+        let report = HealthReport::new(&health, &connections);
+        assert!(report.live);
+        assert!(!report.ready);
+        assert_eq!(report.problems, vec!["Okex is not connected".to_string()]);
+
+        okex.on_connected(Timestamp::UNIX_EPOCH);
+        let report = HealthReport::new(&health, &connections);
+        assert_eq!(report.problems, vec!["Okex has 0 of 1 subscriptions".to_string()]);
+
+        okex.set_subscriptions(1, 1);
+        let report = HealthReport::new(&health, &connections);
+        assert!(report.ready);
+
+        // An unhealthy adapter makes the server unready but it is still live
+        health.set("okex", HealthStatus::Unhealthy("authentication failed".to_string()));
+        let report = HealthReport::new(&health, &connections);
+        assert!(report.live);
+        assert!(!report.ready);
+
+        health.set("worker/okex-ws-consumer", HealthStatus::Unhealthy("exited".to_string()));
+        let report = HealthReport::new(&health, &connections);
+        assert!(!report.live);
+    }
+}
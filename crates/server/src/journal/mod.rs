@@ -0,0 +1,194 @@
+use std::path::Path;
+
+use common::{ArbitrageError, ArbitrageResult, Context, SharedRef, SpawnResult, Worker};
+use jiff::Timestamp;
+use models::{ArbitrageOpportunity, InternalMessage};
+use rusqlite::{params, Connection};
+use tokio::sync::broadcast::{error::{RecvError, TryRecvError}, Sender};
+
+use crate::metrics;
+
+mod query;
+
+pub use query::*;
+
+/// Opportunities written in a single transaction at most
+const MAX_BATCH_SIZE: usize = 500;
+
+/// Decimals are stored as text so that they keep their precision, times as microseconds since the epoch
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS opportunities (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        product TEXT NOT NULL,
+        buy_exchange TEXT NOT NULL,
+        sell_exchange TEXT NOT NULL,
+        buy_price TEXT NOT NULL,
+        sell_price TEXT NOT NULL,
+        size TEXT NOT NULL,
+        edge TEXT NOT NULL,
+        buy_book_age_millis INTEGER NOT NULL,
+        sell_book_age_millis INTEGER NOT NULL,
+        trigger_exchange TEXT NOT NULL,
+        exchange_time INTEGER NOT NULL,
+        received_time INTEGER NOT NULL,
+        processed_time INTEGER,
+        journaled_time INTEGER NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS opportunities_received_time ON opportunities (received_time);
+    CREATE INDEX IF NOT EXISTS opportunities_product ON opportunities (product, received_time);
+";
+
+
+/// Embedded SQLite database of the opportunities emitted by the order book manager
+#[derive(Clone)]
+pub struct Journal {
+    connection: SharedRef<Connection>,
+}
+
+impl Journal {
+    /// Opens the database, creating it and its tables if needed
+    pub fn open(path: &str) -> ArbitrageResult<Self> {
+        if let Some(directory) = Path::new(path).parent().filter(|directory| !directory.as_os_str().is_empty()) {
+            std::fs::create_dir_all(directory)
+                .map_err(|e| ArbitrageError::GenericError(format!("failed to create the journal directory {}: {}", directory.display(), e)))?;
+        }
+        let connection = Connection::open(path).map_err(journal_error)?;
+        // The journal is written by a single worker while it is queried, losing the last writes on a crash is fine
+        connection.pragma_update(None, "journal_mode", "WAL").map_err(journal_error)?;
+        connection.pragma_update(None, "synchronous", "NORMAL").map_err(journal_error)?;
+        Self::from_connection(connection)
+    }
+
+    fn from_connection(connection: Connection) -> ArbitrageResult<Self> {
+        connection.execute_batch(SCHEMA).map_err(journal_error)?;
+        Ok(Self { connection: SharedRef::new(connection) })
+    }
+
+    pub fn insert(&self, opportunities: &[ArbitrageOpportunity], journaled_time: Timestamp) -> ArbitrageResult<()> {
+        let mut connection = self.connection.lock();
+        let transaction = connection.transaction().map_err(journal_error)?;
+        {
+            let mut statement = transaction
+                .prepare_cached(
+                    "INSERT INTO opportunities (
+                        product, buy_exchange, sell_exchange, buy_price, sell_price, size, edge,
+                        buy_book_age_millis, sell_book_age_millis, trigger_exchange,
+                        exchange_time, received_time, processed_time, journaled_time
+                    ) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14)",
+                )
+                .map_err(journal_error)?;
+            for opportunity in opportunities {
+                let timestamps = &opportunity.timestamps;
+                statement
+                    .execute(params![
+                        opportunity.product.to_string(),
+                        exchange_name(&opportunity.buy_exchange),
+                        exchange_name(&opportunity.sell_exchange),
+                        opportunity.buy_price.to_string(),
+                        opportunity.sell_price.to_string(),
+                        opportunity.size.to_string(),
+                        opportunity.edge.to_string(),
+                        opportunity.buy_book_age_millis,
+                        opportunity.sell_book_age_millis,
+                        exchange_name(&opportunity.trigger_exchange),
+                        timestamps.exchange_time.as_microsecond(),
+                        timestamps.received_time.as_microsecond(),
+                        timestamps.processed_time.map(|time| time.as_microsecond()),
+                        journaled_time.as_microsecond(),
+                    ])
+                    .map_err(journal_error)?;
+            }
+        }
+        transaction.commit().map_err(journal_error)
+    }
+}
+
+pub fn journal_error(error: rusqlite::Error) -> ArbitrageError {
+    ArbitrageError::GenericError(format!("journal error: {}", error))
+}
+
+
+/// Writes the opportunities broadcast by the order book manager to the journal
+///
+/// Opportunities are written in batches of whatever was broadcast while the previous batch was written.
+#[derive(Clone)]
+pub struct JournalWriter {
+    context: Context,
+    journal: Journal,
+    broadcaster: Sender<InternalMessage>,
+}
+
+impl JournalWriter {
+    pub fn new(context: Context, journal: Journal, broadcaster: Sender<InternalMessage>) -> Self {
+        Self { context, journal, broadcaster }
+    }
+
+    async fn write(&self, opportunities: Vec<ArbitrageOpportunity>) {
+        let journal = self.journal.clone();
+        let count = opportunities.len();
+        let result = tokio::task::spawn_blocking(move || journal.insert(&opportunities, Timestamp::now())).await;
+        match result {
+            Ok(Ok(())) => log::debug!("journaled {} opportunities", count),
+            Ok(Err(e)) => {
+                metrics::JOURNAL_ERRORS.inc_by(count as u64);
+                log::error!("failed to journal {} opportunities: {}", count, e);
+            }
+            Err(e) => {
+                metrics::JOURNAL_ERRORS.inc_by(count as u64);
+                log::error!("journal writer task failed: {}", e);
+            }
+        }
+    }
+}
+
+impl Worker for JournalWriter {
+    fn name(&self) -> String {
+        self.context.name.clone()
+    }
+
+    fn spawn(&mut self) -> SpawnResult {
+        let writer = self.clone();
+
+        tokio::spawn(async move {
+            let mut receiver = writer.broadcaster.subscribe();
+            let mut app = writer.context.app.subscribe();
+            loop {
+                let message = tokio::select! {
+                    _ = app.recv() => {
+                        return Err(ArbitrageError::Exit);
+                    }
+
+                    message = receiver.recv() => message,
+                };
+
+                let mut batch = vec![];
+                let mut next = Some(message);
+                while let Some(message) = next.take() {
+                    match message {
+                        Ok(InternalMessage::ArbitrageOpportunity(arbitrage_opportunity)) => batch.push(arbitrage_opportunity),
+                        Ok(InternalMessage::OrderBookUpdate(_)) => {}
+                        Err(RecvError::Lagged(skipped)) => {
+                            metrics::JOURNAL_ERRORS.inc_by(skipped);
+                            log::warn!("journal writer lagging behind, {} opportunities were not journaled", skipped);
+                        }
+                        Err(RecvError::Closed) => {
+                            return Err(ArbitrageError::GenericError("broadcaster closed".to_string()));
+                        }
+                    }
+                    if batch.len() < MAX_BATCH_SIZE {
+                        next = match receiver.try_recv() {
+                            Ok(message) => Some(Ok(message)),
+                            Err(TryRecvError::Lagged(skipped)) => Some(Err(RecvError::Lagged(skipped))),
+                            Err(TryRecvError::Closed) => Some(Err(RecvError::Closed)),
+                            Err(TryRecvError::Empty) => None,
+                        };
+                    }
+                }
+
+                if !batch.is_empty() {
+                    writer.write(batch).await;
+                }
+            }
+        })
+    }
+}
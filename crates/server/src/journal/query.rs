@@ -0,0 +1,210 @@
+use std::str::FromStr;
+
+use common::ArbitrageResult;
+use jiff::Timestamp;
+use models::Exchange;
+use rust_decimal::Decimal;
+use rusqlite::{types::{FromSqlError, Type}, Row, ToSql};
+use serde::{Deserialize, Serialize};
+
+use super::{journal_error, Journal};
+
+/// Opportunities returned by a query when it does not set a limit
+pub const DEFAULT_QUERY_LIMIT: u32 = 1000;
+
+/// Opportunities returned by a query at most
+pub const MAX_QUERY_LIMIT: u32 = 10000;
+
+
+/// Filters of the journaled opportunities, all optional
+///
+/// e.g. `?from=2025-02-21T00:00:00Z&product=BTC-USD-250221-90000-P&buy_exchange=Okex&limit=100`
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+pub struct JournalQuery {
+    /// Opportunities received at or after this time
+    pub from: Option<Timestamp>,
+    /// Opportunities received before this time
+    pub to: Option<Timestamp>,
+    /// Product in its exchange agnostic format, e.g. `BTC-USD-250221-90000-P`
+    pub product: Option<String>,
+    pub buy_exchange: Option<Exchange>,
+    pub sell_exchange: Option<Exchange>,
+    /// Number of opportunities returned, the most recent first
+    pub limit: Option<u32>,
+}
+
+/// Opportunity as stored in the journal
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct JournaledOpportunity {
+    pub id: i64,
+    pub product: String,
+    pub buy_exchange: Exchange,
+    pub sell_exchange: Exchange,
+    pub buy_price: Decimal,
+    pub sell_price: Decimal,
+    pub size: Decimal,
+    pub edge: Decimal,
+    pub buy_book_age_millis: i64,
+    pub sell_book_age_millis: i64,
+    pub trigger_exchange: Exchange,
+    pub exchange_time: Timestamp,
+    pub received_time: Timestamp,
+    pub processed_time: Option<Timestamp>,
+    pub journaled_time: Timestamp,
+}
+
+
+impl Journal {
+    pub fn query(&self, query: &JournalQuery) -> ArbitrageResult<Vec<JournaledOpportunity>> {
+        let mut conditions = vec![];
+        let mut params: Vec<Box<dyn ToSql>> = vec![];
+        if let Some(from) = query.from {
+            conditions.push("received_time >= ?");
+            params.push(Box::new(from.as_microsecond()));
+        }
+        if let Some(to) = query.to {
+            conditions.push("received_time < ?");
+            params.push(Box::new(to.as_microsecond()));
+        }
+        if let Some(product) = query.product.as_ref() {
+            conditions.push("product = ?");
+            params.push(Box::new(product.clone()));
+        }
+        if let Some(buy_exchange) = query.buy_exchange.as_ref() {
+            conditions.push("buy_exchange = ?");
+            params.push(Box::new(exchange_name(buy_exchange)));
+        }
+        if let Some(sell_exchange) = query.sell_exchange.as_ref() {
+            conditions.push("sell_exchange = ?");
+            params.push(Box::new(exchange_name(sell_exchange)));
+        }
+        let filter = if conditions.is_empty() { String::new() } else { format!("WHERE {}", conditions.join(" AND ")) };
+        let limit = query.limit.unwrap_or(DEFAULT_QUERY_LIMIT).min(MAX_QUERY_LIMIT);
+        let sql = format!(
+            "SELECT id, product, buy_exchange, sell_exchange, buy_price, sell_price, size, edge,
+                buy_book_age_millis, sell_book_age_millis, trigger_exchange,
+                exchange_time, received_time, processed_time, journaled_time
+            FROM opportunities {} ORDER BY received_time DESC, id DESC LIMIT {}",
+            filter, limit
+        );
+
+        let connection = self.connection.lock();
+        let mut statement = connection.prepare(&sql).map_err(journal_error)?;
+        let rows = statement
+            .query_map(rusqlite::params_from_iter(params.iter()), journaled_opportunity)
+            .map_err(journal_error)?;
+        rows.collect::<Result<Vec<_>, _>>().map_err(journal_error)
+    }
+}
+
+fn journaled_opportunity(row: &Row) -> rusqlite::Result<JournaledOpportunity> {
+    Ok(JournaledOpportunity {
+        id: row.get(0)?,
+        product: row.get(1)?,
+        buy_exchange: exchange(row, 2)?,
+        sell_exchange: exchange(row, 3)?,
+        buy_price: decimal(row, 4)?,
+        sell_price: decimal(row, 5)?,
+        size: decimal(row, 6)?,
+        edge: decimal(row, 7)?,
+        buy_book_age_millis: row.get(8)?,
+        sell_book_age_millis: row.get(9)?,
+        trigger_exchange: exchange(row, 10)?,
+        exchange_time: timestamp(row, 11)?,
+        received_time: timestamp(row, 12)?,
+        processed_time: row.get::<_, Option<i64>>(13)?.map(|micros| to_timestamp(13, micros)).transpose()?,
+        journaled_time: timestamp(row, 14)?,
+    })
+}
+
+/// Name of the exchange in the journal
+pub fn exchange_name(exchange: &Exchange) -> String {
+    format!("{:?}", exchange)
+}
+
+fn exchange(row: &Row, index: usize) -> rusqlite::Result<Exchange> {
+    let name = row.get::<_, String>(index)?;
+    match name.as_str() {
+        "Okex" => Ok(Exchange::Okex),
+        "Deribit" => Ok(Exchange::Deribit),
+        _ => Err(conversion_error(index, format!("unknown exchange {:?}", name))),
+    }
+}
+
+fn decimal(row: &Row, index: usize) -> rusqlite::Result<Decimal> {
+    let text = row.get::<_, String>(index)?;
+    Decimal::from_str(&text).map_err(|e| conversion_error(index, e.to_string()))
+}
+
+fn timestamp(row: &Row, index: usize) -> rusqlite::Result<Timestamp> {
+    to_timestamp(index, row.get(index)?)
+}
+
+fn to_timestamp(index: usize, micros: i64) -> rusqlite::Result<Timestamp> {
+    Timestamp::from_microsecond(micros).map_err(|e| conversion_error(index, e.to_string()))
+}
+
+fn conversion_error(index: usize, message: String) -> rusqlite::Error {
+    rusqlite::Error::FromSqlConversionFailure(index, Type::Text, Box::new(FromSqlError::Other(message.into())))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use models::{ArbitrageOpportunity, PipelineTimestamps, Product};
+    use rusqlite::Connection;
+    use rust_decimal_macros::dec;
+
+    use super::*;
+
+    fn opportunity(product: &str, buy_exchange: Exchange, sell_exchange: Exchange, received_millis: i64) -> ArbitrageOpportunity {
+        let received_time = Timestamp::from_millisecond(received_millis).unwrap();
+        let mut timestamps = PipelineTimestamps::new(Timestamp::from_millisecond(received_millis - 5).unwrap(), received_time);
+        timestamps.processed_time = Some(received_time);
+        ArbitrageOpportunity {
+            product: Product::from_okex_exhchange(product).unwrap(),
+            buy_exchange: buy_exchange.clone(),
+            sell_exchange,
+            buy_price: dec!(0.0100),
+            sell_price: dec!(0.0120),
+            size: dec!(2),
+            edge: dec!(0.0020),
+            buy_book_age_millis: 100,
+            sell_book_age_millis: 0,
+            trigger_exchange: buy_exchange,
+            timestamps,
+        }
+    }
+
+    #[test]
+    fn test_insert_and_query() {
+        let journal = Journal::from_connection(Connection::open_in_memory().unwrap()).unwrap();
+        let opportunities = [
+            opportunity("BTC-USD-250221-90000-P", Exchange::Okex, Exchange::Deribit, 1000),
+            opportunity("BTC-USD-250221-90000-P", Exchange::Deribit, Exchange::Okex, 2000),
+            opportunity("BTC-USD-250221-95000-C", Exchange::Okex, Exchange::Deribit, 3000),
+        ];
+        journal.insert(&opportunities, Timestamp::from_millisecond(4000).unwrap()).unwrap();
+
+        let all = journal.query(&JournalQuery::default()).unwrap();
+        assert_eq!(all.iter().map(|opportunity| opportunity.id).collect::<Vec<_>>(), vec![3, 2, 1]);
+        let first = &all[2];
+        assert_eq!(first.product, "BTC-USD-250221-90000-P");
+        assert_eq!((first.buy_price, first.edge), (dec!(0.0100), dec!(0.0020)));
+        assert_eq!(first.exchange_time, Timestamp::from_millisecond(995).unwrap());
+        assert_eq!(first.processed_time, Some(Timestamp::from_millisecond(1000).unwrap()));
+
+        let query = JournalQuery { product: Some("BTC-USD-250221-90000-P".to_string()), ..JournalQuery::default() };
+        assert_eq!(journal.query(&query).unwrap().len(), 2);
+        let query = JournalQuery { buy_exchange: Some(Exchange::Okex), sell_exchange: Some(Exchange::Deribit), ..JournalQuery::default() };
+        assert_eq!(journal.query(&query).unwrap().iter().map(|opportunity| opportunity.id).collect::<Vec<_>>(), vec![3, 1]);
+        let query = JournalQuery {
+            from: Some(Timestamp::from_millisecond(2000).unwrap()),
+            to: Some(Timestamp::from_millisecond(3000).unwrap()),
+            ..JournalQuery::default()
+        };
+        assert_eq!(journal.query(&query).unwrap().iter().map(|opportunity| opportunity.id).collect::<Vec<_>>(), vec![2]);
+        let query = JournalQuery { limit: Some(1), ..JournalQuery::default() };
+        assert_eq!(journal.query(&query).unwrap().len(), 1);
+    }
+}
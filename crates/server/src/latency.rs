@@ -0,0 +1,107 @@
+use std::collections::HashMap;
+
+use common::{Histogram, SharedRef};
+use jiff::Timestamp;
+use models::Exchange;
+use serde::Serialize;
+
+/// Stages of the pipeline an update goes through
+#[derive(Debug, Clone, Copy, Hash, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LatencyStage {
+    /// From the exchange event time to the local receive time, includes clock skew
+    Network,
+    /// From the local receive time until the order book manager processed the update
+    Processing,
+    /// From the order book manager until the opportunity was sent to a client
+    Delivery,
+    /// From the exchange event time until the opportunity was sent to a client
+    EndToEnd,
+}
+
+impl LatencyStage {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LatencyStage::Network => "network",
+            LatencyStage::Processing => "processing",
+            LatencyStage::Delivery => "delivery",
+            LatencyStage::EndToEnd => "end_to_end",
+        }
+    }
+}
+
+/// Latency percentiles of a stage for an exchange
+#[derive(Debug, Clone, Serialize)]
+pub struct LatencyReport {
+    pub exchange: Exchange,
+    pub stage: LatencyStage,
+    pub count: u64,
+    pub p50_micros: u64,
+    pub p99_micros: u64,
+}
+
+/// Latency histograms per exchange and pipeline stage, shared between the workers
+#[derive(Clone, Default)]
+pub struct LatencyTracker {
+    histograms: SharedRef<HashMap<(Exchange, LatencyStage), Histogram>>,
+}
+
+impl LatencyTracker {
+    /// Records the time elapsed between `from` and `to` for a stage.
+    /// Negative values, which can happen due to clock skew, are recorded as 0.
+    pub fn record(&self, exchange: &Exchange, stage: LatencyStage, from: Timestamp, to: Timestamp) {
+        let micros = to.duration_since(from).as_micros().max(0) as u64;
+        let mut histograms = self.histograms.lock();
+        match histograms.get_mut(&(exchange.clone(), stage)) {
+            Some(histogram) => histogram.record(micros),
+            None => {
+                let mut histogram = Histogram::default();
+                histogram.record(micros);
+                histograms.insert((exchange.clone(), stage), histogram);
+            }
+        }
+    }
+
+    pub fn report(&self) -> Vec<LatencyReport> {
+        let histograms = self.histograms.lock();
+        let mut reports = histograms
+            .iter()
+            .map(|((exchange, stage), histogram)| LatencyReport {
+                exchange: exchange.clone(),
+                stage: *stage,
+                count: histogram.count(),
+                p50_micros: histogram.quantile(0.5).unwrap_or_default(),
+                p99_micros: histogram.quantile(0.99).unwrap_or_default(),
+            })
+            .collect::<Vec<LatencyReport>>();
+        reports.sort_by_key(|report| (format!("{:?}", report.exchange), report.stage as u8));
+        reports
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use jiff::SignedDuration;
+
+    use super::*;
+
+    #[test]
+    fn test_report() {
+        let tracker = LatencyTracker::default();
+        let now = Timestamp::now();
+        tracker.record(&Exchange::Okex, LatencyStage::Network, now, now + SignedDuration::from_millis(10));
+        tracker.record(&Exchange::Okex, LatencyStage::Network, now, now - SignedDuration::from_millis(10));
+        tracker.record(&Exchange::Deribit, LatencyStage::Processing, now, now + SignedDuration::from_micros(100));
+
+        let report = tracker.report();
+        assert_eq!(report.len(), 2);
+        assert_eq!(report[0].exchange, Exchange::Deribit);
+        assert_eq!(report[0].stage, LatencyStage::Processing);
+        assert_eq!(report[0].count, 1);
+        assert_eq!(report[1].exchange, Exchange::Okex);
+        assert_eq!(report[1].count, 2);
+        assert_eq!(report[1].p50_micros, 0);
+        assert!(report[1].p99_micros >= 10_000);
+    }
+}
@@ -0,0 +1,20 @@
+pub mod adapters;
+pub mod runner;
+pub mod manager;
+pub mod endpoint;
+pub mod websocket;
+pub mod stream;
+pub mod books;
+pub mod health;
+pub mod latency;
+pub mod metrics;
+pub mod settings;
+pub mod reload;
+pub mod cli;
+pub mod check;
+pub mod replay;
+pub mod backtest;
+pub mod journal;
+pub mod execution;
+pub mod greeks;
+pub mod funding;
@@ -1,13 +1,9 @@
-use common::run_app;
-use runner::ServerRunner;
+use std::process::ExitCode;
 
-mod adapters;
-mod runner;
-mod manager;
-mod endpoint;
-mod websocket;
+use clap::Parser;
+use server::cli::Cli;
 
 
-fn main() {
-    run_app(ServerRunner::default());
+fn main() -> ExitCode {
+    Cli::parse().run()
 }
@@ -1,73 +1,199 @@
-use std::{cmp::min, collections::HashMap};
+use std::cmp::min;
 
-use common::{ArbitrageError, Context, MpSc, Worker};
-use models::{ArbitrageOpportunity, Exchange, ExchangeProduct, InternalMessage, OrderBook, Product};
+use common::{ArbitrageError, Context, MpSc, SharedReceiver, SharedRef, Worker};
+use jiff::Timestamp;
+use models::{ArbitrageOpportunity, Exchange, ExchangeProduct, InternalMessage, OrderBook, OrderBookUpdate, PipelineTimestamps};
 use rust_decimal::Decimal;
 use tokio::sync::broadcast::Sender;
 
+use crate::{books::OrderBooks, funding::Funding, latency::{LatencyStage, LatencyTracker}, metrics, settings::ManagerConfig};
+
 #[derive(Clone)]
 pub struct OrderBookManager {
     context: Context,
-    order_books: HashMap<ExchangeProduct, OrderBook>,
-    producer: MpSc<InternalMessage>,
+    /// Shared with the endpoint, which serves the books to the clients
+    order_books: OrderBooks,
+    receiver: SharedReceiver<InternalMessage>,
     broadcaster: Sender<InternalMessage>,
+    /// Updates of the books, kept off `broadcaster` so that they never crowd out the opportunities
+    book_broadcaster: Option<Sender<OrderBookUpdate>>,
+    latency: LatencyTracker,
+    /// Shared with the config watcher which swaps it on reload
+    config: SharedRef<ManagerConfig>,
+    /// Caps the sizes of the opportunities when set
+    funding: Option<Funding>,
 }
 
 
 impl OrderBookManager {
-    pub fn new(context: Context, producer: MpSc<InternalMessage>, broadcaster: Sender<InternalMessage>) -> Self {
-        Self { context, order_books: HashMap::new(), producer, broadcaster }
+    /// Create a new OrderBookManager from the `manager` section of the configuration
+    pub fn new(
+        context: Context,
+        config: SharedRef<ManagerConfig>,
+        mut producer: MpSc<InternalMessage>,
+        broadcaster: Sender<InternalMessage>,
+        latency: LatencyTracker,
+    ) -> Self {
+        let receiver = producer.shared_receiver().expect("internal message receiver should not be taken");
+        Self { context, order_books: OrderBooks::default(), receiver, broadcaster, book_broadcaster: None, latency, config, funding: None }
+    }
+
+    /// Broadcasts every update applied to the books, for the workers acting on the opportunities to keep their own
+    /// copy of the books
+    pub fn with_book_broadcaster(mut self, book_broadcaster: Sender<OrderBookUpdate>) -> Self {
+        self.book_broadcaster = Some(book_broadcaster);
+        self
+    }
+
+    /// Caps the size of the opportunities by the funds available on the exchange of each leg,
+    /// dropping those which a leg can not fund
+    pub fn with_funding(mut self, funding: Funding) -> Self {
+        self.funding = Some(funding);
+        self
+    }
+
+    pub fn order_book(&self, exchange_product: &ExchangeProduct) -> Option<OrderBook> {
+        self.order_books.book(exchange_product)
+    }
+
+    pub fn order_books(&self) -> OrderBooks {
+        self.order_books.clone()
+    }
+
+    /// Applies the update to its order book and checks the product for an arbitrage opportunity
+    pub fn process(&mut self, order_book_update: OrderBookUpdate) -> Option<ArbitrageOpportunity> {
+        let exchange_product = order_book_update.exchange_product.clone();
+        let timestamps = order_book_update.timestamps.clone();
+        self.order_books.apply(order_book_update);
+
+        let mut arbitrage_opportunity = self.check_arbitrage_opportunities(&exchange_product, &timestamps);
+
+        let processed_time = Timestamp::now();
+        let exchange = &exchange_product.exchange;
+        self.latency.record(exchange, LatencyStage::Network, timestamps.exchange_time, timestamps.received_time);
+        self.latency.record(exchange, LatencyStage::Processing, timestamps.received_time, processed_time);
+        if let Some(arbitrage_opportunity) = arbitrage_opportunity.as_mut() {
+            arbitrage_opportunity.timestamps.processed_time = Some(processed_time);
+        }
+        arbitrage_opportunity
     }
 
-    pub fn check_arbitrage_opportunities(&self, product: &Product) -> Option<ArbitrageOpportunity> {
+    /// Checks for an arbitrage opportunity on the product of the update which triggered the check.
+    ///
+    /// The receive time of the update is used as the current time, instead of the wall clock,
+    /// so that the outcome only depends on the updates.
+    pub fn check_arbitrage_opportunities(&self, trigger: &ExchangeProduct, timestamps: &PipelineTimestamps) -> Option<ArbitrageOpportunity> {
+        let product = &trigger.product;
+        let now = timestamps.received_time;
 
         // This can be made more efficient instead of hardcoding for two exchanges.
         // It can be made generic for any number of exchanges. However, it would make the code more complex.
         // For the purpose of this project, it is fine to keep it as is.
-        let okex_order_book = self.order_books
+        let order_books = self.order_books.lock();
+        let okex_order_book = order_books
             .get(&ExchangeProduct { exchange: Exchange::Okex, product: product.clone() })?;
-        let deribit_order_book = self.order_books.get(&ExchangeProduct { exchange: Exchange::Deribit, product: product.clone() })?;
+        let deribit_order_book = order_books.get(&ExchangeProduct { exchange: Exchange::Deribit, product: product.clone() })?;
+
+        // A stale book on either side is the most common source of false positives
+        let okex_book_age_millis = self.fresh_book_age_millis(okex_order_book, now)?;
+        let deribit_book_age_millis = self.fresh_book_age_millis(deribit_order_book, now)?;
 
         if let (Some(okex_best_ask), Some(deribit_best_bid)) = (okex_order_book.best_ask(), deribit_order_book.best_bid()) {
-            let profit = deribit_best_bid.0 - okex_best_ask.0;
-            if profit > Decimal::ZERO {
-                return Some(ArbitrageOpportunity {
+            let edge = self.edge(&Exchange::Okex, okex_best_ask.0, &Exchange::Deribit, deribit_best_bid.0);
+            if self.is_worth_it(edge) {
+                let arbitrage_opportunity = self.funded(ArbitrageOpportunity {
                     product: product.clone(),
                     buy_exchange: Exchange::Okex,
                     sell_exchange: Exchange::Deribit,
                     buy_price: okex_best_ask.0,
                     sell_price: deribit_best_bid.0,
                     size: min(okex_best_ask.1, deribit_best_bid.1),
+                    edge,
+                    buy_book_age_millis: okex_book_age_millis,
+                    sell_book_age_millis: deribit_book_age_millis,
+                    trigger_exchange: trigger.exchange.clone(),
+                    timestamps: timestamps.clone(),
                 });
+                if arbitrage_opportunity.is_some() {
+                    return arbitrage_opportunity;
+                }
             }
         }
 
         if let (Some(okex_best_bid), Some(deribit_best_ask)) = (okex_order_book.best_bid(), deribit_order_book.best_ask()) {
-            let profit = okex_best_bid.0 - deribit_best_ask.0;
-            if profit > Decimal::ZERO {
-                return Some(ArbitrageOpportunity {
+            let edge = self.edge(&Exchange::Deribit, deribit_best_ask.0, &Exchange::Okex, okex_best_bid.0);
+            if self.is_worth_it(edge) {
+                let arbitrage_opportunity = self.funded(ArbitrageOpportunity {
                     product: product.clone(),
                     buy_exchange: Exchange::Deribit,
                     sell_exchange: Exchange::Okex,
                     buy_price: deribit_best_ask.0,
                     sell_price: okex_best_bid.0,
                     size: min(okex_best_bid.1, deribit_best_ask.1),
+                    edge,
+                    buy_book_age_millis: deribit_book_age_millis,
+                    sell_book_age_millis: okex_book_age_millis,
+                    trigger_exchange: trigger.exchange.clone(),
+                    timestamps: timestamps.clone(),
                 });
+                if arbitrage_opportunity.is_some() {
+                    return arbitrage_opportunity;
+                }
             }
         }
 
         None
 
     }
+
+    /// Caps the size of the opportunity by the funds of its legs, None if a leg can not be funded
+    fn funded(&self, mut arbitrage_opportunity: ArbitrageOpportunity) -> Option<ArbitrageOpportunity> {
+        let Some(funding) = self.funding.as_ref() else {
+            return Some(arbitrage_opportunity);
+        };
+        let fees = self.config.lock().fees.clone();
+        match funding.cap(&mut arbitrage_opportunity, &fees) {
+            Ok(()) => Some(arbitrage_opportunity),
+            Err(exchange) => {
+                log::debug!("dropping opportunity on {} as {:?} can not fund it", arbitrage_opportunity.product, exchange);
+                metrics::UNFUNDED_OPPORTUNITIES.with_label_values(&[&format!("{:?}", exchange)]).inc();
+                None
+            }
+        }
+    }
+
+    /// Profit per unit of buying and selling at the given prices, net of the fees of both legs
+    fn edge(&self, buy_exchange: &Exchange, buy_price: Decimal, sell_exchange: &Exchange, sell_price: Decimal) -> Decimal {
+        let fees = &self.config.lock().fees;
+        sell_price * (Decimal::ONE - fees.rate(sell_exchange)) - buy_price * (Decimal::ONE + fees.rate(buy_exchange))
+    }
+
+    fn is_worth_it(&self, edge: Decimal) -> bool {
+        edge > Decimal::ZERO && edge >= self.config.lock().min_edge
+    }
+
+    fn fresh_book_age_millis(&self, order_book: &OrderBook, now: Timestamp) -> Option<i64> {
+        let age_millis = order_book.age_millis(now)?;
+        if age_millis > self.config.lock().max_book_age_millis {
+            log::debug!("skipping stale book {:?}, last update received {} ms ago", order_book.exchange_product, age_millis);
+            return None;
+        }
+        Some(age_millis)
+    }
 }
 
 
 impl Worker for OrderBookManager {
+    fn name(&self) -> String {
+        self.context.name.clone()
+    }
+
     fn spawn(&mut self) -> common::SpawnResult {
         let mut order_book_manager = self.clone();
-        let mut receiver = self.producer.receiver().unwrap();
 
         tokio::spawn(async move {
+            // Held for as long as this task runs, a restarted manager takes it over
+            let mut receiver = order_book_manager.receiver.clone().lock_owned().await;
             let mut app = order_book_manager.context.app.subscribe();
             loop {
                 tokio::select! {
@@ -76,24 +202,31 @@ impl Worker for OrderBookManager {
                     }
 
                     result = receiver.recv() => {
+                        metrics::INTERNAL_QUEUE_DEPTH.set(receiver.len() as i64);
                         match result {
                             Some(InternalMessage::OrderBookUpdate(order_book_update)) => {
-                                // Entry api for rust hashmap creates a new copy of the key even it already exists
-                                // hence we try to avoid it.
-                                let order_book = match order_book_manager.order_books.get_mut(&order_book_update.exchange_product) {
-                                    Some(order_book) => order_book,
-                                    None => {
-                                        order_book_manager.order_books.insert(order_book_update.exchange_product.clone(), OrderBook::new(&order_book_update.exchange_product));
-                                        order_book_manager.order_books.get_mut(&order_book_update.exchange_product).unwrap()
+                                let arbitrage_opportunity = match order_book_manager.book_broadcaster.clone() {
+                                    Some(book_broadcaster) => {
+                                        let arbitrage_opportunity = order_book_manager.process(order_book_update.clone());
+                                        // Sent ahead of the opportunity it triggered. Nobody may be listening.
+                                        let _ = book_broadcaster.send(order_book_update);
+                                        arbitrage_opportunity
                                     }
+                                    None => order_book_manager.process(order_book_update),
                                 };
-                                let product = order_book_update.exchange_product.product.clone();
-                                order_book.update(order_book_update);
-                                let arbitrage_opportunity = order_book_manager.check_arbitrage_opportunities(&product);
                                 if let Some(arbitrage_opportunity) = arbitrage_opportunity {
                                     log::info!("arbitrage opportunity: {:?}", arbitrage_opportunity);
+                                    metrics::OPPORTUNITIES
+                                        .with_label_values(&[
+                                            &arbitrage_opportunity.product.to_string(),
+                                            &format!("{:?}", arbitrage_opportunity.buy_exchange),
+                                            &format!("{:?}", arbitrage_opportunity.sell_exchange),
+                                        ])
+                                        .inc();
                                     match order_book_manager.broadcaster.send(InternalMessage::ArbitrageOpportunity(arbitrage_opportunity)) {
-                                        Ok(_) => {}
+                                        Ok(_) => {
+                                            metrics::BROADCAST_QUEUE_DEPTH.set(order_book_manager.broadcaster.len() as i64);
+                                        }
                                         Err(e) => {
                                             log::error!("error sending arbitrage opportunity to broadcaster: {:?}", e);
                                         }
@@ -123,25 +256,27 @@ mod tests {
     use std::str::FromStr;
     use chrono::NaiveDate;
     use config::Config;
-    use models::{Exchange, ExchangeProduct, OrderBookUpdate, Product};
+    use jiff::{SignedDuration, Timestamp};
+    use models::{Exchange, ExchangeProduct, OrderBookUpdate, PipelineTimestamps, Product};
     use rust_decimal::Decimal;
     use rust_decimal_macros::dec;
     use tokio::sync::broadcast;
+    use crate::settings::{BalancesConfig, FundingConfig, FundingSource};
     use super::*;
 
     fn setup_order_book_manager(product: Product) -> OrderBookManager {
         let context = Context::from_config(Config::default());
         let producer = MpSc::new(100);
         let (broadcaster, _) = broadcast::channel(100);
-        let mut order_book_manager = OrderBookManager::new(context, producer, broadcaster);
+        let order_book_manager = OrderBookManager::new(context, SharedRef::default(), producer, broadcaster, LatencyTracker::default());
 
         // Setup Order Book for Okex
         let okex_order_book = OrderBook::new(&ExchangeProduct { exchange: Exchange::Okex, product: product.clone() });
-        order_book_manager.order_books.insert(ExchangeProduct { exchange: Exchange::Okex, product: product.clone() }, okex_order_book);
+        order_book_manager.order_books.lock().insert(ExchangeProduct { exchange: Exchange::Okex, product: product.clone() }, okex_order_book);
 
         // Setup Order Book for Deribit
         let deribit_order_book = OrderBook::new(&ExchangeProduct { exchange: Exchange::Deribit, product: product.clone() });
-        order_book_manager.order_books.insert(ExchangeProduct { exchange: Exchange::Deribit, product: product.clone() }, deribit_order_book);
+        order_book_manager.order_books.lock().insert(ExchangeProduct { exchange: Exchange::Deribit, product: product.clone() }, deribit_order_book);
 
         order_book_manager
     }
@@ -156,30 +291,138 @@ mod tests {
             expiration: NaiveDate::from_ymd_opt(2025, 2, 21).unwrap(),
         };
 
-        let mut order_book_manager = setup_order_book_manager(product.clone());
+        let order_book_manager = setup_order_book_manager(product.clone());
+        let now = Timestamp::now();
 
         // Setup Order Book for Okex
-        let okex_order_book = order_book_manager.order_books.get_mut(&ExchangeProduct { exchange: Exchange::Okex, product: product.clone() }).unwrap();
+        let mut order_books = order_book_manager.order_books.lock();
+        let okex_order_book = order_books.get_mut(&ExchangeProduct { exchange: Exchange::Okex, product: product.clone() }).unwrap();
         okex_order_book.update(OrderBookUpdate {
             exchange_product: ExchangeProduct { exchange: Exchange::Okex, product: product.clone() },
             bids: vec![(dec!(0.018), dec!(5400)), (dec!(0.019), dec!(1000))],
             asks: vec![(dec!(0.015), dec!(1000)), (dec!(0.021), dec!(5400))],
+            timestamps: PipelineTimestamps::new(now, now),
         });
 
-        let deribit_order_book = order_book_manager.order_books.get_mut(&ExchangeProduct { exchange: Exchange::Deribit, product: product.clone() }).unwrap();
+        let deribit_order_book = order_books.get_mut(&ExchangeProduct { exchange: Exchange::Deribit, product: product.clone() }).unwrap();
         deribit_order_book.update(OrderBookUpdate {
             exchange_product: ExchangeProduct { exchange: Exchange::Deribit, product: product.clone() },
             bids: vec![(dec!(0.018), dec!(5400)), (dec!(0.019), dec!(1000))],
             asks: vec![(dec!(0.020), dec!(1000)), (dec!(0.021), dec!(5400))],
+            timestamps: PipelineTimestamps::new(now, now),
         });
 
-        let arbitrage_opportunity = order_book_manager.check_arbitrage_opportunities(&product).unwrap();
+        drop(order_books);
+        let arbitrage_opportunity = order_book_manager.check_arbitrage_opportunities(&ExchangeProduct { exchange: Exchange::Okex, product: product.clone() }, &PipelineTimestamps::new(now, now)).unwrap();
         assert_eq!(arbitrage_opportunity.buy_exchange, Exchange::Okex);
         assert_eq!(arbitrage_opportunity.sell_exchange, Exchange::Deribit);
         assert_eq!(arbitrage_opportunity.product, product);
         assert_eq!(arbitrage_opportunity.buy_price, dec!(0.015));
         assert_eq!(arbitrage_opportunity.sell_price, dec!(0.019));
         assert_eq!(arbitrage_opportunity.size, dec!(1000));
+        assert_eq!(arbitrage_opportunity.buy_book_age_millis, 0);
+        assert_eq!(arbitrage_opportunity.sell_book_age_millis, 0);
+    }
+
+    /// Okex asks 0.015 and Deribit bids 0.019 for 1000, an edge of 0.004 before the fees
+    fn setup_okex_buy_deribit_sell(product: &Product, now: Timestamp) -> OrderBookManager {
+        let order_book_manager = setup_order_book_manager(product.clone());
+        order_book_manager.order_books.apply(OrderBookUpdate {
+            exchange_product: ExchangeProduct { exchange: Exchange::Okex, product: product.clone() },
+            bids: vec![(dec!(0.014), dec!(1000))],
+            asks: vec![(dec!(0.015), dec!(1000))],
+            timestamps: PipelineTimestamps::new(now, now),
+        });
+        order_book_manager.order_books.apply(OrderBookUpdate {
+            exchange_product: ExchangeProduct { exchange: Exchange::Deribit, product: product.clone() },
+            bids: vec![(dec!(0.019), dec!(1000))],
+            asks: vec![(dec!(0.020), dec!(1000))],
+            timestamps: PipelineTimestamps::new(now, now),
+        });
+        order_book_manager
+    }
+
+    #[test]
+    fn test_arbitrage_edge_net_of_fees() {
+        let product = Product::Option {
+            underlying: models::CryptoAsset::BTC,
+            settlement: models::SettlementAsset::USD,
+            strike: Decimal::from_str("90000").unwrap(),
+            option_type: models::OptionType::Call,
+            expiration: NaiveDate::from_ymd_opt(2025, 2, 21).unwrap(),
+        };
+        let now = Timestamp::now();
+        let trigger = ExchangeProduct { exchange: Exchange::Okex, product: product.clone() };
+        let timestamps = PipelineTimestamps::new(now, now);
+
+        let order_book_manager = setup_okex_buy_deribit_sell(&product, now);
+        let arbitrage_opportunity = order_book_manager.check_arbitrage_opportunities(&trigger, &timestamps).unwrap();
+        assert_eq!(arbitrage_opportunity.edge, dec!(0.004));
+
+        // 0.1 of 0.015 and of 0.019 eat most of the edge
+        order_book_manager.config.lock().fees.okex = dec!(0.1);
+        order_book_manager.config.lock().fees.deribit = dec!(0.1);
+        let arbitrage_opportunity = order_book_manager.check_arbitrage_opportunities(&trigger, &timestamps).unwrap();
+        assert_eq!(arbitrage_opportunity.edge, dec!(0.0006));
+
+        // and all of it once they are higher
+        order_book_manager.config.lock().fees.deribit = dec!(0.2);
+        assert!(order_book_manager.check_arbitrage_opportunities(&trigger, &timestamps).is_none());
+    }
+
+    #[test]
+    fn test_arbitrage_rejects_edge_below_min_edge() {
+        let product = Product::Option {
+            underlying: models::CryptoAsset::BTC,
+            settlement: models::SettlementAsset::USD,
+            strike: Decimal::from_str("90000").unwrap(),
+            option_type: models::OptionType::Call,
+            expiration: NaiveDate::from_ymd_opt(2025, 2, 21).unwrap(),
+        };
+        let now = Timestamp::now();
+        let trigger = ExchangeProduct { exchange: Exchange::Okex, product: product.clone() };
+        let timestamps = PipelineTimestamps::new(now, now);
+
+        let order_book_manager = setup_okex_buy_deribit_sell(&product, now);
+        order_book_manager.config.lock().min_edge = dec!(0.004);
+        let arbitrage_opportunity = order_book_manager.check_arbitrage_opportunities(&trigger, &timestamps).unwrap();
+        assert_eq!(arbitrage_opportunity.edge, dec!(0.004));
+
+        order_book_manager.config.lock().min_edge = dec!(0.0041);
+        assert!(order_book_manager.check_arbitrage_opportunities(&trigger, &timestamps).is_none());
+    }
+
+    #[test]
+    fn test_arbitrage_size_capped_by_funding() {
+        let product = Product::Option {
+            underlying: models::CryptoAsset::BTC,
+            settlement: models::SettlementAsset::USD,
+            strike: Decimal::from_str("90000").unwrap(),
+            option_type: models::OptionType::Call,
+            expiration: NaiveDate::from_ymd_opt(2025, 2, 21).unwrap(),
+        };
+        let now = Timestamp::now();
+        let trigger = ExchangeProduct { exchange: Exchange::Okex, product: product.clone() };
+        let timestamps = PipelineTimestamps::new(now, now);
+
+        let order_book_manager = setup_okex_buy_deribit_sell(&product, now);
+        order_book_manager.config.lock().fees.okex = dec!(0.1);
+        order_book_manager.config.lock().fees.deribit = dec!(0.1);
+
+        // Okex pays the premium and fee of 1.8, Deribit the margin and fee of 1.9
+        let balances = BalancesConfig { okex: dec!(0.03), deribit: dec!(0.3) };
+        let funding = Funding::new(&FundingConfig { source: FundingSource::Static, balances, ..FundingConfig::default() });
+        let order_book_manager = order_book_manager.with_funding(funding.clone());
+        let arbitrage_opportunity = order_book_manager.check_arbitrage_opportunities(&trigger, &timestamps).unwrap();
+        assert_eq!(arbitrage_opportunity.size, dec!(1.8));
+
+        funding.set(&Exchange::Okex, &models::CryptoAsset::BTC, dec!(100));
+        let arbitrage_opportunity = order_book_manager.check_arbitrage_opportunities(&trigger, &timestamps).unwrap();
+        assert_eq!(arbitrage_opportunity.size, dec!(1.9));
+
+        // Not even a lot can be sold on Deribit
+        funding.set(&Exchange::Deribit, &models::CryptoAsset::BTC, dec!(0.01));
+        assert!(order_book_manager.check_arbitrage_opportunities(&trigger, &timestamps).is_none());
     }
 
     #[test]
@@ -192,23 +435,28 @@ mod tests {
             expiration: NaiveDate::from_ymd_opt(2025, 2, 21).unwrap(),
         };
 
-        let mut order_book_manager = setup_order_book_manager(product.clone());
+        let order_book_manager = setup_order_book_manager(product.clone());
+        let now = Timestamp::now();
 
-        let deribit_order_book = order_book_manager.order_books.get_mut(&ExchangeProduct { exchange: Exchange::Deribit, product: product.clone() }).unwrap();
+        let mut order_books = order_book_manager.order_books.lock();
+        let deribit_order_book = order_books.get_mut(&ExchangeProduct { exchange: Exchange::Deribit, product: product.clone() }).unwrap();
         deribit_order_book.update(OrderBookUpdate {
             exchange_product: ExchangeProduct { exchange: Exchange::Deribit, product: product.clone() },
             bids: vec![(dec!(0.018), dec!(5400)), (dec!(0.019), dec!(1000))],
             asks: vec![(dec!(0.015), dec!(1000)), (dec!(0.021), dec!(5400))],
+            timestamps: PipelineTimestamps::new(now, now),
         });
 
-        let okex_order_book = order_book_manager.order_books.get_mut(&ExchangeProduct { exchange: Exchange::Okex, product: product.clone() }).unwrap();
+        let okex_order_book = order_books.get_mut(&ExchangeProduct { exchange: Exchange::Okex, product: product.clone() }).unwrap();
         okex_order_book.update(OrderBookUpdate {
             exchange_product: ExchangeProduct { exchange: Exchange::Okex, product: product.clone() },
             bids: vec![(dec!(0.018), dec!(5400)), (dec!(0.019), dec!(1000))],
             asks: vec![(dec!(0.020), dec!(1000)), (dec!(0.021), dec!(5400))],
+            timestamps: PipelineTimestamps::new(now, now),
         });
 
-        let arbitrage_opportunity = order_book_manager.check_arbitrage_opportunities(&product).unwrap();
+        drop(order_books);
+        let arbitrage_opportunity = order_book_manager.check_arbitrage_opportunities(&ExchangeProduct { exchange: Exchange::Okex, product: product.clone() }, &PipelineTimestamps::new(now, now)).unwrap();
         assert_eq!(arbitrage_opportunity.buy_exchange, Exchange::Deribit);
         assert_eq!(arbitrage_opportunity.sell_exchange, Exchange::Okex);
         assert_eq!(arbitrage_opportunity.product, product);
@@ -217,6 +465,88 @@ mod tests {
         assert_eq!(arbitrage_opportunity.size, dec!(1000));
     }
 
+    #[test]
+    fn test_arbitrage_skips_stale_books() {
+        let product = Product::Option {
+            underlying: models::CryptoAsset::BTC,
+            settlement: models::SettlementAsset::USD,
+            strike: Decimal::from_str("90000").unwrap(),
+            option_type: models::OptionType::Call,
+            expiration: NaiveDate::from_ymd_opt(2025, 2, 21).unwrap(),
+        };
+
+        let order_book_manager = setup_order_book_manager(product.clone());
+        let now = Timestamp::now();
+        let max_book_age_millis = order_book_manager.config.lock().max_book_age_millis;
+        let deribit_received_time = now - SignedDuration::from_millis(max_book_age_millis);
+
+        let mut order_books = order_book_manager.order_books.lock();
+        let okex_order_book = order_books.get_mut(&ExchangeProduct { exchange: Exchange::Okex, product: product.clone() }).unwrap();
+        okex_order_book.update(OrderBookUpdate {
+            exchange_product: ExchangeProduct { exchange: Exchange::Okex, product: product.clone() },
+            bids: vec![(dec!(0.018), dec!(5400))],
+            asks: vec![(dec!(0.015), dec!(1000))],
+            timestamps: PipelineTimestamps::new(now, now),
+        });
+
+        let deribit_order_book = order_books.get_mut(&ExchangeProduct { exchange: Exchange::Deribit, product: product.clone() }).unwrap();
+        deribit_order_book.update(OrderBookUpdate {
+            exchange_product: ExchangeProduct { exchange: Exchange::Deribit, product: product.clone() },
+            bids: vec![(dec!(0.019), dec!(1000))],
+            asks: vec![(dec!(0.020), dec!(1000))],
+            timestamps: PipelineTimestamps::new(deribit_received_time, deribit_received_time),
+        });
+
+        drop(order_books);
+        let arbitrage_opportunity = order_book_manager.check_arbitrage_opportunities(&ExchangeProduct { exchange: Exchange::Okex, product: product.clone() }, &PipelineTimestamps::new(now, now)).unwrap();
+        assert_eq!(arbitrage_opportunity.buy_book_age_millis, 0);
+        assert_eq!(arbitrage_opportunity.sell_book_age_millis, max_book_age_millis);
+
+        let later = now + SignedDuration::from_millis(1);
+        let timestamps = PipelineTimestamps::new(later, later);
+        assert!(order_book_manager.check_arbitrage_opportunities(&ExchangeProduct { exchange: Exchange::Okex, product: product.clone() }, &timestamps).is_none());
+    }
+
+    #[test]
+    fn test_process_records_pipeline_timestamps() {
+        let product = Product::Option {
+            underlying: models::CryptoAsset::BTC,
+            settlement: models::SettlementAsset::USD,
+            strike: Decimal::from_str("90000").unwrap(),
+            option_type: models::OptionType::Call,
+            expiration: NaiveDate::from_ymd_opt(2025, 2, 21).unwrap(),
+        };
+
+        let mut order_book_manager = setup_order_book_manager(product.clone());
+        let now = Timestamp::now();
+        let exchange_time = now - SignedDuration::from_millis(20);
+
+        let arbitrage_opportunity = order_book_manager.process(OrderBookUpdate {
+            exchange_product: ExchangeProduct { exchange: Exchange::Deribit, product: product.clone() },
+            bids: vec![(dec!(0.019), dec!(1000))],
+            asks: vec![(dec!(0.020), dec!(1000))],
+            timestamps: PipelineTimestamps::new(exchange_time, now),
+        });
+        assert!(arbitrage_opportunity.is_none());
+
+        let arbitrage_opportunity = order_book_manager.process(OrderBookUpdate {
+            exchange_product: ExchangeProduct { exchange: Exchange::Okex, product: product.clone() },
+            bids: vec![(dec!(0.018), dec!(5400))],
+            asks: vec![(dec!(0.015), dec!(1000))],
+            timestamps: PipelineTimestamps::new(exchange_time, now),
+        }).unwrap();
+
+        assert_eq!(arbitrage_opportunity.trigger_exchange, Exchange::Okex);
+        assert_eq!(arbitrage_opportunity.timestamps.exchange_time, exchange_time);
+        assert_eq!(arbitrage_opportunity.timestamps.received_time, now);
+        assert!(arbitrage_opportunity.timestamps.processed_time.unwrap() >= now);
+        assert!(arbitrage_opportunity.timestamps.sent_time.is_none());
+
+        let report = order_book_manager.latency.report();
+        assert_eq!(report.len(), 4);
+        assert!(report.iter().all(|report| report.count == 1));
+    }
+
     #[test]
     fn test_arbitrage_none_for_different_products() {
         let product1 = Product::Option {
@@ -235,12 +565,12 @@ mod tests {
             expiration: NaiveDate::from_ymd_opt(2025, 2, 21).unwrap(),
         };
 
-        let mut order_book_manager = setup_order_book_manager(product1.clone());
+        let order_book_manager = setup_order_book_manager(product1.clone());
 
         let okex_exchange_product = ExchangeProduct { exchange: Exchange::Okex, product: product2.clone() };
-        order_book_manager.order_books.insert(okex_exchange_product.clone(), OrderBook::new(&okex_exchange_product));
+        order_book_manager.order_books.lock().insert(okex_exchange_product.clone(), OrderBook::new(&okex_exchange_product));
 
-        let arbitrage_opportunity = order_book_manager.check_arbitrage_opportunities(&product2);
+        let arbitrage_opportunity = order_book_manager.check_arbitrage_opportunities(&okex_exchange_product, &PipelineTimestamps::new(Timestamp::now(), Timestamp::now()));
         assert!(arbitrage_opportunity.is_none());
     }
 }
@@ -0,0 +1,108 @@
+use std::sync::LazyLock;
+
+use prometheus::{
+    register_gauge_vec, register_int_counter, register_int_counter_vec, register_int_gauge, Encoder, GaugeVec, IntCounter,
+    IntCounterVec, IntGauge, TextEncoder,
+};
+
+use crate::latency::LatencyTracker;
+
+/// Messages from the exchanges which could not be parsed, per exchange
+pub static PARSE_ERRORS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!("exchange_parse_errors_total", "Number of exchange messages which could not be parsed", &["exchange"])
+        .expect("exchange_parse_errors_total should be registered")
+});
+
+/// Messages waiting in the queue of the order book manager
+pub static INTERNAL_QUEUE_DEPTH: LazyLock<IntGauge> = LazyLock::new(|| {
+    register_int_gauge!("internal_message_queue_depth", "Number of messages waiting to be processed by the order book manager")
+        .expect("internal_message_queue_depth should be registered")
+});
+
+/// Messages waiting in the broadcast channel for the slowest receiver
+pub static BROADCAST_QUEUE_DEPTH: LazyLock<IntGauge> = LazyLock::new(|| {
+    register_int_gauge!("broadcast_queue_depth", "Number of messages retained in the broadcast channel")
+        .expect("broadcast_queue_depth should be registered")
+});
+
+/// Messages skipped by websocket clients which could not keep up with the broadcast channel
+pub static BROADCAST_LAGGED: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!("broadcast_lagged_messages_total", "Number of broadcast messages skipped by lagging websocket clients")
+        .expect("broadcast_lagged_messages_total should be registered")
+});
+
+/// Websocket clients currently connected to the endpoint
+pub static WEBSOCKET_CLIENTS: LazyLock<IntGauge> = LazyLock::new(|| {
+    register_int_gauge!("websocket_clients_connected", "Number of websocket clients connected")
+        .expect("websocket_clients_connected should be registered")
+});
+
+/// Arbitrage opportunities emitted, per product and direction
+pub static OPPORTUNITIES: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "arbitrage_opportunities_total",
+        "Number of arbitrage opportunities emitted",
+        &["product", "buy_exchange", "sell_exchange"]
+    )
+    .expect("arbitrage_opportunities_total should be registered")
+});
+
+/// Opportunities which could not be written to the journal
+pub static JOURNAL_ERRORS: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!("journal_errors_total", "Number of opportunities which could not be written to the journal")
+        .expect("journal_errors_total should be registered")
+});
+
+/// Executions of opportunities, per mode and outcome
+pub static EXECUTIONS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!("executions_total", "Number of opportunities executed", &["mode", "status"])
+        .expect("executions_total should be registered")
+});
+
+/// Opportunities not executed as they would breach a risk limit, per limit
+pub static RISK_SUPPRESSED: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!("risk_suppressed_opportunities_total", "Number of opportunities suppressed by a risk limit", &["limit"])
+        .expect("risk_suppressed_opportunities_total should be registered")
+});
+
+/// Opportunities dropped as a leg could not fund a single lot, per exchange of that leg
+pub static UNFUNDED_OPPORTUNITIES: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!("unfunded_opportunities_total", "Number of opportunities dropped as a leg could not be funded", &["exchange"])
+        .expect("unfunded_opportunities_total should be registered")
+});
+
+/// Orders sent to handle the naked size of the live executions, per action
+pub static LEG_RISK_ORDERS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!("leg_risk_orders_total", "Number of orders sent to chase, unwind or hedge a naked leg", &["action"])
+        .expect("leg_risk_orders_total should be registered")
+});
+
+/// Latency percentiles from the latency tracker, refreshed on every scrape
+static PIPELINE_LATENCY: LazyLock<GaugeVec> = LazyLock::new(|| {
+    register_gauge_vec!(
+        "pipeline_latency_seconds",
+        "Latency of each pipeline stage per exchange",
+        &["exchange", "stage", "quantile"]
+    )
+    .expect("pipeline_latency_seconds should be registered")
+});
+
+/// Renders all the registered metrics in the prometheus text format
+pub fn render(latency: &LatencyTracker) -> String {
+    for report in latency.report() {
+        let exchange = format!("{:?}", report.exchange);
+        let stage = report.stage.as_str();
+        PIPELINE_LATENCY
+            .with_label_values(&[&exchange, stage, "0.5"])
+            .set(report.p50_micros as f64 / 1_000_000.0);
+        PIPELINE_LATENCY
+            .with_label_values(&[&exchange, stage, "0.99"])
+            .set(report.p99_micros as f64 / 1_000_000.0);
+    }
+
+    let mut buffer = vec![];
+    if let Err(e) = TextEncoder::new().encode(&prometheus::gather(), &mut buffer) {
+        log::error!("error encoding metrics: {}", e);
+    }
+    String::from_utf8(buffer).unwrap_or_default()
+}
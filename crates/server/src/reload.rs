@@ -0,0 +1,288 @@
+use std::{collections::HashMap, time::{Duration, SystemTime}};
+
+use common::{ArbitrageError, Context, SharedRef, SpawnResult, Worker};
+use models::{Exchange, SubscriptionCommand};
+use tokio::sync::mpsc;
+
+use crate::settings::{ConfigOverrides, ManagerConfig, ServerConfig};
+
+/// Interval at which the configuration file is checked for changes
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Senders of the subscription commands of each running exchange adapter
+pub type SubscriptionSenders = HashMap<Exchange, mpsc::Sender<SubscriptionCommand>>;
+
+
+/// Changes between the running configuration and a reloaded one
+#[derive(Debug, Default, PartialEq)]
+pub struct ConfigDiff {
+    /// New thresholds and fees of the manager
+    pub manager: Option<ManagerConfig>,
+    /// Commands to send to the adapters whose products changed
+    pub subscriptions: Vec<(Exchange, SubscriptionCommand)>,
+    pub log_level: Option<String>,
+    /// Changes which cannot be applied without a restart
+    pub rejected: Vec<String>,
+}
+
+impl ConfigDiff {
+    pub fn new(current: &ServerConfig, new: &ServerConfig) -> Self {
+        let mut diff = Self::default();
+
+        if current.manager != new.manager {
+            diff.manager = Some(new.manager.clone());
+        }
+        if current.logging != new.logging {
+            diff.log_level = Some(new.logging.level.clone());
+        }
+        if current.endpoint.port != new.endpoint.port {
+            diff.rejected.push(format!("endpoint.port changed from {} to {}", current.endpoint.port, new.endpoint.port));
+        }
+        if current.supervision != new.supervision {
+            diff.rejected.push(format!("supervision changed from {:?} to {:?}", current.supervision, new.supervision));
+        }
+        if current.recorder != new.recorder {
+            diff.rejected.push(format!("recorder changed from {:?} to {:?}", current.recorder, new.recorder));
+        }
+        if current.journal != new.journal {
+            diff.rejected.push(format!("journal changed from {:?} to {:?}", current.journal, new.journal));
+        }
+        if current.paper != new.paper {
+            diff.rejected.push(format!("paper changed from {:?} to {:?}", current.paper, new.paper));
+        }
+        if current.risk != new.risk {
+            diff.rejected.push(format!("risk changed from {:?} to {:?}", current.risk, new.risk));
+        }
+        if current.funding != new.funding {
+            diff.rejected.push(format!("funding changed from {:?} to {:?}", current.funding, new.funding));
+        }
+        if current.live != new.live {
+            // The credentials are redacted, a rotated secret shows as an unchanged section
+            diff.rejected.push(format!("live changed from {:?} to {:?}", current.live, new.live));
+        }
+
+        for ((exchange, current), (_, new)) in current.exchanges().into_iter().zip(new.exchanges()) {
+            let section = format!("exchanges.{}", format!("{:?}", exchange).to_lowercase());
+            if current.enabled != new.enabled {
+                diff.rejected.push(format!("{}.enabled changed from {} to {}", section, current.enabled, new.enabled));
+            }
+            // The adapter of a disabled exchange is not running, none of its settings can be applied
+            if !current.enabled {
+                continue;
+            }
+            if current.ws_url != new.ws_url {
+                diff.rejected.push(format!("{}.ws_url changed from {:?} to {:?}", section, current.ws_url, new.ws_url));
+            }
+            if current.heartbeat_millis != new.heartbeat_millis {
+                diff.rejected.push(format!("{}.heartbeat_millis changed from {} to {}", section, current.heartbeat_millis, new.heartbeat_millis));
+            }
+            if current.max_silent_heartbeats != new.max_silent_heartbeats {
+                diff.rejected.push(format!(
+                    "{}.max_silent_heartbeats changed from {} to {}",
+                    section, current.max_silent_heartbeats, new.max_silent_heartbeats
+                ));
+            }
+
+            let removed = difference(&current.products, &new.products);
+            if !removed.is_empty() {
+                diff.subscriptions.push((exchange.clone(), SubscriptionCommand::Unsubscribe(removed)));
+            }
+            let added = difference(&new.products, &current.products);
+            if !added.is_empty() {
+                diff.subscriptions.push((exchange, SubscriptionCommand::Subscribe(added)));
+            }
+        }
+
+        diff
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self == &Self::default()
+    }
+
+    /// Updates the running configuration with the settings which were applied,
+    /// the rejected ones keep their current value until the next restart
+    fn update(&self, current: &mut ServerConfig, new: &ServerConfig) {
+        current.manager = new.manager.clone();
+        current.logging = new.logging.clone();
+        if current.exchanges.okex.enabled {
+            current.exchanges.okex.products = new.exchanges.okex.products.clone();
+        }
+        if current.exchanges.deribit.enabled {
+            current.exchanges.deribit.products = new.exchanges.deribit.products.clone();
+        }
+    }
+}
+
+fn difference(products: &[String], other: &[String]) -> Vec<String> {
+    products.iter().filter(|product| !other.contains(product)).cloned().collect()
+}
+
+
+/// Watches the configuration file and applies its changes to the running server
+///
+/// The manager thresholds and fees, the products of each exchange and the log level are applied live.
+/// Any other change is logged and ignored until the server is restarted.
+#[derive(Clone)]
+pub struct ConfigWatcher {
+    context: Context,
+    config_file: String,
+    overrides: ConfigOverrides,
+    /// Configuration currently applied, kept across restarts of the watcher
+    current: SharedRef<ServerConfig>,
+    manager: SharedRef<ManagerConfig>,
+    subscriptions: SubscriptionSenders,
+}
+
+impl ConfigWatcher {
+    pub fn new(
+        context: Context,
+        config_file: &str,
+        overrides: ConfigOverrides,
+        current: ServerConfig,
+        manager: SharedRef<ManagerConfig>,
+        subscriptions: SubscriptionSenders,
+    ) -> Self {
+        Self { context, config_file: config_file.to_string(), overrides, current: SharedRef::new(current), manager, subscriptions }
+    }
+
+    /// Reloads the configuration file, an invalid configuration is logged and the running one is kept
+    async fn reload(&self) {
+        let new = match ServerConfig::load(Some(&self.config_file), &self.overrides) {
+            Ok((_, new)) => new,
+            Err(e) => {
+                log::error!("keeping the running configuration, failed to reload {}: {}", self.config_file, e);
+                return;
+            }
+        };
+
+        let current = self.current.lock().clone();
+        let diff = ConfigDiff::new(&current, &new);
+        if diff.is_empty() {
+            log::debug!("{} changed but the configuration is the same", self.config_file);
+            return;
+        }
+
+        for rejected in diff.rejected.iter() {
+            log::error!("ignoring a change which requires a restart: {}", rejected);
+        }
+        if let Some(manager) = diff.manager.as_ref() {
+            log::info!("applying manager configuration {:?}", manager);
+            *self.manager.lock() = manager.clone();
+        }
+        for (exchange, command) in diff.subscriptions.iter() {
+            log::info!("applying {:?} subscriptions {:?}", exchange, command);
+            match self.subscriptions.get(exchange) {
+                Some(sender) => {
+                    if let Err(e) = sender.send(command.clone()).await {
+                        log::error!("failed to forward {:?} subscription command: {}", exchange, e);
+                    }
+                }
+                None => log::error!("exchange {:?} is not running", exchange),
+            }
+        }
+        let mut applied = new;
+        if let Some(log_level) = diff.log_level.as_ref() {
+            match common::set_log_level(log_level) {
+                Ok(_) => log::info!("log level changed to {}", log_level),
+                Err(e) => {
+                    log::error!("keeping the log level {}: {}", current.logging.level, e);
+                    applied.logging = current.logging.clone();
+                }
+            }
+        }
+
+        let mut current = self.current.lock();
+        diff.update(&mut current, &applied);
+    }
+}
+
+/// Modification time and size of the file, `None` when it cannot be read
+async fn file_stamp(path: &str) -> Option<(SystemTime, u64)> {
+    let metadata = tokio::fs::metadata(path).await.ok()?;
+    Some((metadata.modified().ok()?, metadata.len()))
+}
+
+
+impl Worker for ConfigWatcher {
+    fn name(&self) -> String {
+        self.context.name.clone()
+    }
+
+    fn spawn(&mut self) -> SpawnResult {
+        let watcher = self.clone();
+
+        tokio::spawn(async move {
+            let mut app = watcher.context.app.subscribe();
+            let mut interval = tokio::time::interval(POLL_INTERVAL);
+            let mut stamp = file_stamp(&watcher.config_file).await;
+            log::info!("watching {} for configuration changes", watcher.config_file);
+            loop {
+                tokio::select! {
+                    _ = app.recv() => {
+                        return Err(ArbitrageError::Exit);
+                    }
+
+                    _ = interval.tick() => {
+                        let new_stamp = file_stamp(&watcher.config_file).await;
+                        // The file may be missing for a moment while an editor replaces it
+                        if new_stamp.is_none() || new_stamp == stamp {
+                            continue;
+                        }
+                        stamp = new_stamp;
+                        log::info!("{} changed, reloading the configuration", watcher.config_file);
+                        watcher.reload().await;
+                    }
+                }
+            }
+        })
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+
+    use super::*;
+
+    fn server_config() -> ServerConfig {
+        let mut config = ServerConfig::default();
+        config.exchanges.okex.ws_url = "wss://ws.okx.com:8443/ws/v5/public".to_string();
+        config.exchanges.okex.products = vec!["BTC-USD-250221-90000-P".to_string(), "BTC-USD-250221-95000-C".to_string()];
+        config.exchanges.deribit.enabled = false;
+        config
+    }
+
+    #[test]
+    fn test_config_diff() {
+        let current = server_config();
+        assert!(ConfigDiff::new(&current, &current).is_empty());
+
+        let mut new = current.clone();
+        new.manager.min_edge = dec!(0.001);
+        new.logging.level = "debug".to_string();
+        new.exchanges.okex.products = vec!["BTC-USD-250221-95000-C".to_string(), "BTC-USD-250228-90000-P".to_string()];
+        new.exchanges.okex.ws_url = "wss://wsaws.okx.com:8443/ws/v5/public".to_string();
+        new.exchanges.deribit.products = vec!["book.BTC-21FEB25-90000-P.none.20.100ms".to_string()];
+        new.endpoint.port = 9028;
+
+        let diff = ConfigDiff::new(&current, &new);
+        assert_eq!(diff.manager, Some(new.manager.clone()));
+        assert_eq!(diff.log_level, Some("debug".to_string()));
+        assert_eq!(diff.subscriptions, vec![
+            (Exchange::Okex, SubscriptionCommand::Unsubscribe(vec!["BTC-USD-250221-90000-P".to_string()])),
+            (Exchange::Okex, SubscriptionCommand::Subscribe(vec!["BTC-USD-250228-90000-P".to_string()])),
+        ]);
+        assert_eq!(diff.rejected.len(), 2, "{:?}", diff.rejected);
+        assert!(diff.rejected[0].starts_with("endpoint.port"));
+        assert!(diff.rejected[1].starts_with("exchanges.okex.ws_url"));
+
+        // Once applied only the rejected changes remain
+        let mut updated = current.clone();
+        diff.update(&mut updated, &new);
+        let diff = ConfigDiff::new(&updated, &new);
+        assert!(diff.manager.is_none() && diff.log_level.is_none() && diff.subscriptions.is_empty());
+        assert_eq!(diff.rejected.len(), 2);
+    }
+}
@@ -0,0 +1,264 @@
+use std::{collections::HashMap, fs::File, io::{BufWriter, Write}, path::PathBuf, time::Duration};
+
+use common::{ArbitrageError, ArbitrageResult, Context, MpSc, Runner, SharedRef};
+use config::Config;
+use jiff::Timestamp;
+use models::{ArbitrageOpportunity, Exchange, InternalMessage, OrderBookUpdate};
+use tokio::{sync::{broadcast, mpsc::Sender}, time::Instant};
+use wsclient::{RecordingReader, ReplaySpeed, ReplayTarget};
+
+use crate::{adapters::{DeribitExchangeAdapter, OkexExchangeAdapter}, latency::LatencyTracker, manager::OrderBookManager, settings::{ConfigOverrides, ServerConfig}};
+
+/// Internal messages buffered while replaying a frame, a frame produces at most one update
+const REPLAY_BUFFER_SIZE: usize = 100;
+
+
+/// Replays recordings through the exchange callbacks and the order book manager
+///
+/// The frames are fed with their recorded receive time, which the manager uses as the current time,
+/// so that the opportunities are the same as the ones of the recorded run, whatever the speed.
+/// They are written as JSON lines to the output, or stdout, in the order they are detected.
+pub struct ReplayRunner {
+    context: Context,
+    server_config: ServerConfig,
+    recordings: Vec<PathBuf>,
+    speed: ReplaySpeed,
+    output: Option<PathBuf>,
+}
+
+/// Follows the updates and the opportunities of a replay, in the order they are processed
+pub trait ReplayObserver: Send {
+    /// Called before the update is applied to the books of the manager
+    fn on_update(&mut self, _order_book_manager: &OrderBookManager, _order_book_update: &OrderBookUpdate) -> ArbitrageResult<()> {
+        Ok(())
+    }
+
+    /// Called once the update which triggered the opportunity is applied
+    fn on_opportunity(&mut self, order_book_manager: &OrderBookManager, arbitrage_opportunity: ArbitrageOpportunity) -> ArbitrageResult<()>;
+
+    /// Called after the last update of the replay
+    fn on_end(&mut self, _order_book_manager: &OrderBookManager) -> ArbitrageResult<()> {
+        Ok(())
+    }
+}
+
+/// Writes the opportunities as JSON lines
+pub struct OpportunityWriter<W> {
+    output: W,
+}
+
+impl<W: Write + Send> OpportunityWriter<W> {
+    pub fn new(output: W) -> Self {
+        Self { output }
+    }
+}
+
+impl<W: Write + Send> ReplayObserver for OpportunityWriter<W> {
+    fn on_opportunity(&mut self, _order_book_manager: &OrderBookManager, arbitrage_opportunity: ArbitrageOpportunity) -> ArbitrageResult<()> {
+        let json = serde_json::to_string(&arbitrage_opportunity).map_err(ArbitrageError::JsonError)?;
+        writeln!(self.output, "{}", json).map_err(output_error)
+    }
+
+    fn on_end(&mut self, _order_book_manager: &OrderBookManager) -> ArbitrageResult<()> {
+        self.output.flush().map_err(output_error)
+    }
+}
+
+/// Counts reported once the replay is over
+#[derive(Debug, Default, PartialEq)]
+pub struct ReplayStats {
+    pub frames: u64,
+    /// Frames of exchanges which are not enabled
+    pub skipped_frames: u64,
+    pub updates: u64,
+    pub opportunities: u64,
+}
+
+impl ReplayRunner {
+    pub fn new(
+        config_file: Option<&str>,
+        overrides: ConfigOverrides,
+        recordings: Vec<PathBuf>,
+        speed: ReplaySpeed,
+        output: Option<PathBuf>,
+    ) -> ArbitrageResult<Self> {
+        let (config, server_config) = ServerConfig::load(config_file, &overrides)?;
+        let context = Context::from_config(config);
+        Ok(Self { context, server_config, recordings, speed, output })
+    }
+
+    pub fn server_config(&self) -> &ServerConfig {
+        &self.server_config
+    }
+
+    pub async fn replay(&self, observer: &mut dyn ReplayObserver) -> ArbitrageResult<ReplayStats> {
+        let reader = RecordingReader::open(&self.recordings)?;
+        let mut internal_message_producer = MpSc::new(REPLAY_BUFFER_SIZE);
+        let mut receiver = internal_message_producer.receiver().expect("internal message receiver should not be taken");
+        let mut targets = replay_targets(&self.context, &self.server_config, internal_message_producer.sender());
+        let (broadcaster, _) = broadcast::channel(1);
+        let mut order_book_manager = OrderBookManager::new(
+            self.context.with_name("order-book-manager"),
+            SharedRef::new(self.server_config.manager.clone()),
+            MpSc::new(1),
+            broadcaster,
+            LatencyTracker::default(),
+        );
+
+        let mut app = self.context.app.subscribe();
+        let mut stats = ReplayStats::default();
+        let mut start: Option<(Timestamp, Instant)> = None;
+        for frame in reader {
+            let frame = frame?;
+            let (first_received_time, started) = *start.get_or_insert((frame.received_time, Instant::now()));
+            let elapsed = Duration::try_from(frame.received_time.duration_since(first_received_time)).unwrap_or_default();
+            match self.speed.delay(elapsed) {
+                Some(delay) => {
+                    tokio::select! {
+                        _ = app.recv() => {
+                            log::warn!("replay interrupted");
+                            break;
+                        }
+                        _ = tokio::time::sleep_until(started + delay) => {}
+                    }
+                }
+                None if app.try_recv().is_ok() => {
+                    log::warn!("replay interrupted");
+                    break;
+                }
+                None => {}
+            }
+
+            let Some(target) = targets.get_mut(&frame.exchange) else {
+                stats.skipped_frames += 1;
+                continue;
+            };
+            stats.frames += 1;
+            // A live connection would have been reset, the recording already holds what happened next
+            if let Err(e) = target.replay(frame).await {
+                log::warn!("error while replaying frame: {}", e);
+            }
+
+            while let Ok(message) = receiver.try_recv() {
+                if let InternalMessage::OrderBookUpdate(order_book_update) = message {
+                    stats.updates += 1;
+                    observer.on_update(&order_book_manager, &order_book_update)?;
+                    if let Some(arbitrage_opportunity) = order_book_manager.process(order_book_update) {
+                        stats.opportunities += 1;
+                        observer.on_opportunity(&order_book_manager, arbitrage_opportunity)?;
+                    }
+                }
+            }
+        }
+
+        observer.on_end(&order_book_manager)?;
+        Ok(stats)
+    }
+}
+
+/// A replay target for each enabled exchange, keyed by the name of the exchange in the recordings
+fn replay_targets(
+    context: &Context,
+    server_config: &ServerConfig,
+    producer: Sender<InternalMessage>,
+) -> HashMap<String, Box<dyn ReplayTarget>> {
+    let mut targets: HashMap<String, Box<dyn ReplayTarget>> = HashMap::new();
+
+    let okex_config = &server_config.exchanges.okex;
+    if okex_config.enabled {
+        let mut okex_adapter = OkexExchangeAdapter::new(context.clone(), okex_config);
+        let okex_callback = okex_adapter.callback(producer.clone());
+        targets.insert(recording_name(&Exchange::Okex), Box::new(okex_adapter.replay(okex_callback)));
+    }
+
+    let deribit_config = &server_config.exchanges.deribit;
+    if deribit_config.enabled {
+        let mut deribit_adapter = DeribitExchangeAdapter::new(context.clone(), deribit_config);
+        let deribit_callback = deribit_adapter.callback(producer);
+        targets.insert(recording_name(&Exchange::Deribit), Box::new(deribit_adapter.replay(deribit_callback)));
+    }
+
+    targets
+}
+
+/// Name of the exchange in the recordings, see `RecorderConfig::recorder`
+fn recording_name(exchange: &Exchange) -> String {
+    format!("{:?}", exchange).to_lowercase()
+}
+
+pub fn output_error(error: std::io::Error) -> ArbitrageError {
+    ArbitrageError::GenericError(format!("failed to write the output of the replay: {}", error))
+}
+
+
+/// The given file, or stdout without it
+pub fn create_output(path: Option<&PathBuf>) -> ArbitrageResult<Box<dyn Write + Send>> {
+    Ok(match path {
+        Some(path) => Box::new(BufWriter::new(File::create(path).map_err(output_error)?)),
+        None => Box::new(BufWriter::new(std::io::stdout())),
+    })
+}
+
+
+#[async_trait::async_trait]
+impl Runner for ReplayRunner {
+    async fn run(&mut self) -> ArbitrageResult<String> {
+        log::info!("replaying {:?} at {} speed", self.recordings, self.speed);
+        let mut writer = OpportunityWriter::new(create_output(self.output.as_ref())?);
+        let stats = self.replay(&mut writer).await?;
+        log::info!("{:?}", stats);
+        Ok("replay".to_string())
+    }
+
+    fn config(&self) -> &Config {
+        &self.context.config
+    }
+
+    fn context(&self) -> &Context {
+        &self.context
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use tokio_tungstenite::tungstenite::Message;
+    use wsclient::Recorder;
+
+    use super::*;
+
+    const OKEX_SNAPSHOT: &str = r#"{"arg":{"channel":"books","instId":"BTC-USD-250221-90000-P"},"action":"snapshot",
+        "data":[{"asks":[["0.0100","5","0","1"]],"bids":[["0.0090","5","0","1"]],"ts":"1739000000000","checksum":0}]}"#;
+    const DERIBIT_BOOK: &str = r#"{"jsonrpc":"2.0","method":"subscription","params":{"channel":"book.BTC-21FEB25-90000-P.none.20.100ms",
+        "data":{"instrument_name":"BTC-21FEB25-90000-P","timestamp":1739000000100,"asks":[[0.013,3]],"bids":[[0.012,3]]}}}"#;
+
+    #[tokio::test]
+    async fn test_replay_detects_recorded_opportunity() {
+        let directory = std::env::temp_dir().join(format!("replay-runner-test-{}", std::process::id()));
+        let okex = Recorder::new(&directory, "okex", 1 << 20, Duration::from_secs(3600));
+        let deribit = Recorder::new(&directory, "deribit", 1 << 20, Duration::from_secs(3600));
+        okex.record(1, Timestamp::from_millisecond(1739000000010).unwrap(), &Message::text(OKEX_SNAPSHOT)).unwrap();
+        deribit.record(1, Timestamp::from_millisecond(1739000000110).unwrap(), &Message::text(DERIBIT_BOOK)).unwrap();
+        okex.flush().unwrap();
+        deribit.flush().unwrap();
+
+        let runner = ReplayRunner {
+            context: Context::from_config(Config::default()),
+            server_config: ServerConfig::default(),
+            recordings: vec![directory.clone()],
+            speed: ReplaySpeed::AsFastAsPossible,
+            output: None,
+        };
+        let mut writer = OpportunityWriter::new(vec![]);
+        let stats = runner.replay(&mut writer).await.unwrap();
+        std::fs::remove_dir_all(&directory).unwrap();
+
+        assert_eq!(stats, ReplayStats { frames: 2, skipped_frames: 0, updates: 2, opportunities: 1 });
+        let opportunity = serde_json::from_slice::<serde_json::Value>(&writer.output).unwrap();
+        assert_eq!(opportunity["buy_exchange"], "Okex");
+        assert_eq!(opportunity["sell_exchange"], "Deribit");
+        assert_eq!(opportunity["edge"], "0.0020");
+        // The deribit book is 100ms older than the okex book as per the recorded receive times
+        assert_eq!(opportunity["buy_book_age_millis"], 100);
+    }
+}
@@ -1,21 +1,117 @@
-use common::{create_config, ArbitrageResult, Context, MpSc, Runner, Workers};
+use std::collections::HashMap;
+
+use common::{ArbitrageResult, Context, MpSc, RestartPolicy, Runner, SharedRef, Workers};
 use config::Config;
-use tokio::sync::broadcast;
+use models::{Exchange, InternalMessage};
+use tokio::sync::{broadcast, mpsc::Sender};
+use wsclient::RecordingCallback;
 
-use crate::{adapters::{DeribitExchangeAdapter, OkexExchangeAdapter}, endpoint::Endpoint, manager::OrderBookManager};
+use crate::{adapters::{DeribitExchangeAdapter, DeribitPrivateAdapter, OkexExchangeAdapter, OkexPrivateAdapter}, endpoint::Endpoint, execution::{ExecutionManager, OrderCommand, OrderUpdate, PaperTrader, ORDER_UPDATES_BUFFER_SIZE}, funding::Funding, health::Connections, journal::{Journal, JournalWriter}, latency::LatencyTracker, manager::OrderBookManager, reload::{ConfigWatcher, SubscriptionSenders}, settings::{ConfigOverrides, FundingSource, ServerConfig}};
 
 pub struct ServerRunner {
     context: Context,
+    server_config: ServerConfig,
+    /// Watched for changes when given
+    config_file: Option<String>,
+    overrides: ConfigOverrides,
 }
 
-impl Default for ServerRunner {
-    fn default() -> Self {
-        let config = create_config(".env/server.env")
-            .build()
-            .expect("server config should be created");
+impl ServerRunner {
+    /// Loads and validates the configuration, see `ServerConfig::load`
+    pub fn new(config_file: Option<&str>, overrides: ConfigOverrides) -> ArbitrageResult<Self> {
+        let (config, server_config) = ServerConfig::load(config_file, &overrides)?;
         let context = Context::from_config(config);
-        Self { context }
+        Ok(Self { context, server_config, config_file: config_file.map(str::to_string), overrides })
+    }
+}
+
+
+/// Adds a worker for each enabled exchange adapter, which sends its order book updates to `producer`
+/// and records the frames it receives when the recorder is enabled
+///
+/// Returns the subscription command senders and the connection state of the adapters.
+pub fn add_exchange_adapters(
+    context: &Context,
+    server_config: &ServerConfig,
+    producer: Sender<InternalMessage>,
+    workers: &mut Workers,
+    restart_policy: &RestartPolicy,
+) -> (SubscriptionSenders, Connections) {
+    let mut subscriptions = HashMap::new();
+    let mut connections = HashMap::new();
+
+    let okex_config = &server_config.exchanges.okex;
+    if okex_config.enabled {
+        let mut okex_adapter = OkexExchangeAdapter::new(context.clone(), okex_config);
+        let okex_callback = okex_adapter.callback(producer.clone());
+        subscriptions.insert(Exchange::Okex, okex_adapter.commands());
+        connections.insert(Exchange::Okex, okex_adapter.connection());
+
+        let okex_worker = if server_config.recorder.enabled {
+            let recorder = server_config.recorder.recorder(&Exchange::Okex);
+            okex_adapter.worker(RecordingCallback::new(okex_callback, recorder))
+        } else {
+            okex_adapter.worker(okex_callback)
+        };
+        workers.add_supervised_worker(okex_worker, restart_policy.clone());
+    }
+
+    let deribit_config = &server_config.exchanges.deribit;
+    if deribit_config.enabled {
+        let mut deribit_adapter = DeribitExchangeAdapter::new(context.clone(), deribit_config);
+        let deribit_callback = deribit_adapter.callback(producer);
+        subscriptions.insert(Exchange::Deribit, deribit_adapter.commands());
+        connections.insert(Exchange::Deribit, deribit_adapter.connection());
+
+        let deribit_worker = if server_config.recorder.enabled {
+            let recorder = server_config.recorder.recorder(&Exchange::Deribit);
+            deribit_adapter.worker(RecordingCallback::new(deribit_callback, recorder))
+        } else {
+            deribit_adapter.worker(deribit_callback)
+        };
+        workers.add_supervised_worker(deribit_worker, restart_policy.clone());
     }
+
+    (subscriptions, connections)
+}
+
+/// Adds a worker for the private session of each enabled exchange, which sends its order updates to `order_updates`
+/// and its account balances to `funding` when given
+///
+/// Returns the order command senders of the sessions.
+pub fn add_private_sessions(
+    context: &Context,
+    server_config: &ServerConfig,
+    order_updates: Sender<OrderUpdate>,
+    funding: Option<&Funding>,
+    workers: &mut Workers,
+    restart_policy: &RestartPolicy,
+) -> HashMap<Exchange, Sender<OrderCommand>> {
+    let mut order_senders = HashMap::new();
+
+    let okex_config = &server_config.exchanges.okex;
+    if okex_config.enabled {
+        let mut okex_session = OkexPrivateAdapter::new(context.clone(), okex_config, &server_config.live.okex);
+        if let Some(funding) = funding {
+            okex_session = okex_session.with_funding(funding.clone());
+        }
+        let okex_callback = okex_session.callback(order_updates.clone());
+        order_senders.insert(Exchange::Okex, okex_session.commands());
+        workers.add_supervised_worker(okex_session.worker(okex_callback), restart_policy.clone());
+    }
+
+    let deribit_config = &server_config.exchanges.deribit;
+    if deribit_config.enabled {
+        let mut deribit_session = DeribitPrivateAdapter::new(context.clone(), deribit_config, &server_config.live.deribit);
+        if let Some(funding) = funding {
+            deribit_session = deribit_session.with_funding(funding.clone());
+        }
+        let deribit_callback = deribit_session.callback(order_updates);
+        order_senders.insert(Exchange::Deribit, deribit_session.commands());
+        workers.add_supervised_worker(deribit_session.worker(deribit_callback), restart_policy.clone());
+    }
+
+    order_senders
 }
 
 
@@ -26,26 +122,128 @@ impl Runner for ServerRunner {
         log::info!("starting arbitrage server");
 
         let (broadcaster, _) = broadcast::channel(5000);
+        // The updates of the books are sent on their own channel, so that the opportunities are not lost when a
+        // slow reader of the books lags behind
+        let (book_broadcaster, _) = broadcast::channel(5000);
         let mut internal_message_producer = MpSc::new(5000);
-        let order_book_manager = OrderBookManager::new(self.context.with_name("order-book-manager"), internal_message_producer.clone_with_receiver(), broadcaster.clone());
+        let latency = LatencyTracker::default();
+        let manager_config = SharedRef::new(self.server_config.manager.clone());
+        let mut order_book_manager = OrderBookManager::new(
+            self.context.with_name("order-book-manager"),
+            manager_config.clone(),
+            internal_message_producer.clone_with_receiver(),
+            broadcaster.clone(),
+            latency.clone(),
+        )
+        .with_book_broadcaster(book_broadcaster.clone());
+        // The clients of the endpoint read the books they subscribe to from those of the manager
+        let order_books = order_book_manager.order_books();
+        let funding_config = &self.server_config.funding;
+        let funding = (funding_config.source != FundingSource::None).then(|| Funding::new(funding_config));
+        if let Some(funding) = funding.clone() {
+            log::info!("sizing the opportunities by the {:?} funds", funding_config.source);
+            order_book_manager = order_book_manager.with_funding(funding);
+        }
+        // The accounts are learnt by whatever executes the opportunities
+        let account_funding = funding.clone().filter(|_| funding_config.source == FundingSource::Account);
 
         let mut workers = Workers::new(self.context.with_name("arbitrage-workers"), 0);
+        // A failing worker is restarted on its own, so that e.g. a deribit outage does not take down okex
+        let restart_policy = self.server_config.supervision.restart_policy();
 
-        workers.add_worker(Box::new(order_book_manager));
+        workers.add_supervised_worker(Box::new(order_book_manager), restart_policy.clone());
 
-        let mut okex_adapter = OkexExchangeAdapter::new(self.context.clone())?;
-        let okex_callback = okex_adapter.callback(internal_message_producer.sender());
+        let (subscriptions, connections) = add_exchange_adapters(
+            &self.context,
+            &self.server_config,
+            internal_message_producer.sender(),
+            &mut workers,
+            &restart_policy,
+        );
 
-        workers.add_worker(okex_adapter.worker(okex_callback));
+        if let Some(config_file) = self.config_file.as_deref() {
+            let config_watcher = ConfigWatcher::new(
+                self.context.with_name("config-watcher"),
+                config_file,
+                self.overrides.clone(),
+                self.server_config.clone(),
+                manager_config.clone(),
+                subscriptions,
+            );
+            workers.add_supervised_worker(Box::new(config_watcher), restart_policy.clone());
+        }
 
-        let mut deribit_adapter = DeribitExchangeAdapter::new(self.context.clone())?;
-        let deribit_callback = deribit_adapter.callback(internal_message_producer.sender());
+        let journal = if self.server_config.journal.enabled {
+            let journal = Journal::open(&self.server_config.journal.path)?;
+            log::info!("journaling opportunities to {}", self.server_config.journal.path);
+            let journal_writer = JournalWriter::new(self.context.with_name("journal-writer"), journal.clone(), broadcaster.clone());
+            workers.add_supervised_worker(Box::new(journal_writer), restart_policy.clone());
+            Some(journal)
+        } else {
+            None
+        };
 
-        workers.add_worker(deribit_adapter.worker(deribit_callback));
+        let paper_trader = self.server_config.paper.enabled.then(|| {
+            let paper_trader = PaperTrader::new(
+                self.context.with_name("paper-trader"),
+                &self.server_config.paper,
+                &self.server_config.risk,
+                manager_config,
+                broadcaster.clone(),
+                book_broadcaster.clone(),
+            );
+            match account_funding.clone() {
+                Some(funding) => paper_trader.with_funding(funding),
+                None => paper_trader,
+            }
+        });
 
+        let execution_manager = if self.server_config.live.enabled {
+            let mut order_updates = MpSc::new(ORDER_UPDATES_BUFFER_SIZE);
+            let order_senders = add_private_sessions(
+                &self.context,
+                &self.server_config,
+                order_updates.sender(),
+                account_funding.as_ref(),
+                &mut workers,
+                &restart_policy,
+            );
+            Some(ExecutionManager::new(
+                self.context.with_name("execution-manager"),
+                &self.server_config.live,
+                &self.server_config.risk,
+                broadcaster.clone(),
+                book_broadcaster.clone(),
+                order_senders,
+                &mut order_updates,
+            ))
+        } else {
+            None
+        };
 
-        let endpoint = Endpoint::new(self.context.clone(), broadcaster);
-        workers.add_worker(Box::new(endpoint));
+        let mut endpoint = Endpoint::new(
+            self.context.with_name("endpoint"),
+            &self.server_config.endpoint,
+            broadcaster,
+            connections,
+            latency,
+            journal,
+        )
+        .with_order_books(order_books, book_broadcaster);
+        if let Some(funding) = funding {
+            endpoint = endpoint.with_funding(funding);
+        }
+        if let Some(paper_trader) = paper_trader {
+            log::info!("paper trading the opportunities");
+            endpoint = endpoint.with_execution(paper_trader.handle());
+            workers.add_supervised_worker(Box::new(paper_trader), restart_policy.clone());
+        }
+        if let Some(execution_manager) = execution_manager {
+            log::warn!("executing the opportunities on the exchanges");
+            endpoint = endpoint.with_execution(execution_manager.handle());
+            workers.add_supervised_worker(Box::new(execution_manager), restart_policy.clone());
+        }
+        workers.add_supervised_worker(Box::new(endpoint), restart_policy);
 
         workers.run().await
     }
@@ -53,4 +251,8 @@ impl Runner for ServerRunner {
     fn config(&self) -> &Config {
         &self.context.config
     }
+
+    fn context(&self) -> &Context {
+        &self.context
+    }
 }
@@ -0,0 +1,963 @@
+use std::{collections::HashMap, fmt, time::Duration};
+
+use common::{create_config, create_config_from_file, ArbitrageResult, RestartPolicy};
+use config::{Config, ConfigError, Environment};
+use models::{Exchange, Product};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize, Serializer};
+use tracing_subscriber::filter::Directive;
+use wsclient::{Recorder, DEFAULT_MAX_SILENT_HEARTBEATS};
+
+/// Env file read on startup when there is no configuration file,
+/// the variables it sets are overridden by the actual environment
+pub const ENV_FILE: &str = ".env/server.env";
+
+/// Prefix of the environment variables overriding any setting, e.g. `ARBITRAGE__ENDPOINT__PORT`
+const ENV_PREFIX: &str = "ARBITRAGE";
+
+/// Environment variables predating the configuration file and the setting they override
+const LEGACY_ENV_VARS: [(&str, &str); 15] = [
+    ("OKEX_WS_URL", "exchanges.okex.ws_url"),
+    ("OKEX_PRODUCTS_TO_SUBSCRIBE", "exchanges.okex.products"),
+    ("OKEX_HEARTBEAT_MILLIS", "exchanges.okex.heartbeat_millis"),
+    ("OKEX_MAX_SILENT_HEARTBEATS", "exchanges.okex.max_silent_heartbeats"),
+    ("DERIBIT_WS_URL", "exchanges.deribit.ws_url"),
+    ("DERIBIT_PRODUCTS_TO_SUBSCRIBE", "exchanges.deribit.products"),
+    ("DERIBIT_HEARTBEAT_MILLIS", "exchanges.deribit.heartbeat_millis"),
+    ("DERIBIT_MAX_SILENT_HEARTBEATS", "exchanges.deribit.max_silent_heartbeats"),
+    ("WEBSOCKET_SERVER_PORT", "endpoint.port"),
+    ("MAX_BOOK_AGE_MILLIS", "manager.max_book_age_millis"),
+    ("MIN_EDGE", "manager.min_edge"),
+    ("LOG_LEVEL", "logging.level"),
+    ("WORKER_MAX_RESTARTS", "supervision.max_restarts"),
+    ("WORKER_RESTART_WINDOW_SECS", "supervision.restart_window_secs"),
+    ("WORKER_RESTART_DELAY_MILLIS", "supervision.restart_delay_millis"),
+];
+
+/// Settings which are lists, given as comma separated values in the environment
+const LIST_KEYS: [&str; 2] = ["exchanges.okex.products", "exchanges.deribit.products"];
+
+
+/// Configuration of the server
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ServerConfig {
+    pub exchanges: ExchangesConfig,
+    pub endpoint: EndpointConfig,
+    pub manager: ManagerConfig,
+    pub logging: LoggingConfig,
+    pub supervision: SupervisionConfig,
+    pub recorder: RecorderConfig,
+    pub journal: JournalConfig,
+    pub backtest: BacktestConfig,
+    pub paper: PaperConfig,
+    pub live: LiveConfig,
+    pub risk: RiskConfig,
+    pub funding: FundingConfig,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ExchangesConfig {
+    pub okex: ExchangeConfig,
+    pub deribit: ExchangeConfig,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ExchangeConfig {
+    pub enabled: bool,
+    pub ws_url: String,
+    /// Okex instrument ids or Deribit book channels to subscribe to
+    pub products: Vec<String>,
+    /// Interval of the keepalives sent to the exchange
+    pub heartbeat_millis: u64,
+    /// Heartbeats without any message before reconnecting, 0 disables it
+    pub max_silent_heartbeats: u32,
+}
+
+impl Default for ExchangeConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            ws_url: String::new(),
+            products: vec![],
+            heartbeat_millis: 5000,
+            max_silent_heartbeats: DEFAULT_MAX_SILENT_HEARTBEATS,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct EndpointConfig {
+    pub port: u16,
+}
+
+impl Default for EndpointConfig {
+    fn default() -> Self {
+        Self { port: 9027 }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ManagerConfig {
+    /// Books which did not receive an update for longer than this are not considered for arbitrage
+    pub max_book_age_millis: i64,
+    /// Minimum profit per unit, after fees, for an opportunity to be reported
+    pub min_edge: Decimal,
+    pub fees: FeesConfig,
+}
+
+impl Default for ManagerConfig {
+    fn default() -> Self {
+        Self {
+            max_book_age_millis: 5000,
+            min_edge: Decimal::ZERO,
+            fees: FeesConfig::default(),
+        }
+    }
+}
+
+/// Fee paid on each leg of an opportunity, as a fraction of its price
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FeesConfig {
+    pub okex: Decimal,
+    pub deribit: Decimal,
+}
+
+impl FeesConfig {
+    pub fn rate(&self, exchange: &Exchange) -> Decimal {
+        match exchange {
+            Exchange::Okex => self.okex,
+            Exchange::Deribit => self.deribit,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LoggingConfig {
+    /// Default log directive, e.g. `info` or `server=debug`
+    pub level: String,
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        Self { level: "info".to_string() }
+    }
+}
+
+/// Restarts of the workers which fail, see `common::RestartPolicy`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SupervisionConfig {
+    /// Restarts allowed within the window, the server stops once a worker fails more often
+    pub max_restarts: usize,
+    pub restart_window_secs: u64,
+    /// Delay before restarting a failed worker
+    pub restart_delay_millis: u64,
+}
+
+impl Default for SupervisionConfig {
+    fn default() -> Self {
+        Self { max_restarts: 5, restart_window_secs: 60, restart_delay_millis: 1000 }
+    }
+}
+
+impl SupervisionConfig {
+    /// Only the failed worker is restarted
+    pub fn restart_policy(&self) -> RestartPolicy {
+        RestartPolicy::OneForOne {
+            max_restarts: self.max_restarts,
+            window: Duration::from_secs(self.restart_window_secs),
+            delay: Duration::from_millis(self.restart_delay_millis),
+        }
+    }
+}
+
+/// Recording of the raw frames received from the exchanges, see `wsclient::Recorder`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RecorderConfig {
+    pub enabled: bool,
+    pub directory: String,
+    /// Uncompressed size after which a file is rotated
+    pub max_file_mb: u64,
+    /// Age after which a file is rotated
+    pub rotate_minutes: u64,
+}
+
+impl Default for RecorderConfig {
+    fn default() -> Self {
+        Self { enabled: false, directory: "recordings".to_string(), max_file_mb: 256, rotate_minutes: 60 }
+    }
+}
+
+impl RecorderConfig {
+    pub fn recorder(&self, exchange: &Exchange) -> Recorder {
+        Recorder::new(
+            &self.directory,
+            &format!("{:?}", exchange).to_lowercase(),
+            self.max_file_mb * 1024 * 1024,
+            Duration::from_secs(self.rotate_minutes * 60),
+        )
+    }
+}
+
+
+/// SQLite database the emitted opportunities are stored to, see `journal::Journal`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct JournalConfig {
+    pub enabled: bool,
+    pub path: String,
+}
+
+impl Default for JournalConfig {
+    fn default() -> Self {
+        Self { enabled: true, path: "journal/opportunities.sqlite3".to_string() }
+    }
+}
+
+
+/// Execution simulated by the backtest, see `backtest::Backtester`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BacktestConfig {
+    /// Time between the detection of an opportunity and the arrival of its orders on the exchanges
+    pub latency_millis: u64,
+    /// Largest quantity sent on each leg of an opportunity
+    pub max_order_size: Decimal,
+    /// How far beyond the detected price the orders may be filled, in basis points
+    pub limit_bps: Decimal,
+    /// Price impact added to every fill, in basis points, as the recorded books do not react to the orders
+    pub slippage_bps: Decimal,
+}
+
+impl Default for BacktestConfig {
+    fn default() -> Self {
+        Self { latency_millis: 50, max_order_size: Decimal::ONE, limit_bps: Decimal::ZERO, slippage_bps: Decimal::ZERO }
+    }
+}
+
+
+/// Execution of the opportunities against the local books, see `execution::PaperTrader`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PaperConfig {
+    pub enabled: bool,
+    /// Time between the detection of an opportunity and the arrival of its orders on the exchanges
+    pub latency_millis: u64,
+    /// Largest quantity sent on each leg of an opportunity
+    pub max_order_size: Decimal,
+    /// How far beyond the detected price the orders may be filled, in basis points
+    pub limit_bps: Decimal,
+    /// Virtual balance of each exchange when the server starts, in the currency the options are quoted in
+    pub balances: BalancesConfig,
+}
+
+impl Default for PaperConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            latency_millis: 50,
+            max_order_size: Decimal::ONE,
+            limit_bps: Decimal::ZERO,
+            balances: BalancesConfig::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BalancesConfig {
+    pub okex: Decimal,
+    pub deribit: Decimal,
+}
+
+impl BalancesConfig {
+    pub fn balance(&self, exchange: &Exchange) -> Decimal {
+        match exchange {
+            Exchange::Okex => self.okex,
+            Exchange::Deribit => self.deribit,
+        }
+    }
+}
+
+
+/// Limits checked before executing an opportunity, by paper trading as well as live, see `execution::RiskManager`
+///
+/// A limit which is not set is not enforced.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RiskConfig {
+    /// Largest position of a product on an exchange, long or short
+    pub max_position: Option<Decimal>,
+    /// Largest net delta of an underlying across the exchanges, in units of the underlying, should only one leg fill
+    pub max_net_delta: Option<Decimal>,
+    /// Largest notional of the positions on an exchange, in USD
+    pub max_notional: Option<Decimal>,
+    /// Most executions in flight at once
+    pub max_open_opportunities: Option<usize>,
+}
+
+
+/// Where the funds available on each exchange are learnt from
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FundingSource {
+    /// The opportunities are not sized by the funds
+    #[default]
+    None,
+    /// The `balances` of the `funding` section
+    Static,
+    /// The virtual balances of paper trading, or the accounts of the private sessions when live
+    Account,
+}
+
+/// Funds the sizes of the opportunities are capped by, see `funding::Funding`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FundingConfig {
+    pub source: FundingSource,
+    /// Funds available on each exchange with the `static` source, in the currency the options are quoted in
+    pub balances: BalancesConfig,
+    /// Initial margin of a short option, in units of its underlying, and of a perpetual, as a fraction of its value
+    pub margin_rate: Decimal,
+    /// Increment the funded sizes are rounded down to
+    pub lot_size: Decimal,
+}
+
+impl Default for FundingConfig {
+    fn default() -> Self {
+        Self {
+            source: FundingSource::None,
+            balances: BalancesConfig::default(),
+            margin_rate: Decimal::new(15, 2),
+            lot_size: Decimal::new(1, 1),
+        }
+    }
+}
+
+
+/// Orders sent to the exchanges for the opportunities, see `execution::ExecutionManager`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LiveConfig {
+    pub enabled: bool,
+    /// Largest quantity sent on each leg of an opportunity
+    pub max_order_size: Decimal,
+    /// How far beyond the detected price the orders may be filled, in basis points
+    pub limit_bps: Decimal,
+    /// Time after which an execution is reported with the fills known so far, should an order not reach a final state
+    pub order_timeout_millis: u64,
+    pub leg_risk: LegRiskConfig,
+    pub okex: OkexLiveConfig,
+    pub deribit: DeribitLiveConfig,
+}
+
+impl Default for LiveConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            max_order_size: Decimal::ONE,
+            limit_bps: Decimal::ZERO,
+            order_timeout_millis: 5000,
+            leg_risk: LegRiskConfig::default(),
+            okex: OkexLiveConfig::default(),
+            deribit: DeribitLiveConfig::default(),
+        }
+    }
+}
+
+/// What is done with the naked size of an execution, i.e. what one leg filled beyond the other
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LegRiskAction {
+    /// Left open on the exchange of the leg which filled
+    None,
+    /// Sent again to the exchange of the leg which missed, up to `chase_bps` beyond its detected price, then unwound
+    Chase,
+    /// Closed at the best price of the exchange of the leg which filled
+    Unwind,
+    /// Left open, its delta offset with the perpetual of `hedge_exchange`
+    Hedge,
+}
+
+impl LegRiskAction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LegRiskAction::None => "none",
+            LegRiskAction::Chase => "chase",
+            LegRiskAction::Unwind => "unwind",
+            LegRiskAction::Hedge => "hedge",
+        }
+    }
+}
+
+/// Handling of the naked size of the executions, see `execution::LiveExecutor`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LegRiskConfig {
+    pub action: LegRiskAction,
+    /// How far beyond the detected price of the leg which missed it may be chased, in basis points, reached by steps
+    /// from `limit_bps` over `max_attempts` orders
+    pub chase_bps: Decimal,
+    /// Orders sent to chase, and then to unwind, before leaving the naked size open
+    pub max_attempts: u32,
+    /// Exchange of the perpetual which hedges the delta, its book should be subscribed to price the hedge
+    pub hedge_exchange: Exchange,
+    /// Increment of the size of the perpetual orders, 10 USD for `BTC-PERPETUAL` and a contract of 100 USD for
+    /// `BTC-USD-SWAP`
+    pub hedge_lot_size: Decimal,
+    /// How far beyond the best price of the perpetual the hedge may be filled, in basis points
+    pub hedge_limit_bps: Decimal,
+}
+
+impl Default for LegRiskConfig {
+    fn default() -> Self {
+        Self {
+            action: LegRiskAction::Unwind,
+            chase_bps: Decimal::from(50),
+            max_attempts: 3,
+            hedge_exchange: Exchange::Deribit,
+            hedge_lot_size: Decimal::from(10),
+            hedge_limit_bps: Decimal::from(10),
+        }
+    }
+}
+
+/// Private websocket of Okex, logged in with an api key
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct OkexLiveConfig {
+    pub ws_url: String,
+    pub api_key: String,
+    pub secret_key: Secret,
+    pub passphrase: Secret,
+    /// Margin mode of the orders, `cross` or `isolated`
+    pub trade_mode: String,
+}
+
+impl Default for OkexLiveConfig {
+    fn default() -> Self {
+        Self {
+            ws_url: "wss://ws.okx.com:8443/ws/v5/private".to_string(),
+            api_key: String::new(),
+            secret_key: Secret::default(),
+            passphrase: Secret::default(),
+            trade_mode: "cross".to_string(),
+        }
+    }
+}
+
+/// Websocket of Deribit, authenticated with the client credentials of an api key
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DeribitLiveConfig {
+    pub ws_url: String,
+    pub client_id: String,
+    pub client_secret: Secret,
+}
+
+impl Default for DeribitLiveConfig {
+    fn default() -> Self {
+        Self { ws_url: "wss://www.deribit.com/ws/api/v2".to_string(), client_id: String::new(), client_secret: Secret::default() }
+    }
+}
+
+/// Credential which is never printed, neither by `validate-config` nor in the logs of a configuration reload
+#[derive(Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(transparent)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn new(secret: &str) -> Self {
+        Self(secret.to_string())
+    }
+
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    fn redacted(&self) -> &'static str {
+        if self.is_empty() { "" } else { "<redacted>" }
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self.redacted())
+    }
+}
+
+impl Serialize for Secret {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.redacted())
+    }
+}
+
+
+/// Settings given on the command line, which take precedence over the file and the environment
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ConfigOverrides {
+    /// Only these exchanges are enabled
+    pub exchanges: Option<Vec<Exchange>>,
+    pub port: Option<u16>,
+}
+
+
+impl ServerConfig {
+    /// Loads the configuration from an optional TOML or YAML file, overridden by the environment
+    /// and then by the command line
+    ///
+    /// Returns the raw configuration, which is also used by the common workers settings,
+    /// along with the validated server configuration.
+    pub fn load(config_file: Option<&str>, overrides: &ConfigOverrides) -> ArbitrageResult<(Config, ServerConfig)> {
+        let config = Self::resolve(config_file, overrides)?;
+        let server_config = Self::from_config(&config)?;
+        Ok((config, server_config))
+    }
+
+    /// Merges the sources of the configuration, without validating it
+    pub fn resolve(config_file: Option<&str>, overrides: &ConfigOverrides) -> ArbitrageResult<Config> {
+        let builder = match config_file {
+            Some(config_file) => create_config_from_file(config_file),
+            None => create_config(ENV_FILE),
+        };
+        let env_vars = std::env::vars().collect::<HashMap<String, String>>();
+        let mut builder = builder
+            .add_source(env_source(legacy_env_vars(&env_vars)))
+            .add_source(env_source(env_vars).prefix(ENV_PREFIX));
+        if let Some(exchanges) = overrides.exchanges.as_ref() {
+            for exchange in [Exchange::Okex, Exchange::Deribit] {
+                let key = format!("exchanges.{}.enabled", format!("{:?}", exchange).to_lowercase());
+                builder = builder.set_override(key, exchanges.contains(&exchange))?;
+            }
+        }
+        if let Some(port) = overrides.port {
+            builder = builder.set_override("endpoint.port", port)?;
+        }
+        Ok(builder.build()?)
+    }
+
+    /// Deserializes and validates the configuration, reporting every problem at once
+    pub fn from_config(config: &Config) -> ArbitrageResult<ServerConfig> {
+        let server_config = config.clone().try_deserialize::<ServerConfig>()?;
+        let problems = server_config.validate();
+        if !problems.is_empty() {
+            let message = format!("invalid configuration:\n  - {}", problems.join("\n  - "));
+            return Err(ConfigError::Message(message).into());
+        }
+        Ok(server_config)
+    }
+
+    /// Configuration of each exchange, whether enabled or not
+    pub fn exchanges(&self) -> [(Exchange, &ExchangeConfig); 2] {
+        [(Exchange::Okex, &self.exchanges.okex), (Exchange::Deribit, &self.exchanges.deribit)]
+    }
+
+    pub fn validate(&self) -> Vec<String> {
+        let mut problems = vec![];
+
+        if self.exchanges().iter().all(|(_, config)| !config.enabled) {
+            problems.push("at least one exchange should be enabled".to_string());
+        }
+        for (exchange, config) in self.exchanges() {
+            if !config.enabled {
+                continue;
+            }
+            let section = format!("exchanges.{}", format!("{:?}", exchange).to_lowercase());
+            if !config.ws_url.starts_with("ws://") && !config.ws_url.starts_with("wss://") {
+                problems.push(format!("{}.ws_url should be a ws:// or wss:// url, got {:?}", section, config.ws_url));
+            }
+            if config.heartbeat_millis == 0 {
+                problems.push(format!("{}.heartbeat_millis should be positive", section));
+            }
+            for product in config.products.iter() {
+                if parse_product(&exchange, product).is_none() {
+                    problems.push(format!("{}.products contains {:?} which is not a known product", section, product));
+                }
+            }
+        }
+
+        if self.endpoint.port == 0 {
+            problems.push("endpoint.port should be positive".to_string());
+        }
+        if self.supervision.restart_window_secs == 0 {
+            problems.push("supervision.restart_window_secs should be positive".to_string());
+        }
+        if self.manager.max_book_age_millis <= 0 {
+            problems.push("manager.max_book_age_millis should be positive".to_string());
+        }
+        if self.manager.min_edge < Decimal::ZERO {
+            problems.push("manager.min_edge should not be negative".to_string());
+        }
+        for (exchange, _) in self.exchanges() {
+            let fee = self.manager.fees.rate(&exchange);
+            if fee < Decimal::ZERO || fee >= Decimal::ONE {
+                problems.push(format!("manager.fees.{} should be within [0, 1), got {}", format!("{:?}", exchange).to_lowercase(), fee));
+            }
+        }
+        if self.recorder.enabled {
+            if self.recorder.directory.is_empty() {
+                problems.push("recorder.directory should not be empty".to_string());
+            }
+            if self.recorder.max_file_mb == 0 {
+                problems.push("recorder.max_file_mb should be positive".to_string());
+            }
+            if self.recorder.rotate_minutes == 0 {
+                problems.push("recorder.rotate_minutes should be positive".to_string());
+            }
+        }
+        if self.journal.enabled && self.journal.path.is_empty() {
+            problems.push("journal.path should not be empty".to_string());
+        }
+        if self.backtest.max_order_size <= Decimal::ZERO {
+            problems.push("backtest.max_order_size should be positive".to_string());
+        }
+        if self.backtest.limit_bps < Decimal::ZERO {
+            problems.push("backtest.limit_bps should not be negative".to_string());
+        }
+        if self.backtest.slippage_bps < Decimal::ZERO {
+            problems.push("backtest.slippage_bps should not be negative".to_string());
+        }
+        if self.paper.enabled {
+            if self.paper.max_order_size <= Decimal::ZERO {
+                problems.push("paper.max_order_size should be positive".to_string());
+            }
+            if self.paper.limit_bps < Decimal::ZERO {
+                problems.push("paper.limit_bps should not be negative".to_string());
+            }
+        }
+        if self.live.enabled {
+            problems.extend(self.validate_live());
+        }
+        let limits = [
+            ("max_position", self.risk.max_position),
+            ("max_net_delta", self.risk.max_net_delta),
+            ("max_notional", self.risk.max_notional),
+            ("max_open_opportunities", self.risk.max_open_opportunities.map(Decimal::from)),
+        ];
+        for (name, limit) in limits {
+            if limit.is_some_and(|limit| limit <= Decimal::ZERO) {
+                problems.push(format!("risk.{} should be positive when set", name));
+            }
+        }
+        if self.funding.source != FundingSource::None {
+            if self.funding.margin_rate < Decimal::ZERO || self.funding.margin_rate > Decimal::ONE {
+                problems.push(format!("funding.margin_rate should be within [0, 1], got {}", self.funding.margin_rate));
+            }
+            if self.funding.lot_size <= Decimal::ZERO {
+                problems.push("funding.lot_size should be positive".to_string());
+            }
+        }
+        if self.funding.source == FundingSource::Account && !self.paper.enabled && !self.live.enabled {
+            problems.push("funding.source account needs paper or live to be enabled".to_string());
+        }
+        if let Err(e) = self.logging.level.parse::<Directive>() {
+            problems.push(format!("logging.level {:?} is invalid: {}", self.logging.level, e));
+        }
+
+        problems
+    }
+
+    fn validate_live(&self) -> Vec<String> {
+        let mut problems = vec![];
+        if self.paper.enabled {
+            problems.push("paper and live should not both be enabled".to_string());
+        }
+        if self.live.max_order_size <= Decimal::ZERO {
+            problems.push("live.max_order_size should be positive".to_string());
+        }
+        if self.live.limit_bps < Decimal::ZERO {
+            problems.push("live.limit_bps should not be negative".to_string());
+        }
+        if self.live.order_timeout_millis == 0 {
+            problems.push("live.order_timeout_millis should be positive".to_string());
+        }
+        let leg_risk = &self.live.leg_risk;
+        if leg_risk.chase_bps < self.live.limit_bps {
+            problems.push("live.leg_risk.chase_bps should not be below live.limit_bps".to_string());
+        }
+        if leg_risk.max_attempts == 0 {
+            problems.push("live.leg_risk.max_attempts should be positive".to_string());
+        }
+        if leg_risk.hedge_lot_size <= Decimal::ZERO {
+            problems.push("live.leg_risk.hedge_lot_size should be positive".to_string());
+        }
+        if leg_risk.hedge_limit_bps < Decimal::ZERO {
+            problems.push("live.leg_risk.hedge_limit_bps should not be negative".to_string());
+        }
+        let hedge_enabled = match leg_risk.hedge_exchange {
+            Exchange::Okex => self.exchanges.okex.enabled,
+            Exchange::Deribit => self.exchanges.deribit.enabled,
+        };
+        if leg_risk.action == LegRiskAction::Hedge && !hedge_enabled {
+            problems.push(format!("live.leg_risk.hedge_exchange {:?} should be enabled", leg_risk.hedge_exchange));
+        }
+        let ws_url = |section: &str, ws_url: &str| {
+            (!ws_url.starts_with("ws://") && !ws_url.starts_with("wss://"))
+                .then(|| format!("{}.ws_url should be a ws:// or wss:// url, got {:?}", section, ws_url))
+        };
+        if self.exchanges.okex.enabled {
+            let okex = &self.live.okex;
+            problems.extend(ws_url("live.okex", &okex.ws_url));
+            if okex.api_key.is_empty() || okex.secret_key.is_empty() || okex.passphrase.is_empty() {
+                problems.push("live.okex.api_key, secret_key and passphrase should be set".to_string());
+            }
+            if okex.trade_mode != "cross" && okex.trade_mode != "isolated" {
+                problems.push(format!("live.okex.trade_mode should be cross or isolated, got {:?}", okex.trade_mode));
+            }
+        }
+        if self.exchanges.deribit.enabled {
+            let deribit = &self.live.deribit;
+            problems.extend(ws_url("live.deribit", &deribit.ws_url));
+            if deribit.client_id.is_empty() || deribit.client_secret.is_empty() {
+                problems.push("live.deribit.client_id and client_secret should be set".to_string());
+            }
+        }
+        problems
+    }
+}
+
+
+/// Parses a subscription of an exchange, i.e. an Okex instrument id or a Deribit book channel
+pub fn parse_product(exchange: &Exchange, subscription: &str) -> Option<Product> {
+    match exchange {
+        Exchange::Okex => Product::from_okex_exhchange(subscription),
+        // e.g. book.BTC-21FEB25-90000-P.none.20.100ms
+        Exchange::Deribit => Product::from_deribit_exchange(subscription.split('.').nth(1)?),
+    }
+}
+
+fn env_source(env_vars: HashMap<String, String>) -> Environment {
+    let source = Environment::default()
+        .separator("__")
+        .list_separator(",")
+        .try_parsing(true)
+        .source(Some(env_vars));
+    LIST_KEYS.iter().fold(source, |source, key| source.with_list_parse_key(key))
+}
+
+/// Renames the legacy environment variables after the setting they override, e.g. `ENDPOINT__PORT`
+fn legacy_env_vars(env_vars: &HashMap<String, String>) -> HashMap<String, String> {
+    LEGACY_ENV_VARS
+        .iter()
+        .filter_map(|(name, key)| {
+            let value = env_vars.get(*name)?;
+            Some((key.replace('.', "__").to_uppercase(), value.clone()))
+        })
+        .collect()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use config::{File, FileFormat};
+    use rust_decimal_macros::dec;
+
+    use super::*;
+
+    const TOML: &str = r#"
+        [exchanges.okex]
+        ws_url = "wss://ws.okx.com:8443/ws/v5/public"
+        products = ["BTC-USD-250221-90000-P"]
+
+        [exchanges.deribit]
+        ws_url = "wss://www.deribit.com/ws/api/v2"
+        products = ["book.BTC-21FEB25-90000-P.none.20.100ms"]
+        heartbeat_millis = 10000
+
+        [manager]
+        min_edge = "0.0005"
+        fees = { okex = "0.0003" }
+    "#;
+
+    #[test]
+    fn test_load_toml_with_env_overrides() {
+        let env_vars = HashMap::from([
+            ("WEBSOCKET_SERVER_PORT".to_string(), "9100".to_string()),
+            ("OKEX_PRODUCTS_TO_SUBSCRIBE".to_string(), "BTC-USD-250221-90000-P,BTC-USD-250221-95000-C".to_string()),
+        ]);
+        let config = Config::builder()
+            .add_source(File::from_str(TOML, FileFormat::Toml))
+            .add_source(env_source(legacy_env_vars(&env_vars)))
+            .build()
+            .unwrap();
+
+        let server_config = ServerConfig::from_config(&config).unwrap();
+        assert_eq!(server_config.endpoint.port, 9100);
+        assert_eq!(server_config.exchanges.okex.products, vec!["BTC-USD-250221-90000-P", "BTC-USD-250221-95000-C"]);
+        assert_eq!(server_config.exchanges.okex.heartbeat_millis, 5000);
+        assert_eq!(server_config.exchanges.deribit.heartbeat_millis, 10000);
+        assert_eq!(server_config.manager.min_edge, dec!(0.0005));
+        assert_eq!(server_config.manager.fees.rate(&Exchange::Okex), dec!(0.0003));
+        assert_eq!(server_config.manager.fees.rate(&Exchange::Deribit), Decimal::ZERO);
+    }
+
+    #[test]
+    fn test_supervision_config() {
+        let env_vars = HashMap::from([
+            ("WORKER_MAX_RESTARTS".to_string(), "2".to_string()),
+            ("WORKER_RESTART_DELAY_MILLIS".to_string(), "250".to_string()),
+        ]);
+        let config = Config::builder()
+            .add_source(File::from_str(TOML, FileFormat::Toml))
+            .add_source(env_source(legacy_env_vars(&env_vars)))
+            .build()
+            .unwrap();
+        let server_config = ServerConfig::from_config(&config).unwrap();
+        assert_eq!(server_config.supervision.restart_policy(), RestartPolicy::OneForOne {
+            max_restarts: 2,
+            window: Duration::from_secs(60),
+            delay: Duration::from_millis(250),
+        });
+
+        // A negative value is refused rather than wrapped
+        let env_vars = HashMap::from([("WORKER_MAX_RESTARTS".to_string(), "-1".to_string())]);
+        let config = Config::builder()
+            .add_source(File::from_str(TOML, FileFormat::Toml))
+            .add_source(env_source(legacy_env_vars(&env_vars)))
+            .build()
+            .unwrap();
+        assert!(ServerConfig::from_config(&config).is_err());
+
+        let mut server_config = ServerConfig::default();
+        server_config.exchanges.okex.ws_url = "wss://ws.okx.com:8443/ws/v5/public".to_string();
+        server_config.exchanges.deribit.ws_url = "wss://www.deribit.com/ws/api/v2".to_string();
+        server_config.supervision.restart_window_secs = 0;
+        assert_eq!(server_config.validate(), vec!["supervision.restart_window_secs should be positive"]);
+    }
+
+    #[test]
+    fn test_validation_reports_every_problem() {
+        let mut server_config = ServerConfig::default();
+        server_config.exchanges.okex.ws_url = "wss://ws.okx.com:8443/ws/v5/public".to_string();
+        server_config.exchanges.okex.products = vec!["BTC-USD-250221-90000-X".to_string()];
+        server_config.exchanges.deribit.ws_url = "https://www.deribit.com".to_string();
+        server_config.manager.fees.deribit = dec!(1.5);
+        server_config.logging.level = "server=loud".to_string();
+
+        let problems = server_config.validate();
+        assert_eq!(problems.len(), 4, "{:?}", problems);
+        assert_eq!(problems[..3], [
+            "exchanges.okex.products contains \"BTC-USD-250221-90000-X\" which is not a known product",
+            "exchanges.deribit.ws_url should be a ws:// or wss:// url, got \"https://www.deribit.com\"",
+            "manager.fees.deribit should be within [0, 1), got 1.5",
+        ]);
+        assert!(problems[3].starts_with("logging.level \"server=loud\" is invalid"), "{:?}", problems);
+
+        let error = ServerConfig::from_config(&Config::default()).unwrap_err().to_string();
+        assert!(error.contains("exchanges.okex.ws_url"));
+        assert!(error.contains("exchanges.deribit.ws_url"));
+    }
+
+    #[test]
+    fn test_live_validation() {
+        let mut server_config = ServerConfig::default();
+        server_config.exchanges.okex.ws_url = "wss://ws.okx.com:8443/ws/v5/public".to_string();
+        server_config.exchanges.deribit.ws_url = "wss://www.deribit.com/ws/api/v2".to_string();
+        server_config.paper.enabled = true;
+        server_config.live.enabled = true;
+        server_config.live.max_order_size = Decimal::ZERO;
+        server_config.live.order_timeout_millis = 0;
+        server_config.live.okex.ws_url = "https://ws.okx.com:8443/ws/v5/private".to_string();
+        server_config.live.okex.api_key = "key".to_string();
+        server_config.live.okex.secret_key = Secret::new("secret");
+        server_config.live.okex.trade_mode = "portfolio".to_string();
+        assert_eq!(server_config.validate(), vec![
+            "paper and live should not both be enabled",
+            "live.max_order_size should be positive",
+            "live.order_timeout_millis should be positive",
+            "live.okex.ws_url should be a ws:// or wss:// url, got \"https://ws.okx.com:8443/ws/v5/private\"",
+            "live.okex.api_key, secret_key and passphrase should be set",
+            "live.okex.trade_mode should be cross or isolated, got \"portfolio\"",
+            "live.deribit.client_id and client_secret should be set",
+        ]);
+        assert!(!format!("{:?}", server_config).contains("secret\""));
+        assert_eq!(serde_json::to_value(&server_config).unwrap()["live"]["okex"]["secret_key"], "<redacted>");
+
+        // Live trading is not checked unless enabled
+        server_config.live.enabled = false;
+        assert!(server_config.validate().is_empty());
+    }
+
+    #[test]
+    fn test_leg_risk_validation() {
+        let mut server_config = ServerConfig::default();
+        server_config.exchanges.okex.ws_url = "wss://ws.okx.com:8443/ws/v5/public".to_string();
+        server_config.exchanges.deribit.enabled = false;
+        server_config.live.enabled = true;
+        server_config.live.okex.api_key = "key".to_string();
+        server_config.live.okex.secret_key = Secret::new("secret");
+        server_config.live.okex.passphrase = Secret::new("passphrase");
+        server_config.live.limit_bps = dec!(20);
+        server_config.live.leg_risk.action = LegRiskAction::Hedge;
+        server_config.live.leg_risk.chase_bps = dec!(10);
+        server_config.live.leg_risk.max_attempts = 0;
+        server_config.live.leg_risk.hedge_lot_size = Decimal::ZERO;
+        server_config.live.leg_risk.hedge_limit_bps = dec!(-1);
+        assert_eq!(server_config.validate(), vec![
+            "live.leg_risk.chase_bps should not be below live.limit_bps",
+            "live.leg_risk.max_attempts should be positive",
+            "live.leg_risk.hedge_lot_size should be positive",
+            "live.leg_risk.hedge_limit_bps should not be negative",
+            "live.leg_risk.hedge_exchange Deribit should be enabled",
+        ]);
+
+        server_config.live.leg_risk = LegRiskConfig { hedge_exchange: Exchange::Okex, ..LegRiskConfig::default() };
+        assert!(server_config.validate().is_empty());
+    }
+
+    #[test]
+    fn test_risk_validation() {
+        let mut server_config = ServerConfig::default();
+        server_config.exchanges.okex.ws_url = "wss://ws.okx.com:8443/ws/v5/public".to_string();
+        server_config.exchanges.deribit.ws_url = "wss://www.deribit.com/ws/api/v2".to_string();
+        server_config.risk.max_position = Some(dec!(10));
+        server_config.risk.max_net_delta = Some(dec!(-1));
+        server_config.risk.max_notional = Some(Decimal::ZERO);
+        server_config.risk.max_open_opportunities = Some(0);
+        assert_eq!(server_config.validate(), vec![
+            "risk.max_net_delta should be positive when set",
+            "risk.max_notional should be positive when set",
+            "risk.max_open_opportunities should be positive when set",
+        ]);
+
+        server_config.risk = RiskConfig::default();
+        assert!(server_config.validate().is_empty());
+    }
+
+    #[test]
+    fn test_funding_validation() {
+        let mut server_config = ServerConfig::default();
+        server_config.exchanges.okex.ws_url = "wss://ws.okx.com:8443/ws/v5/public".to_string();
+        server_config.exchanges.deribit.ws_url = "wss://www.deribit.com/ws/api/v2".to_string();
+        server_config.funding.source = FundingSource::Account;
+        server_config.funding.margin_rate = dec!(1.5);
+        server_config.funding.lot_size = Decimal::ZERO;
+        assert_eq!(server_config.validate(), vec![
+            "funding.margin_rate should be within [0, 1], got 1.5",
+            "funding.lot_size should be positive",
+            "funding.source account needs paper or live to be enabled",
+        ]);
+
+        // The funds are not checked when they do not size the opportunities
+        server_config.funding.source = FundingSource::None;
+        assert!(server_config.validate().is_empty());
+
+        server_config.funding.source = FundingSource::Account;
+        server_config.funding.margin_rate = dec!(0.1);
+        server_config.funding.lot_size = Decimal::ONE;
+        server_config.paper.enabled = true;
+        assert!(server_config.validate().is_empty());
+    }
+}
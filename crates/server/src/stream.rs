@@ -0,0 +1,569 @@
+use std::collections::{BTreeMap, HashMap};
+
+use chrono::NaiveDate;
+use jiff::{SignedDuration, Timestamp};
+use models::{ArbitrageOpportunity, CryptoAsset, Exchange, ExchangeProduct, OrderBook, OrderBookUpdate, Product};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+
+use crate::books::OrderBooks;
+
+/// Exchanges whose books are sent when a book subscription does not name any
+const EXCHANGES: [Exchange; 2] = [Exchange::Okex, Exchange::Deribit];
+
+
+/// Request of a client of `/stream/v1`, answered by a `StreamResponse` with the same id
+///
+/// e.g. `{"id": 1, "method": "subscribe", "params": {"channel": "opportunities", "underlying": "BTC"}}`,
+/// `{"id": 2, "method": "subscribe", "params": {"channel": "book", "product": "BTC-USD-250221-90000-P"}}`
+/// or `{"id": 3, "method": "unsubscribe", "params": {"subscription": 1}}`
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct StreamRequest {
+    #[serde(default)]
+    pub id: Option<u64>,
+    #[serde(flatten)]
+    pub command: StreamCommand,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(tag = "method", content = "params", rename_all = "snake_case")]
+pub enum StreamCommand {
+    Subscribe(StreamSubscription),
+    Unsubscribe { subscription: u64 },
+}
+
+/// What a subscription sends the client, by channel
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(tag = "channel", rename_all = "snake_case")]
+pub enum StreamSubscription {
+    Opportunities(OpportunityFilter),
+    Book(BookFilter),
+}
+
+/// Opportunities sent by a subscription, every criterion which is set has to match
+///
+/// The criteria on the expiry and the strike only match options.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct OpportunityFilter {
+    pub underlying: Option<CryptoAsset>,
+    /// e.g. `2025-02-21`
+    pub expiry: Option<NaiveDate>,
+    pub min_strike: Option<Decimal>,
+    pub max_strike: Option<Decimal>,
+    pub buy_exchange: Option<Exchange>,
+    pub sell_exchange: Option<Exchange>,
+    pub min_edge: Option<Decimal>,
+}
+
+impl OpportunityFilter {
+    fn validate(&self) -> Result<(), String> {
+        if let (Some(min_strike), Some(max_strike)) = (self.min_strike, self.max_strike) {
+            if min_strike > max_strike {
+                return Err(format!("min_strike {} should not be above max_strike {}", min_strike, max_strike));
+            }
+        }
+        if self.buy_exchange.is_some() && self.buy_exchange == self.sell_exchange {
+            return Err("buy_exchange and sell_exchange should differ".to_string());
+        }
+        Ok(())
+    }
+
+    pub fn matches(&self, opportunity: &ArbitrageOpportunity) -> bool {
+        let product = &opportunity.product;
+        if self.underlying.as_ref().is_some_and(|underlying| underlying != product.underlying()) {
+            return false;
+        }
+        if self.expiry.is_some() || self.min_strike.is_some() || self.max_strike.is_some() {
+            let Product::Option { strike, expiration, .. } = product else {
+                return false;
+            };
+            if self.expiry.is_some_and(|expiry| expiry != *expiration)
+                || self.min_strike.is_some_and(|min_strike| *strike < min_strike)
+                || self.max_strike.is_some_and(|max_strike| *strike > max_strike)
+            {
+                return false;
+            }
+        }
+        self.buy_exchange.as_ref().is_none_or(|exchange| *exchange == opportunity.buy_exchange)
+            && self.sell_exchange.as_ref().is_none_or(|exchange| *exchange == opportunity.sell_exchange)
+            && self.min_edge.is_none_or(|min_edge| opportunity.edge >= min_edge)
+    }
+}
+
+/// Books of a product sent by a subscription, side by side in each message
+///
+/// The first message is a snapshot of the levels of each book, the next ones are deltas of the levels which changed
+/// since the previous message, a size of zero removing the level.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BookFilter {
+    /// Okex or Deribit name of the product, e.g. `BTC-USD-250221-90000-P` or `BTC-21FEB25-90000-P`
+    pub product: String,
+    /// Both exchanges when not set
+    #[serde(default)]
+    pub exchanges: Option<Vec<Exchange>>,
+    /// Levels of each side, 1 for the top of the book and 0 for the full depth
+    #[serde(default = "default_levels")]
+    pub levels: usize,
+    /// Least time between two messages of the subscription, 0 sends every change
+    #[serde(default = "default_interval_millis")]
+    pub interval_millis: u64,
+}
+
+fn default_levels() -> usize {
+    1
+}
+
+fn default_interval_millis() -> u64 {
+    100
+}
+
+impl BookFilter {
+    fn product(&self) -> Result<Product, String> {
+        Product::from_okex_exhchange(&self.product)
+            .or_else(|| Product::from_deribit_exchange(&self.product))
+            .ok_or_else(|| format!("unknown product {:?}", self.product))
+    }
+}
+
+/// Levels of a book, the best first
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct BookLevels {
+    pub exchange: Exchange,
+    pub bids: Vec<(Decimal, Decimal)>,
+    pub asks: Vec<(Decimal, Decimal)>,
+    /// Exchange time of the last update of the book, None if it never received one
+    pub exchange_time: Option<Timestamp>,
+}
+
+impl BookLevels {
+    /// Levels of a book which never received an update
+    fn empty(exchange: &Exchange) -> Self {
+        Self { exchange: exchange.clone(), bids: vec![], asks: vec![], exchange_time: None }
+    }
+
+    /// Up to `levels` levels of each side of the book, all of them if zero
+    fn new(order_book: &OrderBook, levels: usize) -> Self {
+        let depth = if levels == 0 { usize::MAX } else { levels };
+        let level = |(price, size): (&Decimal, &Decimal)| (*price, *size);
+        Self {
+            exchange: order_book.exchange_product.exchange.clone(),
+            bids: order_book.bids.iter().rev().take(depth).map(level).collect(),
+            asks: order_book.asks.iter().take(depth).map(level).collect(),
+            exchange_time: order_book.exchange_timestamp,
+        }
+    }
+
+    /// Levels which changed from `previous`, with a size of zero for those removed, None if none did
+    fn delta(&self, previous: &BookLevels) -> Option<BookLevels> {
+        let side = |current: &[(Decimal, Decimal)], previous: &[(Decimal, Decimal)]| {
+            let before = previous.iter().copied().collect::<HashMap<_, _>>();
+            let after = current.iter().copied().collect::<HashMap<_, _>>();
+            let removed = previous.iter().filter(|(price, _)| !after.contains_key(price)).map(|(price, _)| (*price, Decimal::ZERO));
+            let changed = current.iter().filter(|(price, size)| before.get(price) != Some(size)).copied();
+            changed.chain(removed).collect::<Vec<_>>()
+        };
+        let (bids, asks) = (side(&self.bids, &previous.bids), side(&self.asks, &previous.asks));
+        if bids.is_empty() && asks.is_empty() {
+            return None;
+        }
+        Some(BookLevels { exchange: self.exchange.clone(), bids, asks, exchange_time: self.exchange_time })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BookMessageType {
+    Snapshot,
+    Delta,
+}
+
+/// Message of a book subscription, with the books which changed for a delta
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct BookMessage {
+    /// Always `book`, to tell the books from the opportunities and the replies
+    pub channel: &'static str,
+    pub subscription: u64,
+    #[serde(rename = "type")]
+    pub message_type: BookMessageType,
+    pub product: Product,
+    pub books: Vec<BookLevels>,
+}
+
+/// State of a book subscription
+///
+/// The books are read from those of the order book manager, each message being the difference between them and the
+/// levels last sent, so that the updates of the books only tell which of them changed.
+#[derive(Debug)]
+struct BookStream {
+    product: Product,
+    levels: usize,
+    interval: SignedDuration,
+    books: Vec<ExchangeProduct>,
+    /// Levels of each book as last sent, None until the snapshot is sent
+    sent: Option<Vec<BookLevels>>,
+    /// Set when a book changed since the last message
+    changed: bool,
+    /// No message is sent before
+    next_time: Timestamp,
+}
+
+impl BookStream {
+    fn new(filter: &BookFilter, product: Product) -> Self {
+        let exchanges = filter.exchanges.clone().unwrap_or_else(|| EXCHANGES.to_vec());
+        Self {
+            books: exchanges.into_iter().map(|exchange| ExchangeProduct { exchange, product: product.clone() }).collect(),
+            product,
+            levels: filter.levels,
+            interval: SignedDuration::from_millis(filter.interval_millis as i64),
+            sent: None,
+            changed: true,
+            next_time: Timestamp::MIN,
+        }
+    }
+
+    fn on_order_book_update(&mut self, order_book_update: &OrderBookUpdate) {
+        if self.books.contains(&order_book_update.exchange_product) {
+            self.changed = true;
+        }
+    }
+
+    fn message(&mut self, subscription: u64, order_books: &OrderBooks, now: Timestamp) -> Option<BookMessage> {
+        self.changed = false;
+        self.next_time = now + self.interval;
+        let current = {
+            let order_books = order_books.lock();
+            self.books
+                .iter()
+                .map(|exchange_product| match order_books.get(exchange_product) {
+                    Some(order_book) => BookLevels::new(order_book, self.levels),
+                    None => BookLevels::empty(&exchange_product.exchange),
+                })
+                .collect::<Vec<_>>()
+        };
+        let (message_type, books) = match self.sent.as_ref() {
+            None => (BookMessageType::Snapshot, current.clone()),
+            Some(sent) => {
+                let books = current.iter().zip(sent).filter_map(|(current, sent)| current.delta(sent)).collect::<Vec<_>>();
+                if books.is_empty() {
+                    return None;
+                }
+                (BookMessageType::Delta, books)
+            }
+        };
+        self.sent = Some(current);
+        Some(BookMessage { channel: "book", subscription, message_type, product: self.product.clone(), books })
+    }
+}
+
+/// Subscription of a client, as kept by its session
+#[derive(Debug)]
+enum Subscription {
+    Opportunities(OpportunityFilter),
+    Book(Box<BookStream>),
+}
+
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct StreamResult {
+    /// Id of the subscription created or removed
+    pub subscription: u64,
+}
+
+/// Acknowledgement or error reply to a `StreamRequest`, whose id is null if the request could not be read
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct StreamResponse {
+    pub id: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub result: Option<StreamResult>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl StreamResponse {
+    fn result(id: Option<u64>, subscription: u64) -> Self {
+        Self { id, result: Some(StreamResult { subscription }), error: None }
+    }
+
+    fn error(id: Option<u64>, error: String) -> Self {
+        Self { id, result: None, error: Some(error) }
+    }
+}
+
+
+/// Subscriptions of a client of `/stream/v1`
+///
+/// A client which never subscribed is sent every opportunity, as the clients predating the subscriptions expect.
+/// Once it subscribed, it is only sent the opportunities matching one of its subscriptions, and the books it
+/// subscribed to.
+#[derive(Default)]
+pub struct StreamSession {
+    order_books: OrderBooks,
+    next_id: u64,
+    subscribed: bool,
+    subscriptions: BTreeMap<u64, Subscription>,
+}
+
+impl StreamSession {
+    pub fn new(order_books: OrderBooks) -> Self {
+        Self { order_books, ..Self::default() }
+    }
+
+    /// Applies a request of the client, returns the reply to send it
+    pub fn on_request(&mut self, text: &str) -> StreamResponse {
+        let request = match serde_json::from_str::<StreamRequest>(text) {
+            Ok(request) => request,
+            Err(e) => {
+                // The id is echoed back whenever it can be read
+                let id = serde_json::from_str::<serde_json::Value>(text).ok().and_then(|request| request["id"].as_u64());
+                return StreamResponse::error(id, format!("invalid request: {}", e));
+            }
+        };
+        match request.command {
+            StreamCommand::Subscribe(subscription) => {
+                let subscription = match self.subscription(subscription) {
+                    Ok(subscription) => subscription,
+                    Err(e) => return StreamResponse::error(request.id, e),
+                };
+                self.next_id += 1;
+                self.subscribed = true;
+                self.subscriptions.insert(self.next_id, subscription);
+                StreamResponse::result(request.id, self.next_id)
+            }
+            StreamCommand::Unsubscribe { subscription } => match self.subscriptions.remove(&subscription) {
+                Some(_) => StreamResponse::result(request.id, subscription),
+                None => StreamResponse::error(request.id, format!("unknown subscription {}", subscription)),
+            },
+        }
+    }
+
+    fn subscription(&self, subscription: StreamSubscription) -> Result<Subscription, String> {
+        match subscription {
+            StreamSubscription::Opportunities(filter) => {
+                filter.validate()?;
+                Ok(Subscription::Opportunities(filter))
+            }
+            StreamSubscription::Book(filter) => {
+                let product = filter.product()?;
+                if filter.exchanges.as_ref().is_some_and(|exchanges| exchanges.is_empty()) {
+                    return Err("exchanges should not be empty".to_string());
+                }
+                Ok(Subscription::Book(Box::new(BookStream::new(&filter, product))))
+            }
+        }
+    }
+
+    /// Whether the opportunity is sent to the client
+    pub fn wants_opportunity(&self, opportunity: &ArbitrageOpportunity) -> bool {
+        !self.subscribed
+            || self.subscriptions.values().any(|subscription| match subscription {
+                Subscription::Opportunities(filter) => filter.matches(opportunity),
+                Subscription::Book(_) => false,
+            })
+    }
+
+    pub fn on_order_book_update(&mut self, order_book_update: &OrderBookUpdate) {
+        for subscription in self.subscriptions.values_mut() {
+            if let Subscription::Book(stream) = subscription {
+                stream.on_order_book_update(order_book_update);
+            }
+        }
+    }
+
+    /// Whether the client subscribed to books, and has to be sent their updates
+    pub fn has_books(&self) -> bool {
+        self.subscriptions.values().any(|subscription| matches!(subscription, Subscription::Book(_)))
+    }
+
+    /// Checks every book of the subscriptions for changes, once the client missed some of their updates
+    pub fn resync(&mut self) {
+        for subscription in self.subscriptions.values_mut() {
+            if let Subscription::Book(stream) = subscription {
+                stream.changed = true;
+            }
+        }
+    }
+
+    /// Time the next book message is due, None if no book changed
+    pub fn next_book_time(&self) -> Option<Timestamp> {
+        self.subscriptions
+            .values()
+            .filter_map(|subscription| match subscription {
+                Subscription::Book(stream) if stream.changed => Some(stream.next_time),
+                _ => None,
+            })
+            .min()
+    }
+
+    /// Messages of the book subscriptions which changed and are due by `now`
+    pub fn book_messages(&mut self, now: Timestamp) -> Vec<BookMessage> {
+        let order_books = &self.order_books;
+        self.subscriptions
+            .iter_mut()
+            .filter_map(|(id, subscription)| match subscription {
+                Subscription::Book(stream) if stream.changed && stream.next_time <= now => stream.message(*id, order_books, now),
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use jiff::Timestamp;
+    use models::PipelineTimestamps;
+    use rust_decimal_macros::dec;
+
+    use super::*;
+
+    fn opportunity(instrument: &str, buy_exchange: Exchange, edge: Decimal) -> ArbitrageOpportunity {
+        let now = Timestamp::from_millisecond(0).unwrap();
+        let sell_exchange = match buy_exchange {
+            Exchange::Okex => Exchange::Deribit,
+            Exchange::Deribit => Exchange::Okex,
+        };
+        ArbitrageOpportunity {
+            product: Product::from_okex_exhchange(instrument).unwrap(),
+            buy_exchange,
+            sell_exchange,
+            buy_price: dec!(0.01),
+            sell_price: dec!(0.01) + edge,
+            size: Decimal::ONE,
+            edge,
+            buy_book_age_millis: 0,
+            sell_book_age_millis: 0,
+            trigger_exchange: Exchange::Okex,
+            timestamps: PipelineTimestamps::new(now, now),
+        }
+    }
+
+    #[test]
+    fn test_subscribe_and_filter_opportunities() {
+        let mut session = StreamSession::default();
+        let put = opportunity("BTC-USD-250221-90000-P", Exchange::Okex, dec!(0.002));
+        let far_call = opportunity("BTC-USD-250328-120000-C", Exchange::Deribit, dec!(0.0005));
+        let eth = opportunity("ETH-USD-250221-3000-C", Exchange::Okex, dec!(0.002));
+        assert!(session.wants_opportunity(&put) && session.wants_opportunity(&eth));
+
+        let request = r#"{"id": 1, "method": "subscribe", "params": {"channel": "opportunities", "underlying": "BTC", "expiry": "2025-02-21", "max_strike": "100000", "buy_exchange": "Okex"}}"#;
+        let response = session.on_request(request);
+        assert_eq!(serde_json::to_value(&response).unwrap(), serde_json::json!({"id": 1, "result": {"subscription": 1}}));
+        assert!(session.wants_opportunity(&put));
+        assert!(!session.wants_opportunity(&far_call));
+        assert!(!session.wants_opportunity(&eth));
+
+        let request = r#"{"id": 2, "method": "subscribe", "params": {"channel": "opportunities", "sell_exchange": "Okex", "min_edge": 0.001}}"#;
+        assert_eq!(session.on_request(request), StreamResponse::result(Some(2), 2));
+        assert!(!session.wants_opportunity(&far_call));
+        let perpetual = ArbitrageOpportunity {
+            product: Product::from_okex_exhchange("BTC-USD-SWAP").unwrap(),
+            ..opportunity("BTC-USD-250328-120000-C", Exchange::Deribit, dec!(0.001))
+        };
+        assert!(session.wants_opportunity(&perpetual));
+
+        assert_eq!(session.on_request(r#"{"id": 3, "method": "unsubscribe", "params": {"subscription": 1}}"#), StreamResponse::result(Some(3), 1));
+        assert!(!session.wants_opportunity(&put));
+
+        // Errors are replied to with the id of the request when it can be read
+        let errors = [
+            (r#"{"id": 4, "method": "unsubscribe", "params": {"subscription": 1}}"#, Some(4), "unknown subscription 1"),
+            (r#"{"id": 5, "method": "subscribe", "params": {"channel": "opportunities", "strike": "90000"}}"#, Some(5), "unknown field `strike`"),
+            (r#"{"id": 6, "method": "subscribe", "params": {"channel": "opportunities", "min_strike": 2, "max_strike": 1}}"#, Some(6), "should not be above"),
+            (r#"{"id": 7, "method": "subscribe", "params": {"channel": "trades"}}"#, Some(7), "unknown variant `trades`"),
+            (r#"{"method": "list"}"#, None, "unknown variant `list`"),
+            ("subscribe", None, "invalid request"),
+        ];
+        for (request, id, error) in errors {
+            let response = session.on_request(request);
+            assert_eq!(response.id, id, "{}", request);
+            assert!(response.error.as_ref().is_some_and(|e| e.contains(error)), "{:?}", response);
+        }
+        assert_eq!(session.subscriptions.len(), 1);
+
+        // No subscription left, nothing is sent
+        session.on_request(r#"{"id": 8, "method": "unsubscribe", "params": {"subscription": 2}}"#);
+        assert!(!session.wants_opportunity(&perpetual));
+    }
+
+    fn update(exchange: Exchange, bids: Vec<(Decimal, Decimal)>, asks: Vec<(Decimal, Decimal)>) -> OrderBookUpdate {
+        let now = Timestamp::from_millisecond(0).unwrap();
+        OrderBookUpdate {
+            exchange_product: ExchangeProduct { exchange, product: Product::from_okex_exhchange("BTC-USD-250221-90000-P").unwrap() },
+            bids,
+            asks,
+            timestamps: PipelineTimestamps::new(now, now),
+        }
+    }
+
+    #[test]
+    fn test_stream_book_snapshot_and_deltas() {
+        let order_books = OrderBooks::default();
+        order_books.apply(update(Exchange::Okex, vec![(dec!(0.01), dec!(5)), (dec!(0.009), dec!(2))], vec![(dec!(0.012), dec!(3))]));
+        let mut session = StreamSession::new(order_books.clone());
+        // The manager applies the update to its books before broadcasting it
+        let apply = |session: &mut StreamSession, order_book_update: OrderBookUpdate| {
+            order_books.apply(order_book_update.clone());
+            session.on_order_book_update(&order_book_update);
+        };
+        let start = Timestamp::from_millisecond(1_000).unwrap();
+        assert_eq!(session.next_book_time(), None);
+
+        // Named by its Deribit name, the top of the Okex book every second
+        let request = r#"{"id": 1, "method": "subscribe", "params": {"channel": "book", "product": "BTC-21FEB25-90000-P", "exchanges": ["Okex"], "interval_millis": 1000}}"#;
+        assert_eq!(session.on_request(request), StreamResponse::result(Some(1), 1));
+        assert!(!session.wants_opportunity(&opportunity("BTC-USD-250221-90000-P", Exchange::Okex, dec!(0.002))));
+        let messages = session.book_messages(start);
+        let expected = serde_json::json!([{
+            "channel": "book",
+            "subscription": 1,
+            "type": "snapshot",
+            "product": Product::from_okex_exhchange("BTC-USD-250221-90000-P").unwrap(),
+            "books": [{"exchange": "Okex", "bids": [["0.01", "5"]], "asks": [["0.012", "3"]], "exchange_time": "1970-01-01T00:00:00Z"}],
+        }]);
+        assert_eq!(serde_json::to_value(&messages).unwrap(), expected);
+        assert_eq!(session.next_book_time(), None);
+
+        // Below the top of the book, or on another exchange, nothing changed
+        apply(&mut session, update(Exchange::Okex, vec![(dec!(0.009), dec!(4))], vec![]));
+        apply(&mut session, update(Exchange::Deribit, vec![(dec!(0.011), dec!(1))], vec![]));
+        assert_eq!(session.next_book_time(), Some(start + SignedDuration::from_secs(1)));
+        assert!(session.book_messages(start + SignedDuration::from_secs(1)).is_empty());
+
+        // Throttled until the interval elapsed, then the changed levels and the removed ones
+        apply(&mut session, update(Exchange::Okex, vec![(dec!(0.01), Decimal::ZERO)], vec![(dec!(0.012), dec!(1))]));
+        let now = start + SignedDuration::from_millis(1_500);
+        assert!(session.book_messages(now).is_empty());
+        let messages = session.book_messages(start + SignedDuration::from_secs(2));
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].message_type, BookMessageType::Delta);
+        assert_eq!(messages[0].books[0].bids, vec![(dec!(0.009), dec!(4)), (dec!(0.01), Decimal::ZERO)]);
+        assert_eq!(messages[0].books[0].asks, vec![(dec!(0.012), dec!(1))]);
+
+        // An update the client missed is sent once it catches up, the books being those of the manager
+        order_books.apply(update(Exchange::Okex, vec![(dec!(0.0095), dec!(7))], vec![]));
+        assert_eq!(session.next_book_time(), None);
+        session.resync();
+        let messages = session.book_messages(start + SignedDuration::from_secs(3));
+        assert_eq!(messages[0].books[0].bids, vec![(dec!(0.0095), dec!(7)), (dec!(0.009), Decimal::ZERO)]);
+
+        // The full depth of both books
+        let request = r#"{"id": 2, "method": "subscribe", "params": {"channel": "book", "product": "BTC-USD-250221-90000-P", "levels": 0}}"#;
+        assert_eq!(session.on_request(request), StreamResponse::result(Some(2), 2));
+        let messages = session.book_messages(start + SignedDuration::from_secs(3));
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].books.len(), 2);
+        assert_eq!(messages[0].books[1].exchange, Exchange::Deribit);
+        assert_eq!(messages[0].books[1].bids, vec![(dec!(0.011), dec!(1))]);
+
+        let errors = [
+            (r#"{"id": 3, "method": "subscribe", "params": {"channel": "book", "product": "BTC-USD"}}"#, "unknown product"),
+            (r#"{"id": 4, "method": "subscribe", "params": {"channel": "book", "product": "BTC-USD-SWAP", "exchanges": []}}"#, "should not be empty"),
+        ];
+        for (request, error) in errors {
+            let response = session.on_request(request);
+            assert!(response.error.as_ref().is_some_and(|e| e.contains(error)), "{:?}", response);
+        }
+    }
+}
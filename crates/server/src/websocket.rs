@@ -1,53 +1,144 @@
-use common::ArbitrageResult;
-use models::InternalMessage;
-use tokio::sync::broadcast::Receiver;
+use std::time::Duration;
+
+use common::{AppMesssage, ArbitrageError, ArbitrageResult};
+use jiff::Timestamp;
+use models::{Exchange, InternalMessage, OrderBookUpdate, PipelineTimestamps};
+use tokio::sync::broadcast::{error::RecvError, Receiver, Sender};
 use futures_util::{stream::StreamExt, SinkExt};
+use serde::Serialize;
+
+use crate::{books::OrderBooks, latency::{LatencyStage, LatencyTracker}, metrics, stream::StreamSession};
+
+/// Close code sent to the clients when the server shuts down
+const CLOSE_GOING_AWAY: u16 = 1001;
 
+/// Session of a client of `/stream/v1`, sent the opportunities and the books it subscribed to, see `StreamSession`
 pub struct WebSocket {
     receiver: Receiver<InternalMessage>,
+    app: Receiver<AppMesssage>,
+    latency: LatencyTracker,
+    session: StreamSession,
+    book_broadcaster: Sender<OrderBookUpdate>,
+    /// Subscribed to while the client subscribes to books
+    books: Option<Receiver<OrderBookUpdate>>,
 }
 
 
 impl WebSocket {
-    pub fn new(receiver: Receiver<InternalMessage>) -> Self {
-        Self { receiver }
+    pub fn new(
+        receiver: Receiver<InternalMessage>,
+        app: Receiver<AppMesssage>,
+        latency: LatencyTracker,
+        order_books: OrderBooks,
+        book_broadcaster: Sender<OrderBookUpdate>,
+    ) -> Self {
+        Self { receiver, app, latency, session: StreamSession::new(order_books), book_broadcaster, books: None }
     }
 
     pub async fn serve(&mut self, ws: warp::ws::WebSocket) -> ArbitrageResult<()> {
-        // For simplicity, we are not parsing any request or looking for any subscription.
-        // If a connection is established, we will send arbitrage opportunities to the client.
         let (mut ws_tx, mut ws_rx) = ws.split();
         log::info!("a new websocket connection established");
         loop {
+            // Books which changed are sent once their subscription's interval elapsed
+            let next_book = self.session.next_book_time().map(|next_time| {
+                Duration::try_from(next_time.duration_since(Timestamp::now())).unwrap_or(Duration::ZERO)
+            });
             tokio::select! {
-                _ = ws_rx.next() => {
-                    if let Some(Ok(msg)) = ws_rx.next().await {
-                        if msg.is_close() {
+                _ = self.app.recv() => {
+                    log::info!("closing websocket connection as the server is shutting down");
+                    let close = warp::ws::Message::close_with(CLOSE_GOING_AWAY, "server shutting down");
+                    if let Err(e) = ws_tx.send(close).await {
+                        log::warn!("error sending close frame to websocket client: {}", e);
+                    }
+                    return Ok(());
+                }
+                message = ws_rx.next() => {
+                    match message {
+                        Some(Ok(msg)) if msg.is_close() => {
                             log::info!("websocket connection closed as received close message");
                             return Ok(());
                         }
-
+                        Some(Ok(msg)) => {
+                            if let Ok(text) = msg.to_str() {
+                                // Subscribed to before the snapshot of the books is read, for none of their later changes to be missed
+                                if self.books.is_none() {
+                                    self.books = Some(self.book_broadcaster.subscribe());
+                                }
+                                let response = self.session.on_request(text);
+                                if !self.session.has_books() {
+                                    self.books = None;
+                                }
+                                log::debug!("websocket client request {} answered with {:?}", text, response);
+                                let json = serde_json::to_string(&response).map_err(ArbitrageError::JsonError)?;
+                                if let Err(e) = ws_tx.send(warp::ws::Message::text(json)).await {
+                                    return Err(ArbitrageError::GenericError(format!("error sending to websocket client: {}", e)));
+                                }
+                            }
+                        }
+                        Some(Err(e)) => {
+                            return Err(ArbitrageError::GenericError(format!("error receiving from websocket client: {}", e)));
+                        }
+                        None => {
+                            log::info!("websocket connection closed by the client");
+                            return Ok(());
+                        }
+                    }
+                }
+                _ = tokio::time::sleep(next_book.unwrap_or_default()), if next_book.is_some() => {
+                    for book_message in self.session.book_messages(Timestamp::now()) {
+                        let json = serde_json::to_string(&book_message).map_err(ArbitrageError::JsonError)?;
+                        if let Err(e) = ws_tx.send(warp::ws::Message::text(json)).await {
+                            return Err(ArbitrageError::GenericError(format!("error sending to websocket client: {}", e)));
+                        }
+                    }
+                }
+                update = async {
+                    match self.books.as_mut() {
+                        Some(books) => books.recv().await,
+                        None => std::future::pending().await,
+                    }
+                } => {
+                    match update {
+                        Ok(order_book_update) => self.session.on_order_book_update(&order_book_update),
+                        Err(RecvError::Lagged(skipped)) => {
+                            metrics::BROADCAST_LAGGED.inc_by(skipped);
+                            log::warn!("websocket client lagging behind, skipped {} book updates", skipped);
+                            // The books missed some of their updates
+                            self.session.resync();
+                        }
+                        Err(RecvError::Closed) => {
+                            log::error!("book broadcaster closed");
+                            break;
+                        }
                     }
                 }
                 message = self.receiver.recv() => {
                     match message {
                         Ok(msg) => {
                             match msg {
-                                InternalMessage::ArbitrageOpportunity(opportunity) => {
+                                InternalMessage::ArbitrageOpportunity(opportunity) if !self.session.wants_opportunity(&opportunity) => {}
+                                InternalMessage::ArbitrageOpportunity(mut opportunity) => {
+                                    let sent_time = Timestamp::now();
+                                    opportunity.timestamps.sent_time = Some(sent_time);
                                     match serde_json::to_string(&opportunity) {
                                         Ok(json) => {
-                                            ws_tx.send(warp::ws::Message::text(json)).await.unwrap();
+                                            if let Err(e) = ws_tx.send(warp::ws::Message::text(json)).await {
+                                                return Err(ArbitrageError::GenericError(format!("error sending to websocket client: {}", e)));
+                                            }
+                                            self.record_latency(&opportunity.trigger_exchange, &opportunity.timestamps, sent_time);
                                         }
                                         Err(e) => {
                                             log::error!("error serializing arbitrage opportunity: {}", e);
                                         }
                                     }
                                 }
-                                _ => {
-                                    log::error!("received unknown message from broadcaster, only arbitrage opportunities are supported");
-                                }
+                                InternalMessage::OrderBookUpdate(_) => {}
                             }
                         }
+                        Err(RecvError::Lagged(skipped)) => {
+                            metrics::BROADCAST_LAGGED.inc_by(skipped);
+                            log::warn!("websocket client lagging behind, skipped {} messages", skipped);
+                        }
                         Err(e) => {
                             log::error!("error receiving message from broadcaster: {}", e);
                             break;
@@ -59,4 +150,57 @@ impl WebSocket {
         Ok(())
 
     }
+
+    fn record_latency(&self, exchange: &Exchange, timestamps: &PipelineTimestamps, sent_time: Timestamp) {
+        if let Some(processed_time) = timestamps.processed_time {
+            self.latency.record(exchange, LatencyStage::Delivery, processed_time, sent_time);
+        }
+        self.latency.record(exchange, LatencyStage::EndToEnd, timestamps.exchange_time, sent_time);
+    }
+}
+
+
+/// Sends every message of the receiver to the client as JSON, until either side closes the connection
+pub async fn forward<T: Serialize + Clone>(
+    ws: warp::ws::WebSocket,
+    mut receiver: Receiver<T>,
+    mut app: Receiver<AppMesssage>,
+) -> ArbitrageResult<()> {
+    let (mut ws_tx, mut ws_rx) = ws.split();
+    loop {
+        tokio::select! {
+            _ = app.recv() => {
+                let close = warp::ws::Message::close_with(CLOSE_GOING_AWAY, "server shutting down");
+                if let Err(e) = ws_tx.send(close).await {
+                    log::warn!("error sending close frame to websocket client: {}", e);
+                }
+                return Ok(());
+            }
+            message = ws_rx.next() => {
+                match message {
+                    Some(Ok(msg)) if msg.is_close() => return Ok(()),
+                    Some(Ok(_)) => {}
+                    Some(Err(e)) => {
+                        return Err(ArbitrageError::GenericError(format!("error receiving from websocket client: {}", e)));
+                    }
+                    None => return Ok(()),
+                }
+            }
+            message = receiver.recv() => {
+                match message {
+                    Ok(message) => {
+                        let json = serde_json::to_string(&message).map_err(ArbitrageError::JsonError)?;
+                        if let Err(e) = ws_tx.send(warp::ws::Message::text(json)).await {
+                            return Err(ArbitrageError::GenericError(format!("error sending to websocket client: {}", e)));
+                        }
+                    }
+                    Err(RecvError::Lagged(skipped)) => {
+                        metrics::BROADCAST_LAGGED.inc_by(skipped);
+                        log::warn!("websocket client lagging behind, skipped {} messages", skipped);
+                    }
+                    Err(RecvError::Closed) => return Ok(()),
+                }
+            }
+        }
+    }
 }
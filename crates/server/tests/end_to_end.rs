@@ -0,0 +1,111 @@
+use std::{net::TcpListener, time::Duration};
+
+use common::Runner;
+use futures_util::{SinkExt, StreamExt};
+use mockexchange::{MockBook, MockExchange, MockProtocol};
+use rust_decimal_macros::dec;
+use serde_json::Value;
+use server::{runner::ServerRunner, settings::ConfigOverrides};
+use tokio_tungstenite::tungstenite::Message;
+
+const OKEX_PRODUCT: &str = "BTC-USD-250221-90000-P";
+const DERIBIT_INSTRUMENT: &str = "BTC-21FEB25-90000-P";
+const DERIBIT_CHANNEL: &str = "book.BTC-21FEB25-90000-P.none.20.100ms";
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port()
+}
+
+async fn connect(url: &str) -> tokio_tungstenite::WebSocketStream<tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>> {
+    for _ in 0..100 {
+        if let Ok((stream, _)) = tokio_tungstenite::connect_async(url).await {
+            return stream;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    panic!("{} is not listening", url);
+}
+
+async fn next_json<S>(stream: &mut S) -> Value
+where
+    S: StreamExt<Item = Result<Message, tokio_tungstenite::tungstenite::Error>> + Unpin,
+{
+    match tokio::time::timeout(TIMEOUT, stream.next()).await {
+        Ok(Some(Ok(Message::Text(text)))) => serde_json::from_str(&text).unwrap(),
+        message => panic!("expected a JSON message, got {:?}", message),
+    }
+}
+
+/// Runs the server against both mock exchanges, from the subscriptions to the opportunities sent to a client
+#[tokio::test]
+async fn test_opportunities_streamed_and_subscriptions_restored() {
+    let okex = MockExchange::start(MockProtocol::Okex).await.unwrap();
+    let deribit = MockExchange::start(MockProtocol::Deribit).await.unwrap();
+    let port = free_port();
+
+    // The environment is shared by the whole test binary, which is why this is its only test
+    std::env::set_var("OKEX_WS_URL", okex.url());
+    std::env::set_var("OKEX_PRODUCTS_TO_SUBSCRIBE", format!("{},BTC-USD-250221-1-P", OKEX_PRODUCT));
+    std::env::set_var("DERIBIT_WS_URL", deribit.url());
+    std::env::set_var("DERIBIT_PRODUCTS_TO_SUBSCRIBE", DERIBIT_CHANNEL);
+    std::env::set_var("WEBSOCKET_SERVER_PORT", port.to_string());
+    std::env::set_var("ARBITRAGE__JOURNAL__ENABLED", "false");
+    std::env::set_var("ARBITRAGE__PAPER__ENABLED", "true");
+    okex.reject("BTC-USD-250221-1-P");
+
+    let mut runner = ServerRunner::new(None, ConfigOverrides::default()).unwrap();
+    let context = runner.context().clone();
+    let server = tokio::spawn(async move { runner.run().await });
+
+    assert!(okex.wait_for(TIMEOUT, |okex| okex.subscriptions().contains(OKEX_PRODUCT)).await);
+    assert!(deribit.wait_for(TIMEOUT, |deribit| deribit.subscriptions().contains(DERIBIT_CHANNEL)).await);
+    assert!(okex.requests().iter().any(|request| request.contains("BTC-USD-250221-1-P")));
+
+    let mut client = connect(&format!("ws://127.0.0.1:{}/stream/v1", port)).await;
+    let mut executions = connect(&format!("ws://127.0.0.1:{}/executions/v1", port)).await;
+
+    // A client which subscribed is only sent the opportunities matching its subscriptions
+    let mut filtered = connect(&format!("ws://127.0.0.1:{}/stream/v1", port)).await;
+    let subscribe = r#"{"id": 1, "method": "subscribe", "params": {"channel": "opportunities", "underlying": "BTC", "max_strike": "95000", "sell_exchange": "Deribit"}}"#;
+    filtered.send(Message::text(subscribe)).await.unwrap();
+    assert_eq!(next_json(&mut filtered).await, serde_json::json!({"id": 1, "result": {"subscription": 1}}));
+
+    // Okex sizes its options in contracts of 0.01 BTC, its ask is for 2
+    okex.push_snapshot(MockBook::new(OKEX_PRODUCT, vec![(dec!(0.009), dec!(500))], vec![(dec!(0.010), dec!(200))]));
+    deribit.push_snapshot(MockBook::new(DERIBIT_INSTRUMENT, vec![(dec!(0.012), dec!(3))], vec![(dec!(0.013), dec!(3))]));
+
+    let opportunity = next_json(&mut client).await;
+    assert_eq!(opportunity["product"]["Option"]["strike"], "90000");
+    assert_eq!(opportunity["buy_exchange"], "Okex");
+    assert_eq!(opportunity["sell_exchange"], "Deribit");
+    assert_eq!(opportunity["size"], "2");
+    assert_eq!(next_json(&mut filtered).await["sell_exchange"], "Deribit");
+
+    // Paper trading sends orders of 1 by default, both filled on the books the opportunity was detected on
+    let execution = next_json(&mut executions).await;
+    assert_eq!(execution["status"], "Filled");
+    assert_eq!(execution["buy"]["exchange"], "Okex");
+    assert_eq!(execution["sell"]["filled_size"], "1");
+
+    // Both adapters reconnect and subscribe again, the snapshots sent on subscription rebuild the books
+    // and report the first opportunity again before the update shrinks it
+    okex.drop_connections();
+    deribit.drop_connections();
+    assert!(okex.wait_for(TIMEOUT, |okex| okex.connections() == 2 && okex.subscriptions().contains(OKEX_PRODUCT)).await);
+    assert!(deribit.wait_for(TIMEOUT, |deribit| deribit.connections() == 2 && deribit.subscriptions().contains(DERIBIT_CHANNEL)).await);
+
+    okex.push_update(MockBook::new(OKEX_PRODUCT, vec![], vec![(dec!(0.010), dec!(100))]));
+    loop {
+        let opportunity = next_json(&mut client).await;
+        assert_eq!(opportunity["buy_exchange"], "Okex");
+        if opportunity["size"] == "1" {
+            break;
+        }
+        assert_eq!(opportunity["size"], "2");
+    }
+
+    context.exit();
+    let result = tokio::time::timeout(TIMEOUT, server).await.expect("server did not stop");
+    assert!(result.unwrap().is_ok());
+}
@@ -0,0 +1,96 @@
+use std::time::Duration;
+
+use common::{ArbitrageError, Context, SpawnResult};
+use config::Config;
+use mockexchange::{MockExchange, MockProtocol};
+use server::{adapters::{DeribitExchangeAdapter, OkexExchangeAdapter}, settings::ExchangeConfig};
+use tokio::sync::mpsc;
+
+const OKEX_PRODUCT: &str = "BTC-USD-250221-90000-P";
+const DERIBIT_CHANNEL: &str = "book.BTC-21FEB25-90000-P.none.20.100ms";
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Configuration of an adapter of the mock exchange whose only check of a stale connection is its keepalives
+fn exchange_config(exchange: &MockExchange, product: &str, heartbeat_millis: u64) -> ExchangeConfig {
+    ExchangeConfig {
+        ws_url: exchange.url(),
+        products: vec![product.to_string()],
+        heartbeat_millis,
+        max_silent_heartbeats: 0,
+        ..ExchangeConfig::default()
+    }
+}
+
+async fn stop(context: &Context, handle: SpawnResult) {
+    context.exit();
+    let result = tokio::time::timeout(TIMEOUT, handle).await.expect("adapter did not stop");
+    assert!(matches!(result.unwrap(), Err(ArbitrageError::Exit)));
+}
+
+/// Okex answers `ping` with `pong`, the adapter reconnects when it does not by the next heartbeat
+#[tokio::test]
+async fn test_okex_reconnects_without_pong() {
+    let okex = MockExchange::start(MockProtocol::Okex).await.unwrap();
+    let context = Context::from_config(Config::default());
+    let (producer, _books) = mpsc::channel(100);
+    let mut adapter = OkexExchangeAdapter::new(context.clone(), &exchange_config(&okex, OKEX_PRODUCT, 100));
+    let callback = adapter.callback(producer);
+    let handle = adapter.worker(callback).spawn();
+
+    assert!(okex.wait_for(TIMEOUT, |okex| okex.subscriptions().contains(OKEX_PRODUCT)).await);
+    assert!(okex.wait_for(TIMEOUT, |okex| okex.requests().iter().filter(|request| *request == "ping").count() >= 3).await);
+    assert_eq!(okex.connections(), 1);
+
+    okex.mute_keepalives(true);
+    assert!(okex.wait_for(TIMEOUT, |okex| okex.connections() == 2).await);
+    okex.mute_keepalives(false);
+    assert!(okex.wait_for(TIMEOUT, |okex| okex.subscriptions().contains(OKEX_PRODUCT)).await);
+
+    stop(&context, handle).await;
+}
+
+/// Deribit answers `public/test`, the adapter reconnects when it does not by the next heartbeat
+#[tokio::test]
+async fn test_deribit_reconnects_without_test_response() {
+    let deribit = MockExchange::start(MockProtocol::Deribit).await.unwrap();
+    let context = Context::from_config(Config::default());
+    let (producer, _books) = mpsc::channel(100);
+    let mut adapter = DeribitExchangeAdapter::new(context.clone(), &exchange_config(&deribit, DERIBIT_CHANNEL, 100));
+    let callback = adapter.callback(producer);
+    let handle = adapter.worker(callback).spawn();
+
+    assert!(deribit.wait_for(TIMEOUT, |deribit| deribit.subscriptions().contains(DERIBIT_CHANNEL)).await);
+    assert!(deribit.wait_for(TIMEOUT, |deribit| public_tests(deribit) >= 3).await);
+    assert_eq!(deribit.connections(), 1);
+
+    deribit.mute_keepalives(true);
+    assert!(deribit.wait_for(TIMEOUT, |deribit| deribit.connections() == 2).await);
+    deribit.mute_keepalives(false);
+    assert!(deribit.wait_for(TIMEOUT, |deribit| deribit.subscriptions().contains(DERIBIT_CHANNEL)).await);
+
+    stop(&context, handle).await;
+}
+
+/// Deribit sends a `test_request` heartbeat, which the adapter answers with `public/test`
+#[tokio::test]
+async fn test_deribit_answers_test_request() {
+    let deribit = MockExchange::start(MockProtocol::Deribit).await.unwrap();
+    let context = Context::from_config(Config::default());
+    let (producer, _books) = mpsc::channel(100);
+    // No heartbeat of the adapter sends `public/test` in the meantime
+    let mut adapter = DeribitExchangeAdapter::new(context.clone(), &exchange_config(&deribit, DERIBIT_CHANNEL, 60_000));
+    let callback = adapter.callback(producer);
+    let handle = adapter.worker(callback).spawn();
+
+    assert!(deribit.wait_for(TIMEOUT, |deribit| deribit.subscriptions().contains(DERIBIT_CHANNEL)).await);
+    assert_eq!(public_tests(&deribit), 0);
+    deribit.push_text(r#"{"jsonrpc":"2.0","method":"heartbeat","params":{"type":"test_request"}}"#);
+    assert!(deribit.wait_for(TIMEOUT, |deribit| public_tests(deribit) == 1).await);
+    assert_eq!(deribit.connections(), 1);
+
+    stop(&context, handle).await;
+}
+
+fn public_tests(deribit: &MockExchange) -> usize {
+    deribit.requests().iter().filter(|request| request.contains(r#""method":"public/test""#)).count()
+}
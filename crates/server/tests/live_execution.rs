@@ -0,0 +1,103 @@
+use std::{net::TcpListener, time::Duration};
+
+use common::Runner;
+use futures_util::StreamExt;
+use mockexchange::{MockBook, MockCredentials, MockExchange, MockOrderState, MockProtocol};
+use rust_decimal_macros::dec;
+use serde_json::Value;
+use server::{runner::ServerRunner, settings::ConfigOverrides};
+use tokio_tungstenite::tungstenite::Message;
+
+const OKEX_PRODUCT: &str = "BTC-USD-250221-90000-P";
+const DERIBIT_INSTRUMENT: &str = "BTC-21FEB25-90000-P";
+const DERIBIT_CHANNEL: &str = "book.BTC-21FEB25-90000-P.none.20.100ms";
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port()
+}
+
+async fn connect(url: &str) -> tokio_tungstenite::WebSocketStream<tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>> {
+    for _ in 0..100 {
+        if let Ok((stream, _)) = tokio_tungstenite::connect_async(url).await {
+            return stream;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    panic!("{} is not listening", url);
+}
+
+async fn next_json<S>(stream: &mut S) -> Value
+where
+    S: StreamExt<Item = Result<Message, tokio_tungstenite::tungstenite::Error>> + Unpin,
+{
+    match tokio::time::timeout(TIMEOUT, stream.next()).await {
+        Ok(Some(Ok(Message::Text(text)))) => serde_json::from_str(&text).unwrap(),
+        message => panic!("expected a JSON message, got {:?}", message),
+    }
+}
+
+/// Runs the server with live execution against both mock exchanges, from the logins to the execution report
+#[tokio::test]
+async fn test_live_execution_against_mock_exchanges() {
+    let okex = MockExchange::start(MockProtocol::Okex).await.unwrap();
+    let deribit = MockExchange::start(MockProtocol::Deribit).await.unwrap();
+    okex.require_credentials(MockCredentials { key: "okex-key".to_string(), secret: "okex-secret".to_string(), passphrase: Some("okex-passphrase".to_string()) });
+    deribit.require_credentials(MockCredentials { key: "deribit-id".to_string(), secret: "deribit-secret".to_string(), passphrase: None });
+    let port = free_port();
+
+    // The environment is shared by the whole test binary, which is why this is its only test
+    std::env::set_var("OKEX_WS_URL", okex.url());
+    std::env::set_var("OKEX_PRODUCTS_TO_SUBSCRIBE", OKEX_PRODUCT);
+    std::env::set_var("DERIBIT_WS_URL", deribit.url());
+    std::env::set_var("DERIBIT_PRODUCTS_TO_SUBSCRIBE", DERIBIT_CHANNEL);
+    std::env::set_var("WEBSOCKET_SERVER_PORT", port.to_string());
+    std::env::set_var("ARBITRAGE__JOURNAL__ENABLED", "false");
+    std::env::set_var("ARBITRAGE__LIVE__ENABLED", "true");
+    std::env::set_var("ARBITRAGE__LIVE__MAX_ORDER_SIZE", "3");
+    std::env::set_var("ARBITRAGE__LIVE__OKEX__WS_URL", okex.url());
+    std::env::set_var("ARBITRAGE__LIVE__OKEX__API_KEY", "okex-key");
+    std::env::set_var("ARBITRAGE__LIVE__OKEX__SECRET_KEY", "okex-secret");
+    std::env::set_var("ARBITRAGE__LIVE__OKEX__PASSPHRASE", "okex-passphrase");
+    std::env::set_var("ARBITRAGE__LIVE__DERIBIT__WS_URL", deribit.url());
+    std::env::set_var("ARBITRAGE__LIVE__DERIBIT__CLIENT_ID", "deribit-id");
+    std::env::set_var("ARBITRAGE__LIVE__DERIBIT__CLIENT_SECRET", "deribit-secret");
+
+    let mut runner = ServerRunner::new(None, ConfigOverrides::default()).unwrap();
+    let context = runner.context().clone();
+    let server = tokio::spawn(async move { runner.run().await });
+
+    // The private sessions subscribe to the order updates once logged in
+    assert!(okex.wait_for(TIMEOUT, |okex| okex.subscriptions().is_superset(&[OKEX_PRODUCT.to_string(), "orders".to_string()].into())).await);
+    assert!(deribit.wait_for(TIMEOUT, |deribit| {
+        deribit.subscriptions().is_superset(&[DERIBIT_CHANNEL.to_string(), "user.orders.any.any.raw".to_string()].into())
+    }).await);
+
+    let mut executions = connect(&format!("ws://127.0.0.1:{}/executions/v1", port)).await;
+
+    // Okex sizes its options in contracts of 0.01 BTC, its best ask is for 2
+    okex.push_snapshot(MockBook::new(OKEX_PRODUCT, vec![(dec!(0.009), dec!(500))], vec![(dec!(0.010), dec!(200)), (dec!(0.011), dec!(500))]));
+    deribit.push_snapshot(MockBook::new(DERIBIT_INSTRUMENT, vec![(dec!(0.012), dec!(1))], vec![(dec!(0.013), dec!(3))]));
+
+    // The opportunity is for 1, the size of the deribit bid, both legs fill it
+    let execution = next_json(&mut executions).await;
+    assert_eq!(execution["status"], "Filled", "{}", execution);
+    assert_eq!(execution["buy"]["exchange"], "Okex");
+    assert_eq!(execution["buy"]["average_price"], "0.01");
+    assert_eq!(execution["buy"]["filled_size"], "1");
+    assert_eq!(execution["sell"]["filled_size"], "1");
+    assert_eq!(execution["hedged_pnl"], "0.002");
+
+    let okex_orders = okex.orders();
+    assert_eq!(okex_orders.len(), 1);
+    assert_eq!((okex_orders[0].instrument.as_str(), okex_orders[0].state), (OKEX_PRODUCT, MockOrderState::Filled));
+    assert!(okex_orders[0].immediate_or_cancel);
+    // 1 BTC is sent and filled as 100 contracts
+    assert_eq!((okex_orders[0].size, okex_orders[0].filled_size), (dec!(100), dec!(100)));
+    let deribit_orders = deribit.orders();
+    assert_eq!((deribit_orders[0].instrument.as_str(), deribit_orders[0].filled_size), (DERIBIT_INSTRUMENT, dec!(1)));
+
+    context.exit();
+    let result = tokio::time::timeout(TIMEOUT, server).await.expect("server did not stop");
+    assert!(result.unwrap().is_ok());
+}
@@ -3,8 +3,15 @@ use tokio_tungstenite::tungstenite::Message;
 
 #[async_trait::async_trait]
 pub trait WsCallback {
+    /// Commands forwarded to the callback while the websocket is connected
+    type Command: Send + 'static;
+
     async fn on_connect(&mut self, timestamp: jiff::Timestamp) -> ArbitrageResult<()>;
     async fn on_message(&mut self, message: Message, received_time: jiff::Timestamp) -> ArbitrageResult<()>;
     fn on_disconnect(&mut self) -> ArbitrageResult<()>;
     fn on_heartbeat(&mut self) -> ArbitrageResult<()>;
+    async fn on_command(&mut self, command: Self::Command) -> ArbitrageResult<()>;
+    /// Called on application exit while still connected, messages written to the client
+    /// from here are sent before the websocket is closed
+    fn on_shutdown(&mut self) -> ArbitrageResult<()>;
 }
@@ -1,15 +1,19 @@
-use common::{ArbitrageError, ArbitrageResult, Backoff, Context, MpSc, SharedRef};
+use common::{ArbitrageError, ArbitrageResult, Backoff, Context, MpSc, RunningFlag};
 use tokio_tungstenite::tungstenite::Message;
 
-use crate::{WsCallback, WsConsumer};
+use crate::{ConnectionState, ReplayClient, WsCallback, WsConsumer};
+
+/// Number of heartbeat intervals without any frame after which the connection is considered dead
+pub const DEFAULT_MAX_SILENT_HEARTBEATS: u32 = 3;
 
 #[allow(unused)]
 pub struct WsClient {
     ws_url: String,
-    connected: SharedRef<bool>,
+    connection: ConnectionState,
     mpsc: MpSc<Message>,
     client_id: String,
     heartbeat_millis: u64,
+    max_silent_heartbeats: u32,
 }
 
 
@@ -17,10 +21,11 @@ impl Clone for WsClient {
     fn clone(&self) -> Self {
         Self {
             ws_url: self.ws_url.clone(),
-            connected: self.connected.clone(),
+            connection: self.connection.clone(),
             mpsc: self.mpsc.clone(),
             client_id: self.client_id.clone(),
             heartbeat_millis: self.heartbeat_millis,
+            max_silent_heartbeats: self.max_silent_heartbeats,
         }
     }
 }
@@ -30,9 +35,10 @@ impl WsClient {
         let mpsc = MpSc::new(100);
         Self {
             ws_url,
-           connected: SharedRef::new(false),
+            connection: ConnectionState::default(),
             client_id: "".to_string(),
             heartbeat_millis,
+            max_silent_heartbeats: DEFAULT_MAX_SILENT_HEARTBEATS,
             mpsc,
         }
     }
@@ -42,12 +48,28 @@ impl WsClient {
         self
     }
 
+    /// Sets the number of heartbeat intervals without any frame after which
+    /// the consumer reconnects, 0 disables the check
+    pub fn with_max_silent_heartbeats(mut self, max_silent_heartbeats: u32) -> Self {
+        self.max_silent_heartbeats = max_silent_heartbeats;
+        self
+    }
+
     pub fn ws_url(&self) -> &str {
         &self.ws_url
     }
 
+    pub fn heartbeat_millis(&self) -> u64 {
+        self.heartbeat_millis
+    }
+
     pub fn is_connected(&self) -> bool {
-        *self.connected.lock()
+        self.connection.is_connected()
+    }
+
+    /// Connection state maintained by the consumer of this client
+    pub fn connection(&self) -> ConnectionState {
+        self.connection.clone()
     }
 
     pub fn write(&self, message: Message) -> ArbitrageResult<()> {
@@ -63,7 +85,8 @@ impl WsClient {
         self.write(Message::Close(None))
     }
 
-    pub fn consumer<C>(&mut self, context: Context, callback: C) -> WsConsumer<C>
+    /// Creates the consumer of this client, it can only be created once
+    pub fn consumer<C>(&mut self, context: Context, callback: C, mut commands: MpSc<C::Command>) -> WsConsumer<C>
     where
         C: WsCallback,
     {
@@ -72,9 +95,22 @@ impl WsClient {
             ws_url: self.ws_url.clone(),
             callback,
             heartbeat_millis: self.heartbeat_millis,
+            max_silent_heartbeats: self.max_silent_heartbeats,
             backoff: Backoff::default(),
             context,
-            mpsc: self.mpsc.clone_with_receiver(),
+            messages: self.mpsc.shared_receiver().expect("ws client receiver should not be taken"),
+            commands: commands.shared_receiver().expect("commands receiver should not be taken"),
+            connection: self.connection.clone(),
+            running: RunningFlag::default(),
         }
     }
+
+    /// Creates a client feeding recorded frames to the callback, in place of the consumer
+    pub fn replay<C>(&mut self, callback: C) -> ReplayClient<C>
+    where
+        C: WsCallback,
+    {
+        let writes = self.mpsc.receiver().expect("ws client receiver should not be taken");
+        ReplayClient::new(callback, writes, self.connection.clone())
+    }
 }
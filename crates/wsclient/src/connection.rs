@@ -0,0 +1,95 @@
+use common::SharedRef;
+use jiff::Timestamp;
+use serde::Serialize;
+
+/// Snapshot of the state of a websocket connection
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct ConnectionStatus {
+    pub connected: bool,
+    pub connected_since: Option<Timestamp>,
+    pub last_message_time: Option<Timestamp>,
+    /// Number of times an established connection was lost
+    pub disconnects: u64,
+    /// Number of subscriptions acknowledged by the exchange
+    pub subscribed: usize,
+    /// Number of subscriptions requested
+    pub subscriptions: usize,
+}
+
+impl ConnectionStatus {
+    /// A connection is ready when it is connected and all the requested subscriptions are acknowledged
+    pub fn is_ready(&self) -> bool {
+        self.connected && self.subscribed == self.subscriptions
+    }
+}
+
+/// Connection state shared between the consumer which maintains it and whoever wants to observe it
+#[derive(Clone, Default)]
+pub struct ConnectionState {
+    status: SharedRef<ConnectionStatus>,
+}
+
+impl ConnectionState {
+    pub fn on_connected(&self, timestamp: Timestamp) {
+        let mut status = self.status.lock();
+        status.connected = true;
+        status.connected_since = Some(timestamp);
+    }
+
+    pub fn on_disconnected(&self) {
+        let mut status = self.status.lock();
+        if status.connected {
+            status.disconnects += 1;
+        }
+        status.connected = false;
+        status.connected_since = None;
+        status.subscribed = 0;
+    }
+
+    pub fn on_message(&self, timestamp: Timestamp) {
+        self.status.lock().last_message_time = Some(timestamp);
+    }
+
+    /// Updates the number of acknowledged and requested subscriptions
+    pub fn set_subscriptions(&self, subscribed: usize, subscriptions: usize) {
+        let mut status = self.status.lock();
+        status.subscribed = subscribed;
+        status.subscriptions = subscriptions;
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.status.lock().connected
+    }
+
+    pub fn status(&self) -> ConnectionStatus {
+        self.status.lock().clone()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_connection_state() {
+        let state = ConnectionState::default();
+        state.set_subscriptions(0, 2);
+        assert!(!state.status().is_ready());
+
+        state.on_connected(Timestamp::UNIX_EPOCH);
+        state.on_message(Timestamp::UNIX_EPOCH);
+        state.set_subscriptions(2, 2);
+        assert!(state.is_connected());
+        assert!(state.status().is_ready());
+        assert_eq!(state.status().disconnects, 0);
+
+        state.on_disconnected();
+        assert!(!state.status().is_ready());
+        assert_eq!(state.status().subscribed, 0);
+        assert_eq!(state.status().disconnects, 1);
+
+        state.on_connected(Timestamp::UNIX_EPOCH);
+        assert_eq!(state.status().last_message_time, Some(Timestamp::UNIX_EPOCH));
+    }
+}
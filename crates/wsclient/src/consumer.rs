@@ -2,12 +2,15 @@ use std::time::Duration;
 use futures_util::{SinkExt, StreamExt};
 
 use jiff::Timestamp;
-use tokio::{io, sync::mpsc::Receiver};
+use tokio::{io, sync::mpsc::Receiver, time::Instant};
 
-use common::{ArbitrageError, ArbitrageResult, Backoff, Context, MpSc, SpawnResult, Worker};
+use common::{ArbitrageError, ArbitrageResult, Backoff, Context, RunningFlag, SharedReceiver, SpawnResult, Worker};
 use tokio_tungstenite::{tungstenite::Message, WebSocketStream};
 
-use crate::WsCallback;
+use crate::{metrics, ConnectionState, WsCallback};
+
+/// Time given to the exchange to answer our close frame on shutdown
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(2);
 
 pub struct WsConsumer<C>
 where
@@ -17,9 +20,13 @@ where
     pub ws_url: String,
     pub callback: C,
     pub heartbeat_millis: u64,
+    pub max_silent_heartbeats: u32,
     pub backoff: Backoff,
     pub context: Context,
-    pub mpsc: MpSc<Message>,
+    pub messages: SharedReceiver<Message>,
+    pub commands: SharedReceiver<C::Command>,
+    pub connection: ConnectionState,
+    pub running: RunningFlag,
 }
 
 impl<C> Clone for WsConsumer<C>
@@ -33,9 +40,13 @@ where
             ws_url: self.ws_url.clone(),
             callback: self.callback.clone(),
             heartbeat_millis: self.heartbeat_millis,
+            max_silent_heartbeats: self.max_silent_heartbeats,
             backoff: self.backoff.clone(),
             context: self.context.clone(),
-            mpsc: self.mpsc.clone(),
+            messages: self.messages.clone(),
+            commands: self.commands.clone(),
+            connection: self.connection.clone(),
+            running: self.running.clone(),
         }
     }
 }
@@ -47,12 +58,29 @@ where
 {
     pub async fn run(&mut self) -> ArbitrageResult<String> {
         let context = self.context.clone();
-        let mut receiver = self.mpsc.receiver().unwrap();
+        // Held for as long as this task runs, a restarted consumer takes them over
+        let mut receiver = self.messages.clone().lock_owned().await;
+        let mut commands = self.commands.clone().lock_owned().await;
+        let mut rate_limited = false;
+        let mut first_attempt = true;
+        let mut app = self.context.app.subscribe();
         loop {
             match self.backoff.next() {
                 Some(delay_secs) => {
+                    if !first_attempt {
+                        metrics::RECONNECTS.with_label_values(&[&self.client_id]).inc();
+                    }
+                    first_attempt = false;
+                    metrics::BACKOFF_ITERATIONS
+                        .with_label_values(&[&self.client_id])
+                        .set(self.backoff.get_iteration_count() as i64);
                     if delay_secs > 0 {
-                        tokio::time::sleep(Duration::from_secs(delay_secs as u64)).await;
+                        tokio::select! {
+                            _ = app.recv() => {
+                                return Err(ArbitrageError::Exit);
+                            }
+                            _ = tokio::time::sleep(Duration::from_secs(delay_secs as u64)) => {}
+                        }
                     }
                 }
                 None => {
@@ -64,10 +92,20 @@ where
             }
 
             log::info!("connecting to websocket: {}", self.ws_url);
-            let ws_stream = match tokio_tungstenite::connect_async(&self.ws_url).await {
+            let connect_result = tokio::select! {
+                _ = app.recv() => {
+                    return Err(ArbitrageError::Exit);
+                }
+                result = tokio_tungstenite::connect_async(&self.ws_url) => result,
+            };
+            let ws_stream = match connect_result {
                 Ok((ws_stream, _)) => {
                     log::info!("connected to websocket: {}", &self.ws_url);
-                    self.backoff.reset();
+                    // Keep growing the backoff while the exchange keeps rate limiting us
+                    if !rate_limited {
+                        self.backoff.reset();
+                        metrics::BACKOFF_ITERATIONS.with_label_values(&[&self.client_id]).set(0);
+                    }
                     ws_stream
                 }
                 Err(e) => {
@@ -80,15 +118,18 @@ where
                 }
             };
 
-            let stream_result = self.stream(&mut receiver, ws_stream).await;
+            let stream_result = self.stream(&mut receiver, &mut commands, ws_stream).await;
+            self.connection.on_disconnected();
             self.on_disconnect()?;
+            rate_limited = matches!(stream_result, Err(ArbitrageError::RateLimited(_)));
 
             match stream_result {
                 Ok(_) => {
                     log::warn!("websocket {} disconnected", self.client_id);
                 }
                 Err(ArbitrageError::Exit) => {
-
+                    log::info!("websocket {} closed on exit", self.client_id);
+                    return Err(ArbitrageError::Exit);
                 }
                 Err(ArbitrageError::UnrecoverableError(e)) => {
                     log::error!("unrecoverable error: {}", e);
@@ -97,6 +138,9 @@ where
                 Err(ArbitrageError::Warning(e)) => {
                     log::error!("websocket {} scheduled reconnect", self.client_id);
                 }
+                Err(ArbitrageError::RateLimited(e)) => {
+                    log::warn!("websocket {} rate limited, backing off before reconnect: {}", self.client_id, e);
+                }
                 Err(e) => {
                     log::error!("error while streaming websocket: {}", e);
                 }
@@ -104,25 +148,36 @@ where
         }
     }
 
-    async fn stream<S>(&mut self, receiver: &mut Receiver<Message>, mut ws_stream: WebSocketStream<S>) -> ArbitrageResult<()>
+    async fn stream<S>(
+        &mut self,
+        receiver: &mut Receiver<Message>,
+        commands: &mut Receiver<C::Command>,
+        mut ws_stream: WebSocketStream<S>,
+    ) -> ArbitrageResult<()>
     where
         S: io::AsyncRead + io::AsyncWrite + Unpin + Send + 'static,
     {
         self.on_connect().await?;
         let mut app = self.context.app.subscribe();
+        let messages_received = metrics::MESSAGES_RECEIVED.with_label_values(&[&self.client_id]);
         let mut num_messages_since_last_heartbeat = 0;
-        let mut heartbeat = tokio::time::interval(Duration::from_millis(self.heartbeat_millis));
+        let mut silent_heartbeats = 0;
+        let heartbeat_period = Duration::from_millis(self.heartbeat_millis);
+        let mut heartbeat = tokio::time::interval_at(Instant::now() + heartbeat_period, heartbeat_period);
 
         loop {
             tokio::select! {
                 _ = app.recv() => {
+                    self.shutdown(receiver, &mut ws_stream).await;
                     return Err(ArbitrageError::Exit);
                 }
                 result = ws_stream.next() => {
                     match result {
                         Some(result) => {
                             let received_time = Timestamp::now();
+                            self.connection.on_message(received_time);
                             num_messages_since_last_heartbeat += 1;
+                            messages_received.inc();
                             match result {
                                 Ok(message) => {
                                     self.callback.on_message(message, received_time).await?;
@@ -150,18 +205,72 @@ where
                         }
                     }
                 }
+                Some(command) = commands.recv() => {
+                    self.callback.on_command(command).await?;
+                }
                 _ = heartbeat.tick() => {
-                    self.callback.on_heartbeat();
+                    self.callback.on_heartbeat()?;
                     log::info!("{} received {} messages since last heartbeat", self.context.name, num_messages_since_last_heartbeat);
+                    if num_messages_since_last_heartbeat == 0 {
+                        silent_heartbeats += 1;
+                    } else {
+                        silent_heartbeats = 0;
+                    }
                     num_messages_since_last_heartbeat = 0;
+
+                    // A half open connection never errors, it just goes silent
+                    if self.max_silent_heartbeats > 0 && silent_heartbeats >= self.max_silent_heartbeats {
+                        return Err(ArbitrageError::Warning(format!(
+                            "no message received from {} in {} heartbeats, considering the connection stale",
+                            self.ws_url,
+                            silent_heartbeats
+                        )));
+                    }
                 }
             }
 
         }
     }
 
+    /// Lets the callback say goodbye, e.g. unsubscribe, sends whatever it wrote and closes the websocket
+    async fn shutdown<S>(&mut self, receiver: &mut Receiver<Message>, ws_stream: &mut WebSocketStream<S>)
+    where
+        S: io::AsyncRead + io::AsyncWrite + Unpin + Send + 'static,
+    {
+        if let Err(e) = self.callback.on_shutdown() {
+            log::warn!("websocket {} failed to shutdown cleanly: {}", self.client_id, e);
+        }
+
+        let mut close_sent = false;
+        while let Ok(message) = receiver.try_recv() {
+            close_sent = matches!(message, Message::Close(_));
+            if let Err(e) = ws_stream.send(message).await {
+                log::warn!("error while sending message to websocket {} on shutdown: {}", self.client_id, e);
+                return;
+            }
+            if close_sent {
+                break;
+            }
+        }
+        if !close_sent {
+            if let Err(e) = ws_stream.close(None).await {
+                log::warn!("error while closing websocket {}: {}", self.client_id, e);
+                return;
+            }
+        }
+
+        // Wait for the exchange to answer the close frame
+        let closed = tokio::time::timeout(CLOSE_TIMEOUT, async {
+            while let Some(Ok(_)) = ws_stream.next().await {}
+        }).await;
+        if closed.is_err() {
+            log::warn!("websocket {} did not answer the close frame within {:?}", self.client_id, CLOSE_TIMEOUT);
+        }
+    }
+
     async fn on_connect(&mut self) -> ArbitrageResult<()> {
         let timestamp = Timestamp::now();
+        self.connection.on_connected(timestamp);
         self.callback.on_connect(timestamp).await
     }
 
@@ -178,9 +287,97 @@ where
 {
     fn spawn(&mut self) -> SpawnResult {
         let mut consumer = self.clone();
-        consumer.mpsc = self.mpsc.clone_with_receiver();
         tokio::spawn(async move {
-            consumer.run().await
+            consumer.running.start();
+            let result = consumer.run().await;
+            consumer.running.stop();
+            result
         })
     }
+
+    fn name(&self) -> String {
+        self.context.name.clone()
+    }
+
+    fn is_running(&self) -> bool {
+        self.running.is_running()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use common::{MpSc, SharedRef};
+    use config::Config;
+    use mockexchange::{MockExchange, MockProtocol};
+
+    use crate::WsClient;
+
+    use super::*;
+
+    const TIMEOUT: Duration = Duration::from_secs(10);
+
+    /// Counts the connections and heartbeats, answering nothing
+    #[derive(Clone, Default)]
+    struct CountingCallback {
+        counts: SharedRef<(u32, u32)>,
+    }
+
+    #[async_trait::async_trait]
+    impl WsCallback for CountingCallback {
+        type Command = ();
+
+        async fn on_connect(&mut self, _timestamp: Timestamp) -> ArbitrageResult<()> {
+            self.counts.lock().0 += 1;
+            Ok(())
+        }
+
+        async fn on_message(&mut self, _message: Message, _received_time: Timestamp) -> ArbitrageResult<()> {
+            Ok(())
+        }
+
+        fn on_disconnect(&mut self) -> ArbitrageResult<()> {
+            Ok(())
+        }
+
+        fn on_heartbeat(&mut self) -> ArbitrageResult<()> {
+            self.counts.lock().1 += 1;
+            Ok(())
+        }
+
+        async fn on_command(&mut self, _command: ()) -> ArbitrageResult<()> {
+            Ok(())
+        }
+
+        fn on_shutdown(&mut self) -> ArbitrageResult<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_silent_connection_reconnects() {
+        let exchange = MockExchange::start(MockProtocol::Okex).await.unwrap();
+        exchange.go_silent(true);
+        let context = Context::from_config(Config::default());
+        let callback = CountingCallback::default();
+        let mut client = WsClient::new(exchange.url(), 50).with_max_silent_heartbeats(2);
+        let mut consumer = client.consumer(context.clone(), callback.clone(), MpSc::new(1));
+
+        // The connection is considered stale on the second heartbeat without any message
+        let (ws_stream, _) = tokio_tungstenite::connect_async(exchange.url()).await.unwrap();
+        let mut receiver = consumer.messages.clone().lock_owned().await;
+        let mut commands = consumer.commands.clone().lock_owned().await;
+        let result = consumer.stream(&mut receiver, &mut commands, ws_stream).await;
+        assert!(matches!(&result, Err(ArbitrageError::Warning(e)) if e.contains("in 2 heartbeats")), "{:?}", result);
+        assert_eq!(*callback.counts.lock(), (1, 2));
+        drop((receiver, commands));
+
+        // Run by itself, the consumer connects again after each stale connection
+        let mut handle = consumer.spawn();
+        assert!(exchange.wait_for(TIMEOUT, |exchange| exchange.connections() >= 3).await);
+        assert!(consumer.connection.status().disconnects >= 1);
+        context.exit();
+        let result = tokio::time::timeout(TIMEOUT, &mut handle).await.expect("consumer did not stop");
+        assert!(matches!(result.unwrap(), Err(ArbitrageError::Exit)));
+    }
 }
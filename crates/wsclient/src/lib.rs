@@ -1,7 +1,14 @@
 mod callback;
 mod client;
+mod connection;
 mod consumer;
+mod metrics;
+mod recorder;
+mod replay;
 
 pub use callback::*;
 pub use client::*;
+pub use connection::*;
 pub use consumer::*;
+pub use recorder::*;
+pub use replay::*;
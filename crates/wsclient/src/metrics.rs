@@ -0,0 +1,27 @@
+use std::sync::LazyLock;
+
+use prometheus::{register_int_counter_vec, register_int_gauge_vec, IntCounterVec, IntGaugeVec};
+
+/// Frames received from the websocket, per client
+pub static MESSAGES_RECEIVED: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!("ws_messages_received_total", "Number of frames received from the websocket", &["client"])
+        .expect("ws_messages_received_total should be registered")
+});
+
+/// Connection attempts made after the first one, per client
+pub static RECONNECTS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!("ws_reconnects_total", "Number of reconnection attempts to the websocket", &["client"])
+        .expect("ws_reconnects_total should be registered")
+});
+
+/// Current iteration of the backoff, 0 once connected
+pub static BACKOFF_ITERATIONS: LazyLock<IntGaugeVec> = LazyLock::new(|| {
+    register_int_gauge_vec!("ws_backoff_iterations", "Current iteration of the reconnection backoff", &["client"])
+        .expect("ws_backoff_iterations should be registered")
+});
+
+/// Frames which could not be recorded, per exchange
+pub static RECORDER_ERRORS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!("ws_recorder_errors_total", "Number of frames which could not be recorded", &["exchange"])
+        .expect("ws_recorder_errors_total should be registered")
+});
@@ -0,0 +1,316 @@
+use std::{fs::{self, File, OpenOptions}, io::Write, path::PathBuf, time::{Duration, Instant}};
+
+use common::{ArbitrageError, ArbitrageResult, SharedRef};
+use flate2::{write::GzEncoder, Compression};
+use jiff::Timestamp;
+use serde::{Deserialize, Serialize};
+use tokio_tungstenite::tungstenite::{protocol::{frame::coding::CloseCode, CloseFrame}, Message};
+
+use crate::{metrics, WsCallback};
+
+/// Extension of the recording files, one JSON encoded `RecordedFrame` per line, gzip compressed
+pub const RECORDING_EXTENSION: &str = "jsonl.gz";
+
+
+/// Frame received from an exchange, as recorded on disk
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RecordedFrame {
+    pub received_time: Timestamp,
+    pub exchange: String,
+    /// Sequence number of the connection the frame was received on, starting at 1 for each recorder
+    pub connection_id: u64,
+    pub message: RecordedMessage,
+}
+
+/// Serializable copy of a websocket `Message`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", content = "data", rename_all = "snake_case")]
+pub enum RecordedMessage {
+    Text(String),
+    Binary(Vec<u8>),
+    Ping(Vec<u8>),
+    Pong(Vec<u8>),
+    Close(Option<(u16, String)>),
+}
+
+impl From<&Message> for RecordedMessage {
+    fn from(message: &Message) -> Self {
+        match message {
+            Message::Text(text) => RecordedMessage::Text(text.to_string()),
+            Message::Binary(data) => RecordedMessage::Binary(data.to_vec()),
+            Message::Ping(data) => RecordedMessage::Ping(data.to_vec()),
+            Message::Pong(data) => RecordedMessage::Pong(data.to_vec()),
+            Message::Close(frame) => RecordedMessage::Close(
+                frame.as_ref().map(|frame| (u16::from(frame.code), frame.reason.to_string()))
+            ),
+            // Raw frames are only written, never read from the websocket
+            Message::Frame(frame) => RecordedMessage::Binary(frame.payload().to_vec()),
+        }
+    }
+}
+
+impl From<RecordedMessage> for Message {
+    fn from(message: RecordedMessage) -> Self {
+        match message {
+            RecordedMessage::Text(text) => Message::text(text),
+            RecordedMessage::Binary(data) => Message::binary(data),
+            RecordedMessage::Ping(data) => Message::Ping(data.into()),
+            RecordedMessage::Pong(data) => Message::Pong(data.into()),
+            RecordedMessage::Close(frame) => Message::Close(
+                frame.map(|(code, reason)| CloseFrame { code: CloseCode::from(code), reason: reason.into() })
+            ),
+        }
+    }
+}
+
+
+/// Uncompressed frames buffered before they are compressed and written to the file
+const MEMBER_BYTES: usize = 1 << 20;
+
+
+/// Appends the frames received from an exchange to rotating, compressed JSONL files
+///
+/// Files are named after the exchange and the time they were created, e.g. `okex-20250221T101500.123Z.jsonl.gz`,
+/// and are rotated once they hold `max_file_bytes` of uncompressed frames or are older than `rotate_after`.
+/// Frames are buffered and written as a new gzip member on every flush, so a file is always complete
+/// up to the last flush, as a multi member gzip file.
+#[derive(Clone)]
+pub struct Recorder {
+    state: SharedRef<RecorderState>,
+}
+
+struct RecorderState {
+    directory: PathBuf,
+    exchange: String,
+    max_file_bytes: u64,
+    rotate_after: Duration,
+    connections: u64,
+    file: Option<RecordingFile>,
+}
+
+struct RecordingFile {
+    path: PathBuf,
+    file: File,
+    /// Frames not written yet
+    pending: Vec<u8>,
+    opened_at: Instant,
+    /// Uncompressed bytes recorded
+    written: u64,
+}
+
+impl Recorder {
+    pub fn new(directory: impl Into<PathBuf>, exchange: &str, max_file_bytes: u64, rotate_after: Duration) -> Self {
+        let state = RecorderState {
+            directory: directory.into(),
+            exchange: exchange.to_string(),
+            max_file_bytes,
+            rotate_after,
+            connections: 0,
+            file: None,
+        };
+        Self { state: SharedRef::new(state) }
+    }
+
+    pub fn exchange(&self) -> String {
+        self.state.lock().exchange.clone()
+    }
+
+    /// Id of a new connection, frames are recorded along with the id of the connection they were received on
+    pub fn next_connection_id(&self) -> u64 {
+        let mut state = self.state.lock();
+        state.connections += 1;
+        state.connections
+    }
+
+    pub fn record(&self, connection_id: u64, received_time: Timestamp, message: &Message) -> ArbitrageResult<()> {
+        let mut state = self.state.lock();
+        let frame = RecordedFrame {
+            received_time,
+            exchange: state.exchange.clone(),
+            connection_id,
+            message: RecordedMessage::from(message),
+        };
+        let mut line = serde_json::to_vec(&frame).map_err(ArbitrageError::JsonError)?;
+        line.push(b'\n');
+
+        let file = state.file()?;
+        file.pending.extend_from_slice(&line);
+        file.written += line.len() as u64;
+        if file.pending.len() >= MEMBER_BYTES {
+            file.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Writes the buffered frames, so that the file can be read up to the last recorded frame
+    pub fn flush(&self) -> ArbitrageResult<()> {
+        match self.state.lock().file.as_mut() {
+            Some(file) => file.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl RecorderState {
+    /// The file to write to, rotating the current one when it is full or too old
+    fn file(&mut self) -> ArbitrageResult<&mut RecordingFile> {
+        let rotate = self.file.as_ref().is_some_and(|file| {
+            file.written >= self.max_file_bytes || file.opened_at.elapsed() >= self.rotate_after
+        });
+        if rotate {
+            if let Some(mut file) = self.file.take() {
+                file.flush()?;
+                log::info!("recording rotated to a new file, {} is complete", file.path.display());
+            }
+        }
+
+        if self.file.is_none() {
+            fs::create_dir_all(&self.directory).map_err(|e| recorder_error(&self.directory, e))?;
+            let file_name = format!("{}-{}.{}", self.exchange, Timestamp::now().strftime("%Y%m%dT%H%M%S%.3fZ"), RECORDING_EXTENSION);
+            let path = self.directory.join(file_name);
+            let file = OpenOptions::new().write(true).create_new(true).open(&path).map_err(|e| recorder_error(&path, e))?;
+            log::info!("recording {} frames to {}", self.exchange, path.display());
+            self.file = Some(RecordingFile { path, file, pending: vec![], opened_at: Instant::now(), written: 0 });
+        }
+
+        Ok(self.file.as_mut().expect("recording file should be open"))
+    }
+}
+
+impl RecordingFile {
+    /// Compresses the pending frames into a new gzip member appended to the file
+    fn flush(&mut self) -> ArbitrageResult<()> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder
+            .write_all(&self.pending)
+            .and_then(|_| encoder.finish())
+            .and_then(|member| self.file.write_all(&member))
+            .map_err(|e| recorder_error(&self.path, e))?;
+        self.pending.clear();
+        Ok(())
+    }
+}
+
+impl Drop for RecordingFile {
+    fn drop(&mut self) {
+        if let Err(e) = self.flush() {
+            log::error!("{}", e);
+        }
+    }
+}
+
+fn recorder_error(path: &std::path::Path, error: std::io::Error) -> ArbitrageError {
+    ArbitrageError::GenericError(format!("failed to record to {}: {}", path.display(), error))
+}
+
+
+/// Records every frame before handing it to the wrapped callback
+///
+/// A failure to record is logged and counted, it never interrupts the feed.
+#[derive(Clone)]
+pub struct RecordingCallback<C> {
+    callback: C,
+    recorder: Recorder,
+    connection_id: u64,
+}
+
+impl<C> RecordingCallback<C> {
+    pub fn new(callback: C, recorder: Recorder) -> Self {
+        Self { callback, recorder, connection_id: 0 }
+    }
+
+    fn flush(&self) {
+        if let Err(e) = self.recorder.flush() {
+            metrics::RECORDER_ERRORS.with_label_values(&[&self.recorder.exchange()]).inc();
+            log::error!("{}", e);
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl<C> WsCallback for RecordingCallback<C>
+where
+    C: WsCallback + Send,
+{
+    type Command = C::Command;
+
+    async fn on_connect(&mut self, timestamp: Timestamp) -> ArbitrageResult<()> {
+        self.connection_id = self.recorder.next_connection_id();
+        self.callback.on_connect(timestamp).await
+    }
+
+    async fn on_message(&mut self, message: Message, received_time: Timestamp) -> ArbitrageResult<()> {
+        if let Err(e) = self.recorder.record(self.connection_id, received_time, &message) {
+            metrics::RECORDER_ERRORS.with_label_values(&[&self.recorder.exchange()]).inc();
+            log::error!("{}", e);
+        }
+        self.callback.on_message(message, received_time).await
+    }
+
+    fn on_disconnect(&mut self) -> ArbitrageResult<()> {
+        self.flush();
+        self.callback.on_disconnect()
+    }
+
+    fn on_heartbeat(&mut self) -> ArbitrageResult<()> {
+        self.flush();
+        self.callback.on_heartbeat()
+    }
+
+    async fn on_command(&mut self, command: Self::Command) -> ArbitrageResult<()> {
+        self.callback.on_command(command).await
+    }
+
+    fn on_shutdown(&mut self) -> ArbitrageResult<()> {
+        self.flush();
+        self.callback.on_shutdown()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader};
+
+    use flate2::read::MultiGzDecoder;
+
+    use super::*;
+
+    fn read_frames(path: &std::path::Path) -> Vec<RecordedFrame> {
+        let reader = BufReader::new(MultiGzDecoder::new(File::open(path).unwrap()));
+        reader.lines().map(|line| serde_json::from_str(&line.unwrap()).unwrap()).collect()
+    }
+
+    #[test]
+    fn test_record_and_rotate() {
+        let directory = std::env::temp_dir().join(format!("recorder-test-{}", std::process::id()));
+        let recorder = Recorder::new(&directory, "okex", 150, Duration::from_secs(3600));
+        let connection_id = recorder.next_connection_id();
+        let messages = [
+            Message::text(r#"{"arg":{"channel":"books","instId":"BTC-USD-250221-90000-P"},"action":"snapshot","data":[]}"#),
+            Message::text("pong"),
+            Message::Close(Some(CloseFrame { code: CloseCode::Away, reason: "going away".into() })),
+        ];
+        for message in messages.iter() {
+            recorder.record(connection_id, Timestamp::UNIX_EPOCH, message).unwrap();
+            // Files are opened with a millisecond timestamp in their name
+            std::thread::sleep(Duration::from_millis(2));
+        }
+        recorder.flush().unwrap();
+
+        // The first frame fills the first file, the next ones go to a second file
+        let mut paths = fs::read_dir(&directory).unwrap().map(|entry| entry.unwrap().path()).collect::<Vec<_>>();
+        paths.sort();
+        assert_eq!(paths.len(), 2, "{:?}", paths);
+        let frames = paths.iter().flat_map(|path| read_frames(path)).collect::<Vec<_>>();
+        fs::remove_dir_all(&directory).unwrap();
+
+        assert_eq!(frames.len(), 3);
+        assert!(frames.iter().all(|frame| frame.exchange == "okex" && frame.connection_id == 1));
+        let replayed = frames.into_iter().map(|frame| Message::from(frame.message)).collect::<Vec<_>>();
+        assert_eq!(replayed, messages);
+    }
+}
@@ -0,0 +1,236 @@
+use std::{cmp::Reverse, collections::BinaryHeap, fmt, fs::{self, File}, io::{BufRead, BufReader, Lines}, path::{Path, PathBuf}, str::FromStr, time::Duration};
+
+use common::{ArbitrageError, ArbitrageResult};
+use flate2::read::MultiGzDecoder;
+use jiff::Timestamp;
+use tokio::sync::mpsc::Receiver;
+use tokio_tungstenite::tungstenite::Message;
+
+use crate::{ConnectionState, RecordedFrame, WsCallback, RECORDING_EXTENSION};
+
+
+/// Speed at which recorded frames are replayed
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReplaySpeed {
+    /// Multiple of the recorded pace, 1 being real speed
+    Paced(f64),
+    AsFastAsPossible,
+}
+
+impl ReplaySpeed {
+    /// Wall clock time to wait between two frames received `elapsed` apart
+    pub fn delay(&self, elapsed: Duration) -> Option<Duration> {
+        match self {
+            ReplaySpeed::Paced(multiple) => Some(elapsed.div_f64(*multiple)),
+            ReplaySpeed::AsFastAsPossible => None,
+        }
+    }
+}
+
+/// Parses `max`, or a multiple of the real speed such as `1x`, `10x` or `0.5x`
+impl FromStr for ReplaySpeed {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "max" {
+            return Ok(ReplaySpeed::AsFastAsPossible);
+        }
+        match s.strip_suffix('x').and_then(|multiple| multiple.parse::<f64>().ok()) {
+            Some(multiple) if multiple > 0.0 && multiple.is_finite() => Ok(ReplaySpeed::Paced(multiple)),
+            _ => Err(format!("invalid replay speed {:?}, expected e.g. 1x, 10x or max", s)),
+        }
+    }
+}
+
+impl fmt::Display for ReplaySpeed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReplaySpeed::Paced(multiple) => write!(f, "{}x", multiple),
+            ReplaySpeed::AsFastAsPossible => write!(f, "max"),
+        }
+    }
+}
+
+
+/// Reads the frames of recordings in the order they were received, across exchanges and files
+pub struct RecordingReader {
+    files: Vec<RecordingFileReader>,
+    /// Receive time of the next frame of each file
+    heads: BinaryHeap<Reverse<(Timestamp, usize)>>,
+}
+
+struct RecordingFileReader {
+    path: PathBuf,
+    lines: Lines<BufReader<MultiGzDecoder<File>>>,
+    next: Option<RecordedFrame>,
+}
+
+impl RecordingReader {
+    /// Opens the given recording files, directories are expanded to the recordings they contain
+    pub fn open(paths: &[PathBuf]) -> ArbitrageResult<Self> {
+        let mut files = vec![];
+        for path in recording_paths(paths)? {
+            let file = File::open(&path).map_err(|e| replay_error(&path, e))?;
+            let lines = BufReader::new(MultiGzDecoder::new(file)).lines();
+            files.push(RecordingFileReader { path, lines, next: None });
+        }
+        if files.is_empty() {
+            return Err(ArbitrageError::GenericError(format!("no recording found in {:?}", paths)));
+        }
+
+        let mut heads = BinaryHeap::new();
+        for (index, file) in files.iter_mut().enumerate() {
+            if let Some(received_time) = file.advance()? {
+                heads.push(Reverse((received_time, index)));
+            }
+        }
+        Ok(Self { files, heads })
+    }
+}
+
+impl Iterator for RecordingReader {
+    type Item = ArbitrageResult<RecordedFrame>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let Reverse((_, index)) = self.heads.pop()?;
+        let file = &mut self.files[index];
+        let frame = file.next.take().expect("a file in the heads should have a next frame");
+        match file.advance() {
+            Ok(Some(received_time)) => self.heads.push(Reverse((received_time, index))),
+            Ok(None) => {}
+            Err(e) => return Some(Err(e)),
+        }
+        Some(Ok(frame))
+    }
+}
+
+impl RecordingFileReader {
+    /// Reads the next frame, returning its receive time, or None at the end of the file
+    fn advance(&mut self) -> ArbitrageResult<Option<Timestamp>> {
+        self.next = match self.lines.next() {
+            Some(line) => {
+                let line = line.map_err(|e| replay_error(&self.path, e))?;
+                let frame = serde_json::from_str::<RecordedFrame>(&line).map_err(ArbitrageError::JsonError)?;
+                Some(frame)
+            }
+            None => None,
+        };
+        Ok(self.next.as_ref().map(|frame| frame.received_time))
+    }
+}
+
+fn recording_paths(paths: &[PathBuf]) -> ArbitrageResult<Vec<PathBuf>> {
+    let mut recordings = vec![];
+    for path in paths {
+        if path.is_dir() {
+            let mut entries = fs::read_dir(path)
+                .map_err(|e| replay_error(path, e))?
+                .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+                .filter(|path| path.to_string_lossy().ends_with(RECORDING_EXTENSION))
+                .collect::<Vec<_>>();
+            entries.sort();
+            recordings.extend(entries);
+        } else {
+            recordings.push(path.clone());
+        }
+    }
+    Ok(recordings)
+}
+
+fn replay_error(path: &Path, error: std::io::Error) -> ArbitrageError {
+    ArbitrageError::GenericError(format!("failed to read recording {}: {}", path.display(), error))
+}
+
+
+/// Feeds recorded frames to a callback instead of a websocket
+///
+/// The callback sees a new connection whenever the connection id of the frames changes, the messages
+/// it writes are dropped and it is not sent heartbeats.
+pub struct ReplayClient<C> {
+    callback: C,
+    writes: Receiver<Message>,
+    connection: ConnectionState,
+    connection_id: Option<u64>,
+}
+
+impl<C> ReplayClient<C> {
+    pub fn new(callback: C, writes: Receiver<Message>, connection: ConnectionState) -> Self {
+        Self { callback, writes, connection, connection_id: None }
+    }
+}
+
+/// A replayed exchange, regardless of the type of its callback
+#[async_trait::async_trait]
+pub trait ReplayTarget: Send {
+    async fn replay(&mut self, frame: RecordedFrame) -> ArbitrageResult<()>;
+}
+
+#[async_trait::async_trait]
+impl<C> ReplayTarget for ReplayClient<C>
+where
+    C: WsCallback + Send,
+{
+    async fn replay(&mut self, frame: RecordedFrame) -> ArbitrageResult<()> {
+        if self.connection_id != Some(frame.connection_id) {
+            if self.connection_id.is_some() {
+                self.connection.on_disconnected();
+                self.callback.on_disconnect()?;
+            }
+            self.connection_id = Some(frame.connection_id);
+            self.connection.on_connected(frame.received_time);
+            self.callback.on_connect(frame.received_time).await?;
+        }
+
+        self.connection.on_message(frame.received_time);
+        let result = self.callback.on_message(frame.message.into(), frame.received_time).await;
+        while let Ok(message) = self.writes.try_recv() {
+            log::trace!("dropping message written during replay: {:?}", message);
+        }
+        result
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use tokio_tungstenite::tungstenite::Message;
+
+    use crate::Recorder;
+
+    use super::*;
+
+    #[test]
+    fn test_replay_speed() {
+        assert_eq!("max".parse::<ReplaySpeed>(), Ok(ReplaySpeed::AsFastAsPossible));
+        assert_eq!("1x".parse::<ReplaySpeed>(), Ok(ReplaySpeed::Paced(1.0)));
+        assert_eq!("0.5x".parse::<ReplaySpeed>(), Ok(ReplaySpeed::Paced(0.5)));
+        assert!("0x".parse::<ReplaySpeed>().is_err());
+        assert!("fast".parse::<ReplaySpeed>().is_err());
+        assert_eq!(ReplaySpeed::Paced(10.0).delay(Duration::from_secs(1)), Some(Duration::from_millis(100)));
+    }
+
+    #[test]
+    fn test_read_recordings_in_order() {
+        let directory = std::env::temp_dir().join(format!("replay-test-{}", std::process::id()));
+        let okex = Recorder::new(&directory, "okex", 1 << 20, Duration::from_secs(3600));
+        let deribit = Recorder::new(&directory, "deribit", 1 << 20, Duration::from_secs(3600));
+        for millis in [0, 20, 30] {
+            okex.record(1, Timestamp::from_millisecond(millis).unwrap(), &Message::text("okex")).unwrap();
+        }
+        for millis in [10, 20, 40] {
+            deribit.record(1, Timestamp::from_millisecond(millis).unwrap(), &Message::text("deribit")).unwrap();
+        }
+        okex.flush().unwrap();
+        deribit.flush().unwrap();
+
+        let frames = RecordingReader::open(std::slice::from_ref(&directory)).unwrap().collect::<ArbitrageResult<Vec<_>>>().unwrap();
+        fs::remove_dir_all(&directory).unwrap();
+
+        let frames = frames
+            .iter()
+            .map(|frame| (frame.received_time.as_millisecond(), frame.exchange.as_str()))
+            .collect::<Vec<_>>();
+        // Frames received at the same time are read in the order of the file names
+        assert_eq!(frames, vec![(0, "okex"), (10, "deribit"), (20, "deribit"), (20, "okex"), (30, "okex"), (40, "deribit")]);
+    }
+}